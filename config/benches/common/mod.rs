@@ -0,0 +1,27 @@
+//! Helpers shared by the benches, each of them uses only some.
+#![allow(dead_code)]
+
+/// Deterministic pseudo random numbers, so runs compare the same rule lists.
+pub struct Lcg(pub u64);
+
+impl Lcg {
+    pub fn next(&mut self) -> u32 {
+        self.0 = self
+            .0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1);
+        (self.0 >> 32) as u32
+    }
+
+    pub fn label(&mut self) -> String {
+        let len = 3 + self.next() % 8;
+        (0..len)
+            .map(|_| (b'a' + (self.next() % 26) as u8) as char)
+            .collect()
+    }
+
+    pub fn domain(&mut self) -> String {
+        let tld = ["com", "net", "org", "cn", "io"][(self.next() % 5) as usize];
+        format!("{}.{}.{}", self.label(), self.label(), tld)
+    }
+}
@@ -0,0 +1,59 @@
+//! Domain rule matching with adblock sized rule lists, against a linear scan of the rules.
+mod common;
+
+use common::Lcg;
+use config::rule::{Matcher, ProxyRules, Rule};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::str::FromStr;
+
+/// Mostly suffixes like adblock lists, some exact domains and a few keywords.
+fn rules(n: usize) -> ProxyRules {
+    let mut rng = Lcg(1);
+    let rules = (0..n)
+        .map(|i| {
+            let rule = match i % 20 {
+                0 => format!("DOMAIN-KEYWORD,{},REJECT", rng.label()),
+                1..=4 => format!("DOMAIN,{},REJECT", rng.domain()),
+                _ => format!("DOMAIN-SUFFIX,{},REJECT", rng.domain()),
+            };
+            Rule::from_str(&rule).unwrap()
+        })
+        .collect();
+    ProxyRules::new(rules)
+}
+
+fn linear_scan<'a>(rules: &'a ProxyRules, domain: &str) -> Option<&'a Rule> {
+    rules.rules().iter().find(|rule| match &rule.matcher {
+        Matcher::Domain(d) => domain == d,
+        Matcher::DomainSuffix(d) => domain.ends_with(d.as_str()),
+        Matcher::DomainKeyword(d) => domain.contains(d.as_str()),
+        _ => false,
+    })
+}
+
+fn bench_domain_rules(c: &mut Criterion) {
+    let mut group = c.benchmark_group("domain_rules");
+    let mut rng = Lcg(2);
+    let domains: Vec<String> = (0..256).map(|_| rng.domain()).collect();
+    for n in [1_000, 10_000, 100_000].iter() {
+        let rules = rules(*n);
+        group.bench_with_input(BenchmarkId::new("index", n), &rules, |b, rules| {
+            b.iter(|| {
+                for domain in &domains {
+                    black_box(rules.rule_for_domain(domain));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("linear", n), &rules, |b, rules| {
+            b.iter(|| {
+                for domain in &domains {
+                    black_box(linear_scan(rules, domain));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_domain_rules);
+criterion_main!(benches);
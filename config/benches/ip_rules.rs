@@ -0,0 +1,54 @@
+//! IP rule matching with chnroutes sized rule lists, against a linear scan of the rules.
+mod common;
+
+use common::Lcg;
+use config::rule::{Matcher, ProxyRules, Rule};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+fn rules(n: usize) -> ProxyRules {
+    let mut rng = Lcg(1);
+    let rules = (0..n)
+        .map(|_| {
+            let len = 8 + rng.next() % 17;
+            let ip = Ipv4Addr::from(rng.next() & (u32::MAX << (32 - len)));
+            Rule::from_str(&format!("IP-CIDR,{}/{},DIRECT", ip, len)).unwrap()
+        })
+        .collect();
+    ProxyRules::new(rules)
+}
+
+fn linear_scan(rules: &ProxyRules, ip: Ipv4Addr) -> Option<&Rule> {
+    rules.rules().iter().find(|rule| match &rule.matcher {
+        Matcher::IpCidr(cidr) => cidr.contains_addr(&ip.into()),
+        _ => false,
+    })
+}
+
+fn bench_ip_rules(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ip_rules");
+    let mut rng = Lcg(2);
+    let ips: Vec<Ipv4Addr> = (0..1024).map(|_| Ipv4Addr::from(rng.next())).collect();
+    for n in [1_000, 10_000, 50_000].iter() {
+        let rules = rules(*n);
+        group.bench_with_input(BenchmarkId::new("trie", n), &rules, |b, rules| {
+            b.iter(|| {
+                for ip in &ips {
+                    black_box(rules.rule_for_ip(IpAddr::V4(*ip)));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("linear", n), &rules, |b, rules| {
+            b.iter(|| {
+                for ip in &ips {
+                    black_box(linear_scan(rules, *ip));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_ip_rules);
+criterion_main!(benches);
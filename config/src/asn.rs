@@ -0,0 +1,38 @@
+//! Autonomous system numbers of addresses from a MaxMind `GeoLite2-ASN.mmdb`, used by
+//! `IP-ASN` rules.
+use maxminddb::{geoip2, Reader};
+use std::fmt;
+use std::io::{self, Error, ErrorKind};
+use std::net::IpAddr;
+
+pub struct AsnDb {
+    reader: Reader<Vec<u8>>,
+}
+
+impl fmt::Debug for AsnDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsnDb")
+            .field("database_type", &self.reader.metadata.database_type)
+            .finish()
+    }
+}
+
+impl AsnDb {
+    pub fn from_file(path: &str) -> io::Result<Self> {
+        let reader = Reader::open_readfile(path).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("read asn database {}: {}", path, e),
+            )
+        })?;
+        Ok(AsnDb { reader })
+    }
+
+    /// The autonomous system announcing `ip`, `None` when the database does not know it.
+    pub fn asn(&self, ip: IpAddr) -> Option<u32> {
+        self.reader
+            .lookup::<geoip2::Asn>(ip)
+            .ok()?
+            .autonomous_system_number
+    }
+}
@@ -0,0 +1,218 @@
+use crate::Config;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What changed between two configs, used to report the effect of a reload.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConfigDiff {
+    pub rules_added: Vec<String>,
+    pub rules_removed: Vec<String>,
+    pub servers_added: Vec<String>,
+    pub servers_removed: Vec<String>,
+    /// Names of the other settings whose value changed.
+    pub settings_changed: Vec<String>,
+}
+
+macro_rules! changed_settings {
+    ($old:expr, $new:expr, $($field:ident),+ $(,)?) => {{
+        let mut changed = vec![];
+        $(
+            if format!("{:?}", $old.$field) != format!("{:?}", $new.$field) {
+                changed.push(stringify!($field).to_string());
+            }
+        )+
+        changed
+    }};
+}
+
+impl ConfigDiff {
+    pub fn between(old: &Config, new: &Config) -> Self {
+        let old_rules = old.rules.rules();
+        let new_rules = new.rules.rules();
+        let mut settings_changed = changed_settings!(
+            old,
+            new,
+            mode,
+            profile,
+            subscription_url,
+            subscription_path,
+            subscription_interval,
+            proxy_groups,
+            final_target,
+            rule_providers,
+            dns_start_ip,
+            dns_servers,
+            dns_resolvers,
+            dnssec,
+            dnssec_trust_anchor,
+            dns_ipv6,
+            dns_rebind_protection,
+            dns_rebind_allowlist,
+            lan_dns,
+            system_dns,
+            dns_query_log_size,
+            rule_decision_log_size,
+            dns_ttl,
+            nat64_prefix,
+            tun_name,
+            tun_ip,
+            verbose,
+            log_rate_limit,
+            tun_cidr,
+            tun_ip6,
+            tun_cidr6,
+            tun_stack,
+            tun_fd,
+            tun_persistent,
+            tun_offload,
+            tun_queues,
+            user,
+            tun_mtu,
+            http3,
+            sniff_timeout,
+            sniff_override,
+            geosite_file,
+            asn_file,
+            domestic_ip_file,
+            domestic_dns,
+            dns_listen,
+            tproxy_listen,
+            redirect_listen,
+            dot_listen,
+            doh_listen,
+            tls_cert,
+            tls_key,
+            dns_hijack,
+            kill_switch,
+            auto_route,
+            auto_route_exclude,
+            gateway_mode,
+            ping_timeout,
+            dns_timeout,
+            probe_timeout,
+            connect_timeout,
+            read_timeout,
+            write_timeout,
+            max_connect_errors,
+            proxy_fail_closed,
+            udp_queue_size,
+            tcp_relay_buffer,
+            tcp_socket_buffer,
+            quarantine_duration,
+            api_listen,
+            api_secret,
+            api_allowed_origins,
+            watch_config,
+            watch_network,
+            lan_bypass,
+            conn_events,
+            conn_hook,
+            conn_hook_timeout,
+            conn_hook_fail_closed,
+            interactive,
+            interactive_timeout,
+            interactive_rules_file,
+            task_max_failures,
+            rule_script,
+        );
+
+        let rule_name = |r: &crate::rule::Rule| match &r.tag {
+            Some(tag) => format!("{} (tag: {})", r, tag),
+            None => r.to_string(),
+        };
+        let rules_added = difference(new_rules, old_rules, rule_name);
+        let rules_removed = difference(old_rules, new_rules, rule_name);
+        // Rules are evaluated in order, so moving a rule changes behaviour too.
+        if rules_added.is_empty() && rules_removed.is_empty() && old_rules != new_rules {
+            settings_changed.push("rules_order".to_string());
+        }
+
+        let server_name = |s: &crate::ServerConfig| format!("{} ({})", s.name(), s.addr());
+        ConfigDiff {
+            rules_added,
+            rules_removed,
+            servers_added: difference(&new.servers, &old.servers, server_name),
+            servers_removed: difference(&old.servers, &new.servers, server_name),
+            settings_changed,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == ConfigDiff::default()
+    }
+}
+
+/// Items of `a` not in `b`, rendered with `name`.
+fn difference<T: PartialEq>(a: &[T], b: &[T], name: impl Fn(&T) -> String) -> Vec<String> {
+    a.iter().filter(|x| !b.contains(x)).map(name).collect()
+}
+
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no changes");
+        }
+        write!(
+            f,
+            "rules +{}/-{}, servers +{}/-{}",
+            self.rules_added.len(),
+            self.rules_removed.len(),
+            self.servers_added.len(),
+            self.servers_removed.len()
+        )?;
+        if !self.settings_changed.is_empty() {
+            write!(
+                f,
+                ", settings changed: {}",
+                self.settings_changed.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config::config_text;
+
+    const SERVERS_AND_RULES: &str = r#"servers:
+  - name: server1
+    addr: 127.0.0.1:1080
+    protocol: Socks5
+rules:
+  - 'DOMAIN-SUFFIX,google.com,PROXY'
+  - 'MATCH,DIRECT'
+"#;
+
+    fn config(s: &str) -> Config {
+        Config::from_reader(s.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_no_changes() {
+        let text = config_text(SERVERS_AND_RULES);
+        let diff = ConfigDiff::between(&config(&text), &config(&text));
+        assert!(diff.is_empty());
+        assert_eq!(diff.to_string(), "no changes");
+    }
+
+    #[test]
+    fn test_diff() {
+        let text = config_text(SERVERS_AND_RULES);
+        let new = text
+            .replace("max_connect_errors: 2", "max_connect_errors: 3")
+            .replace("server1", "server2")
+            .replace("google.com", "github.com");
+        let diff = ConfigDiff::between(&config(&text), &config(&new));
+        assert_eq!(diff.rules_added, vec!["DOMAIN-SUFFIX,github.com,PROXY"]);
+        assert_eq!(diff.rules_removed, vec!["DOMAIN-SUFFIX,google.com,PROXY"]);
+        assert_eq!(diff.servers_added, vec!["server2 (127.0.0.1:1080)"]);
+        assert_eq!(diff.servers_removed, vec!["server1 (127.0.0.1:1080)"]);
+        assert_eq!(diff.settings_changed, vec!["max_connect_errors"]);
+        assert_eq!(
+            diff.to_string(),
+            "rules +1/-1, servers +1/-1, settings changed: max_connect_errors"
+        );
+    }
+}
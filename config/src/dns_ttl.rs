@@ -0,0 +1,85 @@
+//! Clamps for the TTL of DNS answers, so CDNs answering with TTL 1 still get cached and
+//! huge TTLs do not delay failover.
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, PartialEq)]
+pub struct DnsTtl {
+    /// Answers are kept at least this long.
+    #[serde(default, deserialize_with = "crate::duration::deserialize_option")]
+    #[schemars(with = "Option<String>")]
+    pub min: Option<Duration>,
+    /// Answers are kept at most this long.
+    #[serde(default, deserialize_with = "crate::duration::deserialize_option")]
+    #[schemars(with = "Option<String>")]
+    pub max: Option<Duration>,
+    /// Clamps for domains under a suffix, the first matching one replaces the global clamps.
+    #[serde(default)]
+    pub domains: Vec<DomainTtl>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct DomainTtl {
+    /// Domain suffix, `example.com` also covers `www.example.com`.
+    pub domain: String,
+    #[serde(default, deserialize_with = "crate::duration::deserialize_option")]
+    #[schemars(with = "Option<String>")]
+    pub min: Option<Duration>,
+    #[serde(default, deserialize_with = "crate::duration::deserialize_option")]
+    #[schemars(with = "Option<String>")]
+    pub max: Option<Duration>,
+}
+
+impl DnsTtl {
+    /// The TTL, in seconds, to answer `domain` with instead of `ttl`.
+    pub fn clamp(&self, domain: &str, ttl: u32) -> u32 {
+        let (min, max) = match self
+            .domains
+            .iter()
+            .find(|d| is_subdomain(domain, &d.domain))
+        {
+            Some(d) => (d.min, d.max),
+            None => (self.min, self.max),
+        };
+        let mut ttl = ttl;
+        if let Some(max) = max {
+            ttl = ttl.min(max.as_secs() as u32);
+        }
+        if let Some(min) = min {
+            ttl = ttl.max(min.as_secs() as u32);
+        }
+        ttl
+    }
+}
+
+fn is_subdomain(domain: &str, suffix: &str) -> bool {
+    domain == suffix
+        || (domain.ends_with(suffix) && domain[..domain.len() - suffix.len()].ends_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clamp() {
+        let ttl: DnsTtl = serde_yaml::from_str(
+            r#"
+min: 60s
+max: 3600s
+domains:
+  - domain: example.com
+    max: 10s
+"#,
+        )
+        .unwrap();
+        assert_eq!(ttl.clamp("cdn.net", 1), 60);
+        assert_eq!(ttl.clamp("cdn.net", 86400), 3600);
+        assert_eq!(ttl.clamp("cdn.net", 600), 600);
+        assert_eq!(ttl.clamp("www.example.com", 600), 10);
+        assert_eq!(ttl.clamp("www.example.com", 1), 1);
+        assert_eq!(ttl.clamp("badexample.com", 1), 60);
+        assert_eq!(DnsTtl::default().clamp("cdn.net", 1), 1);
+    }
+}
@@ -0,0 +1,137 @@
+//! `DOMAIN`, `DOMAIN-SUFFIX` and `DOMAIN-KEYWORD` rules compiled into a hash map, a trie
+//! over reversed domains and an Aho-Corasick automaton, so lists with hundreds of
+//! thousands of entries match in time proportional to the length of the domain.
+use aho_corasick::AhoCorasick;
+use std::collections::HashMap;
+
+/// Index of the first rule of each domain, suffix and keyword.
+#[derive(Debug, Default)]
+pub struct DomainIndex {
+    exact: HashMap<String, usize>,
+    suffixes: SuffixTrie,
+    keywords: Option<AhoCorasick>,
+    /// Rule of each pattern of `keywords`.
+    keyword_rules: Vec<usize>,
+}
+
+/// Keys are the bytes of a suffix from the last one, so all suffixes of a domain are
+/// found walking it backwards once.
+#[derive(Debug)]
+struct SuffixTrie {
+    /// Child of a node for a byte, node 0 is the root.
+    edges: HashMap<(u32, u8), u32>,
+    values: Vec<Option<usize>>,
+}
+
+impl Default for SuffixTrie {
+    fn default() -> Self {
+        SuffixTrie {
+            edges: HashMap::new(),
+            values: vec![None],
+        }
+    }
+}
+
+impl SuffixTrie {
+    fn insert(&mut self, suffix: &str, rule: usize) {
+        let mut node = 0;
+        for b in suffix.bytes().rev() {
+            let next = self.values.len() as u32;
+            node = *self.edges.entry((node, b)).or_insert(next);
+            if node == next {
+                self.values.push(None);
+            }
+        }
+        let value = &mut self.values[node as usize];
+        *value = min(*value, Some(rule));
+    }
+
+    /// The first rule among the suffixes of `domain`.
+    fn first_match(&self, domain: &str) -> Option<usize> {
+        let mut node = 0;
+        let mut first = self.values[0];
+        for b in domain.bytes().rev() {
+            node = match self.edges.get(&(node, b)) {
+                Some(child) => *child,
+                None => break,
+            };
+            first = min(first, self.values[node as usize]);
+        }
+        first
+    }
+}
+
+fn min(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+impl DomainIndex {
+    /// Build the index from `(rule, domain)` pairs of each kind, lists can be in any order.
+    pub fn new(
+        domains: Vec<(usize, &str)>,
+        suffixes: Vec<(usize, &str)>,
+        keywords: Vec<(usize, &str)>,
+    ) -> Self {
+        let mut index = DomainIndex::default();
+        for (rule, domain) in domains {
+            let first = index.exact.entry(domain.to_string()).or_insert(rule);
+            *first = (*first).min(rule);
+        }
+        for (rule, suffix) in suffixes {
+            index.suffixes.insert(suffix, rule);
+        }
+        let mut patterns: Vec<&str> = vec![];
+        let mut positions: HashMap<&str, usize> = HashMap::new();
+        for (rule, keyword) in keywords {
+            match positions.get(keyword) {
+                Some(&i) => index.keyword_rules[i] = index.keyword_rules[i].min(rule),
+                None => {
+                    positions.insert(keyword, patterns.len());
+                    patterns.push(keyword);
+                    index.keyword_rules.push(rule);
+                }
+            }
+        }
+        if !patterns.is_empty() {
+            index.keywords = Some(AhoCorasick::new(&patterns));
+        }
+        index
+    }
+
+    /// The first rule matching `domain`.
+    pub fn first_match(&self, domain: &str) -> Option<usize> {
+        let exact = self.exact.get(domain).copied();
+        let suffix = self.suffixes.first_match(domain);
+        let keyword = self.keywords.as_ref().and_then(|ac| {
+            ac.find_overlapping_iter(domain)
+                .map(|m| self.keyword_rules[m.pattern()])
+                .min()
+        });
+        min(min(exact, suffix), keyword)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_index() {
+        let index = DomainIndex::new(
+            vec![(3, "www.google.com")],
+            vec![(1, "google.com"), (4, "com"), (0, "mail.google.com")],
+            vec![(2, "goo"), (5, "ogle")],
+        );
+        assert_eq!(index.first_match("mail.google.com"), Some(0));
+        assert_eq!(index.first_match("www.google.com"), Some(1));
+        // Suffixes are matched like `str::ends_with`.
+        assert_eq!(index.first_match("notgoogle.com"), Some(1));
+        assert_eq!(index.first_match("goo.gl"), Some(2));
+        assert_eq!(index.first_match("example.com"), Some(4));
+        assert_eq!(index.first_match("oogle.org"), Some(5));
+        assert_eq!(index.first_match("example.org"), None);
+    }
+}
@@ -0,0 +1,94 @@
+//! `${NAME}` in the config is replaced by the environment variable `NAME` before parsing,
+//! so passwords and hosts can stay out of the file. `${NAME:-default}` falls back to
+//! `default` when the variable is unset or empty, `$${` is a literal `${`.
+//!
+//! Lines that are comments as a whole are left alone.
+
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// `text` with the variables of the process environment expanded.
+pub fn expand(text: &str) -> Result<String, String> {
+    expand_with(text, |name| std::env::var(name).ok())
+}
+
+fn expand_with(text: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    for (i, line) in text.lines().enumerate() {
+        if line.trim_start().starts_with('#') {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+        let mut rest = line;
+        while let Some(pos) = rest.find("${") {
+            if rest[..pos].ends_with('$') {
+                out.push_str(&rest[..pos - 1]);
+                out.push_str("${");
+                rest = &rest[pos + 2..];
+                continue;
+            }
+            out.push_str(&rest[..pos]);
+            let end = rest[pos..]
+                .find('}')
+                .ok_or_else(|| format!("line {}: unclosed ${{ in the config.", i + 1))?;
+            let inner = &rest[pos + 2..pos + end];
+            let (name, default) = match inner.find(":-") {
+                Some(at) => (&inner[..at], Some(&inner[at + 2..])),
+                None => (inner, None),
+            };
+            if !is_name(name) {
+                return Err(format!(
+                    "line {}: ${{{}}} is not a valid environment variable name.",
+                    i + 1,
+                    inner
+                ));
+            }
+            let value = match (lookup(name), default) {
+                (Some(value), Some(default)) if value.is_empty() => default.to_string(),
+                (Some(value), _) => value,
+                (None, Some(default)) => default.to_string(),
+                (None, None) => {
+                    return Err(format!(
+                        "line {}: environment variable {} is not set and has no default.",
+                        i + 1,
+                        name
+                    ))
+                }
+            };
+            out.push_str(&value);
+            rest = &rest[pos + end + 1..];
+        }
+        out.push_str(rest);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let lookup = |name: &str| match name {
+            "SS_PASSWORD" => Some("s3cret".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let text = "password: ${SS_PASSWORD}\naddr: ${SS_HOST:-hk.example.com}:${PORT:-8388}\nname: ${EMPTY:-x}${EMPTY}\n# password: ${UNSET}\nliteral: $${SS_PASSWORD}\n";
+        assert_eq!(
+            expand_with(text, lookup).unwrap(),
+            "password: s3cret\naddr: hk.example.com:8388\nname: x\n# password: ${UNSET}\nliteral: ${SS_PASSWORD}\n"
+        );
+        assert_eq!(
+            expand_with("a: 1\npassword: ${UNSET}", lookup).unwrap_err(),
+            "line 2: environment variable UNSET is not set and has no default."
+        );
+        assert!(expand_with("password: ${SS PASSWORD}", lookup).is_err());
+        assert!(expand_with("password: ${SS_PASSWORD", lookup).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! Stable codes for the failures users see, `SEEKER-E1001` and so on.
+//!
+//! Error messages start with their code, so a log line can be looked up with
+//! `seeker explain <code>` or a web search whatever the version or language of the message.
+//! Codes are never reused: retire one instead of giving it a new meaning.
+use std::fmt;
+use std::io;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ErrorCode {
+    pub code: &'static str,
+    pub summary: &'static str,
+    pub causes: &'static [&'static str],
+    pub fixes: &'static [&'static str],
+}
+
+pub const CONFIG_READ: ErrorCode = ErrorCode {
+    code: "SEEKER-E1001",
+    summary: "The config file can not be read.",
+    causes: &[
+        "The path given to --config does not exist.",
+        "seeker runs as a user without permission to read the file.",
+    ],
+    fixes: &["Check the path and the permissions of the config file."],
+};
+
+pub const CONFIG_SYNTAX: ErrorCode = ErrorCode {
+    code: "SEEKER-E1002",
+    summary: "The config is not valid YAML or has a setting of the wrong type.",
+    causes: &[
+        "A typo in a setting name or value, or broken indentation.",
+        "A required setting such as dns_servers or tun_cidr is missing.",
+        "A duration without unit, durations look like `10s` or `500ms`.",
+    ],
+    fixes: &["Compare the config with sample_config.yml, the message names the line and column."],
+};
+
+pub const CONFIG_INVALID: ErrorCode = ErrorCode {
+    code: "SEEKER-E1003",
+    summary: "The config is well-formed but its settings do not fit together.",
+    causes: &[
+        "No servers are configured.",
+        "A server lacks settings its protocol needs, e.g. the method of a shadowsocks server.",
+        "A proxy group lists an unknown server, or a rule targets an unknown group.",
+        "The config uses an environment variable, `${NAME}`, that is not set.",
+        "A `keyring:` password is not in the system keyring, or the keyring is locked.",
+    ],
+    fixes: &["Fix the server, group or rule named in the message."],
+};
+
+pub const CONFIG_DATA_FILE: ErrorCode = ErrorCode {
+    code: "SEEKER-E1004",
+    summary: "A data file used by the rules is missing or can not be parsed.",
+    causes: &[
+        "GEOSITE rules without geosite_file, or IP-ASN rules without asn_file.",
+        "A RULE-SET rule without a matching entry in rule_providers.",
+        "The geosite, ASN, rule provider or domestic ip file does not exist or is corrupt.",
+        "The subscription_url can not be downloaded and subscription_path has no saved copy.",
+    ],
+    fixes: &[
+        "Configure the file the message names, or download it again.",
+        "Remove the rules that need it.",
+    ],
+};
+
+pub const SERVER_UNREACHABLE: ErrorCode = ErrorCode {
+    code: "SEEKER-E2001",
+    summary: "The proxy server can not be reached.",
+    causes: &[
+        "The server address does not resolve or the server is down.",
+        "A firewall or the network blocks the server port.",
+    ],
+    fixes: &[
+        "Check the addr of the server, and that the server is reachable without seeker.",
+        "With several servers seeker quarantines failing ones and uses the others.",
+    ],
+};
+
+pub const HANDSHAKE: ErrorCode = ErrorCode {
+    code: "SEEKER-E2002",
+    summary: "The proxy server refused the handshake or closed the connection during it.",
+    causes: &[
+        "Wrong username, password, method or key.",
+        "The protocol configured does not match the server's.",
+        "The certificate of an https proxy is not trusted.",
+        "The server refuses the target address.",
+    ],
+    fixes: &[
+        "Compare the server settings with the server's own config.",
+        "For shadowsocks, check key_derivation and salt_size against the server.",
+    ],
+};
+
+pub const UDP_PROXY: ErrorCode = ErrorCode {
+    code: "SEEKER-E2003",
+    summary: "UDP can not be relayed through the proxy server.",
+    causes: &[
+        "The protocol of the server has no UDP relay, only socks5 and shadowsocks do.",
+        "The server does not enable UDP, or a firewall blocks UDP to it.",
+        "The socks5 server does not answer UDP ASSOCIATE in time.",
+    ],
+    fixes: &[
+        "Enable UDP on the server, or route UDP to a server that relays it.",
+        "Route the UDP traffic DIRECT with a NETWORK,udp rule.",
+    ],
+};
+
+pub const TUN_SETUP: ErrorCode = ErrorCode {
+    code: "SEEKER-E3001",
+    summary: "The tun device can not be created or configured.",
+    causes: &[
+        "seeker lacks root or CAP_NET_ADMIN.",
+        "Another program uses tun_name, or the tun driver is not loaded.",
+    ],
+    fixes: &[
+        "Run seeker with sudo, or grant it CAP_NET_ADMIN.",
+        "Pick another tun_name, on macOS it has to be utunN.",
+    ],
+};
+
+pub const ROUTE_SETUP: ErrorCode = ErrorCode {
+    code: "SEEKER-E3002",
+    summary: "The routes or firewall rules of auto_route can not be installed.",
+    causes: &[
+        "seeker lacks root or CAP_NET_ADMIN.",
+        "The ip, nft, route or netsh command is missing.",
+        "Another VPN manages the routes or the firewall.",
+    ],
+    fixes: &[
+        "Run seeker with sudo, or grant it CAP_NET_ADMIN.",
+        "Install iproute2 and nftables on Linux.",
+        "Turn off auto_route and set up the routes yourself.",
+    ],
+};
+
+pub const CATALOG: &[&ErrorCode] = &[
+    &CONFIG_READ,
+    &CONFIG_SYNTAX,
+    &CONFIG_INVALID,
+    &CONFIG_DATA_FILE,
+    &SERVER_UNREACHABLE,
+    &HANDSHAKE,
+    &UDP_PROXY,
+    &TUN_SETUP,
+    &ROUTE_SETUP,
+];
+
+/// The entry for `code`, given in full or as the number only: `SEEKER-E1001`, `E1001`, `1001`.
+pub fn lookup(code: &str) -> Option<&'static ErrorCode> {
+    let number = code.trim().to_ascii_uppercase();
+    let number = number.trim_start_matches("SEEKER-").trim_start_matches('E');
+    CATALOG
+        .iter()
+        .copied()
+        .find(|c| &c.code["SEEKER-E".len()..] == number)
+}
+
+impl ErrorCode {
+    pub fn error<M: fmt::Display>(&self, kind: io::ErrorKind, message: M) -> io::Error {
+        io::Error::new(kind, format!("{}: {}", self.code, message))
+    }
+
+    /// Prefix `e` with the code, keeping its kind. Errors with a code already keep theirs.
+    pub fn wrap(&self, e: io::Error) -> io::Error {
+        if e.to_string().starts_with("SEEKER-E") {
+            return e;
+        }
+        self.error(e.kind(), e)
+    }
+
+    /// The text of `seeker explain`.
+    pub fn explain(&self) -> String {
+        let mut out = format!("{}\n{}\n\nPossible causes:\n", self.code, self.summary);
+        for cause in self.causes {
+            out.push_str(&format!("  - {}\n", cause));
+        }
+        out.push_str("\nFixes:\n");
+        for fix in self.fixes {
+            out.push_str(&format!("  - {}\n", fix));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup("SEEKER-E1001"), Some(&CONFIG_READ));
+        assert_eq!(lookup("e2002"), Some(&HANDSHAKE));
+        assert_eq!(lookup("3001"), Some(&TUN_SETUP));
+        assert_eq!(lookup("E9999"), None);
+        let codes: HashSet<_> = CATALOG.iter().map(|c| c.code).collect();
+        assert_eq!(codes.len(), CATALOG.len());
+    }
+
+    #[test]
+    fn test_wrap() {
+        let e = HANDSHAKE.wrap(io::Error::new(io::ErrorKind::InvalidData, "bad auth"));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "SEEKER-E2002: bad auth");
+        assert_eq!(
+            SERVER_UNREACHABLE.wrap(e).to_string(),
+            "SEEKER-E2002: bad auth"
+        );
+    }
+}
@@ -0,0 +1,57 @@
+//! Config files are YAML, or JSON when their name ends with `.json`, so tools can generate
+//! them. Both are read into the same document: `include`, `profiles` and `${NAME}` work the
+//! same in either.
+use serde_yaml::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Json,
+}
+
+impl Format {
+    /// The format of the file at `path`, a file path or URL, by its extension.
+    pub fn of_path(path: &str) -> Format {
+        let path = path.split(|c| c == '?' || c == '#').next().unwrap_or(path);
+        if path.to_ascii_lowercase().ends_with(".json") {
+            Format::Json
+        } else {
+            Format::Yaml
+        }
+    }
+
+    pub(crate) fn parse(self, text: &str) -> Result<Value, String> {
+        match self {
+            Format::Yaml => serde_yaml::from_str(text).map_err(|e| e.to_string()),
+            Format::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(Format::of_path("/etc/seeker/config.json"), Format::Json);
+        assert_eq!(
+            Format::of_path("https://example.com/c.JSON?t=1"),
+            Format::Json
+        );
+        assert_eq!(Format::of_path("config.yml"), Format::Yaml);
+        assert_eq!(Format::of_path("config"), Format::Yaml);
+
+        let json = Format::Json
+            .parse(r#"{"dns_timeout": "1s", "rules": ["MATCH,DIRECT"]}"#)
+            .unwrap();
+        let yaml = Format::Yaml
+            .parse("dns_timeout: 1s\nrules: ['MATCH,DIRECT']")
+            .unwrap();
+        assert_eq!(json, yaml);
+        assert!(Format::Json
+            .parse("{\"a\": 1,}")
+            .unwrap_err()
+            .contains("line 1"));
+    }
+}
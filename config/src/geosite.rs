@@ -0,0 +1,310 @@
+//! Domain lists of the v2ray `geosite.dat` format, used by `GEOSITE` rules.
+//!
+//! The file is a protobuf encoded `GeoSiteList`:
+//!
+//! ```text
+//! message Domain { Type type = 1; string value = 2; repeated Attribute attribute = 3; }
+//! message GeoSite { string country_code = 1; repeated Domain domain = 2; }
+//! message GeoSiteList { repeated GeoSite entry = 1; }
+//! ```
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Error, ErrorKind};
+
+const DOMAIN_PLAIN: u64 = 0;
+const DOMAIN_SUFFIX: u64 = 2;
+const DOMAIN_FULL: u64 = 3;
+
+/// Domains of one geosite category, e.g. `cn` or `category-ads`.
+#[derive(Default)]
+pub struct DomainList {
+    full: HashSet<String>,
+    suffix: HashSet<String>,
+    keyword: Vec<String>,
+}
+
+impl DomainList {
+    /// A list matching the domains in `full` exactly and those in `suffix` with their
+    /// subdomains.
+    pub fn new(full: HashSet<String>, suffix: HashSet<String>) -> Self {
+        DomainList {
+            full,
+            suffix,
+            keyword: vec![],
+        }
+    }
+
+    /// The list also matching domains containing one of `keywords`.
+    pub fn with_keywords(mut self, keywords: Vec<String>) -> Self {
+        self.keyword = keywords;
+        self
+    }
+
+    pub fn matches(&self, domain: &str) -> bool {
+        if self.full.contains(domain) || self.keyword.iter().any(|k| domain.contains(k.as_str())) {
+            return true;
+        }
+        let mut rest = domain;
+        loop {
+            if self.suffix.contains(rest) {
+                return true;
+            }
+            match rest.find('.') {
+                Some(pos) => rest = &rest[pos + 1..],
+                None => return false,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.full.len() + self.suffix.len() + self.keyword.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Categories loaded from a geosite file, keyed by lowercase code.
+#[derive(Default)]
+pub struct GeoSite {
+    lists: HashMap<String, DomainList>,
+}
+
+impl fmt::Debug for GeoSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut codes: Vec<_> = self.lists.keys().collect();
+        codes.sort();
+        f.debug_struct("GeoSite").field("codes", &codes).finish()
+    }
+}
+
+impl GeoSite {
+    /// Load the categories in `codes` from the geosite file at `path`.
+    pub fn from_file(path: &str, codes: &[String]) -> io::Result<Self> {
+        let buf = std::fs::read(path)
+            .map_err(|e| Error::new(e.kind(), format!("read geosite file {}: {}", path, e)))?;
+        let geosite = GeoSite::from_bytes(&buf, codes)?;
+        for code in codes {
+            if geosite.get(code).is_none() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("geosite category {} not found in {}", code, path),
+                ));
+            }
+        }
+        Ok(geosite)
+    }
+
+    /// Decode the categories in `codes` from `buf`, the others are skipped.
+    ///
+    /// Regex entries are not supported and ignored.
+    pub fn from_bytes(buf: &[u8], codes: &[String]) -> io::Result<Self> {
+        let mut lists = HashMap::new();
+        let mut list = Reader::new(buf);
+        while let Some((field, value)) = list.field()? {
+            if let (1, Value::Bytes(entry)) = (field, value) {
+                let (code, domains) = decode_geosite(entry)?;
+                let code = code.to_lowercase();
+                if codes.contains(&code) {
+                    lists.insert(code, decode_domains(&domains)?);
+                }
+            }
+        }
+        Ok(GeoSite { lists })
+    }
+
+    pub fn get(&self, code: &str) -> Option<&DomainList> {
+        self.lists.get(code)
+    }
+}
+
+fn decode_geosite(buf: &[u8]) -> io::Result<(String, Vec<&[u8]>)> {
+    let mut code = String::new();
+    let mut domains = vec![];
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = reader.field()? {
+        match (field, value) {
+            (1, Value::Bytes(b)) => code = utf8(b)?,
+            (2, Value::Bytes(b)) => domains.push(b),
+            _ => {}
+        }
+    }
+    Ok((code, domains))
+}
+
+fn decode_domains(domains: &[&[u8]]) -> io::Result<DomainList> {
+    let mut list = DomainList::default();
+    for domain in domains {
+        let (mut kind, mut value) = (DOMAIN_PLAIN, String::new());
+        let mut reader = Reader::new(domain);
+        while let Some((field, v)) = reader.field()? {
+            match (field, v) {
+                (1, Value::Varint(k)) => kind = k,
+                (2, Value::Bytes(b)) => value = utf8(b)?.to_lowercase(),
+                _ => {}
+            }
+        }
+        match kind {
+            DOMAIN_PLAIN => list.keyword.push(value),
+            DOMAIN_SUFFIX => {
+                list.suffix.insert(value);
+            }
+            DOMAIN_FULL => {
+                list.full.insert(value);
+            }
+            // Regex entries need a regex engine, they are skipped.
+            _ => {}
+        }
+    }
+    Ok(list)
+}
+
+fn utf8(b: &[u8]) -> io::Result<String> {
+    String::from_utf8(b.to_vec()).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+/// Just enough protobuf to walk the fields of a message.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    fn truncated() -> Error {
+        Error::new(ErrorKind::InvalidData, "truncated geosite data")
+    }
+
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.buf.split_first().ok_or_else(Self::truncated)?;
+            self.buf = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::new(ErrorKind::InvalidData, "invalid varint"))
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(Self::truncated());
+        }
+        let (head, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    /// The next field number and its value, `None` at the end of the message.
+    fn field(&mut self) -> io::Result<Option<(u64, Value<'a>)>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => Value::Varint(self.varint()?),
+            1 => {
+                let _ = self.take(8)?;
+                Value::Fixed
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => {
+                let _ = self.take(4)?;
+                Value::Fixed
+            }
+            t => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported wire type {}", t),
+                ))
+            }
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes_field(field: u8, value: &[u8]) -> Vec<u8> {
+        let mut buf = vec![field << 3 | 2, value.len() as u8];
+        buf.extend_from_slice(value);
+        buf
+    }
+
+    fn domain(kind: u8, value: &str) -> Vec<u8> {
+        let mut buf = vec![1 << 3, kind];
+        buf.extend(bytes_field(2, value.as_bytes()));
+        bytes_field(2, &buf)
+    }
+
+    fn geosite(code: &str, domains: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = bytes_field(1, code.as_bytes());
+        for d in domains {
+            buf.extend_from_slice(d);
+        }
+        bytes_field(1, &buf)
+    }
+
+    #[test]
+    fn test_decode_geosite() {
+        let mut buf = geosite(
+            "CN",
+            &[
+                domain(2, "baidu.com"),
+                domain(3, "www.qq.com"),
+                domain(0, "taobao"),
+                domain(1, "^.*\\.cn$"),
+            ],
+        );
+        buf.extend(geosite("GOOGLE", &[domain(2, "google.com")]));
+
+        let geosite = GeoSite::from_bytes(&buf, &["cn".to_string()]).unwrap();
+        assert!(geosite.get("google").is_none());
+        let cn = geosite.get("cn").unwrap();
+        assert_eq!(cn.len(), 3);
+        assert!(cn.matches("baidu.com"));
+        assert!(cn.matches("map.baidu.com"));
+        assert!(!cn.matches("notbaidu.com"));
+        assert!(cn.matches("www.qq.com"));
+        assert!(!cn.matches("mail.qq.com"));
+        assert!(cn.matches("world.taobao.com"));
+
+        assert!(GeoSite::from_bytes(&buf[..buf.len() - 1], &[]).is_err());
+    }
+
+    #[test]
+    fn test_geosite_rules() {
+        use crate::rule::{Action, ProxyRules, Rule};
+        use std::str::FromStr;
+
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("GEOSITE,CN,DIRECT").unwrap(),
+            Rule::from_str("MATCH,PROXY").unwrap(),
+        ]);
+        assert_eq!(rules.geosite_codes(), vec!["cn"]);
+        assert_eq!(rules.rules()[0].to_string(), "GEOSITE,cn,DIRECT");
+        assert_eq!(rules.action_for_domain("baidu.com"), Some(Action::Proxy));
+
+        let buf = geosite("CN", &[domain(2, "baidu.com")]);
+        let rules = rules.with_geosite(GeoSite::from_bytes(&buf, &["cn".to_string()]).unwrap());
+        assert_eq!(rules.action_for_domain("baidu.com"), Some(Action::Direct));
+        assert!(rules.is_explicitly_matched("www.baidu.com"));
+        assert!(!rules.is_explicitly_matched("google.com"));
+    }
+}
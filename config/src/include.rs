@@ -0,0 +1,260 @@
+//! `include: [servers.yml, rules/*.yml]` merges other YAML files into the config, so long
+//! rule lists and server lists can live in files of their own.
+//!
+//! Paths are relative to the directory of the config, a `*` or `?` in the file name matches
+//! the files of that directory in name order. The files are merged in the order they are
+//! listed, then the config itself:
+//!
+//! - lists, like `rules` and `servers`, are joined, so the config's `MATCH` rule stays last;
+//! - mappings, like `rule_providers`, get the keys of all files, the config wins a clash;
+//! - other settings may come from one file only, unless the config sets them itself.
+//!
+//! Included files can not include others.
+use crate::rule::Rule;
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters and `?` one.
+fn wildcard(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first().copied(), name.first().copied()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            wildcard(&pattern[1..], name) || (!name.is_empty() && wildcard(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => wildcard(&pattern[1..], &name[1..]),
+        (Some(p), Some(c)) if p == c => wildcard(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// The files `pattern` names, relative to `dir`.
+fn expand(dir: &Path, pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let path = dir.join(pattern);
+    let file_name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| format!("include {}: not a file.", pattern))?;
+    if !file_name.contains(|c| c == '*' || c == '?') {
+        return Ok(vec![path]);
+    }
+    let parent = path.parent().unwrap_or(dir);
+    if parent.to_string_lossy().contains(|c| c == '*' || c == '?') {
+        return Err(format!(
+            "include {}: only the file name can have wildcards.",
+            pattern
+        ));
+    }
+    let file_pattern: Vec<char> = file_name.chars().collect();
+    let entries =
+        fs::read_dir(parent).map_err(|e| format!("include {}: {}", parent.display(), e))?;
+    let mut paths = vec![];
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("include {}: {}", parent.display(), e))?;
+        let name = entry
+            .file_name()
+            .to_string_lossy()
+            .chars()
+            .collect::<Vec<_>>();
+        if wildcard(&file_pattern, &name) && entry.path().is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Parse the included file at `path`, checking its rules so errors name the file.
+fn load(path: &Path) -> Result<Mapping, String> {
+    let source = path.display();
+    let text = fs::read_to_string(path).map_err(|e| format!("include {}: {}", source, e))?;
+    let text = crate::env_vars::expand(&text).map_err(|e| format!("{}: {}", source, e))?;
+    let doc: Value = serde_yaml::from_str(&text).map_err(|e| format!("{}: {}", source, e))?;
+    let doc = match doc {
+        Value::Mapping(doc) => doc,
+        Value::Null => Mapping::new(),
+        _ => return Err(format!("{}: an included file has to be a mapping.", source)),
+    };
+    if doc.contains_key(&Value::from("include")) {
+        return Err(format!(
+            "{}: included files can not include others.",
+            source
+        ));
+    }
+    let rules = doc
+        .get(&Value::from("rules"))
+        .and_then(Value::as_sequence)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    for rule in rules {
+        let rule = match rule.as_str().or_else(|| rule.get("rule")?.as_str()) {
+            Some(rule) => rule,
+            None => continue,
+        };
+        if Rule::from_str(rule).is_err() {
+            let line = text
+                .lines()
+                .position(|l| l.contains(rule))
+                .map_or(0, |i| i + 1);
+            return Err(format!("{}:{}: invalid rule {}", source, line, rule));
+        }
+    }
+    Ok(doc)
+}
+
+fn key_name(key: &Value) -> String {
+    key.as_str()
+        .map_or_else(|| format!("{:?}", key), str::to_string)
+}
+
+/// Merge `doc`, the config itself, with the files its `include` lists.
+pub fn resolve(mut doc: Mapping, dir: &Path) -> Result<Mapping, String> {
+    let patterns = match doc.remove(&Value::from("include")) {
+        Some(Value::Sequence(patterns)) => patterns,
+        Some(Value::String(pattern)) => vec![Value::String(pattern)],
+        Some(Value::Null) | None => return Ok(doc),
+        Some(_) => return Err("include has to be a list of paths.".to_string()),
+    };
+    let mut merged = Mapping::new();
+    // The file each setting other than lists and mappings came from.
+    let mut origins: HashMap<String, String> = HashMap::new();
+    for pattern in patterns {
+        let pattern = pattern
+            .as_str()
+            .ok_or_else(|| "include has to be a list of paths.".to_string())?;
+        for path in expand(dir, pattern)? {
+            let source = path.display().to_string();
+            for (key, value) in load(&path)? {
+                let entry = merged.get_mut(&key);
+                match (entry, value) {
+                    (Some(Value::Sequence(list)), Value::Sequence(more)) => list.extend(more),
+                    (Some(Value::Mapping(map)), Value::Mapping(more)) => {
+                        for (k, v) in more {
+                            if map.contains_key(&k) {
+                                return Err(format!(
+                                    "{}: {} {} is already set by another included file.",
+                                    source,
+                                    key_name(&key),
+                                    key_name(&k)
+                                ));
+                            }
+                            map.insert(k, v);
+                        }
+                    }
+                    (Some(_), _) => {
+                        return Err(format!(
+                            "{}: {} is already set by {}.",
+                            source,
+                            key_name(&key),
+                            origins[&key_name(&key)]
+                        ));
+                    }
+                    (None, value) => {
+                        origins.insert(key_name(&key), source.clone());
+                        merged.insert(key, value);
+                    }
+                }
+            }
+        }
+    }
+    for (key, value) in doc {
+        let entry = merged.remove(&key);
+        let value = match (entry, value) {
+            (Some(Value::Sequence(mut list)), Value::Sequence(more)) => {
+                list.extend(more);
+                Value::Sequence(list)
+            }
+            (Some(Value::Mapping(mut map)), Value::Mapping(more)) => {
+                for (k, v) in more {
+                    map.insert(k, v);
+                }
+                Value::Mapping(map)
+            }
+            (_, value) => value,
+        };
+        merged.insert(key, value);
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard() {
+        let matches = |p: &str, n: &str| {
+            wildcard(
+                &p.chars().collect::<Vec<_>>(),
+                &n.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(matches("*.yml", "ads.yml"));
+        assert!(matches("rule?.yml", "rule1.yml"));
+        assert!(!matches("*.yml", "ads.yaml"));
+        assert!(matches("*", ""));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = std::env::temp_dir().join(format!("seeker-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("rules")).unwrap();
+        fs::write(
+            dir.join("rules/b.yml"),
+            "rules:\n  - 'DOMAIN-SUFFIX,b.com,DIRECT'\ndns_timeout: 1s\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("rules/a.yml"),
+            "rules:\n  - 'DOMAIN-SUFFIX,a.com,PROXY'\nrule_providers:\n  ads: {format: hosts, path: ads.txt}\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("servers.yml"),
+            "servers:\n  - 'socks5://127.0.0.1:1080'\n",
+        )
+        .unwrap();
+
+        let doc: Mapping = serde_yaml::from_str(
+            "include: [servers.yml, 'rules/*.yml']\ndns_timeout: 2s\nrules:\n  - 'MATCH,DIRECT'\n",
+        )
+        .unwrap();
+        let merged = resolve(doc, &dir).unwrap();
+        let rules: Vec<&str> = merged[&Value::from("rules")]
+            .as_sequence()
+            .unwrap()
+            .iter()
+            .map(|r| r.as_str().unwrap())
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                "DOMAIN-SUFFIX,a.com,PROXY",
+                "DOMAIN-SUFFIX,b.com,DIRECT",
+                "MATCH,DIRECT"
+            ]
+        );
+        assert_eq!(merged[&Value::from("dns_timeout")], Value::from("2s"));
+        assert!(merged.contains_key(&Value::from("servers")));
+        assert!(!merged.contains_key(&Value::from("include")));
+
+        fs::write(
+            dir.join("bad.yml"),
+            "rules:\n  - 'DOMAIN-SUFIX,c.com,PROXY'\n",
+        )
+        .unwrap();
+        let doc: Mapping = serde_yaml::from_str("include: [bad.yml]").unwrap();
+        let err = resolve(doc, &dir).unwrap_err();
+        assert!(err.ends_with("bad.yml:2: invalid rule DOMAIN-SUFIX,c.com,PROXY"));
+
+        fs::write(dir.join("timeout.yml"), "dns_timeout: 3s\n").unwrap();
+        let doc: Mapping = serde_yaml::from_str("include: ['rules/b.yml', timeout.yml]").unwrap();
+        assert!(resolve(doc, &dir).unwrap_err().contains("already set by"));
+
+        let doc: Mapping = serde_yaml::from_str("include: [missing.yml]").unwrap();
+        assert!(resolve(doc, &dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+//! Sets of IPv4 and IPv6 networks, loaded from plain CIDR lists such as `china_ip_list.txt`.
+use std::fmt;
+use std::io::{self, Error, ErrorKind};
+use std::net::IpAddr;
+
+/// Networks stored as sorted, merged ranges of addresses.
+#[derive(Default, Clone)]
+pub struct IpSet {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl fmt::Debug for IpSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IpSet")
+            .field("v4_ranges", &self.v4.len())
+            .field("v6_ranges", &self.v6.len())
+            .finish()
+    }
+}
+
+fn merge<T: Ord + Copy>(ranges: &mut Vec<(T, T)>, next: impl Fn(T) -> Option<T>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if next(last.1).map_or(true, |n| start <= n) => {
+                last.1 = last.1.max(end);
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    match ranges.binary_search_by(|&(start, _)| start.cmp(&ip)) {
+        Ok(_) => true,
+        Err(0) => false,
+        Err(pos) => ip <= ranges[pos - 1].1,
+    }
+}
+
+impl IpSet {
+    /// Load one network per line, blank lines and lines starting with `#` are skipped.
+    pub fn from_file(path: &str) -> io::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("read ip list {}: {}", path, e)))?;
+        IpSet::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let mut set = IpSet::default();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            set.push(line).map_err(|_| {
+                Error::new(ErrorKind::InvalidData, format!("invalid network: {}", line))
+            })?;
+        }
+        merge(&mut set.v4, |n| n.checked_add(1));
+        merge(&mut set.v6, |n| n.checked_add(1));
+        Ok(set)
+    }
+
+    /// `1.0.1.0/24`, `2001:db8::/32` or a single address.
+    fn push(&mut self, network: &str) -> Result<(), ()> {
+        let mut parts = network.splitn(2, '/');
+        let ip: IpAddr = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let len: Option<u32> = parts
+            .next()
+            .map(|l| l.parse().map_err(|_| ()))
+            .transpose()?;
+        match ip {
+            IpAddr::V4(ip) => {
+                let len = len.unwrap_or(32);
+                if len > 32 {
+                    return Err(());
+                }
+                let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+                let start = u32::from(ip) & mask;
+                self.v4.push((start, start | !mask));
+            }
+            IpAddr::V6(ip) => {
+                let len = len.unwrap_or(128);
+                if len > 128 {
+                    return Err(());
+                }
+                let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+                let start = u128::from(ip) & mask;
+                self.v6.push((start, start | !mask));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => match ip.to_ipv4() {
+                Some(v4) if ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+                    contains(&self.v4, u32::from(v4))
+                }
+                _ => contains(&self.v6, u128::from(ip)),
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_set() {
+        let set = IpSet::parse(
+            "# china\n1.0.1.0/24\n1.0.2.0/23\n\n114.114.114.114\n240e::/20\n0.0.0.0/0\n",
+        )
+        .unwrap();
+        assert!(set.contains("8.8.8.8".parse().unwrap()));
+
+        let set = IpSet::parse("1.0.1.0/24\n1.0.2.0/23\n114.114.114.114\n240e::/20\n").unwrap();
+        assert_eq!(
+            set.v4,
+            vec![(0x0100_0100, 0x0100_03ff), (0x7272_7272, 0x7272_7272)]
+        );
+        assert!(set.contains("1.0.3.255".parse().unwrap()));
+        assert!(!set.contains("1.0.4.0".parse().unwrap()));
+        assert!(set.contains("114.114.114.114".parse().unwrap()));
+        assert!(set.contains("::ffff:1.0.1.1".parse().unwrap()));
+        assert!(set.contains("240e:1::1".parse().unwrap()));
+        assert!(!set.contains("2001:db8::1".parse().unwrap()));
+        assert!(IpSet::parse("1.0.1.0/33").is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! Binary tries over IPv4 and IPv6 prefixes, so IP rules match in time proportional to
+//! the address length instead of the number of rules.
+use std::net::IpAddr;
+
+const NONE: u32 = u32::MAX;
+
+/// One trie, keys are the bits of an address from the most significant one.
+#[derive(Debug, Clone)]
+struct Trie<T> {
+    /// Child indexes for bit 0 and bit 1, `NONE` when missing. Node 0 is the root.
+    children: Vec<[u32; 2]>,
+    values: Vec<Option<T>>,
+}
+
+impl<T> Default for Trie<T> {
+    fn default() -> Self {
+        Trie {
+            children: vec![[NONE; 2]],
+            values: vec![None],
+        }
+    }
+}
+
+impl<T> Trie<T> {
+    /// The value at the `len` bit prefix of `key`, adding nodes for the prefix when missing.
+    fn entry(&mut self, key: u128, len: u8) -> &mut Option<T> {
+        let mut node = 0;
+        for i in 0..len {
+            let bit = (key >> (127 - i)) & 1;
+            let child = self.children[node][bit as usize];
+            node = if child == NONE {
+                self.children.push([NONE; 2]);
+                self.values.push(None);
+                let child = self.children.len() - 1;
+                self.children[node][bit as usize] = child as u32;
+                child
+            } else {
+                child as usize
+            };
+        }
+        &mut self.values[node]
+    }
+
+    /// Values of all prefixes of `key`, from the shortest to the longest.
+    fn matches(&self, key: u128, width: u8) -> impl Iterator<Item = &T> {
+        let mut node = Some(0);
+        let mut depth = 0;
+        std::iter::from_fn(move || {
+            while let Some(n) = node {
+                node = if depth < width {
+                    let bit = (key >> (127 - depth)) & 1;
+                    let child = self.children[n][bit as usize];
+                    depth += 1;
+                    if child == NONE {
+                        None
+                    } else {
+                        Some(child as usize)
+                    }
+                } else {
+                    None
+                };
+                if let Some(value) = &self.values[n] {
+                    return Some(value);
+                }
+            }
+            None
+        })
+    }
+}
+
+/// Values keyed by IPv4 and IPv6 networks.
+#[derive(Debug, Clone)]
+pub struct IpTrie<T> {
+    v4: Trie<T>,
+    v6: Trie<T>,
+}
+
+impl<T> Default for IpTrie<T> {
+    fn default() -> Self {
+        IpTrie {
+            v4: Trie::default(),
+            v6: Trie::default(),
+        }
+    }
+}
+
+/// IPv4-mapped IPv6 addresses are looked up as IPv4.
+pub(crate) fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+            v6.to_ipv4().map_or(ip, IpAddr::V4)
+        }
+        ip => ip,
+    }
+}
+
+fn key(ip: IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(ip) => ((u32::from(ip) as u128) << 96, 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    }
+}
+
+impl<T> IpTrie<T> {
+    /// The value of the network `ip/prefix_len`, to be filled in by the caller.
+    pub fn entry(&mut self, ip: IpAddr, prefix_len: u8) -> &mut Option<T> {
+        let (key, width) = key(ip);
+        let len = prefix_len.min(width);
+        match ip {
+            IpAddr::V4(_) => self.v4.entry(key, len),
+            IpAddr::V6(_) => self.v6.entry(key, len),
+        }
+    }
+
+    /// Values of all networks containing `ip`, from the widest to the narrowest.
+    pub fn matches(&self, ip: IpAddr) -> impl Iterator<Item = &T> {
+        let ip = unmap(ip);
+        let (key, width) = key(ip);
+        let trie = match ip {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        };
+        trie.matches(key, width)
+    }
+
+    /// The value of the narrowest network containing `ip`.
+    pub fn longest_match(&self, ip: IpAddr) -> Option<&T> {
+        self.matches(ip).last()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_trie() {
+        let mut trie = IpTrie::default();
+        *trie.entry("10.0.0.0".parse().unwrap(), 8) = Some("a");
+        *trie.entry("10.1.0.0".parse().unwrap(), 16) = Some("b");
+        *trie.entry("0.0.0.0".parse().unwrap(), 0) = Some("any");
+        *trie.entry("2001:db8::".parse().unwrap(), 32) = Some("v6");
+
+        let ip = "10.1.2.3".parse().unwrap();
+        assert_eq!(
+            trie.matches(ip).collect::<Vec<_>>(),
+            vec![&"any", &"a", &"b"]
+        );
+        assert_eq!(trie.longest_match(ip), Some(&"b"));
+        assert_eq!(trie.longest_match("10.2.0.1".parse().unwrap()), Some(&"a"));
+        assert_eq!(trie.longest_match("8.8.8.8".parse().unwrap()), Some(&"any"));
+        assert_eq!(
+            trie.longest_match("::ffff:10.1.0.1".parse().unwrap()),
+            Some(&"b")
+        );
+        assert_eq!(
+            trie.longest_match("2001:db8::1".parse().unwrap()),
+            Some(&"v6")
+        );
+        assert_eq!(trie.longest_match("2001:db9::1".parse().unwrap()), None);
+    }
+}
@@ -0,0 +1,90 @@
+//! A server `password` of `keyring:SERVICE/ACCOUNT`, or `keyring:ACCOUNT` for the service
+//! `seeker`, is read from the system keyring when the config is loaded, so the config can
+//! be shared without its secrets: the macOS Keychain through `security`, the Secret Service
+//! of GNOME Keyring or KWallet through `secret-tool` elsewhere.
+use std::process::Command;
+
+const PREFIX: &str = "keyring:";
+const DEFAULT_SERVICE: &str = "seeker";
+
+/// The service and account `password` refers to, `None` when it is a password itself.
+pub fn reference(password: &str) -> Option<(&str, &str)> {
+    let name = password.strip_prefix(PREFIX)?;
+    Some(match name.find('/') {
+        Some(pos) => (&name[..pos], &name[pos + 1..]),
+        None => (DEFAULT_SERVICE, name),
+    })
+}
+
+fn run(command: &mut Command) -> Result<String, String> {
+    let output = command.output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    let secret = String::from_utf8(output.stdout).map_err(|e| e.to_string())?;
+    Ok(secret.trim_end_matches('\n').to_string())
+}
+
+/// The secret the keyring keeps for `account` of `service`.
+#[cfg(target_os = "macos")]
+pub fn lookup(service: &str, account: &str) -> Result<String, String> {
+    run(Command::new("security").args(&[
+        "find-generic-password",
+        "-s",
+        service,
+        "-a",
+        account,
+        "-w",
+    ]))
+}
+
+/// The secret the keyring keeps for `account` of `service`.
+#[cfg(not(target_os = "macos"))]
+pub fn lookup(service: &str, account: &str) -> Result<String, String> {
+    let mut command = Command::new("secret-tool");
+    command.args(&["lookup", "service", service, "account", account]);
+    match run(&mut command) {
+        // secret-tool exits with 1 and prints nothing when there is no such secret.
+        Err(e) if e.is_empty() => Err("not found".to_string()),
+        result => result,
+    }
+}
+
+/// `password` with a keyring reference replaced by the secret, using `lookup`.
+pub fn resolve_with(
+    password: &str,
+    lookup: impl Fn(&str, &str) -> Result<String, String>,
+) -> Result<String, String> {
+    match reference(password) {
+        Some((service, account)) => lookup(service, account).map_err(|e| {
+            format!(
+                "keyring entry {}/{} can not be read: {}",
+                service, account, e
+            )
+        }),
+        None => Ok(password.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(reference("keyring:work/hk"), Some(("work", "hk")));
+        assert_eq!(reference("keyring:hk"), Some(("seeker", "hk")));
+        assert_eq!(reference("s3cret"), None);
+
+        let lookup = |service: &str, account: &str| match (service, account) {
+            ("seeker", "hk") => Ok("s3cret".to_string()),
+            _ => Err("not found".to_string()),
+        };
+        assert_eq!(resolve_with("keyring:hk", lookup).unwrap(), "s3cret");
+        assert_eq!(resolve_with("plain", lookup).unwrap(), "plain");
+        assert_eq!(
+            resolve_with("keyring:work/jp", lookup).unwrap_err(),
+            "keyring entry work/jp can not be read: not found"
+        );
+    }
+}
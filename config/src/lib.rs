@@ -1,47 +1,409 @@
+pub mod asn;
+mod diff;
+pub mod dns_ttl;
+mod domain_index;
+pub mod env_vars;
+pub mod error_code;
+mod format;
+pub mod geosite;
+mod include;
+pub mod ip_set;
+mod ip_trie;
+mod keyring;
+pub mod nat64;
+mod overrides;
+mod profile;
 pub mod rule;
+pub mod rule_set;
+pub mod schema;
 mod server_config;
-pub use server_config::{DnsServerAddr, ServerConfig, ServerProtocol};
+pub mod share_uri;
+pub mod subscription;
+#[cfg(test)]
+mod test_config;
+pub mod time_window;
+pub mod trust_anchor;
+pub use diff::ConfigDiff;
+pub use format::Format;
+pub use overrides::Overrides;
+pub use server_config::{
+    AddressPreference, DnsServerAddr, GroupStrategy, KeyDerivation, ProxyGroup, ServerConfig,
+    ServerProtocol, ServerWeight,
+};
 pub use socks5_client::Address;
 
-use rule::ProxyRules;
+use asn::AsnDb;
+use dns_ttl::DnsTtl;
+use error_code::{CONFIG_DATA_FILE, CONFIG_INVALID, CONFIG_READ, CONFIG_SYNTAX};
+use geosite::GeoSite;
+use ip_set::IpSet;
+use rule::{DnsPolicy, ProxyRules, Rule, INBOUNDS};
+use rule_set::{RuleProvider, RuleSets};
+use schemars::JsonSchema;
 use serde::Deserialize;
-use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
-use std::fs::File;
+use smoltcp::wire::{Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io;
 use std::io::{ErrorKind, Read};
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
 pub struct Config {
+    #[serde(default)]
+    pub mode: Mode,
+    /// Server configs or share URIs like `ss://...`.
+    #[serde(default, deserialize_with = "share_uri::deserialize_servers")]
+    #[schemars(with = "Vec<schema::ServerEntry>")]
     pub servers: Arc<Vec<ServerConfig>>,
+    /// A Clash or SIP008 server list, its servers are added to `servers`.
+    pub subscription_url: Option<String>,
+    /// Where the downloaded list is saved, loading the config reads the servers from it. Needed
+    /// with `subscription_url`, it also lets seeker start while the provider is unreachable.
+    pub subscription_path: Option<String>,
+    /// Download the list again this often.
+    #[serde(default, deserialize_with = "duration::deserialize_option")]
+    #[schemars(with = "Option<String>")]
+    pub subscription_interval: Option<Duration>,
+    /// Named sets of servers, targets of rules like `DOMAIN-SUFFIX,netflix.com,Streaming`.
+    #[serde(default)]
+    pub proxy_groups: Vec<ProxyGroup>,
     pub dns_start_ip: Ipv4Addr,
     pub dns_servers: Vec<DnsServerAddr>,
+    /// Named upstreams, used instead of `dns_servers` for the domains of rules naming them.
+    #[serde(default)]
+    pub dns_resolvers: BTreeMap<String, Vec<DnsServerAddr>>,
+    #[serde(default)]
+    pub dnssec: bool,
+    /// Zone file with the root DNSKEY records `dnssec` validates from, instead of the root
+    /// keys built into the resolver, e.g. to follow a root key rollover.
+    pub dnssec_trust_anchor: Option<String>,
+    /// Loaded from `dnssec_trust_anchor`.
+    #[serde(skip)]
+    pub dnssec_root_keys: Option<Arc<[trust_anchor::RootKey]>>,
+    #[serde(default)]
+    pub dns_ipv6: Ipv6Policy,
+    /// Strip private and loopback addresses from answers for public domains.
+    #[serde(default)]
+    pub dns_rebind_protection: bool,
+    /// Domain suffixes allowed to resolve to private addresses.
+    #[serde(default)]
+    pub dns_rebind_allowlist: Vec<String>,
+    /// Resolvers for LAN names and reverse lookups, defaults to the system resolvers seeker replaced.
+    #[serde(default)]
+    pub lan_dns: Vec<SocketAddr>,
+    /// Point the system resolver at seeker while it runs: `/etc/resolv.conf`, the DNS of the
+    /// tun with systemd-resolved, or the primary network service on macOS.
+    #[serde(default = "default_system_dns")]
+    pub system_dns: bool,
+    /// Number of recent DNS queries kept for `GET /dns/queries`, 0 disables the log.
+    #[serde(default = "default_dns_query_log_size")]
+    pub dns_query_log_size: usize,
+    /// Number of recent routing decisions kept for `GET /rules/decisions`, 0 disables the log.
+    #[serde(default = "default_rule_decision_log_size")]
+    pub rule_decision_log_size: usize,
+    /// Clamps for the TTL of upstream answers.
+    #[serde(default)]
+    pub dns_ttl: DnsTtl,
+    /// /96 prefix of the NAT64 gateway on IPv6-only networks, e.g. `64:ff9b::`.
+    pub nat64_prefix: Option<Ipv6Addr>,
     pub tun_name: String,
     pub tun_ip: Ipv4Addr,
     #[serde(default)]
     pub verbose: bool,
+    /// Debug and trace messages logged per second from each place in the code, 0 for no limit.
+    #[serde(default = "default_log_rate_limit")]
+    pub log_rate_limit: usize,
     #[serde(with = "ipv4_cidr")]
+    #[schemars(with = "String")]
     pub tun_cidr: Ipv4Cidr,
+    /// IPv6 address of the tun. Connections to `tun_cidr6` are relayed like those to
+    /// `tun_cidr`, both have to be set for the tun to handle IPv6.
+    pub tun_ip6: Option<Ipv6Addr>,
+    #[serde(default, deserialize_with = "ipv6_cidr::deserialize_option")]
+    #[schemars(with = "Option<String>")]
+    pub tun_cidr6: Option<Ipv6Cidr>,
+    /// Network stack handling the packets of the tun.
+    #[serde(default)]
+    pub tun_stack: TunStack,
+    /// Use a tun opened by another process instead of creating `tun_name`, like the
+    /// Android `VpnService` running seeker. The opener sets up its addresses and routes.
+    pub tun_fd: Option<TunFd>,
+    /// `tun_name` is a persistent tun created with its addresses beforehand, like by `ip
+    /// tuntap add mode tun user seeker`, so seeker can open it without root. Linux only.
+    #[serde(default)]
+    pub tun_persistent: bool,
+    /// Let the kernel hand TCP to the tun in segments of up to 64 KB with their checksums
+    /// left to complete, instead of a packet per read. Linux only.
+    #[serde(default)]
+    pub tun_offload: bool,
+    /// Queues of a multi-queue tun, each read by a thread of its own so the tun scales past
+    /// a core. Linux only, a persistent tun has to be created with `multi_queue`.
+    #[serde(default = "default_tun_queues")]
+    pub tun_queues: usize,
+    /// Switch to this user once the tun is open and the listeners are bound, keeping only
+    /// `CAP_NET_ADMIN` and `CAP_NET_BIND_SERVICE`. Linux only.
+    pub user: Option<String>,
+    /// Wait up to this long for the first bytes of TCP connections to bare ips, to match
+    /// the rules against their TLS SNI or HTTP `Host`. Off when unset.
+    #[serde(default, deserialize_with = "crate::duration::deserialize_option")]
+    #[schemars(with = "Option<String>")]
+    pub sniff_timeout: Option<Duration>,
+    /// Connect to the sniffed domain instead of the ip, resolved by the proxy for proxied
+    /// connections.
+    #[serde(default)]
+    pub sniff_override: bool,
+    /// Routing of QUIC flows to domains that advertised HTTP/3.
+    #[serde(default)]
+    pub http3: Http3Policy,
+    /// Fixed MTU of the tun. When unset the MTU is probed from the path to the servers.
+    /// The tun stack clamps the TCP MSS to it and answers bigger UDP packets with ICMP
+    /// "fragmentation needed", also when the tun is preconfigured.
+    pub tun_mtu: Option<u32>,
     #[serde(with = "rules")]
+    #[schemars(with = "Vec<rules::RuleEntry>")]
     pub rules: ProxyRules,
+    /// Where connections no rule matches go, to domains and ips alike: `DIRECT`, `PROXY`,
+    /// `REJECT`, `PROBE` or a proxy group.
+    #[serde(rename = "final", default = "default_final")]
+    pub final_target: String,
+    /// Domain lists for `RULE-SET,name,ACTION` rules, by name.
+    #[serde(default)]
+    pub rule_providers: BTreeMap<String, RuleProvider>,
+    /// v2ray `geosite.dat` providing the domain lists of `GEOSITE` rules.
+    pub geosite_file: Option<String>,
+    /// MaxMind `GeoLite2-ASN.mmdb` providing the autonomous systems of `IP-ASN` rules.
+    pub asn_file: Option<String>,
+    /// Networks counted as domestic, one CIDR per line. Answers for DIRECT domains outside
+    /// of them are treated as poisoned or far away and the domain is proxied instead.
+    pub domestic_ip_file: Option<String>,
+    /// Loaded from `domestic_ip_file`.
+    #[serde(skip)]
+    pub domestic_ips: Option<Arc<IpSet>>,
+    /// The `dns_resolvers` entry resolving DIRECT domains instead of `dns_servers`, unless
+    /// their rule names another one.
+    pub domestic_dns: Option<String>,
     pub dns_listen: String,
+    /// Serve DNS over TLS on this address, requires `tls_cert` and `tls_key`.
+    pub dot_listen: Option<String>,
+    /// Serve DNS over HTTPS (`/dns-query`) on this address, requires `tls_cert` and `tls_key`.
+    pub doh_listen: Option<String>,
+    /// Address TPROXY rules redirect connections to in `mode: tproxy`, e.g. `0.0.0.0:7893`.
+    pub tproxy_listen: Option<String>,
+    /// Address REDIRECT rules send connections to in `mode: redirect`, e.g. `0.0.0.0:7892`.
+    pub redirect_listen: Option<String>,
+    /// PEM certificate chain and private key for the DoT and DoH listeners.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// Answer DNS queries to any address seen on the tun with the internal DNS server.
+    #[serde(default)]
+    pub dns_hijack: bool,
+    /// Block all egress not going through seeker with firewall rules.
+    #[serde(default)]
+    pub kill_switch: bool,
+    /// Install the routes into the tun, or the firewall rules of `mode: tproxy` and
+    /// `mode: redirect`, while seeker runs. Servers and LAN networks are left out.
+    #[serde(default)]
+    pub auto_route: bool,
+    /// More networks `auto_route` leaves out, as CIDRs.
+    #[serde(default)]
+    pub auto_route_exclude: Vec<String>,
     #[serde(default)]
     pub gateway_mode: bool,
     #[serde(with = "duration", default = "default_connect_timeout")]
+    #[schemars(with = "String")]
     pub ping_timeout: Duration,
     #[serde(with = "duration", default = "default_connect_timeout")]
+    #[schemars(with = "String")]
     pub dns_timeout: Duration,
     #[serde(with = "duration", default = "default_ping_timeout")]
+    #[schemars(with = "String")]
     pub probe_timeout: Duration,
     #[serde(with = "duration", default = "default_connect_timeout")]
+    #[schemars(with = "String")]
     pub connect_timeout: Duration,
     #[serde(with = "duration", default = "default_read_timeout")]
+    #[schemars(with = "String")]
     pub read_timeout: Duration,
     #[serde(with = "duration", default = "default_write_timeout")]
+    #[schemars(with = "String")]
     pub write_timeout: Duration,
     pub max_connect_errors: usize,
+    /// Reject connections to proxies while no server is reachable, instead of trying an
+    /// unreachable one or falling through to `DIRECT`.
+    #[serde(default)]
+    pub proxy_fail_closed: bool,
+    /// Datagrams buffered per UDP association before the oldest get dropped.
+    #[serde(default = "default_udp_queue_size")]
+    pub udp_queue_size: usize,
+    /// Bytes a relayed TCP connection reads from one side before writing them to the other,
+    /// per direction. Every connection holds one such buffer per direction, mind the memory
+    /// of many connections when raising it. 16 KB unless set.
+    #[serde(default = "default_tcp_relay_buffer")]
+    pub tcp_relay_buffer: usize,
+    /// Send and receive buffers of relayed TCP sockets, both towards apps and upstream.
+    /// Unset leaves them to the kernel, which grows them as needed on Linux.
+    #[serde(default)]
+    pub tcp_socket_buffer: Option<u32>,
+    #[serde(with = "duration", default = "default_quarantine_duration")]
+    #[schemars(with = "String")]
+    pub quarantine_duration: Duration,
+    /// Needs `api_secret` unless it is a loopback address.
+    pub api_listen: Option<String>,
+    /// Clash's `secret`: every request to the management API has to send it as
+    /// `Authorization: Bearer <secret>`, or as the `token` query parameter for websockets.
+    #[serde(default)]
+    pub api_secret: Option<String>,
+    /// Origins of web dashboards, like `http://yacd.haishan.me`, that browsers let call the
+    /// management API. Pages of any other origin are refused.
+    #[serde(default)]
+    pub api_allowed_origins: Vec<String>,
+    /// Reload the config file when it changes, as on SIGHUP.
+    #[serde(default)]
+    pub watch_config: bool,
+    /// Flush the DNS cache, close proxy connections and ping the servers again when the
+    /// network changes, like from Wi-Fi to LTE, and after waking up. Not on Windows.
+    #[serde(default = "default_watch_network")]
+    pub watch_network: bool,
+    /// Connect directly to private, link-local, loopback and multicast addresses whatever
+    /// the rules say, no proxy can reach them.
+    #[serde(default = "default_lan_bypass")]
+    pub lan_bypass: bool,
+    /// The active one of `profiles`, whose settings replaced those of the config.
+    pub profile: Option<String>,
+    /// Names of the profiles of the config.
+    #[serde(skip)]
+    pub profiles: Vec<String>,
+    /// Send a JSON event for every new outbound connection here, `ip:port` (UDP) or `unix:/path`.
+    pub conn_events: Option<String>,
+    /// Ask this stream socket, `ip:port` or `unix:/path`, whether to allow each connection.
+    pub conn_hook: Option<String>,
+    #[serde(with = "duration", default = "default_conn_hook_timeout")]
+    #[schemars(with = "String")]
+    pub conn_hook_timeout: Duration,
+    /// Deny connections when the hook fails or times out instead of allowing them.
+    #[serde(default)]
+    pub conn_hook_fail_closed: bool,
+    /// Hold connections matched by no explicit rule until a prompt client decides.
+    #[serde(default)]
+    pub interactive: bool,
+    /// Connections nobody decides on within this time are rejected.
+    #[serde(with = "duration", default = "default_interactive_timeout")]
+    #[schemars(with = "String")]
+    pub interactive_timeout: Duration,
+    /// Remembered interactive decisions are appended here as rules.
+    pub interactive_rules_file: Option<String>,
+    /// Exit once a background task failed this many times, restart it forever when `None`.
+    pub task_max_failures: Option<u32>,
+    /// Rhai script deciding the action of connections matched by rules with the `SCRIPT` action.
+    pub rule_script: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Transparent proxy through the tun device.
+    Tun,
+    /// Only run the rules-aware DNS server, answering with real addresses.
+    DnsOnly,
+    /// Accept TCP connections redirected by nftables TPROXY rules on `tproxy_listen`
+    /// instead of creating the tun, Linux only.
+    Tproxy,
+    /// Accept TCP connections redirected by iptables `REDIRECT` or nftables `redirect` rules
+    /// on `redirect_listen` instead of creating the tun, Linux only.
+    Redirect,
+}
+
+/// A number for a descriptor seeker inherited, a path for a unix socket the descriptor is
+/// sent over with `SCM_RIGHTS`.
+#[derive(Debug, Clone, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum TunFd {
+    Fd(i32),
+    Socket(String),
+}
+
+impl FromStr for TunFd {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(fd) => TunFd::Fd(fd),
+            Err(_) => TunFd::Socket(s.to_string()),
+        })
+    }
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::Tun
+    }
+}
+
+impl Mode {
+    /// Whether connections come in through the tun rather than a listener.
+    pub fn uses_tun(self) -> bool {
+        matches!(self, Mode::Tun)
+    }
+}
+
+/// Whether AAAA records are handed to clients and used for outbound connections.
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Ipv6Policy {
+    /// Never return AAAA records, connect over IPv4 only.
+    Off,
+    /// Use IPv6 only when a domain has no IPv4 address.
+    PreferV4,
+    /// Use IPv4 only when a domain has no IPv6 address.
+    PreferV6,
+    /// Return AAAA records only for domains matched by an explicit rule.
+    OnlyMatched,
+}
+
+impl Default for Ipv6Policy {
+    fn default() -> Self {
+        Ipv6Policy::PreferV4
+    }
+}
+
+/// How QUIC flows, UDP/443, to domains that advertised HTTP/3 through `Alt-Svc` are routed.
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Http3Policy {
+    /// Like the TCP connection of the domain that advertised it.
+    FollowTcp,
+    /// By the rules, like any other flow.
+    Allow,
+    /// Rejected, so browsers keep using TCP.
+    Block,
+}
+
+impl Default for Http3Policy {
+    fn default() -> Self {
+        Http3Policy::FollowTcp
+    }
+}
+
+/// Network stacks for the tun, see `tun_nat::StackKind`.
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TunStack {
+    /// Rewrite packets to the relay and let the kernel stack handle TCP and UDP.
+    Nat,
+}
+
+impl Default for TunStack {
+    fn default() -> Self {
+        TunStack::Nat
+    }
+}
+
+fn default_final() -> String {
+    "DIRECT".to_string()
 }
 
 fn default_read_timeout() -> Duration {
@@ -56,9 +418,46 @@ fn default_connect_timeout() -> Duration {
 fn default_ping_timeout() -> Duration {
     Duration::from_secs(3)
 }
+fn default_quarantine_duration() -> Duration {
+    Duration::from_secs(300)
+}
+fn default_udp_queue_size() -> usize {
+    64
+}
+fn default_tun_queues() -> usize {
+    1
+}
+fn default_tcp_relay_buffer() -> usize {
+    16 * 1024
+}
+fn default_dns_query_log_size() -> usize {
+    256
+}
+fn default_rule_decision_log_size() -> usize {
+    256
+}
+fn default_log_rate_limit() -> usize {
+    20
+}
+fn default_watch_network() -> bool {
+    true
+}
+fn default_lan_bypass() -> bool {
+    true
+}
+fn default_system_dns() -> bool {
+    true
+}
+fn default_conn_hook_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+fn default_interactive_timeout() -> Duration {
+    Duration::from_secs(30)
+}
 
 mod ipv4_cidr {
-    use crate::parse_cidr;
+    use crate::rule::parse_cidr4;
+    use serde::de::Error;
     use serde::{Deserialize, Deserializer};
     use smoltcp::wire::Ipv4Cidr;
 
@@ -67,13 +466,29 @@ mod ipv4_cidr {
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        Ok(parse_cidr(s))
+        parse_cidr4(&s).map_err(|()| D::Error::custom(format!("invalid cidr {}", s)))
     }
 }
 
-mod duration {
+mod ipv6_cidr {
+    use crate::rule::parse_cidr6;
     use serde::de::Error;
     use serde::{Deserialize, Deserializer};
+    use smoltcp::wire::Ipv6Cidr;
+
+    pub fn deserialize_option<'de, D>(deserializer: D) -> Result<Option<Ipv6Cidr>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|s| parse_cidr6(&s).map_err(|()| D::Error::custom(format!("invalid cidr {}", s))))
+            .transpose()
+    }
+}
+
+mod duration {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
     use std::time::Duration;
 
     pub fn parse_duration(s: &str) -> Result<Duration, String> {
@@ -86,7 +501,11 @@ mod duration {
                 chars.push(c);
             }
         }
-        let n: u64 = num.into_iter().collect::<String>().parse().unwrap();
+        let n: u64 = num
+            .into_iter()
+            .collect::<String>()
+            .parse()
+            .map_err(|_| format!("invalid value: {}, expected 10s or 10ms", &s))?;
         match chars.into_iter().collect::<String>().as_str() {
             "s" => Ok(Duration::from_secs(n)),
             "ms" => Ok(Duration::from_millis(n)),
@@ -101,61 +520,960 @@ mod duration {
         let s: String = String::deserialize(deserializer)?;
         parse_duration(&s).map_err(Error::custom)
     }
+
+    /// `10s`, or `10ms` for durations of partial seconds, for the defaults of the schema.
+    pub fn serialize<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if duration.subsec_millis() == 0 {
+            serializer.serialize_str(&format!("{}s", duration.as_secs()))
+        } else {
+            serializer.serialize_str(&format!("{}ms", duration.as_millis()))
+        }
+    }
+
+    pub fn deserialize_option<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(s) => parse_duration(&s).map(Some).map_err(Error::custom),
+            None => Ok(None),
+        }
+    }
 }
 
 mod rules {
-    use crate::rule::{ProxyRules, Rule};
+    use crate::duration::parse_duration;
+    use crate::rule::{DnsPolicy, ProxyRules, RejectMode, Rule};
+    use crate::time_window::TimeWindow;
+    use schemars::JsonSchema;
+    use serde::de::Error;
     use serde::{Deserialize, Deserializer};
     use std::str::FromStr;
 
+    /// A rule is either `'DOMAIN-SUFFIX,google.com,PROXY'` or a mapping with options:
+    /// `{ rule: 'DOMAIN-SUFFIX,netflix.com,PROXY', tag: streaming, coalesce: 1ms }` or
+    /// `{ rule: 'GEOSITE,category-ads,REJECT', reject: http-403 }` or
+    /// `{ rule: 'DOMAIN-SUFFIX,steampowered.com,Gaming', time: '19:00-23:00' }`.
+    #[derive(Deserialize, JsonSchema)]
+    #[serde(untagged)]
+    pub(crate) enum RuleEntry {
+        Plain(String),
+        Detailed {
+            rule: String,
+            tag: Option<String>,
+            coalesce: Option<String>,
+            reject: Option<RejectMode>,
+            #[schemars(with = "Option<String>")]
+            time: Option<TimeWindow>,
+            dns: Option<String>,
+        },
+    }
+
     pub fn deserialize<'de, D>(deserializer: D) -> Result<ProxyRules, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let rules: Vec<String> = Vec::deserialize(deserializer)?;
-        let rs: Vec<Rule> = rules
-            .into_iter()
-            .map(|s| Rule::from_str(&s).unwrap())
-            .collect();
-        Ok(ProxyRules::new(rs))
+        let rules: Vec<RuleEntry> = Vec::deserialize(deserializer)?;
+        let mut rs: Vec<Rule> = Vec::with_capacity(rules.len());
+        let parse =
+            |s: &str| Rule::from_str(s).map_err(|()| Error::custom(format!("invalid rule {}", s)));
+        for entry in rules {
+            rs.push(match entry {
+                RuleEntry::Plain(s) => parse(&s)?,
+                RuleEntry::Detailed {
+                    rule,
+                    tag,
+                    coalesce,
+                    reject,
+                    time,
+                    dns,
+                } => Rule {
+                    tag,
+                    time,
+                    dns: dns
+                        .map(|d| DnsPolicy::from_str(&d))
+                        .transpose()
+                        .map_err(|()| Error::custom("dns of a rule can not be empty"))?,
+                    reject: reject.unwrap_or_default(),
+                    coalesce: coalesce
+                        .map(|d| parse_duration(&d))
+                        .transpose()
+                        .map_err(Error::custom)?,
+                    ..parse(&rule)?
+                },
+            });
+        }
+        ProxyRules::try_new(rs)
+            .map_err(|e| Error::custom(format!("invalid DOMAIN-REGEX rules: {}", e)))
     }
 }
 
-fn parse_cidr(s: String) -> Ipv4Cidr {
-    let segments = s.splitn(2, '/').collect::<Vec<&str>>();
-    let addr = segments[0];
-    let len = segments[1];
-    let addr: Ipv4Addr = addr.parse().unwrap();
-    let prefix = len.parse().unwrap();
-    Ipv4Cidr::new(Ipv4Address::from(addr), prefix)
-}
-
 impl Config {
+    /// Whether the tun is set up by someone else, who also takes care of its MTU and of
+    /// pointing the DNS at seeker.
+    pub fn tun_preconfigured(&self) -> bool {
+        self.tun_fd.is_some() || self.tun_persistent
+    }
+
+    /// Whether connections to `ip` skip the rules for `lan_bypass`.
+    pub fn bypasses(&self, ip: IpAddr) -> bool {
+        self.lan_bypass && self.is_lan(ip)
+    }
+
+    /// Whether `ip` is on the LAN, or a loopback, link-local or multicast address. The fake
+    /// ips of `tun_cidr` and `tun_cidr6` are not, even when they are private networks.
+    pub fn is_lan(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => {
+                let shared = ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64;
+                (ip.is_private()
+                    || ip.is_loopback()
+                    || ip.is_link_local()
+                    || ip.is_multicast()
+                    || ip.is_broadcast()
+                    || shared)
+                    && !self.tun_cidr.contains_addr(&Ipv4Address::from(ip))
+            }
+            IpAddr::V6(ip) => {
+                let segment = ip.segments()[0];
+                let fake = self
+                    .tun_cidr6
+                    .map_or(false, |cidr| cidr.contains_addr(&Ipv6Address::from(ip)));
+                (ip.is_loopback()
+                    || ip.is_multicast()
+                    || segment & 0xfe00 == 0xfc00
+                    || segment & 0xffc0 == 0xfe80)
+                    && !fake
+            }
+        }
+    }
+
     pub fn from_config_file(path: &str) -> io::Result<Self> {
-        let file = File::open(&path).unwrap();
-        Config::from_reader(file)
+        Config::from_config_file_with_profile(path, None)
+    }
+
+    /// Load the config file at `path` with the profile `profile` active, instead of the one
+    /// its `profile` names. Files ending with `.json` are JSON.
+    pub fn from_config_file_with_profile(path: &str, profile: Option<&str>) -> io::Result<Self> {
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| CONFIG_READ.error(e.kind(), format!("open config {}: {}", path, e)))?;
+        let dir = Path::new(path).parent().unwrap_or_else(|| Path::new("."));
+        Config::from_text_with_profile(&text, Format::of_path(path), dir, profile)
+    }
+
+    /// Load a config not read from a file, `include` paths are relative to the working
+    /// directory.
+    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut text = String::new();
+        reader
+            .read_to_string(&mut text)
+            .map_err(|e| CONFIG_READ.error(e.kind(), format!("read config: {}", e)))?;
+        Config::from_text(&text, Path::new("."))
+    }
+
+    /// Load the config `text`, with `include` paths relative to `dir`.
+    pub fn from_text(text: &str, dir: &Path) -> io::Result<Self> {
+        Config::from_text_with_profile(text, Format::Yaml, dir, None)
     }
 
-    pub fn from_reader<R: Read>(reader: R) -> io::Result<Self> {
-        let conf: Config = serde_yaml::from_reader(reader).expect("serde yaml deserialize error");
-        if conf.servers.is_empty() {
-            return Err(io::Error::new(
+    /// Load the config `text` with the profile `profile` active, or the one it names.
+    pub fn from_text_with_profile(
+        text: &str,
+        format: Format,
+        dir: &Path,
+        profile: Option<&str>,
+    ) -> io::Result<Self> {
+        let text =
+            env_vars::expand(text).map_err(|e| CONFIG_INVALID.error(ErrorKind::InvalidData, e))?;
+        let syntax = |e| CONFIG_SYNTAX.error(ErrorKind::InvalidData, e);
+        let doc = format.parse(&text).map_err(syntax)?;
+        let mut profiles = vec![];
+        let mut clash_servers = vec![];
+        let included = match doc {
+            serde_yaml::Value::Mapping(mut doc)
+                if profile.is_some()
+                    || format == Format::Json
+                    || ["include", "profile", "profiles", "proxies"]
+                        .iter()
+                        .any(|key| doc.contains_key(&(*key).into())) =>
+            {
+                if doc.contains_key(&"include".into()) {
+                    doc = include::resolve(doc, dir)
+                        .map_err(|e| CONFIG_DATA_FILE.error(ErrorKind::InvalidData, e))?;
+                }
+                profiles = profile::select(&mut doc, dir, profile)
+                    .map_err(|e| CONFIG_INVALID.error(ErrorKind::InvalidData, e))?;
+                if let Some(proxies) = doc.remove(&"proxies".into()) {
+                    clash_servers = subscription::clash_servers(proxies);
+                }
+                Some(doc)
+            }
+            _ => None,
+        };
+        // Without includes, profiles or JSON the errors keep the line of the config.
+        let mut conf: Config = match included {
+            Some(merged) => serde_yaml::from_value(serde_yaml::Value::Mapping(merged)),
+            None => serde_yaml::from_str(&text),
+        }
+        .map_err(|e| syntax(e.to_string()))?;
+        conf.profiles = profiles;
+        if !clash_servers.is_empty() {
+            conf.servers = Arc::new(subscription::merge(&conf.servers, clash_servers));
+        }
+        // The servers of a subscription not downloaded yet come with the first download.
+        let mut not_downloaded = false;
+        if conf.subscription_url.is_some() {
+            let cache = conf.subscription_path.as_deref().ok_or_else(|| {
+                CONFIG_INVALID.error(
+                    ErrorKind::InvalidData,
+                    "subscription_url needs subscription_path to save the list to.",
+                )
+            })?;
+            if Path::new(cache).exists() {
+                let servers = subscription::load(cache).map_err(|e| CONFIG_DATA_FILE.wrap(e))?;
+                conf.servers = Arc::new(subscription::merge(&conf.servers, servers));
+            } else {
+                not_downloaded = true;
+            }
+        }
+        if conf.servers.is_empty() && !not_downloaded {
+            return Err(CONFIG_INVALID.error(ErrorKind::InvalidData, "servers can not be empty."));
+        };
+        if conf.mode == Mode::Tproxy && conf.tproxy_listen.is_none() {
+            return Err(
+                CONFIG_INVALID.error(ErrorKind::InvalidData, "mode tproxy needs tproxy_listen.")
+            );
+        }
+        if conf.mode == Mode::Redirect && conf.redirect_listen.is_none() {
+            return Err(CONFIG_INVALID.error(
                 ErrorKind::InvalidData,
-                "servers can not be empty.",
+                "mode redirect needs redirect_listen.",
             ));
-        };
+        }
+        if let Some(cidr) = conf.auto_route_exclude.iter().find(|c| !is_cidr(c)) {
+            return Err(CONFIG_INVALID.error(
+                ErrorKind::InvalidData,
+                format!("auto_route_exclude {} is not a CIDR.", cidr),
+            ));
+        }
+        for (key, endpoint) in &[
+            ("conn_events", &conf.conn_events),
+            ("conn_hook", &conf.conn_hook),
+        ] {
+            if let Some(endpoint) = endpoint.as_deref().filter(|e| !is_endpoint(e)) {
+                return Err(CONFIG_INVALID.error(
+                    ErrorKind::InvalidData,
+                    format!("{} {} is not ip:port or unix:/path.", key, endpoint),
+                ));
+            }
+        }
+        if let Some(prefix) = conf.nat64_prefix.filter(|p| !nat64::is_prefix(*p)) {
+            return Err(CONFIG_INVALID.error(
+                ErrorKind::InvalidData,
+                format!("nat64_prefix {} is not a /96 prefix.", prefix),
+            ));
+        }
+        if conf.tun_queues == 0 {
+            return Err(
+                CONFIG_INVALID.error(ErrorKind::InvalidData, "tun_queues has to be at least 1.")
+            );
+        }
+        for (name, provider) in &conf.rule_providers {
+            if provider.interval == Some(Duration::from_secs(0)) {
+                return Err(CONFIG_INVALID.error(
+                    ErrorKind::InvalidData,
+                    format!("interval of rule provider {} can not be 0.", name),
+                ));
+            }
+        }
+        match (conf.tun_ip6, conf.tun_cidr6) {
+            (None, None) => {}
+            (Some(ip), Some(cidr)) if cidr.contains_addr(&ip.into()) => {}
+            _ => {
+                return Err(CONFIG_INVALID.error(
+                    ErrorKind::InvalidData,
+                    "tun_ip6 has to be set together with tun_cidr6 and be one of its addresses.",
+                ))
+            }
+        }
+        let from_keyring = |s: &ServerConfig| s.password().and_then(keyring::reference).is_some();
+        if conf.servers.iter().any(from_keyring) {
+            let mut servers = conf.servers.to_vec();
+            for server in &mut servers {
+                server
+                    .resolve_keyring()
+                    .map_err(|e| CONFIG_INVALID.error(ErrorKind::InvalidData, e))?;
+            }
+            conf.servers = Arc::new(servers);
+        }
+        let mut names = HashSet::new();
+        for server in conf.servers.iter() {
+            server
+                .validate()
+                .map_err(|e| CONFIG_INVALID.error(ErrorKind::InvalidData, e))?;
+            if !names.insert(server.name()) {
+                return Err(CONFIG_INVALID.error(
+                    ErrorKind::InvalidData,
+                    format!("server {} is defined twice.", server.name()),
+                ));
+            }
+        }
+        validate_groups(&conf).map_err(|e| CONFIG_INVALID.error(ErrorKind::InvalidData, e))?;
+        let final_rule = Rule::from_str(&format!("MATCH,{}", conf.final_target)).map_err(|()| {
+            CONFIG_INVALID.error(
+                ErrorKind::InvalidData,
+                format!("final {} is not a valid target.", conf.final_target),
+            )
+        })?;
+        conf.rules = conf.rules.with_final(final_rule);
+        for rule in conf.rules.rules() {
+            if let Some(DnsPolicy::Resolver(name)) = &rule.dns {
+                if conf.dns_resolvers.get(name).map_or(true, Vec::is_empty) {
+                    return Err(CONFIG_INVALID.error(
+                        ErrorKind::InvalidData,
+                        format!("rule {} uses unknown dns resolver {}.", rule, name),
+                    ));
+                }
+            }
+        }
+        if let (Some(listen), None) = (&conf.api_listen, &conf.api_secret) {
+            if !is_loopback_listen(listen) {
+                return Err(CONFIG_INVALID.error(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "api_listen {} is not loopback, it needs api_secret.",
+                        listen
+                    ),
+                ));
+            }
+        }
+        if let Some(name) = &conf.domestic_dns {
+            if conf.dns_resolvers.get(name).map_or(true, Vec::is_empty) {
+                return Err(CONFIG_INVALID.error(
+                    ErrorKind::InvalidData,
+                    format!("domestic_dns uses unknown dns resolver {}.", name),
+                ));
+            }
+        }
+        for name in conf.rules.inbound_names() {
+            if !INBOUNDS.contains(&name.as_str()) {
+                return Err(CONFIG_INVALID.error(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "INBOUND {} is unknown, inbounds are {}.",
+                        name,
+                        INBOUNDS.join(", ")
+                    ),
+                ));
+            }
+        }
+        let codes = conf.rules.geosite_codes();
+        if !codes.is_empty() {
+            let path = conf.geosite_file.as_deref().ok_or_else(|| {
+                CONFIG_DATA_FILE.error(ErrorKind::InvalidData, "GEOSITE rules need geosite_file.")
+            })?;
+            let geosite = GeoSite::from_file(path, &codes).map_err(|e| CONFIG_DATA_FILE.wrap(e))?;
+            conf.rules = conf.rules.with_geosite(geosite);
+        }
+        if conf.rules.has_asn_rules() {
+            let path = conf.asn_file.as_deref().ok_or_else(|| {
+                CONFIG_DATA_FILE.error(ErrorKind::InvalidData, "IP-ASN rules need asn_file.")
+            })?;
+            let db = AsnDb::from_file(path).map_err(|e| CONFIG_DATA_FILE.wrap(e))?;
+            conf.rules = conf.rules.with_asn_db(db);
+        }
+        let names = conf.rules.rule_set_names();
+        if !names.is_empty() {
+            let mut lists = HashMap::new();
+            for name in names {
+                let provider = conf.rule_providers.get(&name).ok_or_else(|| {
+                    CONFIG_DATA_FILE.error(
+                        ErrorKind::InvalidData,
+                        format!("RULE-SET {} has no rule provider.", name),
+                    )
+                })?;
+                let list = provider.load().map_err(|e| CONFIG_DATA_FILE.wrap(e))?;
+                lists.insert(name, list);
+            }
+            conf.rules = conf.rules.with_rule_sets(RuleSets::new(lists));
+        }
+        if let Some(path) = &conf.dnssec_trust_anchor {
+            let keys = trust_anchor::from_file(path).map_err(|e| CONFIG_DATA_FILE.wrap(e))?;
+            conf.dnssec_root_keys = Some(keys.into());
+        }
+        if let Some(path) = &conf.domestic_ip_file {
+            let ips = IpSet::from_file(path).map_err(|e| CONFIG_DATA_FILE.wrap(e))?;
+            conf.domestic_ips = Some(Arc::new(ips));
+        }
         Ok(conf)
     }
 }
 
+/// `127.0.0.1:9000` or `unix:/run/seeker/hook.sock`, see `conn_events` and `conn_hook`.
+fn is_endpoint(s: &str) -> bool {
+    s.strip_prefix("unix:")
+        .map_or_else(|| s.parse::<SocketAddr>().is_ok(), |path| !path.is_empty())
+}
+
+/// `192.168.0.0/16` or `fc00::/7`.
+fn is_cidr(s: &str) -> bool {
+    let mut parts = s.splitn(2, '/');
+    let ip = parts.next().and_then(|ip| ip.parse::<IpAddr>().ok());
+    let prefix = parts.next().and_then(|p| p.parse::<u8>().ok());
+    match (ip, prefix) {
+        (Some(IpAddr::V4(_)), Some(prefix)) => prefix <= 32,
+        (Some(IpAddr::V6(_)), Some(prefix)) => prefix <= 128,
+        _ => false,
+    }
+}
+
+/// Groups list known servers under fresh names and every group a rule targets exists.
+fn validate_groups(conf: &Config) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for group in &conf.proxy_groups {
+        if ["DIRECT", "PROXY", "REJECT", "PROBE", "SCRIPT"].contains(&group.name.as_str()) {
+            return Err(format!("proxy group {} shadows an action.", group.name));
+        }
+        if !names.insert(group.name.as_str()) {
+            return Err(format!("proxy group {} is defined twice.", group.name));
+        }
+        if group.servers.is_empty() {
+            return Err(format!("proxy group {} has no servers.", group.name));
+        }
+        if let Some(server) = group
+            .servers
+            .iter()
+            .find(|s| !conf.servers.iter().any(|c| c.name() == s.as_str()))
+        {
+            return Err(format!(
+                "proxy group {} lists unknown server {}.",
+                group.name, server
+            ));
+        }
+    }
+    for rule in conf.rules.rules() {
+        match &rule.group {
+            Some(group) if !names.contains(group.as_str()) => {
+                return Err(format!("rule {} targets unknown proxy group.", rule));
+            }
+            _ => {}
+        }
+    }
+    let is_target =
+        |name: &str| ["DIRECT", "PROXY", "REJECT"].contains(&name) || names.contains(name);
+    match conf.final_target.as_str() {
+        // Scripts fall back to `final` themselves.
+        "SCRIPT" => return Err("final can not be SCRIPT.".to_string()),
+        "PROBE" => {}
+        target if !is_target(target) => {
+            return Err(format!("final targets unknown proxy group {}.", target));
+        }
+        _ => {}
+    }
+    for group in &conf.proxy_groups {
+        let mut seen = vec![group.name.as_str()];
+        let mut next = group.final_target.as_deref();
+        while let Some(target) = next {
+            if target == "PROBE" || target == "SCRIPT" {
+                return Err(format!(
+                    "final of proxy group {} can not be {}.",
+                    group.name, target
+                ));
+            }
+            if !is_target(target) {
+                return Err(format!(
+                    "final of proxy group {} targets unknown group {}.",
+                    group.name, target
+                ));
+            }
+            if seen.contains(&target) {
+                return Err(format!(
+                    "final of proxy group {} leads back to group {}.",
+                    group.name, target
+                ));
+            }
+            seen.push(target);
+            next = conf
+                .proxy_groups
+                .iter()
+                .find(|g| g.name == target)
+                .and_then(|g| g.final_target.as_deref());
+        }
+    }
+    Ok(())
+}
+
+/// Whether only this host can connect to `listen`, like `127.0.0.1:9000` or
+/// `localhost:9000`.
+fn is_loopback_listen(listen: &str) -> bool {
+    match listen.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().is_loopback(),
+        Err(_) => listen.split(':').next() == Some("localhost"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::duration::parse_duration;
+    use super::rule::{Action, DnsPolicy, ProxyRules};
+    use super::{AddressPreference, Ipv6Policy, ServerConfig};
+    use serde::Deserialize;
     use std::time::Duration;
 
     #[test]
     fn test_parse_duration() {
         assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
         assert_eq!(parse_duration("8ms"), Ok(Duration::from_millis(8)));
+        assert!(parse_duration("fast").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+
+    #[test]
+    fn test_parse_ipv6_policy() {
+        let policy: Ipv6Policy = serde_yaml::from_str("only-matched").unwrap();
+        assert_eq!(policy, Ipv6Policy::OnlyMatched);
+        let policy: Ipv6Policy = serde_yaml::from_str("prefer-v6").unwrap();
+        assert_eq!(policy, Ipv6Policy::PreferV6);
+    }
+
+    #[test]
+    fn test_parse_address_preference() {
+        let server: ServerConfig = serde_yaml::from_str(
+            r#"
+name: server1
+addr: 127.0.0.1:1080
+protocol: Socks5
+address_preference: ip-first
+keepalive: 30s
+keepalive_count: 4
+connect_timeout: 5s
+retries: 2
+read_timeout: 600s
+"#,
+        )
+        .unwrap();
+        assert_eq!(server.address_preference(), AddressPreference::IpFirst);
+        assert_eq!(server.keepalive(), Some(Duration::from_secs(30)));
+        assert_eq!(server.keepalive_interval(), Some(Duration::from_secs(30)));
+        assert_eq!(server.keepalive_count(), Some(4));
+        assert_eq!(server.connect_timeout(), Some(Duration::from_secs(5)));
+        assert_eq!(server.retries(), 2);
+        assert_eq!(server.read_timeout(), Some(Duration::from_secs(600)));
+        assert_eq!(server.write_timeout(), None);
+        let server: ServerConfig =
+            serde_yaml::from_str("{name: server2, addr: '127.0.0.1:1080', protocol: Socks5}")
+                .unwrap();
+        assert_eq!(server.address_preference(), AddressPreference::DomainFirst);
+        assert_eq!(server.keepalive(), None);
+        assert_eq!(server.keepalive_interval(), None);
+        assert_eq!(server.retries(), 0);
+    }
+
+    #[test]
+    fn test_parse_ipv6_server_addr() {
+        let server: ServerConfig =
+            serde_yaml::from_str("{name: v6, addr: '[2001:db8::1]:8388', protocol: Socks5}")
+                .unwrap();
+        assert_eq!(server.addr().to_string(), "[2001:db8::1]:8388");
+        let server: ServerConfig =
+            serde_yaml::from_str("{name: v6, addr: '[2001:db8::1]', protocol: Socks5}").unwrap();
+        assert_eq!(server.addr().to_string(), "[2001:db8::1]:80");
+        let server: ServerConfig =
+            serde_yaml::from_str("{name: v6, addr: 'v6.example.com:443', protocol: Socks5}")
+                .unwrap();
+        assert_eq!(server.addr().to_string(), "v6.example.com:443");
+        assert!(serde_yaml::from_str::<ServerConfig>(
+            "{name: v6, addr: '2001:db8::1:x', protocol: Socks5}"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_key_derivation() {
+        let server: ServerConfig = serde_yaml::from_str(
+            r#"
+name: ss
+addr: 127.0.0.1:8388
+protocol: Shadowsocks
+method: chacha20-ietf-poly1305
+password: AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=
+key_derivation: base64
+salt_size: 16
+"#,
+        )
+        .unwrap();
+        assert_eq!(server.key().unwrap().to_vec(), (0..32).collect::<Vec<u8>>());
+        assert_eq!(server.salt_size(), Some(16));
+        assert!(server.validate().is_ok());
+
+        let server: ServerConfig = serde_yaml::from_str(
+            "{name: ss, addr: '127.0.0.1:8388', protocol: Shadowsocks, method: chacha20-ietf-poly1305, password: c2hvcnQ=, key_derivation: base64}",
+        )
+        .unwrap();
+        assert_eq!(server.key(), None);
+        assert!(server.validate().is_err());
+
+        let server: ServerConfig = serde_yaml::from_str(
+            "{name: ss, addr: '127.0.0.1:8388', protocol: Shadowsocks, method: chacha20-ietf, password: pass, salt_size: 16}",
+        )
+        .unwrap();
+        assert!(server.key().is_some());
+        assert!(server.validate().is_err());
+    }
+
+    #[test]
+    fn test_proxy_groups() {
+        let config = |groups: &str, rule: &str| {
+            crate::test_config::load(&format!(
+                r#"servers:
+  - name: hk
+    addr: 127.0.0.1:1080
+    protocol: Socks5
+  - name: us
+    addr: 127.0.0.1:1081
+    protocol: Socks5
+proxy_groups: {}
+rules:
+  - '{}'
+"#,
+                groups, rule
+            ))
+        };
+        let conf = config(
+            "[{name: Streaming, servers: [us, hk], strategy: fallback}]",
+            "DOMAIN-SUFFIX,netflix.com,Streaming",
+        )
+        .unwrap();
+        assert_eq!(
+            conf.proxy_groups[0].strategy,
+            super::GroupStrategy::Fallback
+        );
+        let rule = conf.rules.rule_for_domain("www.netflix.com").unwrap();
+        assert_eq!(rule.action, Action::Proxy);
+        assert_eq!(rule.group.as_deref(), Some("Streaming"));
+
+        assert!(config("[]", "DOMAIN-SUFFIX,netflix.com,Streaming").is_err());
+        assert!(config("[{name: Streaming", "MATCH,DIRECT").is_err());
+        assert!(config("[{name: Streaming, servers: [jp]}]", "MATCH,DIRECT").is_err());
+        assert!(config("[{name: PROXY, servers: [us]}]", "MATCH,DIRECT").is_err());
+        assert_eq!(conf.rules.default_action(), Action::Direct);
+
+        // `final` follows the inline groups in the yaml.
+        let conf = config(
+            "[{name: Streaming, servers: [us], final: DIRECT}]\nfinal: Streaming",
+            "DOMAIN-SUFFIX,netflix.com,REJECT",
+        )
+        .unwrap();
+        assert_eq!(conf.rules.default_action(), Action::Proxy);
+        assert_eq!(conf.rules.final_rule().group.as_deref(), Some("Streaming"));
+        assert!(config("[]\nfinal: Streaming", "MATCH,DIRECT").is_err());
+        assert!(config("[]\nfinal: SCRIPT", "MATCH,DIRECT").is_err());
+        let groups = "[{name: A, servers: [us], final: B}, {name: B, servers: [hk], final: A}]";
+        assert!(config(groups, "MATCH,DIRECT").is_err());
+        assert!(config("[{name: A, servers: [us], final: C}]", "MATCH,DIRECT").is_err());
+    }
+
+    /// The config with `settings`, a server and a rule.
+    fn with_server(settings: &str) -> std::io::Result<super::Config> {
+        crate::test_config::load(&format!(
+            r#"{}
+servers:
+  - name: hk
+    addr: 127.0.0.1:1080
+    protocol: Socks5
+rules:
+  - 'MATCH,DIRECT'
+"#,
+            settings
+        ))
+    }
+
+    #[test]
+    fn test_inbounds() {
+        let conf = with_server("mode: tproxy\ntproxy_listen: 0.0.0.0:7893").unwrap();
+        assert_eq!(conf.mode, super::Mode::Tproxy);
+        assert!(with_server("mode: tproxy").is_err());
+        let conf = with_server("mode: redirect\nredirect_listen: 0.0.0.0:7892").unwrap();
+        assert!(!conf.mode.uses_tun());
+        assert!(with_server("mode: redirect\ntproxy_listen: 0.0.0.0:7892").is_err());
+    }
+
+    #[test]
+    fn test_tun_ip6() {
+        let conf = with_server("tun_ip6: 'fd00::1'\ntun_cidr6: 'fd00::/64'").unwrap();
+        assert_eq!(conf.tun_ip6, Some("fd00::1".parse().unwrap()));
+        assert_eq!(conf.tun_cidr6.unwrap().prefix_len(), 64);
+        assert!(with_server("").unwrap().tun_cidr6.is_none());
+        assert!(with_server("tun_ip6: 'fd00::1'").is_err());
+        assert!(with_server("tun_ip6: 'fd01::1'\ntun_cidr6: 'fd00::/64'").is_err());
+        assert!(with_server("tun_ip6: 'fd00::1'\ntun_cidr6: 'fd00::/129'").is_err());
+    }
+
+    #[test]
+    fn test_tun_queues() {
+        assert_eq!(with_server("").unwrap().tun_queues, 1);
+        assert_eq!(with_server("tun_queues: 4").unwrap().tun_queues, 4);
+        assert!(with_server("tun_queues: 0").is_err());
+    }
+
+    #[test]
+    fn test_tcp_buffers() {
+        let conf = with_server("").unwrap();
+        assert_eq!(conf.tcp_relay_buffer, 16 * 1024);
+        assert_eq!(conf.tcp_socket_buffer, None);
+        let conf = with_server("tcp_relay_buffer: 65536\ntcp_socket_buffer: 4194304").unwrap();
+        assert_eq!(conf.tcp_relay_buffer, 65536);
+        assert_eq!(conf.tcp_socket_buffer, Some(4194304));
+    }
+
+    #[test]
+    fn test_tun_fd() {
+        assert_eq!(
+            with_server("tun_fd: 3").unwrap().tun_fd,
+            Some(super::TunFd::Fd(3))
+        );
+        assert_eq!(
+            with_server("tun_fd: /run/seeker/tun.sock").unwrap().tun_fd,
+            Some(super::TunFd::Socket("/run/seeker/tun.sock".to_string()))
+        );
+        assert_eq!(
+            "/run/seeker/tun.sock".parse::<super::TunFd>(),
+            Ok(super::TunFd::Socket("/run/seeker/tun.sock".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_auto_route_exclude() {
+        let conf =
+            with_server("auto_route: true\nauto_route_exclude: ['100.64.0.0/10', 'fc00::/7']");
+        assert_eq!(conf.unwrap().auto_route_exclude.len(), 2);
+        assert!(with_server("auto_route_exclude: ['100.64.0.0']").is_err());
+        assert!(with_server("auto_route_exclude: ['100.64.0.0/33']").is_err());
+    }
+
+    #[test]
+    fn test_nat64_prefix() {
+        assert!(with_server("nat64_prefix: '64:ff9b::'").is_ok());
+        assert!(with_server("nat64_prefix: '64:ff9b::1'").is_err());
+    }
+
+    #[test]
+    fn test_rule_provider_interval() {
+        let provider = "rule_providers: {ads: {format: hosts, path: ads.txt, interval: ";
+        assert!(with_server(&format!("{}3600s}}}}", provider)).is_ok());
+        assert!(with_server(&format!("{}0s}}}}", provider)).is_err());
+    }
+
+    #[test]
+    fn test_domestic_dns() {
+        let resolvers = "dns_resolvers: {domestic: ['114.114.114.114:53']}";
+        let domestic = with_server(&format!("{}\ndomestic_dns: domestic", resolvers)).unwrap();
+        assert_eq!(domestic.domestic_dns.as_deref(), Some("domestic"));
+        assert!(with_server("domestic_dns: domestic").is_err());
+    }
+
+    #[test]
+    fn test_api_listen() {
+        assert!(with_server("api_listen: 127.0.0.1:9000").is_ok());
+        assert!(with_server("api_listen: '[::1]:9000'").is_ok());
+        assert!(with_server("api_listen: 0.0.0.0:9000").is_err());
+        assert!(with_server("api_listen: 0.0.0.0:9000\napi_secret: xxx").is_ok());
+    }
+
+    #[test]
+    fn test_conn_hook() {
+        assert!(with_server("conn_events: 127.0.0.1:9000").is_ok());
+        assert!(with_server("conn_hook: unix:/run/seeker/hook.sock").is_ok());
+        assert!(with_server("conn_hook: localhost:9000").is_err());
+        assert!(with_server("conn_events: 'unix:'").is_err());
+    }
+
+    #[test]
+    fn test_lan_bypass() {
+        let conf = with_server("tun_cidr6: 'fd00::/64'\ntun_ip6: 'fd00::1'").unwrap();
+        for ip in &[
+            "192.168.1.1",
+            "10.1.2.3",
+            "127.0.0.1",
+            "100.64.0.1",
+            "224.0.0.251",
+        ] {
+            assert!(conf.bypasses(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &["fe80::1", "fd12::1", "::1", "ff02::fb"] {
+            assert!(conf.bypasses(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &[
+            "11.0.0.10",
+            "8.8.8.8",
+            "100.128.0.1",
+            "2001:db8::1",
+            "fd00::10",
+        ] {
+            assert!(!conf.bypasses(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(!with_server("lan_bypass: false")
+            .unwrap()
+            .bypasses("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_tagged_rules() {
+        #[derive(Deserialize)]
+        struct Rules {
+            #[serde(with = "super::rules")]
+            rules: ProxyRules,
+        }
+        let rules: Rules = serde_yaml::from_str(
+            r#"
+rules:
+  - rule: 'DOMAIN-SUFFIX,netflix.com,PROXY'
+    tag: streaming
+  - rule: 'DOMAIN-SUFFIX,github.com,PROXY'
+    coalesce: 1ms
+  - rule: 'DOMAIN-KEYWORD,ads,REJECT'
+    reject: http-403
+  - rule: 'DOMAIN-SUFFIX,example.cn,DIRECT'
+    dns: domestic
+  - 'MATCH,DIRECT'
+"#,
+        )
+        .unwrap();
+        let rules = rules.rules;
+        assert_eq!(
+            rules.action_for_domain("www.netflix.com"),
+            Some(Action::Proxy)
+        );
+        assert_eq!(rules.tag_for_domain("www.netflix.com"), Some("streaming"));
+        assert_eq!(rules.tag_for_domain("baidu.com"), None);
+        assert_eq!(
+            rules.rule_for_domain("github.com").unwrap().coalesce,
+            Some(Duration::from_millis(1))
+        );
+        assert_eq!(
+            rules.rule_for_domain("ads.example.com").unwrap().reject,
+            crate::rule::RejectMode::Http403
+        );
+        assert_eq!(
+            rules.rule_for_domain("baidu.com").unwrap().reject,
+            crate::rule::RejectMode::Reset
+        );
+        assert_eq!(
+            rules.dns_for_domain("www.example.cn"),
+            Some(&DnsPolicy::Resolver("domestic".to_string()))
+        );
+        assert_eq!(rules.dns_for_domain("baidu.com"), None);
+    }
+
+    #[test]
+    fn test_process_rules() {
+        use crate::rule::{ConnectionMeta, Rule};
+        use std::str::FromStr;
+
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("DOMAIN-SUFFIX,baidu.com,DIRECT").unwrap(),
+            Rule::from_str("PROCESS-NAME,firefox,PROXY").unwrap(),
+            Rule::from_str("PROCESS-PATH,/usr/bin/curl,REJECT").unwrap(),
+            Rule::from_str("MATCH,DIRECT").unwrap(),
+        ]);
+        assert!(rules.has_process_rules());
+        assert_eq!(rules.rules()[1].to_string(), "PROCESS-NAME,firefox,PROXY");
+        let conn = ConnectionMeta {
+            domain: Some("google.com"),
+            process_name: Some("firefox"),
+            process_path: None,
+            uid: None,
+            port: None,
+            network: None,
+            inbound: None,
+            ip: None,
+        };
+        assert_eq!(rules.action_for_connection(&conn), Some(Action::Proxy));
+        assert!(rules.is_connection_explicitly_matched(&conn));
+        let conn = ConnectionMeta {
+            domain: Some("google.com"),
+            process_name: Some("curl"),
+            process_path: Some("/usr/bin/curl"),
+            uid: Some(0),
+            port: None,
+            network: None,
+            inbound: None,
+            ip: None,
+        };
+        assert_eq!(rules.action_for_connection(&conn), Some(Action::Reject));
+        assert_eq!(
+            rules.rules()[2].to_string(),
+            "PROCESS-PATH,/usr/bin/curl,REJECT"
+        );
+        assert_eq!(rules.action_for_domain("google.com"), Some(Action::Direct));
+        assert!(!rules.depends_on_connection("www.baidu.com"));
+        assert!(rules.depends_on_connection("google.com"));
+    }
+
+    #[test]
+    fn test_json_and_clash_proxies() {
+        let json = r#"{
+  "dns_start_ip": "11.0.0.10",
+  "dns_servers": ["223.5.5.5:53"],
+  "tun_name": "utun4",
+  "tun_ip": "11.0.0.1",
+  "tun_cidr": "11.0.0.0/16",
+  "dns_listen": "0.0.0.0:53",
+  "max_connect_errors": 2,
+  "proxies": [
+    {"name": "hk", "type": "ss", "server": "hk.example.com", "port": 8388, "cipher": "aes-256-gcm", "password": "pass"},
+    {"name": "jp", "type": "vmess", "server": "jp.example.com", "port": 443}
+  ],
+  "rules": ["DOMAIN-SUFFIX,google.com,PROXY", "MATCH,DIRECT"]
+}"#;
+        let conf = super::Config::from_text_with_profile(
+            json,
+            super::Format::Json,
+            std::path::Path::new("."),
+            None,
+        )
+        .unwrap();
+        let names: Vec<&str> = conf.servers.iter().map(|s| s.name()).collect();
+        assert_eq!(names, vec!["hk"]);
+        assert_eq!(conf.max_connect_errors, 2);
+
+        let err = super::Config::from_text_with_profile(
+            "{\"servers\": [}",
+            super::Format::Json,
+            std::path::Path::new("."),
+            None,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("line 1"));
+    }
+
+    #[test]
+    fn test_subscription_cache() {
+        let cache =
+            std::env::temp_dir().join(format!("seeker-subscription-{}", std::process::id()));
+        let text = crate::test_config::config_text(&format!(
+            "subscription_url: http://127.0.0.1:1/clash.yml\nsubscription_path: {}\nrules: []\n",
+            cache.display()
+        ));
+        let _ = std::fs::remove_file(&cache);
+        // Loading the config never downloads, the servers come with the first download.
+        let conf = super::Config::from_reader(text.as_bytes()).unwrap();
+        assert!(conf.servers.is_empty());
+
+        std::fs::write(
+            &cache,
+            "proxies:\n  - {name: hk, type: socks5, server: 10.0.0.1, port: 1080}\n",
+        )
+        .unwrap();
+        let conf = super::Config::from_reader(text.as_bytes()).unwrap();
+        let names: Vec<&str> = conf.servers.iter().map(|s| s.name()).collect();
+        assert_eq!(names, vec!["hk"]);
+        std::fs::remove_file(&cache).unwrap();
+
+        let text = text.replace("subscription_path", "# subscription_path");
+        assert!(super::Config::from_reader(text.as_bytes()).is_err());
     }
 }
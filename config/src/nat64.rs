@@ -0,0 +1,90 @@
+//! Address mapping for NAT64 (RFC 6052), used on IPv6-only networks.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// The IPv6 address reaching `ip` through the NAT64 gateway of the /96 `prefix`.
+pub fn synthesize(prefix: Ipv6Addr, ip: Ipv4Addr) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    octets[12..].copy_from_slice(&ip.octets());
+    Ipv6Addr::from(octets)
+}
+
+/// The IPv4 address embedded in `ip`, if it was synthesized from the /96 `prefix`.
+pub fn embedded_ipv4(prefix: Ipv6Addr, ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+    if octets[..12] != prefix.octets()[..12] {
+        return None;
+    }
+    Some(Ipv4Addr::new(
+        octets[12], octets[13], octets[14], octets[15],
+    ))
+}
+
+/// Whether `prefix` is a /96 network, the only kind of prefix seeker embeds addresses in.
+pub fn is_prefix(prefix: Ipv6Addr) -> bool {
+    prefix.octets()[12..] == [0; 4]
+}
+
+/// Addresses a NAT64 gateway does not translate (RFC 6052 section 3.1): they are local to
+/// the network they are used in, or not unicast addresses at all.
+pub fn is_special_use(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_multicast()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || a == 0
+        || a >= 240
+        || (a == 100 && b & 0xc0 == 64)
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && b & 0xfe == 18)
+}
+
+/// Map an IPv4 `addr` into the NAT64 `prefix`, special-use and other addresses pass through.
+pub fn translate(prefix: Option<Ipv6Addr>, addr: SocketAddr) -> SocketAddr {
+    match (prefix, addr.ip()) {
+        (Some(prefix), IpAddr::V4(ip)) if !is_special_use(ip) => {
+            SocketAddr::new(synthesize(prefix, ip).into(), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nat64() {
+        let prefix: Ipv6Addr = "64:ff9b::".parse().unwrap();
+        let ip = Ipv4Addr::new(192, 0, 2, 33);
+        let v6 = synthesize(prefix, ip);
+        assert_eq!(v6, "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(embedded_ipv4(prefix, v6), Some(ip));
+        assert_eq!(embedded_ipv4(prefix, "2001:db8::1".parse().unwrap()), None);
+
+        let addr = SocketAddr::new(ip.into(), 443);
+        assert_eq!(translate(None, addr), addr);
+        assert_eq!(
+            translate(Some(prefix), addr),
+            SocketAddr::new(v6.into(), 443)
+        );
+        for ip in &[
+            "10.0.0.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "192.168.1.1",
+            "100.64.0.1",
+        ] {
+            let addr = SocketAddr::new(ip.parse().unwrap(), 80);
+            assert_eq!(translate(Some(prefix), addr), addr, "{}", ip);
+        }
+        let public = SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53);
+        assert!(translate(Some(prefix), public).is_ipv6());
+
+        assert!(is_prefix(prefix));
+        assert!(!is_prefix("64:ff9b::1".parse().unwrap()));
+    }
+}
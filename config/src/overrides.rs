@@ -0,0 +1,105 @@
+//! Settings given on the command line, like `--tun-name`, which win over those of the config
+//! file. They are applied to every config loaded, including reloads, so a quick experiment
+//! or a container can change them without editing the file.
+use crate::error_code::CONFIG_INVALID;
+use crate::rule::Rule;
+use crate::{Config, TunFd};
+use std::io::{self, ErrorKind};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Overrides {
+    pub tun_name: Option<String>,
+    pub tun_fd: Option<TunFd>,
+    pub dns_listen: Option<String>,
+    /// The proxy group connections no rule matches go through, instead of `final`.
+    pub group: Option<String>,
+}
+
+impl Overrides {
+    pub fn apply(&self, mut conf: Config) -> io::Result<Config> {
+        if let Some(tun_name) = &self.tun_name {
+            conf.tun_name = tun_name.clone();
+        }
+        if let Some(tun_fd) = &self.tun_fd {
+            conf.tun_fd = Some(tun_fd.clone());
+        }
+        if let Some(dns_listen) = &self.dns_listen {
+            conf.dns_listen = dns_listen.clone();
+        }
+        if let Some(group) = &self.group {
+            if !conf.proxy_groups.iter().any(|g| &g.name == group) {
+                let names: Vec<&str> = conf.proxy_groups.iter().map(|g| g.name.as_str()).collect();
+                return Err(CONFIG_INVALID.error(
+                    ErrorKind::InvalidData,
+                    match names.len() {
+                        0 => format!("group {} is unknown, the config has none.", group),
+                        _ => format!(
+                            "group {} is unknown, proxy groups are {}.",
+                            group,
+                            names.join(", ")
+                        ),
+                    },
+                ));
+            }
+            // Group names are valid targets, validate_groups made sure none shadows an action.
+            let final_rule = Rule::from_str(&format!("MATCH,{}", group)).map_err(|()| {
+                CONFIG_INVALID.error(
+                    ErrorKind::InvalidData,
+                    format!("group {} is not a valid target.", group),
+                )
+            })?;
+            conf.final_target = group.clone();
+            conf.rules = conf.rules.with_final(final_rule);
+        }
+        Ok(conf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::Action;
+    use crate::test_config::load;
+
+    const CONFIG: &str = r#"servers:
+  - name: hk
+    addr: 127.0.0.1:1080
+    protocol: Socks5
+proxy_groups:
+  - name: Streaming
+    servers: [hk]
+rules:
+  - 'DOMAIN-SUFFIX,local,DIRECT'
+"#;
+
+    #[test]
+    fn test_apply() {
+        let config = load(CONFIG).unwrap();
+        let overrides = Overrides {
+            tun_name: Some("utun9".to_string()),
+            tun_fd: Some(TunFd::Fd(3)),
+            dns_listen: None,
+            group: Some("Streaming".to_string()),
+        };
+        let config = overrides.apply(config).unwrap();
+        assert_eq!(config.tun_name, "utun9");
+        assert_eq!(config.tun_fd, Some(TunFd::Fd(3)));
+        assert_eq!(config.dns_listen, "0.0.0.0:53");
+        assert_eq!(config.final_target, "Streaming");
+        let final_rule = config.rules.final_rule();
+        assert_eq!(final_rule.action, Action::Proxy);
+        assert_eq!(final_rule.group.as_deref(), Some("Streaming"));
+
+        let config = load(CONFIG).unwrap();
+        let overrides = Overrides {
+            group: Some("Gaming".to_string()),
+            ..Overrides::default()
+        };
+        assert!(overrides
+            .apply(config)
+            .unwrap_err()
+            .to_string()
+            .contains("group Gaming is unknown, proxy groups are Streaming."));
+    }
+}
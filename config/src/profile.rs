@@ -0,0 +1,166 @@
+//! `profiles` keeps several variants of the config in one file, like `home`, `work` and
+//! `travel`, and `profile` names the active one:
+//!
+//! ```yaml
+//! profile: home
+//! profiles:
+//!   home: {mode: tun}
+//!   work: {rules: ['DOMAIN-SUFFIX,corp.example.com,DIRECT', 'MATCH,PROXY']}
+//!   travel: profiles/travel.yml
+//! ```
+//!
+//! The settings of the active profile replace those of the config, lists and mappings as a
+//! whole. A profile is a mapping, or the path of a YAML file with one, relative to the
+//! directory of the config. Profiles can not include files or have profiles of their own.
+use serde_yaml::{Mapping, Value};
+use std::fs;
+use std::path::Path;
+
+/// Keys only the config itself can have.
+const CONFIG_ONLY: &[&str] = &["include", "profile", "profiles"];
+
+fn name_of(name: &Value) -> Result<String, String> {
+    name.as_str()
+        .map(str::to_string)
+        .ok_or_else(|| format!("profile names have to be strings, not {:?}.", name))
+}
+
+/// The settings of the profile `value`.
+fn load(name: &str, value: Value, dir: &Path) -> Result<Mapping, String> {
+    let settings = match value {
+        Value::Mapping(settings) => settings,
+        Value::Null => Mapping::new(),
+        Value::String(path) => {
+            let path = dir.join(path);
+            let source = path.display();
+            let text = fs::read_to_string(&path)
+                .map_err(|e| format!("profile {}: {}: {}", name, source, e))?;
+            let text = crate::env_vars::expand(&text)
+                .map_err(|e| format!("profile {}: {}: {}", name, source, e))?;
+            match serde_yaml::from_str(&text)
+                .map_err(|e| format!("profile {}: {}: {}", name, source, e))?
+            {
+                Value::Mapping(settings) => settings,
+                Value::Null => Mapping::new(),
+                _ => return Err(format!("profile {}: {} is not a mapping.", name, source)),
+            }
+        }
+        _ => {
+            return Err(format!(
+                "profile {} has to be a mapping or the path of a file.",
+                name
+            ))
+        }
+    };
+    for key in CONFIG_ONLY {
+        if settings.contains_key(&Value::from(*key)) {
+            return Err(format!("profile {} can not set {}.", name, key));
+        }
+    }
+    Ok(settings)
+}
+
+/// Replace the settings of `doc`, the config, with those of its active profile: `name` when
+/// given, `profile` of the config otherwise. Returns the names of all profiles, in the order
+/// of the config.
+pub fn select(doc: &mut Mapping, dir: &Path, name: Option<&str>) -> Result<Vec<String>, String> {
+    let profiles = match doc.remove(&Value::from("profiles")) {
+        Some(Value::Mapping(profiles)) => profiles,
+        Some(Value::Null) | None => Mapping::new(),
+        Some(_) => return Err("profiles has to be a mapping of names to profiles.".to_string()),
+    };
+    let names = profiles
+        .iter()
+        .map(|(name, _)| name_of(name))
+        .collect::<Result<Vec<_>, _>>()?;
+    let active = match name {
+        Some(name) => Some(name.to_string()),
+        None => match doc.get(&Value::from("profile")) {
+            Some(Value::String(name)) => Some(name.clone()),
+            Some(Value::Null) | None => None,
+            Some(_) => return Err("profile has to be the name of a profile.".to_string()),
+        },
+    };
+    let active = match active {
+        Some(active) => active,
+        None => return Ok(names),
+    };
+    let value = match profiles
+        .into_iter()
+        .find(|(n, _)| n.as_str() == Some(active.as_str()))
+    {
+        Some((_, value)) => value,
+        None if names.is_empty() => {
+            return Err(format!(
+                "profile {} is unknown, the config has none.",
+                active
+            ))
+        }
+        None => {
+            return Err(format!(
+                "profile {} is unknown, profiles are {}.",
+                active,
+                names.join(", ")
+            ))
+        }
+    };
+    for (key, value) in load(&active, value, dir)? {
+        doc.insert(key, value);
+    }
+    doc.insert(Value::from("profile"), Value::from(active));
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+profile: home
+dns_timeout: 1s
+rules: ['MATCH,DIRECT']
+profiles:
+  home: {}
+  work:
+    dns_timeout: 2s
+    rules: ['DOMAIN-SUFFIX,corp.example.com,DIRECT', 'MATCH,PROXY']
+"#;
+
+    #[test]
+    fn test_select() {
+        let dir = Path::new(".");
+        let mut doc: Mapping = serde_yaml::from_str(CONFIG).unwrap();
+        assert_eq!(select(&mut doc, dir, None).unwrap(), vec!["home", "work"]);
+        assert_eq!(doc[&Value::from("dns_timeout")], Value::from("1s"));
+        assert!(!doc.contains_key(&Value::from("profiles")));
+
+        let mut doc: Mapping = serde_yaml::from_str(CONFIG).unwrap();
+        select(&mut doc, dir, Some("work")).unwrap();
+        assert_eq!(doc[&Value::from("dns_timeout")], Value::from("2s"));
+        assert_eq!(doc[&Value::from("profile")], Value::from("work"));
+        assert_eq!(doc[&Value::from("rules")].as_sequence().unwrap().len(), 2);
+
+        let mut doc: Mapping = serde_yaml::from_str(CONFIG).unwrap();
+        assert_eq!(
+            select(&mut doc, dir, Some("travel")).unwrap_err(),
+            "profile travel is unknown, profiles are home, work."
+        );
+
+        let mut doc: Mapping = serde_yaml::from_str("profiles: {a: {profile: b}}").unwrap();
+        assert!(select(&mut doc, dir, Some("a")).is_err());
+        let mut doc: Mapping = serde_yaml::from_str("dns_timeout: 1s").unwrap();
+        assert!(select(&mut doc, dir, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_select_file() {
+        let dir = std::env::temp_dir().join(format!("seeker-profile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("travel.yml"), "dns_timeout: 3s\n").unwrap();
+        let mut doc: Mapping =
+            serde_yaml::from_str("dns_timeout: 1s\nprofiles: {travel: travel.yml}").unwrap();
+        select(&mut doc, &dir, Some("travel")).unwrap();
+        assert_eq!(doc[&Value::from("dns_timeout")], Value::from("3s"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
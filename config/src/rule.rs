@@ -1,18 +1,192 @@
-use crate::parse_cidr;
+use crate::asn::AsnDb;
+use crate::domain_index::DomainIndex;
+use crate::geosite::GeoSite;
+use crate::ip_trie::{unmap, IpTrie};
+use crate::rule_set::RuleSets;
+use crate::time_window::{local_minute_of_day, TimeWindow};
+use regex::{Regex, RegexSet, SetMatches};
+use schemars::JsonSchema;
 use serde::export::Formatter;
-use smoltcp::wire::Ipv4Cidr;
+use serde::Deserialize;
+use smoltcp::wire::{Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr};
+use std::collections::HashMap;
 use std::fmt;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
-pub enum Rule {
-    Domain(String, Action),
-    DomainSuffix(String, Action),
-    DomainKeyword(String, Action),
-    IpCidr(Ipv4Cidr, Action),
-    Match(Action),
+pub enum Matcher {
+    Domain(String),
+    DomainSuffix(String),
+    DomainKeyword(String),
+    /// Regular expression the domain has to match, all of them are compiled into one set.
+    DomainRegex(String),
+    /// Lowercase code of a geosite category.
+    GeoSite(String),
+    /// Name of one of the `rule_providers`.
+    RuleSet(String),
+    IpCidr(Ipv4Cidr),
+    IpCidr6(Ipv6Cidr),
+    /// Autonomous system announcing the address, looked up in the `asn_file`.
+    IpAsn(u32),
+    /// Name of the executable of the process that opened the connection.
+    ProcessName(String),
+    /// Full path of the executable of the process that opened the connection.
+    ProcessPath(String),
+    /// Owner of the process that opened the connection.
+    Uid(u32),
+    /// Port the connection goes to.
+    DstPort(u16),
+    /// Transport of the connection, `tcp` or `udp`.
+    Network(String),
+    /// Lowercase name of the inbound the connection entered through, see `INBOUNDS`.
+    Inbound(String),
+    /// All of the matchers match, e.g. `AND((DST-PORT,443),(DOMAIN-SUFFIX,google.com))`.
+    And(Vec<Matcher>),
+    /// Any of the matchers matches.
+    Or(Vec<Matcher>),
+    /// The matcher does not match, e.g. `NOT((DST-PORT,443))`.
+    Not(Box<Matcher>),
+    Match,
+}
+
+/// What is known about a connection when it is matched against the rules.
+///
+/// Fields left `None` never match, so DNS queries, which only know the domain, skip
+/// rules about processes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConnectionMeta<'a> {
+    pub domain: Option<&'a str>,
+    pub process_name: Option<&'a str>,
+    pub process_path: Option<&'a str>,
+    pub uid: Option<u32>,
+    pub port: Option<u16>,
+    /// `tcp` or `udp`.
+    pub network: Option<&'a str>,
+    /// Name of the inbound the connection entered through.
+    pub inbound: Option<&'a str>,
+    /// Address the connection goes to when it has no domain, for the IP rules.
+    pub ip: Option<IpAddr>,
+}
+
+/// The inbound of connections captured by the tun device.
+pub const TUN_INBOUND: &str = "tun";
+
+/// The inbound of connections redirected by TPROXY rules in `mode: tproxy`.
+pub const TPROXY_INBOUND: &str = "tproxy";
+
+/// The inbound of connections redirected by REDIRECT rules in `mode: redirect`.
+pub const REDIRECT_INBOUND: &str = "redirect";
+
+/// Names `INBOUND` rules can use.
+pub const INBOUNDS: &[&str] = &[TUN_INBOUND, TPROXY_INBOUND, REDIRECT_INBOUND];
+
+impl<'a> ConnectionMeta<'a> {
+    pub fn domain(domain: &'a str) -> Self {
+        ConnectionMeta {
+            domain: Some(domain),
+            ..Default::default()
+        }
+    }
+}
+
+impl Matcher {
+    /// Whether the matcher needs more than the domain to match.
+    pub fn needs_connection(&self) -> bool {
+        match self {
+            Matcher::ProcessName(_)
+            | Matcher::ProcessPath(_)
+            | Matcher::Uid(_)
+            | Matcher::DstPort(_)
+            | Matcher::Network(_)
+            | Matcher::Inbound(_) => true,
+            Matcher::And(matchers) | Matcher::Or(matchers) => {
+                matchers.iter().any(Matcher::needs_connection)
+            }
+            Matcher::Not(matcher) => matcher.needs_connection(),
+            _ => false,
+        }
+    }
+
+    /// Call `f` with this matcher and every matcher nested in it.
+    fn visit(&self, f: &mut dyn FnMut(&Matcher)) {
+        f(self);
+        match self {
+            Matcher::And(matchers) | Matcher::Or(matchers) => {
+                matchers.iter().for_each(|m| m.visit(f))
+            }
+            Matcher::Not(matcher) => matcher.visit(f),
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Rule {
+    pub matcher: Matcher,
+    pub action: Action,
+    /// User defined label attached to connections matched by this rule.
+    pub tag: Option<String>,
+    /// Delay used to coalesce small writes to upstream, off when `None`.
+    pub coalesce: Option<Duration>,
+    /// How `REJECT` answers, ignored by other actions.
+    pub reject: RejectMode,
+    /// Proxy group the connection goes through, any server when `None`.
+    pub group: Option<String>,
+    /// Only match while the local time is in this window.
+    pub time: Option<TimeWindow>,
+    /// How the DNS server answers the domains of this rule, by its action when `None`.
+    pub dns: Option<DnsPolicy>,
+}
+
+/// How the DNS server answers the domains matched by a rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsPolicy {
+    /// A fake ip whatever the action, connections reach the tun and proxied domains are
+    /// resolved by the proxy.
+    FakeIp,
+    /// The real addresses, from `dns_servers`.
+    Real,
+    /// The real addresses, from the upstreams of this entry of `dns_resolvers`.
+    Resolver(String),
+}
+
+impl FromStr for DnsPolicy {
+    type Err = ();
+
+    /// `fake-ip`, `real` or the name of one of the `dns_resolvers`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err(()),
+            "fake-ip" => Ok(DnsPolicy::FakeIp),
+            "real" => Ok(DnsPolicy::Real),
+            name => Ok(DnsPolicy::Resolver(name.to_string())),
+        }
+    }
+}
+
+/// What a `REJECT` rule answers with.
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema, PartialEq, Eq)]
+pub enum RejectMode {
+    /// Domains get an empty DNS answer, TCP connections are reset.
+    #[serde(rename = "reset")]
+    Reset,
+    /// Domains get a fake ip and their connections are held open without an answer, so
+    /// apps do not retry right away.
+    #[serde(rename = "drop")]
+    Drop,
+    /// Domains get a fake ip, plain HTTP requests are answered with `403 Forbidden` and
+    /// other TCP connections are reset.
+    #[serde(rename = "http-403")]
+    Http403,
+}
+
+impl Default for RejectMode {
+    fn default() -> Self {
+        RejectMode::Reset
+    }
 }
 
 #[derive(Eq, PartialEq, Copy, Clone, Debug, Hash)]
@@ -21,48 +195,488 @@ pub enum Action {
     Direct,
     Proxy,
     Probe,
+    /// Ask the `rule_script` for the action.
+    Script,
 }
 
 #[derive(Debug, Clone)]
 pub struct ProxyRules {
     rules: Arc<Vec<Rule>>,
+    geosite: Arc<GeoSite>,
+    rule_sets: Arc<RuleSets>,
+    regexes: Arc<DomainRegexes>,
+    /// The `DOMAIN`, `DOMAIN-SUFFIX` and `DOMAIN-KEYWORD` rules.
+    domain_rules: Arc<DomainIndex>,
+    /// Indexes of the rules matched one by one: all but the domain and IP rules.
+    scanned_rules: Arc<Vec<usize>>,
+    /// Index of the first `IP-CIDR` or `IP-CIDR6` rule of each network.
+    ip_rules: Arc<IpTrie<usize>>,
+    /// Index of the first `IP-ASN` rule of each autonomous system.
+    asn_rules: Arc<HashMap<u32, usize>>,
+    asn_db: Option<Arc<AsnDb>>,
+    /// Minutes since local midnight, for rules with a `time` window.
+    clock: fn() -> u16,
+    /// Where connections no rule matches go, `MATCH,DIRECT` unless the config sets `final`.
+    final_rule: Arc<Rule>,
+}
+
+/// The patterns of all `DOMAIN-REGEX` rules, matched against a domain in a single pass.
+#[derive(Debug, Default)]
+struct DomainRegexes {
+    set: Option<RegexSet>,
+    /// Position of each pattern in `set`.
+    index: HashMap<String, usize>,
+}
+
+impl DomainRegexes {
+    /// Each pattern compiled on its own when parsed, together they can still be bigger
+    /// than the regex size limit.
+    fn new(rules: &[Rule]) -> Result<Self, regex::Error> {
+        let mut patterns = vec![];
+        for rule in rules {
+            rule.matcher.visit(&mut |matcher| {
+                if let Matcher::DomainRegex(pattern) = matcher {
+                    if !patterns.contains(pattern) {
+                        patterns.push(pattern.clone());
+                    }
+                }
+            });
+        }
+        if patterns.is_empty() {
+            return Ok(DomainRegexes::default());
+        }
+        let set = RegexSet::new(&patterns)?;
+        let index = patterns
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| (p, i))
+            .collect();
+        Ok(DomainRegexes {
+            set: Some(set),
+            index,
+        })
+    }
+
+    /// The patterns matching `domain`, `None` without regex rules.
+    fn matches(&self, domain: Option<&str>) -> Option<SetMatches> {
+        Some(self.set.as_ref()?.matches(domain?))
+    }
 }
 
 impl ProxyRules {
+    /// Panics when the `DOMAIN-REGEX` patterns do not compile together, rules from a config
+    /// go through `try_new`.
     pub fn new(rules: Vec<Rule>) -> Self {
-        Self {
+        ProxyRules::try_new(rules).expect("invalid DOMAIN-REGEX patterns")
+    }
+
+    pub fn try_new(rules: Vec<Rule>) -> Result<Self, regex::Error> {
+        let mut ip_rules = IpTrie::default();
+        let (mut domains, mut suffixes, mut keywords) = (vec![], vec![], vec![]);
+        let mut scanned_rules = vec![];
+        let mut asn_rules = HashMap::new();
+        for (i, rule) in rules.iter().enumerate() {
+            // Rules with a time window can not be looked up in the indexes, they only
+            // match part of the day.
+            if rule.time.is_some() {
+                scanned_rules.push(i);
+                continue;
+            }
+            match &rule.matcher {
+                Matcher::IpCidr(cidr) => {
+                    let ip = IpAddr::V4(cidr.address().into());
+                    ip_rules.entry(ip, cidr.prefix_len()).get_or_insert(i);
+                }
+                Matcher::IpCidr6(cidr) => {
+                    let ip = IpAddr::V6(cidr.address().into());
+                    ip_rules.entry(ip, cidr.prefix_len()).get_or_insert(i);
+                }
+                Matcher::IpAsn(asn) => {
+                    asn_rules.entry(*asn).or_insert(i);
+                }
+                Matcher::Domain(d) => domains.push((i, d.as_str())),
+                Matcher::DomainSuffix(d) => suffixes.push((i, d.as_str())),
+                Matcher::DomainKeyword(d) => keywords.push((i, d.as_str())),
+                _ => scanned_rules.push(i),
+            }
+        }
+        Ok(Self {
+            regexes: Arc::new(DomainRegexes::new(&rules)?),
+            domain_rules: Arc::new(DomainIndex::new(domains, suffixes, keywords)),
+            scanned_rules: Arc::new(scanned_rules),
+            ip_rules: Arc::new(ip_rules),
+            asn_rules: Arc::new(asn_rules),
+            asn_db: None,
             rules: Arc::new(rules),
+            geosite: Arc::new(GeoSite::default()),
+            rule_sets: Arc::new(RuleSets::default()),
+            clock: local_minute_of_day,
+            final_rule: Arc::new(Rule::from_str("MATCH,DIRECT").unwrap()),
+        })
+    }
+
+    /// Send connections no rule matches to the target of `rule`, a `MATCH` rule.
+    pub fn with_final(self, rule: Rule) -> Self {
+        Self {
+            final_rule: Arc::new(rule),
+            ..self
         }
     }
 
+    /// Read the time of day for rules with a `time` window from `clock`.
+    pub fn with_clock(self, clock: fn() -> u16) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Look up the autonomous systems of `IP-ASN` rules in `db`.
+    pub fn with_asn_db(self, db: AsnDb) -> Self {
+        Self {
+            asn_db: Some(Arc::new(db)),
+            ..self
+        }
+    }
+
+    pub fn has_asn_rules(&self) -> bool {
+        !self.asn_rules.is_empty()
+    }
+
+    /// Use the domain lists of `geosite` for `GEOSITE` rules.
+    pub fn with_geosite(self, geosite: GeoSite) -> Self {
+        Self {
+            geosite: Arc::new(geosite),
+            ..self
+        }
+    }
+
+    /// Use the lists of `rule_sets` for `RULE-SET` rules.
+    pub fn with_rule_sets(self, rule_sets: RuleSets) -> Self {
+        Self {
+            rule_sets: Arc::new(rule_sets),
+            ..self
+        }
+    }
+
+    /// The lists of the `RULE-SET` rules, shared by all clones of the rules.
+    pub fn rule_sets(&self) -> &RuleSets {
+        &self.rule_sets
+    }
+
+    /// Use the lists of `other`, so refreshing them updates both.
+    pub fn with_rule_sets_of(self, other: &ProxyRules) -> Self {
+        Self {
+            rule_sets: other.rule_sets.clone(),
+            ..self
+        }
+    }
+
+    /// Names of the rule providers used by the rules.
+    pub fn rule_set_names(&self) -> Vec<String> {
+        let mut names = vec![];
+        for rule in self.rules.iter() {
+            rule.matcher.visit(&mut |matcher| {
+                if let Matcher::RuleSet(name) = matcher {
+                    if !names.contains(name) {
+                        names.push(name.clone());
+                    }
+                }
+            });
+        }
+        names
+    }
+
+    /// Codes of the geosite categories used by the rules.
+    pub fn geosite_codes(&self) -> Vec<String> {
+        let mut codes = vec![];
+        for rule in self.rules.iter() {
+            rule.matcher.visit(&mut |matcher| {
+                if let Matcher::GeoSite(code) = matcher {
+                    if !codes.contains(code) {
+                        codes.push(code.clone());
+                    }
+                }
+            });
+        }
+        codes
+    }
+
+    /// Names of the inbounds used by the rules.
+    pub fn inbound_names(&self) -> Vec<String> {
+        let mut names = vec![];
+        for rule in self.rules.iter() {
+            rule.matcher.visit(&mut |matcher| {
+                if let Matcher::Inbound(name) = matcher {
+                    if !names.contains(name) {
+                        names.push(name.clone());
+                    }
+                }
+            });
+        }
+        names
+    }
+
+    /// Whether any rule matches on the transport of the connection.
+    pub fn has_network_rules(&self) -> bool {
+        let mut found = false;
+        for rule in self.rules.iter() {
+            rule.matcher.visit(&mut |matcher| {
+                found |= matches!(matcher, Matcher::Network(_));
+            });
+        }
+        found
+    }
+
+    /// Whether any rule matches on the process that opened the connection or its owner.
+    pub fn has_process_rules(&self) -> bool {
+        let mut found = false;
+        for rule in self.rules.iter() {
+            rule.matcher.visit(&mut |matcher| {
+                found |= matches!(
+                    matcher,
+                    Matcher::ProcessName(_) | Matcher::ProcessPath(_) | Matcher::Uid(_)
+                );
+            });
+        }
+        found
+    }
+
+    /// Whether `matcher` matches `conn`, `regex_hits` are the regex patterns matching its
+    /// domain.
+    fn matches(
+        &self,
+        matcher: &Matcher,
+        conn: &ConnectionMeta,
+        regex_hits: &Option<SetMatches>,
+    ) -> bool {
+        match matcher {
+            Matcher::Domain(d) => conn.domain == Some(d.as_str()),
+            Matcher::DomainSuffix(d) => conn.domain.map_or(false, |domain| domain.ends_with(d)),
+            Matcher::DomainKeyword(d) => conn
+                .domain
+                .map_or(false, |domain| domain.contains(d.as_str())),
+            Matcher::DomainRegex(pattern) => match (regex_hits, self.regexes.index.get(pattern)) {
+                (Some(hits), Some(i)) => hits.matched(*i),
+                _ => false,
+            },
+            Matcher::RuleSet(name) => conn
+                .domain
+                .map_or(false, |domain| self.rule_sets.matches(name, domain)),
+            Matcher::GeoSite(code) => match (conn.domain, self.geosite.get(code)) {
+                (Some(domain), Some(list)) => list.matches(domain),
+                _ => false,
+            },
+            Matcher::ProcessName(name) => conn.process_name == Some(name.as_str()),
+            Matcher::ProcessPath(path) => conn.process_path == Some(path.as_str()),
+            Matcher::Uid(uid) => conn.uid == Some(*uid),
+            Matcher::DstPort(port) => conn.port == Some(*port),
+            Matcher::Network(network) => conn.network == Some(network.as_str()),
+            Matcher::Inbound(name) => conn.inbound == Some(name.as_str()),
+            Matcher::And(matchers) => matchers.iter().all(|m| self.matches(m, conn, regex_hits)),
+            Matcher::Or(matchers) => matchers.iter().any(|m| self.matches(m, conn, regex_hits)),
+            Matcher::Not(matcher) => !self.matches(matcher, conn, regex_hits),
+            Matcher::Match => true,
+            // Never operands of logical rules, see `parse_operands`, only reached for rules
+            // with a time window.
+            Matcher::IpCidr(_) | Matcher::IpCidr6(_) | Matcher::IpAsn(_) => {
+                conn.ip.map_or(false, |ip| self.matches_ip(matcher, ip))
+            }
+        }
+    }
+
+    /// Whether the rule `i` is not limited to a time window or its window is now.
+    fn is_active(&self, i: usize) -> bool {
+        self.rules[i]
+            .time
+            .map_or(true, |window| window.contains((self.clock)()))
+    }
+
+    /// Whether the rule `i` is active and its matcher matches `conn`.
+    fn rule_matches(
+        &self,
+        i: usize,
+        conn: &ConnectionMeta,
+        regex_hits: &Option<SetMatches>,
+    ) -> bool {
+        self.is_active(i) && self.matches(&self.rules[i].matcher, conn, regex_hits)
+    }
+
+    /// Index of the first rule matching `conn`.
+    ///
+    /// The domain and IP rules are looked up in the indexes, the other rules are only
+    /// matched when they come before the indexed rule found.
+    fn first_match(&self, conn: &ConnectionMeta, regex_hits: &Option<SetMatches>) -> Option<usize> {
+        let by_domain = conn
+            .domain
+            .and_then(|domain| self.domain_rules.first_match(domain));
+        let by_ip = conn.ip.and_then(|ip| self.indexed_for_ip(ip));
+        let indexed = first_of(by_domain, by_ip);
+        self.scanned_before(indexed)
+            .find(|&i| self.rule_matches(i, conn, regex_hits))
+            .or(indexed)
+    }
+
+    /// The rules matched one by one that come before the rule `end`.
+    fn scanned_before(&self, end: Option<usize>) -> impl Iterator<Item = usize> + '_ {
+        self.scanned_rules
+            .iter()
+            .copied()
+            .take_while(move |&i| end.map_or(true, |end| i < end))
+    }
+
+    /// Index in `rules()` of the first rule matching `conn`.
+    pub fn index_for_connection(&self, conn: &ConnectionMeta) -> Option<usize> {
+        let lowercase = lowercase(conn.domain);
+        let conn = &ConnectionMeta {
+            domain: lowercase.as_deref().or(conn.domain),
+            ..*conn
+        };
+        let regex_hits = self.regexes.matches(conn.domain);
+        self.first_match(conn, &regex_hits)
+    }
+
+    /// The first rule matching `conn`.
+    pub fn rule_for_connection(&self, conn: &ConnectionMeta) -> Option<&Rule> {
+        let i = self.index_for_connection(conn)?;
+        Some(&self.rules[i])
+    }
+
+    pub fn action_for_connection(&self, conn: &ConnectionMeta) -> Option<Action> {
+        self.rule_for_connection(conn).map(|rule| rule.action)
+    }
+
+    /// The first rule matching `domain`.
+    pub fn rule_for_domain(&self, domain: &str) -> Option<&Rule> {
+        self.rule_for_connection(&ConnectionMeta::domain(domain))
+    }
+
     pub fn action_for_domain(&self, domain: &str) -> Option<Action> {
-        self.rules
+        self.rule_for_domain(domain).map(|rule| rule.action)
+    }
+
+    pub fn tag_for_domain(&self, domain: &str) -> Option<&str> {
+        self.rule_for_domain(domain)?.tag.as_deref()
+    }
+
+    /// The DNS policy of the first rule matching `domain`.
+    pub fn dns_for_domain(&self, domain: &str) -> Option<&DnsPolicy> {
+        self.rule_for_domain(domain)?.dns.as_ref()
+    }
+
+    /// Whether a rule that needs more than the domain comes before the first rule
+    /// matching `domain`, so the action can only be decided once the connection is made.
+    pub fn depends_on_connection(&self, domain: &str) -> bool {
+        let lowercase = lowercase(Some(domain));
+        let domain = lowercase.as_deref().unwrap_or(domain);
+        let conn = ConnectionMeta::domain(domain);
+        let regex_hits = self.regexes.matches(conn.domain);
+        // Domain rules never need the connection, only the rules before them matter.
+        for i in self.scanned_before(self.domain_rules.first_match(domain)) {
+            let matcher = &self.rules[i].matcher;
+            if !self.is_active(i) {
+                continue;
+            }
+            if matcher.needs_connection() {
+                return true;
+            }
+            if self.matches(matcher, &conn, &regex_hits) {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Whether `conn` is matched by a rule other than the `MATCH` catch-all.
+    pub fn is_connection_explicitly_matched(&self, conn: &ConnectionMeta) -> bool {
+        let lowercase = lowercase(conn.domain);
+        let conn = &ConnectionMeta {
+            domain: lowercase.as_deref().or(conn.domain),
+            ..*conn
+        };
+        if let Some(domain) = conn.domain {
+            if self.domain_rules.first_match(domain).is_some() {
+                return true;
+            }
+        }
+        if let Some(ip) = conn.ip {
+            if self.indexed_for_ip(ip).is_some() {
+                return true;
+            }
+        }
+        let regex_hits = self.regexes.matches(conn.domain);
+        self.scanned_rules
             .iter()
-            .filter_map(|rule| match rule {
-                Rule::Domain(d, action) if d == domain => Some(*action),
-                Rule::DomainSuffix(d, action) if domain.ends_with(d) => Some(*action),
-                Rule::DomainKeyword(d, action) if domain.contains(d) => Some(*action),
-                Rule::Match(action) => Some(*action),
-                _ => None,
+            .any(|&i| match &self.rules[i].matcher {
+                Matcher::Match => false,
+                _ => self.rule_matches(i, conn, &regex_hits),
             })
-            .take(1)
-            .next()
     }
 
-    #[allow(dead_code)]
-    pub fn action_for_ip(&self, ip: Ipv4Addr) -> Option<Action> {
-        self.rules
-            .iter()
-            .filter_map(|rule| match rule {
-                Rule::IpCidr(cidr, action) if cidr.contains_addr(&ip.into()) => Some(*action),
-                _ => None,
+    /// Whether `domain` is matched by a rule other than the `MATCH` catch-all.
+    pub fn is_explicitly_matched(&self, domain: &str) -> bool {
+        self.is_connection_explicitly_matched(&ConnectionMeta::domain(domain))
+    }
+
+    /// The first `IP-CIDR`, `IP-CIDR6` or `IP-ASN` rule containing `ip`.
+    ///
+    /// All networks containing `ip` are found by walking the trie along its bits, the rule
+    /// listed first among them wins like for any other rule.
+    pub fn rule_for_ip(&self, ip: IpAddr) -> Option<&Rule> {
+        let i = self.index_for_ip(ip)?;
+        Some(&self.rules[i])
+    }
+
+    /// Index in `rules()` of the rule `rule_for_ip` returns.
+    pub fn index_for_ip(&self, ip: IpAddr) -> Option<usize> {
+        let indexed = self.indexed_for_ip(ip);
+        self.scanned_before(indexed)
+            .find(|&i| {
+                self.rules[i].time.is_some()
+                    && self.is_active(i)
+                    && self.matches_ip(&self.rules[i].matcher, ip)
             })
-            .take(1)
-            .next()
+            .or(indexed)
     }
 
+    /// The first rule containing `ip` in the ip indexes.
+    fn indexed_for_ip(&self, ip: IpAddr) -> Option<usize> {
+        let by_cidr = self.ip_rules.matches(ip).min().copied();
+        let by_asn = self
+            .asn_db
+            .as_ref()
+            .and_then(|db| db.asn(unmap(ip)))
+            .and_then(|asn| self.asn_rules.get(&asn).copied());
+        first_of(by_cidr, by_asn)
+    }
+
+    /// Whether the IP rule `matcher` contains `ip`, for rules left out of the ip indexes.
+    fn matches_ip(&self, matcher: &Matcher, ip: IpAddr) -> bool {
+        match (matcher, unmap(ip)) {
+            (Matcher::IpCidr(cidr), IpAddr::V4(ip)) => cidr.contains_addr(&Ipv4Address::from(ip)),
+            (Matcher::IpCidr6(cidr), IpAddr::V6(ip)) => cidr.contains_addr(&Ipv6Address::from(ip)),
+            (Matcher::IpAsn(asn), ip) => self
+                .asn_db
+                .as_ref()
+                .and_then(|db| db.asn(ip))
+                .map_or(false, |found| found == *asn),
+            _ => false,
+        }
+    }
+
+    pub fn action_for_ip(&self, ip: IpAddr) -> Option<Action> {
+        self.rule_for_ip(ip).map(|rule| rule.action)
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// The action for domains no rule matches, see `final_rule`.
     pub fn default_action(&self) -> Action {
-        Action::Direct
+        self.final_rule.action
+    }
+
+    /// The `final` of the config as a `MATCH` rule, applied after all the rules.
+    pub fn final_rule(&self) -> &Rule {
+        &self.final_rule
     }
 }
 
@@ -75,7 +689,8 @@ impl FromStr for Action {
             "DIRECT" => Action::Direct,
             "PROXY" => Action::Proxy,
             "PROBE" => Action::Probe,
-            _ => unreachable!(),
+            "SCRIPT" => Action::Script,
+            _ => return Err(()),
         })
     }
 }
@@ -86,31 +701,634 @@ impl fmt::Display for Action {
     }
 }
 
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Domain(d) => write!(f, "DOMAIN,{}", d),
+            Matcher::DomainSuffix(d) => write!(f, "DOMAIN-SUFFIX,{}", d),
+            Matcher::DomainKeyword(d) => write!(f, "DOMAIN-KEYWORD,{}", d),
+            Matcher::DomainRegex(pattern) => write!(f, "DOMAIN-REGEX,{}", pattern),
+            Matcher::GeoSite(code) => write!(f, "GEOSITE,{}", code),
+            Matcher::RuleSet(name) => write!(f, "RULE-SET,{}", name),
+            Matcher::IpCidr(cidr) => write!(f, "IP-CIDR,{}", cidr),
+            Matcher::IpCidr6(cidr) => write!(f, "IP-CIDR6,{}", cidr),
+            Matcher::IpAsn(asn) => write!(f, "IP-ASN,{}", asn),
+            Matcher::ProcessName(name) => write!(f, "PROCESS-NAME,{}", name),
+            Matcher::ProcessPath(path) => write!(f, "PROCESS-PATH,{}", path),
+            Matcher::Uid(uid) => write!(f, "UID,{}", uid),
+            Matcher::DstPort(port) => write!(f, "DST-PORT,{}", port),
+            Matcher::Network(network) => write!(f, "NETWORK,{}", network),
+            Matcher::Inbound(name) => write!(f, "INBOUND,{}", name),
+            Matcher::And(matchers) => write_logical(f, "AND", matchers),
+            Matcher::Or(matchers) => write_logical(f, "OR", matchers),
+            Matcher::Not(matcher) => write!(f, "NOT(({}))", matcher),
+            Matcher::Match => write!(f, "MATCH"),
+        }
+    }
+}
+
+fn write_logical(f: &mut Formatter<'_>, op: &str, matchers: &[Matcher]) -> fmt::Result {
+    write!(f, "{}(", op)?;
+    for (i, matcher) in matchers.iter().enumerate() {
+        if i > 0 {
+            write!(f, ",")?;
+        }
+        write!(f, "({})", matcher)?;
+    }
+    write!(f, ")")
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.group {
+            Some(group) => write!(f, "{},{}", self.matcher, group),
+            None => {
+                let action = self.action.to_string().to_uppercase();
+                write!(f, "{},{}", self.matcher, action)
+            }
+        }
+    }
+}
+
 impl FromStr for Rule {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let segments = s.splitn(3, ',').collect::<Vec<_>>();
-        let (rule, criteria, action) = match segments.len() {
-            2 => (segments[0], "", segments[1]),
-            3 => (segments[0], segments[1], segments[2]),
-            _ => unreachable!(),
+        // Clash marks IP rules `no-resolve`, seeker never resolves domains for IP rules.
+        let s = s.strip_suffix(",no-resolve").unwrap_or(s);
+        // The action follows the last comma, matchers of logical rules contain commas too.
+        let (matcher, action) = match s.rfind(',') {
+            Some(pos) => (&s[..pos], &s[pos + 1..]),
+            None => return Err(()),
+        };
+        // Any other target names a proxy group.
+        let (action, group) = match Action::from_str(action) {
+            Ok(action) => (action, None),
+            Err(()) => (Action::Proxy, Some(action.to_string())),
+        };
+        Ok(Rule {
+            matcher: Matcher::from_str(matcher)?,
+            action,
+            tag: None,
+            coalesce: None,
+            reject: RejectMode::default(),
+            group,
+            time: None,
+            dns: None,
+        })
+    }
+}
+
+impl FromStr for Matcher {
+    type Err = ();
+
+    /// `TYPE,criteria`, `MATCH`, or a logical `AND((...),(...))`, `OR((...),(...))` or
+    /// `NOT((...))` over other matchers.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (rule, criteria) = match s.find(|c| c == ',' || c == '(') {
+            Some(pos) => (&s[..pos], s[pos..].trim_start_matches(',')),
+            None => (s, ""),
         };
 
-        Ok(match rule {
-            "DOMAIN" => Rule::Domain(criteria.to_string(), Action::from_str(action).unwrap()),
-            "DOMAIN-SUFFIX" => {
-                Rule::DomainSuffix(criteria.to_string(), Action::from_str(action).unwrap())
+        let matcher = match rule {
+            "DOMAIN" => Matcher::Domain(criteria.to_ascii_lowercase()),
+            "DOMAIN-SUFFIX" => Matcher::DomainSuffix(criteria.to_ascii_lowercase()),
+            "DOMAIN-KEYWORD" => Matcher::DomainKeyword(criteria.to_ascii_lowercase()),
+            "DOMAIN-REGEX" => Matcher::DomainRegex(parse_regex(criteria)?),
+            "GEOSITE" => Matcher::GeoSite(criteria.to_lowercase()),
+            "RULE-SET" => Matcher::RuleSet(criteria.to_string()),
+            "IP-CIDR" => Matcher::IpCidr(parse_cidr4(criteria)?),
+            "IP-CIDR6" => Matcher::IpCidr6(parse_cidr6(criteria)?),
+            "IP-ASN" => Matcher::IpAsn(parse_asn(criteria)?),
+            "PROCESS-NAME" => Matcher::ProcessName(criteria.to_string()),
+            "PROCESS-PATH" => Matcher::ProcessPath(criteria.to_string()),
+            "UID" => Matcher::Uid(parse_uid(criteria)?),
+            "DST-PORT" => Matcher::DstPort(criteria.parse().map_err(|_| ())?),
+            "NETWORK" => match criteria.to_ascii_lowercase().as_str() {
+                network @ "tcp" | network @ "udp" => Matcher::Network(network.to_string()),
+                _ => return Err(()),
+            },
+            "INBOUND" => match criteria {
+                "" => return Err(()),
+                name => Matcher::Inbound(name.to_ascii_lowercase()),
+            },
+            "AND" => Matcher::And(parse_operands(criteria)?),
+            "OR" => Matcher::Or(parse_operands(criteria)?),
+            "NOT" => {
+                let mut operands = parse_operands(criteria)?;
+                if operands.len() != 1 {
+                    return Err(());
+                }
+                Matcher::Not(Box::new(operands.remove(0)))
             }
-            "DOMAIN-KEYWORD" => {
-                Rule::DomainKeyword(criteria.to_string(), Action::from_str(action).unwrap())
+            "MATCH" => Matcher::Match,
+            _ => return Err(()),
+        };
+        Ok(matcher)
+    }
+}
+
+/// Split `((A,a),(B,b))` into the matchers `A,a` and `B,b`. IP rules are matched by the
+/// ip indexes only, so they can not be operands.
+fn parse_operands(s: &str) -> Result<Vec<Matcher>, ()> {
+    let inner = s
+        .strip_prefix('(')
+        .and_then(|s| s.strip_suffix(')'))
+        .ok_or(())?;
+    let mut operands = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '(' => {
+                if depth == 0 {
+                    start = i + 1;
+                }
+                depth += 1;
             }
-            "IP-CIDR" => Rule::IpCidr(
-                parse_cidr(criteria.to_string()),
-                Action::from_str(action).unwrap(),
-            ),
-            "MATCH" => Rule::Match(Action::from_str(action).unwrap()),
-            _ => unreachable!(),
-        })
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    match Matcher::from_str(&inner[start..i])? {
+                        Matcher::IpCidr(_) | Matcher::IpCidr6(_) | Matcher::IpAsn(_) => {
+                            return Err(())
+                        }
+                        operand => operands.push(operand),
+                    }
+                }
+            }
+            ',' | ' ' if depth == 0 => {}
+            _ if depth == 0 => return Err(()),
+            _ => {}
+        }
+        if depth < 0 {
+            return Err(());
+        }
+    }
+    if depth != 0 || operands.is_empty() {
+        return Err(());
+    }
+    Ok(operands)
+}
+
+pub(crate) fn parse_cidr4(s: &str) -> Result<Ipv4Cidr, ()> {
+    let mut parts = s.splitn(2, '/');
+    let addr: Ipv4Addr = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+    let prefix_len: u8 = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+    if prefix_len > 32 {
+        return Err(());
+    }
+    Ok(Ipv4Cidr::new(Ipv4Address::from(addr), prefix_len))
+}
+
+pub(crate) fn parse_cidr6(s: &str) -> Result<Ipv6Cidr, ()> {
+    let mut parts = s.splitn(2, '/');
+    let addr: Ipv6Addr = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+    let prefix_len: u8 = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+    if prefix_len > 128 {
+        return Err(());
+    }
+    Ok(Ipv6Cidr::new(Ipv6Address::from(addr), prefix_len))
+}
+
+/// `13335` or `AS13335`.
+fn parse_asn(s: &str) -> Result<u32, ()> {
+    let s = s.trim();
+    let number = s
+        .strip_prefix("AS")
+        .or_else(|| s.strip_prefix("as"))
+        .unwrap_or(s);
+    number.parse().map_err(|_| ())
+}
+
+/// Check the pattern of a `DOMAIN-REGEX` rule, it is compiled with the others later.
+fn parse_regex(s: &str) -> Result<String, ()> {
+    Regex::new(s).map_err(|_| ())?;
+    Ok(s.to_string())
+}
+
+/// A numeric uid or the name of a user in `/etc/passwd`.
+fn parse_uid(s: &str) -> Result<u32, ()> {
+    if let Ok(uid) = s.parse() {
+        return Ok(uid);
+    }
+    let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+    uid_of_user(&passwd, s).ok_or(())
+}
+
+fn uid_of_user(passwd: &str, name: &str) -> Option<u32> {
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.as_slice() {
+            [user, _, uid, ..] if *user == name => uid.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// `domain` in lowercase when it has uppercase letters. Rules and lists keep their domains
+/// in lowercase, while names from clients, sniffing and DNS queries can come in any case.
+fn lowercase(domain: Option<&str>) -> Option<String> {
+    domain
+        .filter(|d| d.bytes().any(|b| b.is_ascii_uppercase()))
+        .map(str::to_ascii_lowercase)
+}
+
+/// The rule listed first of two index hits.
+fn first_of(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uid_of_user() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\nwork:x:1001:1001::/home/work:/bin/sh\n";
+        assert_eq!(uid_of_user(passwd, "work"), Some(1001));
+        assert_eq!(uid_of_user(passwd, "nobody"), None);
+        assert_eq!(
+            Rule::from_str("UID,1001,PROXY").unwrap().matcher,
+            Matcher::Uid(1001)
+        );
+    }
+
+    #[test]
+    fn test_rule_set() {
+        use crate::rule_set::{parse_list, ListFormat};
+
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("RULE-SET,ads,REJECT").unwrap(),
+            Rule::from_str("MATCH,DIRECT").unwrap(),
+        ]);
+        assert_eq!(rules.rule_set_names(), vec!["ads"]);
+        assert_eq!(rules.rules()[0].to_string(), "RULE-SET,ads,REJECT");
+        let mut lists = HashMap::new();
+        lists.insert(
+            "ads".to_string(),
+            parse_list(ListFormat::Adblock, "||ads.example.com^"),
+        );
+        let rules = rules.with_rule_sets(RuleSets::new(lists));
+        let clone = rules.clone();
+        assert_eq!(
+            rules.action_for_domain("x.ads.example.com"),
+            Some(Action::Reject)
+        );
+        assert_eq!(rules.action_for_domain("example.com"), Some(Action::Direct));
+
+        let list = parse_list(ListFormat::Hosts, "0.0.0.0 example.com");
+        assert!(rules.rule_sets().replace("ads", list));
+        assert_eq!(clone.action_for_domain("example.com"), Some(Action::Reject));
+    }
+
+    #[test]
+    fn test_time_window() {
+        let scheduled = |s: &str| Rule {
+            time: Some("19:00-23:00".parse().unwrap()),
+            ..Rule::from_str(s).unwrap()
+        };
+        let rules = ProxyRules::new(vec![
+            scheduled("DOMAIN-SUFFIX,steampowered.com,Gaming"),
+            scheduled("IP-CIDR,10.0.0.0/8,REJECT"),
+            Rule::from_str("IP-CIDR,10.0.0.0/16,DIRECT").unwrap(),
+            Rule::from_str("MATCH,PROXY").unwrap(),
+        ]);
+        let evening = rules.clone().with_clock(|| 20 * 60);
+        let morning = rules.with_clock(|| 8 * 60);
+        let rule = evening.rule_for_domain("store.steampowered.com").unwrap();
+        assert_eq!(rule.group.as_deref(), Some("Gaming"));
+        assert_eq!(
+            evening.action_for_ip("10.0.1.1".parse().unwrap()),
+            Some(Action::Reject)
+        );
+        assert!(morning
+            .rule_for_domain("store.steampowered.com")
+            .unwrap()
+            .group
+            .is_none());
+        assert_eq!(
+            morning.action_for_ip("10.0.1.1".parse().unwrap()),
+            Some(Action::Direct)
+        );
+        assert_eq!(morning.action_for_ip("10.1.0.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_group_target() {
+        let rule = Rule::from_str("DOMAIN-SUFFIX,netflix.com,Streaming").unwrap();
+        assert_eq!(rule.action, Action::Proxy);
+        assert_eq!(rule.group.as_deref(), Some("Streaming"));
+        assert_eq!(rule.to_string(), "DOMAIN-SUFFIX,netflix.com,Streaming");
+        let rule = Rule::from_str("DOMAIN-SUFFIX,netflix.com,PROXY").unwrap();
+        assert_eq!(rule.group, None);
+    }
+
+    #[test]
+    fn test_logical_rules() {
+        let rule = Rule::from_str("AND((DST-PORT,443),(DOMAIN-SUFFIX,google.com)),REJECT").unwrap();
+        assert_eq!(
+            rule.matcher,
+            Matcher::And(vec![
+                Matcher::DstPort(443),
+                Matcher::DomainSuffix("google.com".to_string())
+            ])
+        );
+        assert_eq!(
+            rule.to_string(),
+            "AND((DST-PORT,443),(DOMAIN-SUFFIX,google.com)),REJECT"
+        );
+        assert!(rule.matcher.needs_connection());
+
+        let rule = Rule::from_str("OR((NOT((DST-PORT,80))),(AND((DOMAIN,a.com),(UID,0)))),DIRECT")
+            .unwrap();
+        assert_eq!(
+            rule.to_string(),
+            "OR((NOT((DST-PORT,80))),(AND((DOMAIN,a.com),(UID,0)))),DIRECT"
+        );
+        let rules = ProxyRules::new(vec![rule]);
+        assert!(rules.has_process_rules());
+        let conn = |domain, uid, port| ConnectionMeta {
+            domain: Some(domain),
+            uid: Some(uid),
+            port: Some(port),
+            ..Default::default()
+        };
+        assert_eq!(
+            rules.action_for_connection(&conn("b.com", 1000, 443)),
+            Some(Action::Direct)
+        );
+        assert_eq!(rules.action_for_connection(&conn("b.com", 1000, 80)), None);
+        assert_eq!(
+            rules.action_for_connection(&conn("a.com", 0, 80)),
+            Some(Action::Direct)
+        );
+
+        assert!(Matcher::from_str("AND(DST-PORT,443)").is_err());
+        assert!(Matcher::from_str("NOT((DST-PORT,443),(DST-PORT,80))").is_err());
+        assert!(Matcher::from_str("AND((DST-PORT,443)").is_err());
+        assert!(Matcher::from_str("AND((DST-PORT,443),(IP-CIDR,10.0.0.0/8))").is_err());
+        assert!(Matcher::from_str("OR((DST-PORT,443),(NOT((IP-ASN,13335))))").is_err());
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        for rule in &[
+            "DOMAIN-SUFFIX",
+            "DOMAIN-SUFIX,google.com,PROXY",
+            "IP-CIDR,10.0.0.0/33,DIRECT",
+            "IP-CIDR,10.0.0.0,DIRECT",
+            "DOMAIN-REGEX,(,DIRECT",
+            "DST-PORT,http,DIRECT",
+        ] {
+            assert!(Rule::from_str(rule).is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn test_network_rules() {
+        let rule = Rule::from_str("AND((NETWORK,UDP),(DST-PORT,443)),REJECT").unwrap();
+        assert_eq!(rule.to_string(), "AND((NETWORK,udp),(DST-PORT,443)),REJECT");
+        assert!(rule.matcher.needs_connection());
+        let rules = ProxyRules::new(vec![rule, Rule::from_str("MATCH,PROXY").unwrap()]);
+        assert!(rules.has_network_rules());
+        let conn = |network| ConnectionMeta {
+            domain: Some("www.google.com"),
+            port: Some(443),
+            network: Some(network),
+            ..Default::default()
+        };
+        assert_eq!(
+            rules.action_for_connection(&conn("udp")),
+            Some(Action::Reject)
+        );
+        assert_eq!(
+            rules.action_for_connection(&conn("tcp")),
+            Some(Action::Proxy)
+        );
+        assert!(rules.depends_on_connection("www.google.com"));
+        // QUIC to a hardcoded ip, without a domain.
+        let conn = ConnectionMeta {
+            ip: Some("142.250.0.1".parse().unwrap()),
+            port: Some(443),
+            network: Some("udp"),
+            ..Default::default()
+        };
+        assert_eq!(rules.action_for_connection(&conn), Some(Action::Reject));
+        let conn = ConnectionMeta {
+            network: Some("tcp"),
+            ..conn
+        };
+        assert_eq!(rules.action_for_connection(&conn), Some(Action::Proxy));
+        assert!(Matcher::from_str("NETWORK,icmp").is_err());
+    }
+
+    #[test]
+    fn test_inbound_rules() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("INBOUND,TUN,Streaming").unwrap(),
+            Rule::from_str("MATCH,DIRECT").unwrap(),
+        ]);
+        assert_eq!(rules.rules()[0].to_string(), "INBOUND,tun,Streaming");
+        assert_eq!(rules.inbound_names(), vec!["tun".to_string()]);
+        let conn = ConnectionMeta {
+            domain: Some("example.com"),
+            inbound: Some(TUN_INBOUND),
+            ..Default::default()
+        };
+        assert_eq!(rules.index_for_connection(&conn), Some(0));
+        assert_eq!(
+            rules.index_for_connection(&ConnectionMeta::domain("example.com")),
+            Some(1)
+        );
+        assert!(rules.depends_on_connection("example.com"));
+        // tproxy and redirect connections keep the original ip as their destination.
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("INBOUND,tproxy,REJECT").unwrap(),
+            Rule::from_str("IP-CIDR,1.0.0.0/8,PROXY").unwrap(),
+        ]);
+        let conn = |inbound| ConnectionMeta {
+            ip: Some("1.1.1.1".parse().unwrap()),
+            inbound: Some(inbound),
+            ..Default::default()
+        };
+        assert_eq!(
+            rules.action_for_connection(&conn(TPROXY_INBOUND)),
+            Some(Action::Reject)
+        );
+        assert_eq!(
+            rules.action_for_connection(&conn(TUN_INBOUND)),
+            Some(Action::Proxy)
+        );
+        assert!(Matcher::from_str("INBOUND,").is_err());
+    }
+
+    #[test]
+    fn test_ip_rules() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("DOMAIN,example.com,REJECT").unwrap(),
+            Rule::from_str("IP-CIDR,10.0.0.0/8,DIRECT").unwrap(),
+            Rule::from_str("IP-CIDR,10.1.0.0/16,PROXY").unwrap(),
+            Rule::from_str("IP-CIDR,10.0.0.0/8,REJECT").unwrap(),
+            Rule::from_str("IP-CIDR6,2001:db8::/32,REJECT,no-resolve").unwrap(),
+        ]);
+        // The rule listed first wins, not the narrowest network.
+        assert_eq!(
+            rules.action_for_ip("10.1.0.1".parse().unwrap()),
+            Some(Action::Direct)
+        );
+        assert_eq!(
+            rules.rule_for_ip("10.1.0.1".parse().unwrap()),
+            Some(&rules.rules()[1])
+        );
+        assert_eq!(
+            rules.action_for_ip("2001:db8::1".parse().unwrap()),
+            Some(Action::Reject)
+        );
+        assert_eq!(rules.action_for_ip("8.8.8.8".parse().unwrap()), None);
+        assert_eq!(
+            rules.rules()[4].to_string(),
+            "IP-CIDR6,2001:db8::/32,REJECT"
+        );
+        assert!(Matcher::from_str("IP-CIDR6,2001:db8::/129").is_err());
+    }
+
+    #[test]
+    fn test_ip_connection() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("IP-CIDR,10.0.0.0/8,DIRECT").unwrap(),
+            Rule::from_str("PROCESS-NAME,curl,REJECT").unwrap(),
+            Rule::from_str("IP-CIDR,1.0.0.0/8,PROXY").unwrap(),
+            Rule::from_str("MATCH,DIRECT").unwrap(),
+        ]);
+        let conn = |ip: &str, process_name| ConnectionMeta {
+            process_name,
+            ip: Some(ip.parse().unwrap()),
+            ..Default::default()
+        };
+        // Rules keep their order whether they are scanned or looked up in the ip index.
+        assert_eq!(
+            rules.index_for_connection(&conn("1.1.1.1", Some("curl"))),
+            Some(1)
+        );
+        assert_eq!(
+            rules.index_for_connection(&conn("10.0.0.1", Some("curl"))),
+            Some(0)
+        );
+        assert_eq!(
+            rules.index_for_connection(&conn("1.1.1.1", Some("firefox"))),
+            Some(2)
+        );
+        assert_eq!(rules.index_for_connection(&conn("8.8.8.8", None)), Some(3));
+        assert!(rules.is_connection_explicitly_matched(&conn("1.1.1.1", None)));
+        assert!(!rules.is_connection_explicitly_matched(&conn("8.8.8.8", None)));
+    }
+
+    #[test]
+    fn test_asn_rules() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("IP-ASN,AS13335,PROXY").unwrap(),
+            Rule::from_str("IP-CIDR,1.1.1.0/24,DIRECT").unwrap(),
+            Rule::from_str("IP-ASN,15169,PROXY,no-resolve").unwrap(),
+        ]);
+        assert!(rules.has_asn_rules());
+        assert_eq!(rules.rules()[0].matcher, Matcher::IpAsn(13335));
+        assert_eq!(rules.rules()[2].to_string(), "IP-ASN,15169,PROXY");
+        // Without a database only the networks match.
+        assert_eq!(
+            rules.action_for_ip("1.1.1.1".parse().unwrap()),
+            Some(Action::Direct)
+        );
+        assert_eq!(rules.action_for_domain("one.one.one.one"), None);
+        assert!(Matcher::from_str("IP-ASN,cloudflare").is_err());
+        assert!(!ProxyRules::new(vec![]).has_asn_rules());
+    }
+
+    #[test]
+    fn test_domain_rules_order() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("DOMAIN-KEYWORD,ads,REJECT").unwrap(),
+            Rule::from_str("AND((DST-PORT,443),(DOMAIN,www.google.com)),DIRECT").unwrap(),
+            Rule::from_str("DOMAIN-SUFFIX,google.com,PROXY").unwrap(),
+            Rule::from_str("PROCESS-NAME,curl,DIRECT").unwrap(),
+            Rule::from_str("DOMAIN-SUFFIX,com,DIRECT").unwrap(),
+            Rule::from_str("MATCH,PROBE").unwrap(),
+        ]);
+        assert_eq!(
+            rules.action_for_domain("ads.google.com"),
+            Some(Action::Reject)
+        );
+        assert_eq!(
+            rules.action_for_domain("www.google.com"),
+            Some(Action::Proxy)
+        );
+        let conn = ConnectionMeta {
+            port: Some(443),
+            process_name: Some("curl"),
+            ..ConnectionMeta::domain("www.google.com")
+        };
+        assert_eq!(rules.action_for_connection(&conn), Some(Action::Direct));
+        let conn = ConnectionMeta {
+            process_name: Some("curl"),
+            ..ConnectionMeta::domain("example.com")
+        };
+        assert_eq!(rules.rule_for_connection(&conn), Some(&rules.rules()[3]));
+        assert_eq!(rules.action_for_domain("example.org"), Some(Action::Probe));
+        assert!(rules.depends_on_connection("www.google.com"));
+        assert!(!rules.depends_on_connection("ads.example.org"));
+        assert!(rules.is_explicitly_matched("example.com"));
+        assert!(!rules.is_explicitly_matched("example.org"));
+    }
+
+    #[test]
+    fn test_domain_case() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("DOMAIN-SUFFIX,Example.com,PROXY").unwrap(),
+            Rule::from_str("DOMAIN,WWW.example.org,REJECT").unwrap(),
+            Rule::from_str("AND((DOMAIN-KEYWORD,ads),(DST-PORT,443)),REJECT").unwrap(),
+        ]);
+        assert_eq!(
+            rules.action_for_domain("WWW.Example.COM"),
+            Some(Action::Proxy)
+        );
+        assert_eq!(
+            rules.action_for_domain("www.example.org"),
+            Some(Action::Reject)
+        );
+        let conn = ConnectionMeta {
+            port: Some(443),
+            ..ConnectionMeta::domain("ADS.example.net")
+        };
+        assert_eq!(rules.action_for_connection(&conn), Some(Action::Reject));
+        assert!(rules.is_explicitly_matched("EXAMPLE.COM"));
+        assert!(rules.depends_on_connection("ADS.example.net"));
+    }
+
+    #[test]
+    fn test_domain_regex() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str(r"DOMAIN-REGEX,^ad[0-9]*\.,REJECT").unwrap(),
+            Rule::from_str(r"AND((DOMAIN-REGEX,\.cn$),(DST-PORT,443)),DIRECT").unwrap(),
+            Rule::from_str(r"DOMAIN-REGEX,\.cn$,PROXY").unwrap(),
+        ]);
+        assert_eq!(
+            rules.action_for_domain("ad12.example.com"),
+            Some(Action::Reject)
+        );
+        assert_eq!(rules.action_for_domain("bad.example.com"), None);
+        let conn = ConnectionMeta {
+            port: Some(443),
+            ..ConnectionMeta::domain("www.gov.cn")
+        };
+        assert_eq!(rules.action_for_connection(&conn), Some(Action::Direct));
+        assert_eq!(rules.action_for_domain("www.gov.cn"), Some(Action::Proxy));
+        assert_eq!(
+            rules.rules()[0].to_string(),
+            r"DOMAIN-REGEX,^ad[0-9]*\.,REJECT"
+        );
     }
 }
@@ -0,0 +1,320 @@
+//! Domain lists kept outside the config, in hosts file or AdGuard/ABP syntax, used by
+//! `RULE-SET,name,REJECT` rules. seeker refreshes them while running, swapping the list
+//! in place so every clone of the rules sees the new one.
+use crate::geosite::DomainList;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Error};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ListFormat {
+    /// `0.0.0.0 ads.example.com`, each name matches exactly.
+    Hosts,
+    /// `||ads.example.com^` matches the domain and its subdomains, `@@` exceptions remove
+    /// the domains they name. Rules with other patterns or modifiers are skipped.
+    Adblock,
+    /// Surge and Quantumult `.list` files, `DOMAIN-SUFFIX,example.com` or
+    /// `HOST-SUFFIX,example.com,Proxy`: the domain types are used, the policy is that of
+    /// the `RULE-SET` rule. `RULE-SET` rules only match domains, so `IP-CIDR` and the
+    /// other types are skipped.
+    Surge,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct RuleProvider {
+    pub format: ListFormat,
+    /// Where the list is read from, and where a downloaded list is saved.
+    pub path: String,
+    /// Download the list from here, at start and every `interval`.
+    pub url: Option<String>,
+    #[serde(default, deserialize_with = "crate::duration::deserialize_option")]
+    #[schemars(with = "Option<String>")]
+    pub interval: Option<Duration>,
+}
+
+impl RuleProvider {
+    /// The list at `path`, empty when it has not been downloaded yet.
+    pub fn load(&self) -> io::Result<DomainList> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(text) => Ok(parse_list(self.format, &text)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && self.url.is_some() => {
+                Ok(DomainList::default())
+            }
+            Err(e) => Err(Error::new(
+                e.kind(),
+                format!("read rule provider {}: {}", self.path, e),
+            )),
+        }
+    }
+}
+
+/// Hostnames of loopback entries and the like found in every hosts file.
+const HOSTS_BOILERPLATE: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+    "0.0.0.0",
+];
+
+fn is_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.parse::<IpAddr>().is_err()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+}
+
+fn is_domain(s: &str) -> bool {
+    s.contains('.') && is_name(s)
+}
+
+/// Parse `text`, dropping duplicates and exact entries already covered by a suffix.
+pub fn parse_list(format: ListFormat, text: &str) -> DomainList {
+    let mut full = HashSet::new();
+    let mut suffix = HashSet::new();
+    let mut exceptions = HashSet::new();
+    let mut keywords = vec![];
+    for line in text.lines() {
+        let line = line.trim();
+        match format {
+            ListFormat::Hosts => {
+                let line = line.split('#').next().unwrap_or_default();
+                let mut fields = line.split_whitespace();
+                let first = match fields.next() {
+                    Some(first) => first,
+                    None => continue,
+                };
+                // A bare name is a plain domain list, as some hosts lists are published.
+                let names: Vec<&str> = if first.parse::<IpAddr>().is_ok() {
+                    fields.collect()
+                } else {
+                    vec![first]
+                };
+                for name in names {
+                    let name = name.trim_end_matches('.').to_ascii_lowercase();
+                    if is_domain(&name) && !HOSTS_BOILERPLATE.contains(&name.as_str()) {
+                        full.insert(name);
+                    }
+                }
+            }
+            ListFormat::Adblock => {
+                if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+                    continue;
+                }
+                // `#` starts comments in AdGuard DNS lists and cosmetic rules in ABP ones.
+                if line.starts_with('#') || line.contains("##") || line.contains("#@#") {
+                    continue;
+                }
+                let (line, exception) = match line.strip_prefix("@@") {
+                    Some(rest) => (rest, true),
+                    None => (line, false),
+                };
+                let line = line.trim_end_matches("$important");
+                if line.contains('$') {
+                    continue;
+                }
+                let (domain, is_suffix) = match line.strip_prefix("||") {
+                    Some(rest) => (rest.trim_end_matches('^').trim_end_matches('|'), true),
+                    None => {
+                        // Hosts entries are valid AdGuard DNS rules.
+                        let mut fields = line.split_whitespace();
+                        match (fields.next(), fields.next()) {
+                            (Some(ip), Some(name)) if ip.parse::<IpAddr>().is_ok() => (name, false),
+                            (Some(name), None) => (name.trim_end_matches('^'), false),
+                            _ => continue,
+                        }
+                    }
+                };
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                if !is_domain(&domain) || HOSTS_BOILERPLATE.contains(&domain.as_str()) {
+                    continue;
+                }
+                if exception {
+                    exceptions.insert(domain);
+                } else if is_suffix {
+                    suffix.insert(domain);
+                } else {
+                    full.insert(domain);
+                }
+            }
+            ListFormat::Surge => {
+                if line.starts_with('#') || line.starts_with(';') || line.starts_with("//") {
+                    continue;
+                }
+                let mut fields = line.split(',').map(str::trim);
+                let (kind, value) = match (fields.next(), fields.next()) {
+                    (Some(kind), Some(value)) => (kind.to_ascii_uppercase(), value),
+                    _ => continue,
+                };
+                let value = value.trim_end_matches('.').to_ascii_lowercase();
+                match kind.as_str() {
+                    "DOMAIN" | "HOST" if is_domain(&value) => {
+                        full.insert(value);
+                    }
+                    // Suffixes can be top level domains like `cn`.
+                    "DOMAIN-SUFFIX" | "HOST-SUFFIX" => {
+                        let value = value.trim_start_matches('.');
+                        if is_name(value) {
+                            suffix.insert(value.to_string());
+                        }
+                    }
+                    "DOMAIN-KEYWORD" | "HOST-KEYWORD" if !value.is_empty() => keywords.push(value),
+                    _ => {}
+                }
+            }
+        }
+    }
+    keywords.sort();
+    keywords.dedup();
+    for domain in &exceptions {
+        full.remove(domain);
+        suffix.remove(domain);
+    }
+    let nested: Vec<String> = suffix
+        .iter()
+        .filter(|d| {
+            d.find('.')
+                .map_or(false, |pos| has_suffix_in(&d[pos + 1..], &suffix))
+        })
+        .cloned()
+        .collect();
+    for domain in &nested {
+        suffix.remove(domain);
+    }
+    full.retain(|domain| !has_suffix_in(domain, &suffix));
+    DomainList::new(full, suffix).with_keywords(keywords)
+}
+
+/// Whether `domain` or one of its parents is in `suffixes`.
+fn has_suffix_in(domain: &str, suffixes: &HashSet<String>) -> bool {
+    let mut rest = domain;
+    loop {
+        if suffixes.contains(rest) {
+            return true;
+        }
+        match rest.find('.') {
+            Some(pos) => rest = &rest[pos + 1..],
+            None => return false,
+        }
+    }
+}
+
+/// The lists of the `rule_providers` used by the rules.
+#[derive(Default)]
+pub struct RuleSets {
+    lists: HashMap<String, RwLock<Arc<DomainList>>>,
+}
+
+impl fmt::Debug for RuleSets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: BTreeMap<_, _> = self.lists.keys().map(|n| (n, self.entries(n))).collect();
+        f.debug_struct("RuleSets").field("lists", &names).finish()
+    }
+}
+
+impl RuleSets {
+    pub fn new(lists: HashMap<String, DomainList>) -> Self {
+        RuleSets {
+            lists: lists
+                .into_iter()
+                .map(|(name, list)| (name, RwLock::new(Arc::new(list))))
+                .collect(),
+        }
+    }
+
+    pub fn matches(&self, name: &str, domain: &str) -> bool {
+        match self.lists.get(name) {
+            Some(list) => list.read().unwrap().matches(domain),
+            None => false,
+        }
+    }
+
+    /// Number of entries in the list `name`.
+    pub fn entries(&self, name: &str) -> Option<usize> {
+        Some(self.lists.get(name)?.read().unwrap().len())
+    }
+
+    /// Swap in a new version of the list `name`, false if no rule uses it.
+    pub fn replace(&self, name: &str, list: DomainList) -> bool {
+        match self.lists.get(name) {
+            Some(current) => {
+                *current.write().unwrap() = Arc::new(list);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hosts() {
+        let list = parse_list(
+            ListFormat::Hosts,
+            "# comment\n127.0.0.1 localhost\n0.0.0.0 ads.example.com tracker.example.com # x\n0.0.0.0 ads.example.com\nbare.example.org\n",
+        );
+        assert_eq!(list.len(), 3);
+        assert!(list.matches("ads.example.com"));
+        assert!(!list.matches("sub.ads.example.com"));
+        assert!(list.matches("bare.example.org"));
+        assert!(!list.matches("localhost"));
+    }
+
+    #[test]
+    fn test_parse_adblock() {
+        let list = parse_list(
+            ListFormat::Adblock,
+            "[Adblock Plus 2.0]\n! comment\n||ads.example.com^\n||cdn.ads.example.com^$important\n||x.example.com^$third-party\n@@||good.example.com^\n||good.example.com^\nexample.com##.banner\n0.0.0.0 hosts.example.net\n/ads[0-9]+/\n",
+        );
+        assert!(list.matches("ads.example.com"));
+        assert!(list.matches("cdn.ads.example.com"));
+        assert!(!list.matches("x.example.com"));
+        assert!(!list.matches("good.example.com"));
+        assert!(list.matches("hosts.example.net"));
+        // `||cdn.ads.example.com^` is covered by `||ads.example.com^`.
+        assert_eq!(list.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_surge() {
+        let list = parse_list(
+            ListFormat::Surge,
+            "# comment\nDOMAIN-SUFFIX,example.com\nDOMAIN,www.example.com\nHOST,cdn.example.net,Proxy\nDOMAIN-KEYWORD,tracker\ndomain-suffix,cn\nIP-CIDR,10.0.0.0/8,no-resolve\nUSER-AGENT,App*\n",
+        );
+        assert!(list.matches("example.com"));
+        assert!(list.matches("a.example.com"));
+        assert!(list.matches("cdn.example.net"));
+        assert!(list.matches("tracker.example.org"));
+        assert!(list.matches("baidu.cn"));
+        assert!(!list.matches("example.org"));
+        // `DOMAIN,www.example.com` is covered by `DOMAIN-SUFFIX,example.com`.
+        assert_eq!(list.len(), 4);
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut lists = HashMap::new();
+        lists.insert("ads".to_string(), DomainList::default());
+        let sets = RuleSets::new(lists);
+        assert!(!sets.matches("ads", "ads.example.com"));
+        assert!(sets.replace("ads", parse_list(ListFormat::Adblock, "||example.com^")));
+        assert!(sets.matches("ads", "ads.example.com"));
+        assert!(!sets.replace("other", DomainList::default()));
+    }
+}
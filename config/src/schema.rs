@@ -0,0 +1,74 @@
+//! JSON Schema of the config for editors to validate and complete configs with, printed by
+//! `seeker schema`. It is derived from the types the config is read into, so settings get
+//! into the schema as they are added.
+use crate::{Config, ServerConfig};
+use schemars::gen::SchemaSettings;
+use schemars::schema::{RootSchema, Schema};
+use schemars::JsonSchema;
+use std::collections::BTreeMap;
+
+/// An entry of `servers`.
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+pub(crate) enum ServerEntry {
+    Config(ServerConfig),
+    /// A share URI like `ss://...`, `socks5://...` or `http://...`.
+    Uri(String),
+}
+
+/// Files merged into the config, relative to its directory.
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum Include {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum Profile {
+    /// Settings replacing those of the config.
+    Settings(BTreeMap<String, serde_json::Value>),
+    /// Path of a YAML file with the settings, relative to the directory of the config.
+    File(String),
+}
+
+/// The schema of the config, with the keys seeker handles before reading it: `include`,
+/// `profiles` and the `proxies` of Clash configs.
+pub fn schema() -> RootSchema {
+    let mut gen = SchemaSettings::draft07().into_generator();
+    let extra: Vec<(&str, Schema)> = vec![
+        ("include", gen.subschema_for::<Include>()),
+        ("profiles", gen.subschema_for::<BTreeMap<String, Profile>>()),
+        ("proxies", gen.subschema_for::<Vec<serde_json::Value>>()),
+    ];
+    let mut root = gen.into_root_schema_for::<Config>();
+    let properties = &mut root.schema.object().properties;
+    for (key, schema) in extra {
+        properties.insert(key.to_string(), schema);
+    }
+    root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema() {
+        let schema = serde_json::to_value(schema()).unwrap();
+        let properties = &schema["properties"];
+        assert_eq!(properties["dns_timeout"]["type"], "string");
+        assert_eq!(properties["tun_cidr"]["type"], "string");
+        assert!(properties["include"].is_object());
+        assert!(properties["servers"].is_object());
+        assert!(properties.get("domestic_ips").is_none());
+        let required = schema["required"].as_array().unwrap();
+        assert!(required.contains(&"tun_name".into()));
+        assert!(!required.contains(&"dns_timeout".into()));
+        assert!(schema["definitions"].get("ServerConfig").is_some());
+    }
+}
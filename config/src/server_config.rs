@@ -1,23 +1,27 @@
-use std::{fmt::Debug, net::SocketAddr};
+use std::{fmt::Debug, net::SocketAddr, time::Duration};
 
+use crate::keyring;
+use crate::time_window::TimeWindow;
 use crate::Address;
 use bytes::Bytes;
-use crypto::CipherType;
+use crypto::{CipherCategory, CipherType};
+use schemars::JsonSchema;
 use serde::Deserialize;
 use url::Url;
 
 /// Server address
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum DnsServerAddr {
     /// IP Address
     UdpSocketAddr(SocketAddr),
     /// eg. tcp://114.114.114.114:53
     #[serde(with = "url_serde")]
+    #[schemars(with = "String")]
     TcpSocketAddr(Url),
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Copy)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Copy)]
 pub enum ServerProtocol {
     Http,
     Https,
@@ -26,26 +30,150 @@ pub enum ServerProtocol {
 }
 
 /// Configuration for a server
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
 pub struct ServerConfig {
     /// Server address
     name: String,
     #[serde(with = "server_addr")]
+    #[schemars(with = "String")]
     addr: Address,
     protocol: ServerProtocol,
     username: Option<String>,
     password: Option<String>,
     #[serde(default)]
     #[serde(with = "cipher_type")]
+    #[schemars(with = "Option<String>")]
     method: Option<CipherType>,
+    #[serde(default)]
+    key_derivation: KeyDerivation,
+    /// Length of the salt of AEAD ciphers, the key size when `None`.
+    salt_size: Option<usize>,
+    #[serde(default)]
+    address_preference: AddressPreference,
+    #[serde(default)]
+    weights: Vec<ServerWeight>,
+    /// Keepalive interval of idle TCP connections to the server, off when `None`.
+    #[serde(default, deserialize_with = "crate::duration::deserialize_option")]
+    #[schemars(with = "Option<String>")]
+    keepalive: Option<Duration>,
+    /// Time between keepalive probes nobody answers, `keepalive` when `None`.
+    #[serde(default, deserialize_with = "crate::duration::deserialize_option")]
+    #[schemars(with = "Option<String>")]
+    keepalive_interval: Option<Duration>,
+    /// Unanswered keepalive probes before the connection is dropped, the system's default
+    /// when `None`.
+    #[serde(default)]
+    keepalive_count: Option<u32>,
+    /// Time for one try to connect and finish the handshake, the global `connect_timeout`
+    /// when `None`.
+    #[serde(default, deserialize_with = "crate::duration::deserialize_option")]
+    #[schemars(with = "Option<String>")]
+    connect_timeout: Option<Duration>,
+    /// Tries after the first before the server counts as failed and the next one is used.
+    #[serde(default)]
+    retries: usize,
+    /// Idle and write timeouts of connections relayed through the server, the global
+    /// `read_timeout` and `write_timeout` when `None`.
+    #[serde(default, deserialize_with = "crate::duration::deserialize_option")]
+    #[schemars(with = "Option<String>")]
+    read_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "crate::duration::deserialize_option")]
+    #[schemars(with = "Option<String>")]
+    write_timeout: Option<Duration>,
+}
+
+/// Servers a rule can send connections to by the name of the group
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
+pub struct ProxyGroup {
+    pub name: String,
+    /// Names of the servers in the group.
+    pub servers: Vec<String>,
+    #[serde(default)]
+    pub strategy: GroupStrategy,
+    /// Where connections go when none of the servers answered the last ping or all are
+    /// quarantined: `DIRECT`, `REJECT`, `PROXY` or another group. Without it the servers
+    /// are used anyway.
+    #[serde(rename = "final", default)]
+    pub final_target: Option<String>,
+}
+
+/// How a group picks one of its servers
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupStrategy {
+    /// The best ranked by the latency pings, like servers outside groups.
+    Latency,
+    /// The first reachable in the order the group lists them.
+    Fallback,
+    /// Every reachable server in turn.
+    RoundRobin,
+}
+
+impl Default for GroupStrategy {
+    fn default() -> Self {
+        GroupStrategy::Latency
+    }
+}
+
+/// Preference for a server during a daily time window
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
+pub struct ServerWeight {
+    /// `HH:MM-HH:MM`, local time.
+    #[schemars(with = "String")]
+    pub time: TimeWindow,
+    /// Latencies are divided by the weight when ranking servers, 1 outside all windows.
+    pub weight: f64,
+}
+
+/// How the key of a shadowsocks server is made from its password
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyDerivation {
+    /// `EVP_BytesToKey` over the password, like the reference implementations.
+    BytesToKey,
+    /// The password is the key itself, base64 encoded.
+    Base64,
+}
+
+impl Default for KeyDerivation {
+    fn default() -> Self {
+        KeyDerivation::BytesToKey
+    }
+}
+
+/// How the target is sent to a proxy when the client connected by domain
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AddressPreference {
+    /// Send the domain and let the proxy resolve it, some providers route by hostname.
+    DomainFirst,
+    /// Resolve the domain locally and send the ip, falling back to the domain on failure.
+    IpFirst,
+}
+
+impl Default for AddressPreference {
+    fn default() -> Self {
+        AddressPreference::DomainFirst
+    }
 }
 
 mod cipher_type {
     use crypto::CipherType;
     use serde::de::Error;
-    use serde::{Deserialize, Deserializer};
+    use serde::{Deserialize, Deserializer, Serializer};
     use std::str::FromStr;
 
+    /// For the default of the schema.
+    pub fn serialize<S>(method: &Option<CipherType>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match method {
+            Some(method) => serializer.serialize_some(&method.to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
     pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<CipherType>, D::Error>
     where
         D: Deserializer<'de>,
@@ -75,6 +203,36 @@ mod server_addr {
 }
 
 impl ServerConfig {
+    /// A server with the default options, for servers that do not come from the config.
+    pub(crate) fn new(
+        name: String,
+        addr: Address,
+        protocol: ServerProtocol,
+        username: Option<String>,
+        password: Option<String>,
+        method: Option<CipherType>,
+    ) -> Self {
+        ServerConfig {
+            name,
+            addr,
+            protocol,
+            username,
+            password,
+            method,
+            key_derivation: KeyDerivation::default(),
+            salt_size: None,
+            address_preference: AddressPreference::default(),
+            weights: vec![],
+            keepalive: None,
+            keepalive_interval: None,
+            keepalive_count: None,
+            connect_timeout: None,
+            retries: 0,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+
     /// Get server name
     pub fn name(&self) -> &str {
         &self.name
@@ -92,7 +250,20 @@ impl ServerConfig {
 
     /// Get encryption key
     pub fn key(&self) -> Option<Bytes> {
-        Some(self.method()?.bytes_to_key(self.password()?.as_bytes()))
+        let method = self.method()?;
+        let password = self.password()?;
+        match self.key_derivation {
+            KeyDerivation::BytesToKey => Some(method.bytes_to_key(password.as_bytes())),
+            KeyDerivation::Base64 => match base64::decode(password) {
+                Ok(key) if key.len() == method.key_size() => Some(Bytes::from(key)),
+                _ => None,
+            },
+        }
+    }
+
+    /// Get the salt length of AEAD ciphers, if it differs from the key size
+    pub fn salt_size(&self) -> Option<usize> {
+        self.salt_size
     }
 
     pub fn username(&self) -> Option<&str> {
@@ -103,8 +274,105 @@ impl ServerConfig {
     pub fn password(&self) -> Option<&str> {
         self.password.as_deref()
     }
+
+    /// Read a `keyring:` password from the system keyring.
+    pub(crate) fn resolve_keyring(&mut self) -> Result<(), String> {
+        if let Some(password) = &self.password {
+            let password = keyring::resolve_with(password, keyring::lookup)
+                .map_err(|e| format!("server {}: {}", self.name, e))?;
+            self.password = Some(password);
+        }
+        Ok(())
+    }
+
     /// Get method
     pub fn method(&self) -> Option<CipherType> {
         self.method
     }
+
+    /// Get the form of the target address sent to this server
+    pub fn address_preference(&self) -> AddressPreference {
+        self.address_preference
+    }
+
+    /// Get the keepalive interval of idle connections
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.keepalive
+    }
+
+    /// Get the time between unanswered keepalive probes
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        self.keepalive_interval.or(self.keepalive)
+    }
+
+    /// Get the number of unanswered keepalive probes before the connection is dropped
+    pub fn keepalive_count(&self) -> Option<u32> {
+        self.keepalive_count
+    }
+
+    /// Get the timeout of one try to connect
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    /// Get the number of tries after the first
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    /// Get the idle timeout of relayed connections
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    /// Get the write timeout of relayed connections
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+
+    /// Check the shadowsocks options fit the method.
+    pub fn validate(&self) -> Result<(), String> {
+        let method = match (self.method, self.protocol) {
+            (Some(method), _) => method,
+            (None, ServerProtocol::Shadowsocks) => {
+                return Err(format!("server {}: shadowsocks needs a method.", self.name))
+            }
+            (None, _) => return Ok(()),
+        };
+        if self.protocol == ServerProtocol::Shadowsocks && self.password.is_none() {
+            return Err(format!(
+                "server {}: shadowsocks needs a password.",
+                self.name
+            ));
+        }
+        // A keyring reference is only replaced by the key when the config is loaded.
+        if self.key_derivation == KeyDerivation::Base64
+            && self
+                .password()
+                .map_or(false, |p| keyring::reference(p).is_none())
+            && self.key().is_none()
+        {
+            return Err(format!(
+                "server {}: password is not a base64 key of {} bytes.",
+                self.name,
+                method.key_size()
+            ));
+        }
+        match self.salt_size {
+            Some(_) if method.category() != CipherCategory::Aead => Err(format!(
+                "server {}: salt_size only applies to AEAD methods.",
+                self.name
+            )),
+            Some(0) => Err(format!("server {}: salt_size can not be 0.", self.name)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the weight of the first window containing `minute_of_day`
+    pub fn weight_at(&self, minute_of_day: u16) -> f64 {
+        self.weights
+            .iter()
+            .find(|w| w.time.contains(minute_of_day))
+            .map_or(1.0, |w| w.weight)
+    }
 }
@@ -0,0 +1,237 @@
+//! Servers of a provider subscription, `subscription_url`: a Clash config with `proxies`,
+//! a SIP008 JSON document, or share URIs one per line, base64 encoded or not. seeker
+//! downloads the list to `subscription_path` when it starts and reloads, and loading the
+//! config adds the servers of that saved copy to its `servers`.
+//!
+//! Entries seeker can not use, like vmess or shadowsocks with a plugin, are skipped.
+use crate::share_uri::{self, address, decode_base64};
+use crate::{ServerConfig, ServerProtocol};
+use crypto::CipherType;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io::{self, Error, ErrorKind};
+use std::str::FromStr;
+
+/// Either format: SIP008 has `servers`, Clash has `proxies`. JSON is valid YAML.
+#[derive(Debug, Deserialize)]
+struct Document {
+    #[serde(default)]
+    proxies: Vec<ClashProxy>,
+    #[serde(default)]
+    servers: Vec<Sip008Server>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClashProxy {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    server: String,
+    port: u16,
+    cipher: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(default)]
+    tls: bool,
+    plugin: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Sip008Server {
+    remarks: Option<String>,
+    server: String,
+    server_port: u16,
+    password: String,
+    method: String,
+    plugin: Option<String>,
+}
+
+impl ClashProxy {
+    fn into_server(self) -> Option<ServerConfig> {
+        if self.plugin.is_some() {
+            return None;
+        }
+        let addr = address(&self.server, self.port)?;
+        let (protocol, method) = match (self.kind.as_str(), self.tls) {
+            ("ss", _) => {
+                let method = CipherType::from_str(self.cipher.as_deref()?).ok()?;
+                (ServerProtocol::Shadowsocks, Some(method))
+            }
+            ("socks5", false) => (ServerProtocol::Socks5, None),
+            ("http", false) => (ServerProtocol::Http, None),
+            ("http", true) => (ServerProtocol::Https, None),
+            _ => return None,
+        };
+        Some(ServerConfig::new(
+            self.name,
+            addr,
+            protocol,
+            self.username,
+            self.password,
+            method,
+        ))
+    }
+}
+
+impl Sip008Server {
+    fn into_server(self) -> Option<ServerConfig> {
+        if self.plugin.map_or(false, |p| !p.is_empty()) {
+            return None;
+        }
+        let addr = address(&self.server, self.server_port)?;
+        let method = CipherType::from_str(&self.method).ok()?;
+        let name = self
+            .remarks
+            .filter(|r| !r.is_empty())
+            .unwrap_or_else(|| addr.to_string());
+        Some(ServerConfig::new(
+            name,
+            addr,
+            ServerProtocol::Shadowsocks,
+            None,
+            Some(self.password),
+            Some(method),
+        ))
+    }
+}
+
+/// The servers listed in `text`, keeping the first of servers with the same name.
+pub fn parse(text: &str) -> io::Result<Vec<ServerConfig>> {
+    let servers: Vec<ServerConfig> = match serde_yaml::from_str::<Document>(text) {
+        Ok(doc) => doc
+            .proxies
+            .into_iter()
+            .filter_map(ClashProxy::into_server)
+            .chain(
+                doc.servers
+                    .into_iter()
+                    .filter_map(Sip008Server::into_server),
+            )
+            .collect(),
+        Err(_) => {
+            let decoded = decode_base64(&text.split_whitespace().collect::<String>());
+            decoded
+                .as_deref()
+                .unwrap_or(text)
+                .lines()
+                .filter_map(|line| share_uri::parse(line).ok())
+                .collect()
+        }
+    };
+    if servers.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "no server seeker supports, the subscription should be a Clash config, SIP008 JSON or share URIs",
+        ));
+    }
+    Ok(merge(&[], servers))
+}
+
+/// The servers of Clash `proxies`, in a config reused from Clash.
+pub(crate) fn clash_servers(proxies: serde_yaml::Value) -> Vec<ServerConfig> {
+    let proxies: Vec<serde_yaml::Value> = serde_yaml::from_value(proxies).unwrap_or_default();
+    proxies
+        .into_iter()
+        .filter_map(|proxy| serde_yaml::from_value::<ClashProxy>(proxy).ok())
+        .filter_map(ClashProxy::into_server)
+        .collect()
+}
+
+/// `servers` followed by those of `extra` with a name not used yet.
+pub fn merge(servers: &[ServerConfig], extra: Vec<ServerConfig>) -> Vec<ServerConfig> {
+    let mut names: HashSet<String> = servers.iter().map(|s| s.name().to_string()).collect();
+    let mut merged = servers.to_vec();
+    for server in extra {
+        if names.insert(server.name().to_string()) {
+            merged.push(server);
+        }
+    }
+    merged
+}
+
+/// Download the subscription at `url` and save it to `cache`. Only a list that parses
+/// replaces the saved one.
+pub fn download(url: &str, cache: &str) -> io::Result<()> {
+    let resp = ureq::get(url)
+        .timeout_connect(5000)
+        .timeout_read(30000)
+        .call();
+    if !resp.ok() {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("download subscription {}: {}", url, resp.status_line()),
+        ));
+    }
+    let text = resp.into_string()?;
+    parse(&text)?;
+    std::fs::write(cache, &text)
+}
+
+/// The servers of the subscription saved to `cache`.
+pub fn load(cache: &str) -> io::Result<Vec<ServerConfig>> {
+    let text = std::fs::read_to_string(cache)
+        .map_err(|e| Error::new(e.kind(), format!("read subscription {}: {}", cache, e)))?;
+    parse(&text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Address;
+
+    #[test]
+    fn test_parse_clash() {
+        let servers = parse(
+            r#"
+port: 7890
+proxies:
+  - {name: hk1, type: ss, server: hk.example.com, port: 8388, cipher: aes-256-gcm, password: pass}
+  - {name: obfs, type: ss, server: 1.2.3.4, port: 8388, cipher: aes-256-gcm, password: pass, plugin: obfs}
+  - {name: jp1, type: vmess, server: jp.example.com, port: 443, uuid: 1234, alterId: 0, cipher: auto}
+  - {name: sock, type: socks5, server: 10.0.0.1, port: 1080, username: u, password: p}
+  - {name: web, type: http, server: proxy.example.com, port: 443, tls: true}
+  - {name: hk1, type: ss, server: other.example.com, port: 8388, cipher: aes-256-gcm, password: pass}
+"#,
+        )
+        .unwrap();
+        let names: Vec<&str> = servers.iter().map(|s| s.name()).collect();
+        assert_eq!(names, vec!["hk1", "sock", "web"]);
+        assert_eq!(servers[0].protocol(), ServerProtocol::Shadowsocks);
+        assert_eq!(
+            servers[0].addr(),
+            &Address::DomainNameAddress("hk.example.com".to_string(), 8388)
+        );
+        assert!(servers[0].key().is_some());
+        assert_eq!(servers[1].username(), Some("u"));
+        assert_eq!(servers[2].protocol(), ServerProtocol::Https);
+    }
+
+    #[test]
+    fn test_parse_sip008() {
+        let servers = parse(
+            r#"{
+  "version": 1,
+  "servers": [
+    {"id": "1", "remarks": "us", "server": "2001:db8::1", "server_port": 8388,
+     "password": "pass", "method": "chacha20-ietf-poly1305"},
+    {"id": "2", "server": "us2.example.com", "server_port": 443, "password": "pass",
+     "method": "aes-128-gcm", "plugin": ""},
+    {"id": "3", "server": "1.2.3.4", "server_port": 443, "password": "pass",
+     "method": "aes-128-gcm", "plugin": "v2ray-plugin"}
+  ]
+}"#,
+        )
+        .unwrap();
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].name(), "us");
+        assert_eq!(servers[0].addr().to_string(), "[2001:db8::1]:8388");
+        assert_eq!(servers[1].name(), "us2.example.com:443");
+
+        assert!(parse("proxies: []").is_err());
+        let list = base64::encode(
+            "ss://YWVzLTI1Ni1nY206cGFzcw@hk.example.com:8388#hk\nvmess://eyJ9\nsocks5://10.0.0.1:1080\n",
+        );
+        assert_eq!(parse(&list).unwrap().len(), 2);
+        assert!(parse("not: [a, subscription").is_err());
+    }
+}
@@ -0,0 +1,25 @@
+//! Configs for the tests: the settings every config needs, followed by those a test is
+//! about.
+use crate::Config;
+use std::io;
+
+/// The DNS and tun settings and the limits no config can do without.
+const REQUIRED: &str = "dns_start_ip: 11.0.0.10
+dns_servers:
+  - 223.5.5.5:53
+tun_name: utun4
+tun_ip: 11.0.0.1
+tun_cidr: 11.0.0.0/16
+dns_listen: 0.0.0.0:53
+max_connect_errors: 2
+";
+
+/// The text of a config with `rest` after the required settings.
+pub fn config_text(rest: &str) -> String {
+    format!("{}{}", REQUIRED, rest)
+}
+
+/// Load the config with `rest` after the required settings.
+pub fn load(rest: &str) -> io::Result<Config> {
+    Config::from_reader(config_text(rest).as_bytes())
+}
@@ -0,0 +1,111 @@
+//! Daily time windows such as `19:00-23:00`, in local time.
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::str::FromStr;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// From `start` up to, but excluding, `end`, in minutes since midnight. Windows with `end`
+/// before `start` wrap around midnight, e.g. `22:00-02:00`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeWindow {
+    start: u16,
+    end: u16,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, minute_of_day: u16) -> bool {
+        if self.start <= self.end {
+            self.start <= minute_of_day && minute_of_day < self.end
+        } else {
+            minute_of_day >= self.start || minute_of_day < self.end
+        }
+    }
+}
+
+/// Minutes since local midnight.
+pub fn local_minute_of_day() -> u16 {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    #[cfg(unix)]
+    let failed = unsafe { libc::localtime_r(&now, &mut tm) }.is_null();
+    #[cfg(windows)]
+    let failed = unsafe { libc::localtime_s(&mut tm, &now) } != 0;
+    if failed {
+        return 0;
+    }
+    (tm.tm_hour * 60 + tm.tm_min) as u16
+}
+
+fn parse_minute(s: &str) -> Option<u16> {
+    let mut parts = s.trim().splitn(2, ':');
+    let hour: u16 = parts.next()?.parse().ok()?;
+    let minute: u16 = parts.next()?.parse().ok()?;
+    // 24:00 is allowed as the end of a day.
+    if minute >= 60 || hour > 24 || hour * 60 + minute > MINUTES_PER_DAY {
+        return None;
+    }
+    Some(hour * 60 + minute)
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid time window: {}, expected HH:MM-HH:MM", s);
+        let mut parts = s.splitn(2, '-');
+        let start = parts.next().and_then(parse_minute).ok_or_else(err)?;
+        let end = parts.next().and_then(parse_minute).ok_or_else(err)?;
+        Ok(TimeWindow {
+            start: start % MINUTES_PER_DAY,
+            end,
+        })
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeWindow {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        TimeWindow::from_str(&s).map_err(Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_window() {
+        let evening: TimeWindow = "19:00-23:00".parse().unwrap();
+        assert!(evening.contains(19 * 60));
+        assert!(evening.contains(22 * 60 + 59));
+        assert!(!evening.contains(23 * 60));
+        assert_eq!(evening.to_string(), "19:00-23:00");
+
+        let night: TimeWindow = "22:30-02:00".parse().unwrap();
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(60));
+        assert!(!night.contains(12 * 60));
+
+        assert!("25:00-26:00".parse::<TimeWindow>().is_err());
+        assert!("19:00".parse::<TimeWindow>().is_err());
+        assert!("65535:00-01:00".parse::<TimeWindow>().is_err());
+    }
+}
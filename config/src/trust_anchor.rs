@@ -0,0 +1,119 @@
+//! Root keys DNSSEC validation starts from, loaded from zone file DNSKEY records such as
+//! those IANA publishes, `. 172800 IN DNSKEY 257 3 8 AwEAAa...`.
+use std::io::{self, Error, ErrorKind};
+
+/// Set in the flags of keys revoked by a rollover (RFC 5011).
+const REVOKE_FLAG: u16 = 0x0080;
+
+/// A DNSKEY of the root zone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootKey {
+    pub flags: u16,
+    pub algorithm: u8,
+    pub public_key: Vec<u8>,
+}
+
+pub fn from_file(path: &str) -> io::Result<Vec<RootKey>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| Error::new(e.kind(), format!("read trust anchor {}: {}", path, e)))?;
+    parse(&content)
+}
+
+/// The DNSKEY records of `content`, skipping comments, other records and revoked keys.
+/// Records may span lines inside parentheses.
+pub fn parse(content: &str) -> io::Result<Vec<RootKey>> {
+    let mut keys = vec![];
+    let mut record = String::new();
+    let mut depth = 0i32;
+    for line in content.lines() {
+        let line = line.split(';').next().unwrap_or_default();
+        depth += line.matches('(').count() as i32 - line.matches(')').count() as i32;
+        record.push(' ');
+        record.push_str(line);
+        if depth > 0 {
+            continue;
+        }
+        if let Some(key) = parse_record(&record)? {
+            if key.flags & REVOKE_FLAG == 0 {
+                keys.push(key);
+            }
+        }
+        record.clear();
+        depth = 0;
+    }
+    if keys.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "no DNSKEY in trust anchor",
+        ));
+    }
+    Ok(keys)
+}
+
+fn parse_record(record: &str) -> io::Result<Option<RootKey>> {
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid DNSKEY: {}", record.trim()),
+        )
+    };
+    let fields = record
+        .split_whitespace()
+        .filter(|f| *f != "(" && *f != ")")
+        .map(|f| f.trim_matches(|c| c == '(' || c == ')'))
+        .collect::<Vec<_>>();
+    let pos = match fields.iter().position(|f| f.eq_ignore_ascii_case("DNSKEY")) {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    if pos == 0 || fields[0] != "." {
+        return Err(invalid());
+    }
+    let rdata = &fields[pos + 1..];
+    if rdata.len() < 4 || rdata[1] != "3" {
+        return Err(invalid());
+    }
+    let flags = rdata[0].parse().map_err(|_| invalid())?;
+    let algorithm = rdata[2].parse().map_err(|_| invalid())?;
+    let public_key = base64::decode(rdata[3..].concat()).map_err(|_| invalid())?;
+    Ok(Some(RootKey {
+        flags,
+        algorithm,
+        public_key,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let keys = parse(
+            "; root keys\n\
+             . 172800 IN DNSKEY 257 3 8 AwEA AQ==\n\
+             . IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D\n\
+             . 172800 IN DNSKEY 385 3 8 AwEB\n\
+             . 172800 IN DNSKEY ( 257 3 8\n  AwEC ) ; KSK-2024\n",
+        )
+        .unwrap();
+        assert_eq!(
+            keys,
+            vec![
+                RootKey {
+                    flags: 257,
+                    algorithm: 8,
+                    public_key: vec![3, 1, 0, 1],
+                },
+                RootKey {
+                    flags: 257,
+                    algorithm: 8,
+                    public_key: vec![3, 1, 2],
+                },
+            ]
+        );
+        assert!(parse("").is_err());
+        assert!(parse("example.com. IN DNSKEY 257 3 8 AwEA").is_err());
+        assert!(parse(". IN DNSKEY 257 3 8 !!").is_err());
+    }
+}
@@ -8,12 +8,12 @@ use std::{
 };
 
 use crate::digest::{self, Digest, DigestType};
+use crate::random::{Random, ThreadRandom};
 use bytes::{BufMut, Bytes, BytesMut};
 #[cfg(feature = "camellia-cfb")]
 use openssl::nid::Nid;
 #[cfg(feature = "openssl")]
 use openssl::symm;
-use rand::{self, RngCore};
 #[cfg(feature = "use-ring")]
 use ring::aead::{AES_128_GCM, AES_256_GCM, CHACHA20_POLY1305};
 
@@ -567,21 +567,25 @@ impl CipherType {
         }
     }
 
-    fn gen_random_bytes(len: usize) -> Bytes {
+    fn gen_random_bytes(len: usize, random: &dyn Random) -> Bytes {
         let mut iv = BytesMut::with_capacity(len);
         unsafe {
             iv.set_len(len);
         }
 
-        let mut rng = rand::thread_rng();
-        rng.fill_bytes(&mut iv);
+        random.fill_bytes(&mut iv);
         iv.freeze()
     }
 
     /// Generate a random initialize vector for this cipher
     pub fn gen_init_vec(self) -> Bytes {
+        self.gen_init_vec_with(&ThreadRandom)
+    }
+
+    /// Generate an initialize vector for this cipher from `random`
+    pub fn gen_init_vec_with(self, random: &dyn Random) -> Bytes {
         let iv_len = self.iv_size();
-        CipherType::gen_random_bytes(iv_len)
+        CipherType::gen_random_bytes(iv_len, random)
     }
 
     /// Get category of cipher
@@ -631,7 +635,12 @@ impl CipherType {
 
     /// Get salt for AEAD ciphers
     pub fn gen_salt(self) -> Bytes {
-        CipherType::gen_random_bytes(self.salt_size())
+        self.gen_salt_with(&ThreadRandom)
+    }
+
+    /// Get salt for AEAD ciphers from `random`
+    pub fn gen_salt_with(self, random: &dyn Random) -> Bytes {
+        CipherType::gen_random_bytes(self.salt_size(), random)
     }
 }
 
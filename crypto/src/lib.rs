@@ -6,6 +6,7 @@ pub use self::{
         BoxAeadEncryptor,
     },
     cipher::{CipherCategory, CipherResult, CipherType},
+    random::{thread_random, Random, SeededRandom, SharedRandom, ThreadRandom},
     stream::{new_stream, BoxStreamCipher, StreamCipher},
 };
 #[cfg(feature = "openssl")]
@@ -17,6 +18,7 @@ pub mod digest;
 pub mod dummy;
 #[cfg(feature = "openssl")]
 pub mod openssl;
+pub mod random;
 #[cfg(feature = "rc4")]
 pub mod rc4_md5;
 #[cfg(feature = "use-ring")]
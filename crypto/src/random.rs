@@ -0,0 +1,65 @@
+//! Sources of the random bytes used for IVs, salts and nonces
+
+use std::sync::{Arc, Mutex};
+
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// Something that fills buffers with random bytes
+pub trait Random: Send + Sync {
+    fn fill_bytes(&self, dest: &mut [u8]);
+}
+
+/// Shared handle to a `Random`
+pub type SharedRandom = Arc<dyn Random>;
+
+/// The thread local CSPRNG, used unless a connection is given something else
+pub struct ThreadRandom;
+
+impl Random for ThreadRandom {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        rand::thread_rng().fill_bytes(dest)
+    }
+}
+
+/// A deterministic generator, so tests can produce byte-identical wire data
+///
+/// Never use it for real traffic, the IVs are predictable from the seed.
+pub struct SeededRandom {
+    rng: Mutex<ChaCha20Rng>,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> SeededRandom {
+        SeededRandom {
+            rng: Mutex::new(ChaCha20Rng::seed_from_u64(seed)),
+        }
+    }
+}
+
+impl Random for SeededRandom {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        self.rng.lock().unwrap().fill_bytes(dest)
+    }
+}
+
+/// The default source, backed by `ThreadRandom`
+pub fn thread_random() -> SharedRandom {
+    Arc::new(ThreadRandom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_random_is_reproducible() {
+        let (a, b) = (SeededRandom::new(42), SeededRandom::new(42));
+        let (mut x, mut y) = ([0u8; 32], [0u8; 32]);
+        a.fill_bytes(&mut x);
+        b.fill_bytes(&mut y);
+        assert_eq!(x, y);
+        a.fill_bytes(&mut x);
+        assert_ne!(x, y);
+    }
+}
@@ -1,21 +1,29 @@
+mod negative_cache;
+pub mod query_log;
+mod rebind;
 pub mod resolver;
+pub mod upstream;
 
-use async_std_resolver::AsyncStdResolver;
 use config::rule::ProxyRules;
 use hermesdns::DnsUdpServer;
-use resolver::RuleBasedDnsResolver;
+use resolver::{ResolverOptions, RuleBasedDnsResolver};
+use std::collections::HashMap;
 use std::net::Ipv4Addr;
 use std::path::Path;
+use upstream::Resolver;
 
 pub async fn create_dns_server<P: AsRef<Path>>(
     path: P,
     listen: String,
     start_ip: Ipv4Addr,
     rules: ProxyRules,
-    async_resolver: AsyncStdResolver,
+    options: ResolverOptions,
+    async_resolver: Resolver,
+    upstreams: HashMap<String, Resolver>,
 ) -> (DnsUdpServer, RuleBasedDnsResolver) {
     let n = u32::from_be_bytes(start_ip.octets());
-    let resolver = RuleBasedDnsResolver::new(path, n, rules, async_resolver).await;
+    let resolver =
+        RuleBasedDnsResolver::new(path, n, rules, options, async_resolver, upstreams).await;
     let server = DnsUdpServer::new(listen, Box::new(resolver.clone())).await;
     (server, resolver)
 }
@@ -24,10 +32,13 @@ pub async fn create_dns_server<P: AsRef<Path>>(
 pub(crate) mod tests {
     use super::*;
     use async_std::io;
+    use async_std::net::UdpSocket;
     use async_std::task;
     use async_std_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
     use hermesdns::{DnsClient, DnsNetworkClient, QueryType};
     use std::time::Duration;
+    use trust_dns_proto::op::{Message, ResponseCode};
+    use trust_dns_proto::rr::{RData, Record, RecordType};
 
     const LOCAL_UDP_PORT: u16 = 1153;
     async fn get_ip(client: &DnsNetworkClient, host: &str) -> Option<String> {
@@ -40,13 +51,42 @@ pub(crate) mod tests {
         resp.get_random_a()
     }
 
-    pub(crate) async fn new_resolver(ip: String, port: u16) -> AsyncStdResolver {
+    /// A DNS server on localhost answering every query with `code`, and with `address` for
+    /// the A queries.
+    pub(crate) async fn stub_upstream(code: ResponseCode, address: Option<Ipv4Addr>) -> u16 {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        task::spawn(async move {
+            let mut buf = vec![0; 512];
+            loop {
+                let (size, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let query = Message::from_vec(&buf[..size]).unwrap();
+                let mut response = Message::error_msg(query.id(), query.op_code(), code);
+                response.add_queries(query.queries().to_vec());
+                for question in query.queries() {
+                    if let (Some(ip), RecordType::A) = (address, question.query_type()) {
+                        let mut record = Record::with(question.name().clone(), RecordType::A, 300);
+                        record.set_rdata(RData::A(ip));
+                        response.add_answer(record);
+                    }
+                }
+                socket
+                    .send_to(&response.to_vec().unwrap(), peer)
+                    .await
+                    .unwrap();
+            }
+        });
+        port
+    }
+
+    pub(crate) async fn new_resolver(ip: String, port: u16) -> Resolver {
         let name_servers = NameServerConfigGroup::from_ips_clear(&[ip.parse().unwrap()], port);
 
         // Construct a new Resolver with default configuration options
-        async_std_resolver::resolver(
+        crate::upstream::resolver(
             ResolverConfig::from_parts(None, Vec::new(), name_servers),
             ResolverOpts::default(),
+            None,
         )
         .await
         .expect("failed to create resolver")
@@ -63,7 +103,9 @@ pub(crate) mod tests {
                 format!("0.0.0.0:{}", LOCAL_UDP_PORT),
                 "10.0.0.1".parse().unwrap(),
                 ProxyRules::new(vec![]),
+                ResolverOptions::default(),
                 resolver,
+                HashMap::new(),
             )
             .await;
             task::spawn(server.run_server());
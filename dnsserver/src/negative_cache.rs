@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Used when upstream gives no SOA to derive the negative TTL from.
+const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(60);
+/// RFC 2308 recommends not caching negative answers for longer than 3 hours.
+const MAX_NEGATIVE_TTL: Duration = Duration::from_secs(3 * 3600);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Domains kept at most, expired entries are swept first once it is reached, then those
+/// expiring soonest.
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegativeAnswer {
+    /// NODATA, answered without asking upstream again.
+    NoRecords,
+    /// The domain does not exist, answered with NXDOMAIN without asking upstream again.
+    NxDomain,
+    /// Upstream failed recently, fail fast until the backoff expires.
+    ServFail,
+}
+
+struct Entry {
+    answer: NegativeAnswer,
+    until: Instant,
+    failures: u32,
+}
+
+/// Negative answers per domain (RFC 2308) and exponential backoff for failing lookups.
+pub struct NegativeCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    capacity: usize,
+}
+
+impl Default for NegativeCache {
+    fn default() -> Self {
+        NegativeCache::with_capacity(MAX_ENTRIES)
+    }
+}
+
+/// Make room for one more entry in `entries` of at most `capacity`.
+fn evict(entries: &mut HashMap<String, Entry>, capacity: usize) {
+    if entries.len() < capacity {
+        return;
+    }
+    let now = Instant::now();
+    entries.retain(|_, entry| entry.until > now);
+    if entries.len() < capacity {
+        return;
+    }
+    let soonest = entries
+        .iter()
+        .min_by_key(|(_, entry)| entry.until)
+        .map(|(domain, _)| domain.clone());
+    if let Some(domain) = soonest {
+        entries.remove(&domain);
+    }
+}
+
+impl NegativeCache {
+    pub fn with_capacity(capacity: usize) -> Self {
+        NegativeCache {
+            entries: Mutex::new(HashMap::new()),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn get(&self, domain: &str) -> Option<NegativeAnswer> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(domain)?;
+        if entry.until > Instant::now() {
+            return Some(entry.answer);
+        }
+        // Keep the failure count of expired backoffs, the next failure backs off longer.
+        if entry.answer != NegativeAnswer::ServFail {
+            entries.remove(domain);
+        }
+        None
+    }
+
+    /// Cache a negative answer for `ttl`, taken from the SOA record of the response.
+    pub fn store_no_records(&self, domain: &str, ttl: Option<Duration>, nxdomain: bool) {
+        let ttl = ttl.unwrap_or(DEFAULT_NEGATIVE_TTL).min(MAX_NEGATIVE_TTL);
+        let answer = if nxdomain {
+            NegativeAnswer::NxDomain
+        } else {
+            NegativeAnswer::NoRecords
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(domain) {
+            evict(&mut entries, self.capacity);
+        }
+        entries.insert(
+            domain.to_string(),
+            Entry {
+                answer,
+                until: Instant::now() + ttl,
+                failures: 0,
+            },
+        );
+    }
+
+    /// Record a failed lookup, returning how long to back off.
+    pub fn store_failure(&self, domain: &str) -> Duration {
+        let mut entries = self.entries.lock().unwrap();
+        let failures = entries
+            .get(domain)
+            .filter(|e| e.answer == NegativeAnswer::ServFail)
+            .map_or(0, |e| e.failures);
+        let backoff = (MIN_BACKOFF * 2u32.saturating_pow(failures)).min(MAX_BACKOFF);
+        if !entries.contains_key(domain) {
+            evict(&mut entries, self.capacity);
+        }
+        entries.insert(
+            domain.to_string(),
+            Entry {
+                answer: NegativeAnswer::ServFail,
+                until: Instant::now() + backoff,
+                failures: failures.saturating_add(1),
+            },
+        );
+        backoff
+    }
+
+    pub fn clear(&self, domain: &str) {
+        self.entries.lock().unwrap().remove(domain);
+    }
+
+    /// Forget all domains, e.g. when the upstreams changed.
+    pub fn clear_all(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_records() {
+        let cache = NegativeCache::default();
+        cache.store_no_records("a.com", Some(Duration::from_secs(10)), false);
+        assert_eq!(cache.get("a.com"), Some(NegativeAnswer::NoRecords));
+        cache.store_no_records("b.com", Some(Duration::from_secs(0)), false);
+        assert_eq!(cache.get("b.com"), None);
+        cache.store_no_records("c.com", None, true);
+        assert_eq!(cache.get("c.com"), Some(NegativeAnswer::NxDomain));
+        cache.clear("a.com");
+        assert_eq!(cache.get("a.com"), None);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let cache = NegativeCache::with_capacity(2);
+        cache.store_no_records("a.com", Some(Duration::from_secs(10)), true);
+        cache.store_no_records("b.com", Some(Duration::from_secs(20)), true);
+        cache.store_no_records("a.com", Some(Duration::from_secs(30)), true);
+        cache.store_no_records("c.com", Some(Duration::from_secs(40)), true);
+        assert_eq!(cache.get("b.com"), None);
+        assert_eq!(cache.get("a.com"), Some(NegativeAnswer::NxDomain));
+        assert_eq!(cache.get("c.com"), Some(NegativeAnswer::NxDomain));
+        cache.store_no_records("d.com", Some(Duration::from_secs(0)), true);
+        cache.store_failure("e.com");
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+        assert_eq!(cache.get("e.com"), Some(NegativeAnswer::ServFail));
+    }
+
+    #[test]
+    fn test_failure_backoff() {
+        let cache = NegativeCache::default();
+        assert_eq!(cache.store_failure("a.com"), Duration::from_secs(1));
+        assert_eq!(cache.get("a.com"), Some(NegativeAnswer::ServFail));
+        assert_eq!(cache.store_failure("a.com"), Duration::from_secs(2));
+        assert_eq!(cache.store_failure("a.com"), Duration::from_secs(4));
+        for _ in 0..10 {
+            cache.store_failure("a.com");
+        }
+        assert_eq!(cache.store_failure("a.com"), MAX_BACKOFF);
+    }
+}
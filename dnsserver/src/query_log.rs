@@ -0,0 +1,138 @@
+use hermesdns::{DnsPacket, DnsRecord, QueryType};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where the answer of a query came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnswerSource {
+    Hosts,
+    FakeIp,
+    Upstream,
+    NegativeCache,
+    Lan,
+    Rejected,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryLogEntry {
+    /// Seconds since the unix epoch.
+    pub time: u64,
+    pub name: String,
+    pub qtype: String,
+    pub answers: Vec<String>,
+    /// `None` when the query failed.
+    pub source: Option<AnswerSource>,
+    pub error: Option<String>,
+    pub latency_ms: u64,
+    /// Action of the rule matching `name`, if any.
+    pub rule: Option<String>,
+}
+
+impl QueryLogEntry {
+    pub fn new(
+        name: &str,
+        qtype: QueryType,
+        result: &std::io::Result<(DnsPacket, AnswerSource)>,
+        latency: Duration,
+        rule: Option<String>,
+    ) -> Self {
+        let (answers, source, error) = match result {
+            Ok((packet, source)) => (
+                packet.answers.iter().filter_map(format_record).collect(),
+                Some(*source),
+                None,
+            ),
+            Err(e) => (vec![], None, Some(e.to_string())),
+        };
+        QueryLogEntry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            name: name.to_string(),
+            qtype: format!("{:?}", qtype),
+            answers,
+            source,
+            error,
+            latency_ms: latency.as_millis() as u64,
+            rule,
+        }
+    }
+}
+
+fn format_record(record: &DnsRecord) -> Option<String> {
+    match record {
+        DnsRecord::A { addr, .. } => Some(addr.to_string()),
+        DnsRecord::AAAA { addr, .. } => Some(addr.to_string()),
+        DnsRecord::CNAME { host, .. } => Some(format!("CNAME {}", host)),
+        DnsRecord::PTR { host, .. } => Some(format!("PTR {}", host)),
+        _ => None,
+    }
+}
+
+/// The most recent DNS queries, oldest first.
+#[derive(Clone)]
+pub struct QueryLog {
+    entries: Arc<Mutex<VecDeque<QueryLogEntry>>>,
+    capacity: usize,
+}
+
+impl QueryLog {
+    /// A log keeping `capacity` queries, 0 disables logging.
+    pub fn new(capacity: usize) -> Self {
+        QueryLog {
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn record(&self, entry: QueryLogEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            let _ = entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Logged queries whose name contains `filter`, all of them if it is `None`.
+    pub fn entries(&self, filter: Option<&str>) -> Vec<QueryLogEntry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| filter.map(|f| e.name.contains(f)).unwrap_or(true))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str) -> QueryLogEntry {
+        let result = Ok((DnsPacket::new(), AnswerSource::FakeIp));
+        QueryLogEntry::new(name, QueryType::A, &result, Duration::from_millis(1), None)
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let log = QueryLog::new(2);
+        log.record(entry("a.com"));
+        log.record(entry("b.com"));
+        log.record(entry("c.com"));
+        let names: Vec<_> = log.entries(None).into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["b.com", "c.com"]);
+        assert_eq!(log.entries(Some("c.")).len(), 1);
+
+        let disabled = QueryLog::new(0);
+        disabled.record(entry("a.com"));
+        assert!(disabled.entries(None).is_empty());
+    }
+}
@@ -0,0 +1,108 @@
+use std::net::IpAddr;
+
+/// Suffixes of names that only make sense inside the LAN.
+pub const LOCAL_SUFFIXES: &[&str] = &[".local", ".lan", ".localdomain", ".home.arpa"];
+
+/// Whether `domain` is a LAN name, which may legitimately resolve to private addresses.
+pub fn is_local_domain(domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    !domain.contains('.') || LOCAL_SUFFIXES.iter().any(|s| domain.ends_with(s))
+}
+
+/// Zones holding PTR records for reverse lookups.
+const REVERSE_SUFFIXES: &[&str] = &[".in-addr.arpa", ".ip6.arpa"];
+
+/// Whether `domain` is a reverse lookup name such as `1.1.168.192.in-addr.arpa`.
+pub fn is_reverse_zone(domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    REVERSE_SUFFIXES.iter().any(|s| domain.ends_with(s))
+}
+
+/// Whether `domain` is a multicast DNS name.
+pub fn is_mdns_domain(domain: &str) -> bool {
+    domain.trim_end_matches('.').ends_with(".local")
+}
+
+/// Whether `domain` is one of the `allowlist` domains or below one, `example.com` allows
+/// `nas.example.com` but not `evilexample.com`.
+pub fn is_allowlisted(domain: &str, allowlist: &[String]) -> bool {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    allowlist.iter().any(|entry| {
+        let entry = entry.trim_matches('.').to_ascii_lowercase();
+        domain == entry
+            || (domain.ends_with(&entry) && domain[..domain.len() - entry.len()].ends_with('.'))
+    })
+}
+
+/// Whether `ip` points into a private, loopback or link-local range.
+pub fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                // 100.64.0.0/10, shared address space used by carrier grade NAT
+                || (a == 100 && (b & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // fc00::/7 unique local and fe80::/10 link local
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                || ip.to_ipv4().map(|v4| is_private_ip(v4.into())) == Some(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_private_ip() {
+        for ip in &[
+            "10.0.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "100.64.0.1",
+            "::1",
+            "fd00::1",
+        ] {
+            assert!(is_private_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &["8.8.8.8", "100.128.0.1", "2001:4860::8888"] {
+            assert!(!is_private_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_is_local_domain() {
+        assert!(is_local_domain("printer.local"));
+        assert!(is_local_domain("nas"));
+        assert!(!is_local_domain("example.com"));
+    }
+
+    #[test]
+    fn test_is_allowlisted() {
+        let allowlist = vec!["example.com".to_string(), ".lan.test.".to_string()];
+        assert!(is_allowlisted("example.com", &allowlist));
+        assert!(is_allowlisted("nas.Example.com.", &allowlist));
+        assert!(is_allowlisted("router.lan.test", &allowlist));
+        assert!(!is_allowlisted("evilexample.com", &allowlist));
+        assert!(!is_allowlisted("example.com.evil", &allowlist));
+        assert!(!is_allowlisted("example.com", &[]));
+    }
+
+    #[test]
+    fn test_is_reverse_zone() {
+        assert!(is_reverse_zone("1.1.168.192.in-addr.arpa"));
+        assert!(is_reverse_zone("1.0.0.0.ip6.arpa."));
+        assert!(!is_reverse_zone("in-addr.arpa.example.com"));
+        assert!(is_mdns_domain("printer.local."));
+        assert!(!is_mdns_domain("printer.lan"));
+    }
+}
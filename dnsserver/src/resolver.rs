@@ -1,20 +1,80 @@
+use crate::negative_cache::{NegativeAnswer, NegativeCache};
+use crate::query_log::{AnswerSource, QueryLog, QueryLogEntry};
+use crate::rebind::{
+    is_allowlisted, is_local_domain, is_mdns_domain, is_private_ip, is_reverse_zone,
+};
+use crate::upstream::Resolver;
 use async_std::net::IpAddr;
-use async_std_resolver::AsyncStdResolver;
 use async_trait::async_trait;
-use config::rule::{Action, ProxyRules};
-use hermesdns::{DnsPacket, DnsRecord, DnsResolver, Hosts, QueryType, TransientTtl};
+use config::dns_ttl::DnsTtl;
+use config::ip_set::IpSet;
+use config::rule::{Action, DnsPolicy, ProxyRules, RejectMode};
+use config::{nat64, Ipv6Policy};
+use hermesdns::{
+    DnsClient, DnsNetworkClient, DnsPacket, DnsRecord, DnsResolver, Hosts, QueryType, ResultCode,
+    TransientTtl,
+};
 use sled::Db;
 use std::any::Any;
+use std::collections::HashMap;
 use std::io;
 use std::io::Result;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::Path;
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use tracing::debug;
 use trust_dns_proto::rr::RData;
+use trust_dns_resolver::error::ResolveErrorKind;
 
 const NEXT_IP: &str = "next_ip";
+/// Multicast DNS group, queried from an ephemeral port so responders answer by unicast.
+const MDNS_ADDR: (&str, u16) = ("224.0.0.251", 5353);
+const LAN_DNS_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a DIRECT domain answered with foreign addresses keeps going through the proxy.
+const GEO_DEMOTE_TTL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone)]
+pub struct ResolverOptions {
+    pub ipv6_policy: Ipv6Policy,
+    /// Answer proxied domains with fake ips, otherwise every domain gets its real address.
+    pub fake_ip: bool,
+    /// Strip private and loopback addresses from answers for public domains.
+    pub rebind_protection: bool,
+    /// Domain suffixes allowed to resolve to private addresses.
+    pub rebind_allowlist: Vec<String>,
+    /// Resolvers answering LAN names and reverse lookups instead of the upstream.
+    pub lan_dns: Vec<SocketAddr>,
+    /// Number of recent queries kept in the query log.
+    pub query_log_size: usize,
+    /// Synthesize AAAA records inside this NAT64 /96 prefix for domains with only A records.
+    pub nat64_prefix: Option<Ipv6Addr>,
+    /// Domestic networks. DIRECT domains answered without any of them are proxied instead,
+    /// needs `fake_ip`.
+    pub domestic_ips: Option<Arc<IpSet>>,
+    /// The upstream in `upstreams` resolving DIRECT domains, unless their rule names another.
+    pub domestic_dns: Option<String>,
+    /// Clamps for the TTL of answers from the upstream.
+    pub ttl: DnsTtl,
+}
+
+impl Default for ResolverOptions {
+    fn default() -> Self {
+        ResolverOptions {
+            ipv6_policy: Ipv6Policy::default(),
+            fake_ip: true,
+            rebind_protection: false,
+            rebind_allowlist: vec![],
+            lan_dns: vec![],
+            query_log_size: 0,
+            nat64_prefix: None,
+            domestic_ips: None,
+            domestic_dns: None,
+            ttl: DnsTtl::default(),
+        }
+    }
+}
 
 /// A Forwarding DNS Resolver
 ///
@@ -26,10 +86,19 @@ pub struct RuleBasedDnsResolver {
 
 struct Inner {
     hosts: Hosts,
-    rules: ProxyRules,
+    /// Swapped by `set_rules` when the config is reloaded.
+    rules: RwLock<ProxyRules>,
+    options: ResolverOptions,
     db: Db,
     next_ip: AtomicU32,
-    resolver: AsyncStdResolver,
+    resolver: RwLock<Resolver>,
+    /// The `dns_resolvers` by name, for domains of rules naming one.
+    upstreams: RwLock<HashMap<String, Resolver>>,
+    negative_cache: NegativeCache,
+    lan_client: DnsNetworkClient,
+    query_log: QueryLog,
+    /// DIRECT domains last answered with foreign addresses, and when.
+    geo_demoted: Mutex<HashMap<String, Instant>>,
 }
 
 impl RuleBasedDnsResolver {
@@ -37,7 +106,9 @@ impl RuleBasedDnsResolver {
         path: P,
         next_ip: u32,
         rules: ProxyRules,
-        resolver: AsyncStdResolver,
+        options: ResolverOptions,
+        resolver: Resolver,
+        upstreams: HashMap<String, Resolver>,
     ) -> Self {
         let db = sled::open(path).expect("open db error");
         let next_ip = match db.get(NEXT_IP.as_bytes()) {
@@ -51,18 +122,82 @@ impl RuleBasedDnsResolver {
                 next_ip
             }
         };
+        let query_log = QueryLog::new(options.query_log_size);
 
         RuleBasedDnsResolver {
             inner: Arc::new(Inner {
                 hosts: Hosts::load().expect("load /etc/hosts"),
-                rules,
+                rules: RwLock::new(rules),
                 next_ip: AtomicU32::new(next_ip),
                 db,
-                resolver,
+                resolver: RwLock::new(resolver),
+                upstreams: RwLock::new(upstreams),
+                negative_cache: NegativeCache::default(),
+                options,
+                lan_client: DnsNetworkClient::new(0, LAN_DNS_TIMEOUT).await,
+                query_log,
+                geo_demoted: Mutex::new(HashMap::new()),
             }),
         }
     }
 
+    fn rules(&self) -> ProxyRules {
+        self.inner.rules.read().unwrap().clone()
+    }
+
+    /// Answer by `rules` from now on. Fake ips already handed out keep their domains.
+    pub fn set_rules(&self, rules: ProxyRules) {
+        *self.inner.rules.write().unwrap() = rules;
+    }
+
+    /// Resolve through `resolver`, and the `dns_resolvers` in `upstreams`, from now on.
+    pub fn set_upstreams(&self, resolver: Resolver, upstreams: HashMap<String, Resolver>) {
+        *self.inner.resolver.write().unwrap() = resolver;
+        *self.inner.upstreams.write().unwrap() = upstreams;
+        self.inner.negative_cache.clear_all();
+    }
+
+    /// Recent queries answered by this resolver.
+    pub fn query_log(&self) -> QueryLog {
+        self.inner.query_log.clone()
+    }
+
+    /// Whether the DIRECT `domain` resolved to foreign addresses recently and has to be
+    /// proxied, so the proxy resolves it remotely.
+    pub fn is_geo_demoted(&self, domain: &str) -> bool {
+        match self.inner.geo_demoted.lock().unwrap().get(domain) {
+            Some(at) => at.elapsed() < GEO_DEMOTE_TTL,
+            None => false,
+        }
+    }
+
+    /// Resolve the DIRECT `domain`, or `None` when its answer has no domestic address,
+    /// which is often a poisoned answer or a CDN node abroad.
+    async fn resolve_domestic(
+        &self,
+        domain: &str,
+        domestic_ips: &IpSet,
+    ) -> Option<Result<(DnsPacket, AnswerSource)>> {
+        if self.is_geo_demoted(domain) {
+            return None;
+        }
+        let (packet, source) = match self.resolve_real_ip(domain).await {
+            Ok(answer) => answer,
+            Err(e) => return Some(Err(e)),
+        };
+        if is_domestic_answer(&packet, domestic_ips) {
+            return Some(Ok((packet, source)));
+        }
+        debug!(
+            "no domestic address for direct domain: {}, proxy it",
+            domain
+        );
+        let mut demoted = self.inner.geo_demoted.lock().unwrap();
+        demoted.retain(|_, at| at.elapsed() < GEO_DEMOTE_TTL);
+        demoted.insert(domain.to_string(), Instant::now());
+        None
+    }
+
     pub fn lookup_host(&self, addr: &str) -> Option<String> {
         let host = self
             .inner
@@ -83,7 +218,168 @@ impl RuleBasedDnsResolver {
         addr.to_string()
     }
 
-    async fn resolve(&self, domain: &str) -> Result<DnsPacket> {
+    fn allow_aaaa(&self, domain: &str) -> bool {
+        match self.inner.options.ipv6_policy {
+            Ipv6Policy::Off => false,
+            Ipv6Policy::OnlyMatched => self.rules().is_explicitly_matched(domain),
+            Ipv6Policy::PreferV4 | Ipv6Policy::PreferV6 => true,
+        }
+    }
+
+    /// Whether answers for `domain` must not point into private ranges.
+    fn protect_from_rebinding(&self, domain: &str) -> bool {
+        let options = &self.inner.options;
+        options.rebind_protection
+            && !is_local_domain(domain)
+            && !is_allowlisted(domain, &options.rebind_allowlist)
+    }
+
+    /// The upstream of `domain`, the resolver its rule names, the domestic one for DIRECT
+    /// domains, or the default one.
+    fn upstream_for(&self, domain: &str) -> Resolver {
+        let rules = self.rules();
+        let name = match rules.dns_for_domain(domain) {
+            Some(DnsPolicy::Resolver(name)) => Some(name),
+            _ if rules.action_for_domain(domain) == Some(Action::Direct) => {
+                self.inner.options.domestic_dns.as_ref()
+            }
+            _ => None,
+        };
+        let upstreams = self.inner.upstreams.read().unwrap();
+        match name.and_then(|name| upstreams.get(name)) {
+            Some(upstream) => upstream.clone(),
+            None => self.inner.resolver.read().unwrap().clone(),
+        }
+    }
+
+    /// Resolve `domain` through the upstream resolver, returning its real addresses.
+    async fn resolve_real_ip(&self, domain: &str) -> Result<(DnsPacket, AnswerSource)> {
+        let mut packet = DnsPacket::new();
+        let negative_cache = &self.inner.negative_cache;
+        match negative_cache.get(domain) {
+            Some(NegativeAnswer::NoRecords) => return Ok((packet, AnswerSource::NegativeCache)),
+            Some(NegativeAnswer::NxDomain) => {
+                packet.header.rescode = ResultCode::NXDOMAIN;
+                return Ok((packet, AnswerSource::NegativeCache));
+            }
+            Some(NegativeAnswer::ServFail) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "upstream failed recently, backing off",
+                ))
+            }
+            None => {}
+        }
+        let upstream = self.upstream_for(domain);
+        let lookup_ip = match upstream.lookup_ip(domain).await {
+            Ok(lookup_ip) => {
+                negative_cache.clear(domain);
+                lookup_ip
+            }
+            Err(e) => {
+                if let ResolveErrorKind::NoRecordsFound { valid_until, .. } = e.kind() {
+                    let ttl = valid_until.map(|t| t.saturating_duration_since(Instant::now()));
+                    let nxdomain = upstream.is_nxdomain(domain).await;
+                    negative_cache.store_no_records(domain, ttl, nxdomain);
+                    debug!(
+                        "no records for domain: {}, nxdomain: {}, ttl: {:?}",
+                        domain, nxdomain, ttl
+                    );
+                    if nxdomain {
+                        packet.header.rescode = ResultCode::NXDOMAIN;
+                    }
+                    return Ok((packet, AnswerSource::Upstream));
+                }
+                let backoff = negative_cache.store_failure(domain);
+                debug!(
+                    "lookup domain: {} failed: {}, back off {:?}",
+                    domain, e, backoff
+                );
+                return Err(io::Error::new(io::ErrorKind::Other, e.to_string()));
+            }
+        };
+        let mut ips: Vec<IpAddr> = vec![];
+        let allow_aaaa = self.allow_aaaa(domain);
+        let protect = self.protect_from_rebinding(domain);
+        for record in lookup_ip.as_lookup().record_iter() {
+            let ttl = self.inner.options.ttl.clamp(domain, record.ttl());
+            let rdata = match record.rdata() {
+                RData::A(ip) if protect && is_private_ip(IpAddr::V4(*ip)) => {
+                    debug!("strip private address {} for domain: {}", ip, domain);
+                    continue;
+                }
+                RData::AAAA(ip) if protect && is_private_ip(IpAddr::V6(*ip)) => {
+                    debug!("strip private address {} for domain: {}", ip, domain);
+                    continue;
+                }
+                RData::A(ip) => {
+                    ips.push(IpAddr::V4(*ip));
+                    DnsRecord::A {
+                        domain: domain.to_string(),
+                        addr: *ip,
+                        ttl: TransientTtl(ttl),
+                    }
+                }
+                RData::AAAA(ip) if allow_aaaa => {
+                    ips.push(IpAddr::V6(*ip));
+                    DnsRecord::AAAA {
+                        domain: domain.to_string(),
+                        addr: *ip,
+                        ttl: TransientTtl(ttl),
+                    }
+                }
+                _ => continue,
+            };
+            packet.answers.push(rdata)
+        }
+        if let Some(prefix) = self.inner.options.nat64_prefix {
+            if allow_aaaa && !ips.iter().any(|ip| ip.is_ipv6()) {
+                synthesize_aaaa(domain, prefix, &mut packet, &mut ips);
+            }
+        }
+
+        debug!("lookup host for direct domain: {}, ip: {:?}", domain, ips);
+        Ok((packet, AnswerSource::Upstream))
+    }
+
+    /// Ask the LAN resolvers, and multicast DNS for `.local` names, about `domain`.
+    ///
+    /// Returns `None` when no LAN resolver answered, so the query can go upstream as usual.
+    async fn resolve_lan(&self, domain: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let client = &self.inner.lan_client;
+        let mut answer = None;
+        for server in &self.inner.options.lan_dns {
+            let ip = server.ip().to_string();
+            match client
+                .send_query(domain, qtype, (ip.as_str(), server.port()), true)
+                .await
+            {
+                Ok(packet) => {
+                    answer = Some(packet);
+                    break;
+                }
+                Err(e) => debug!("lan dns {} failed for {}: {}", server, domain, e),
+            }
+        }
+        let answered = answer.as_ref().map(|p| !p.answers.is_empty()) == Some(true);
+        if !answered && is_mdns_domain(domain) {
+            match client.send_query(domain, qtype, MDNS_ADDR, false).await {
+                Ok(packet) if !packet.answers.is_empty() => answer = Some(packet),
+                Ok(_) => {}
+                Err(e) => debug!("mdns query failed for {}: {}", domain, e),
+            }
+        }
+        let mut packet = answer?;
+        // mDNS responders attach records we do not need to relay.
+        packet.resources.clear();
+        debug!(
+            "lookup lan domain: {}, answers: {:?}",
+            domain, packet.answers
+        );
+        Some(packet)
+    }
+
+    async fn lookup(&self, domain: &str) -> Result<(DnsPacket, AnswerSource)> {
         let mut packet = DnsPacket::new();
         if let Some(ip) = self.inner.hosts.get(domain) {
             packet.answers.push(DnsRecord::A {
@@ -95,45 +391,37 @@ impl RuleBasedDnsResolver {
                 "lookup host for /etc/hosts domain: {}, ip: {:?}",
                 domain, ip
             );
-            return Ok(packet);
+            return Ok((packet, AnswerSource::Hosts));
         }
 
-        match self.inner.rules.action_for_domain(domain) {
-            Some(Action::Direct) => {
-                let lookup_ip = self
-                    .inner
-                    .resolver
-                    .lookup_ip(domain)
-                    .await
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-                let mut ips: Vec<IpAddr> = vec![];
-                for record in lookup_ip.as_lookup().record_iter() {
-                    let rdata = match record.rdata() {
-                        RData::A(ip) => {
-                            ips.push(IpAddr::V4(*ip));
-                            DnsRecord::A {
-                                domain: domain.to_string(),
-                                addr: *ip,
-                                ttl: TransientTtl(record.ttl()),
-                            }
-                        }
-                        RData::AAAA(ip) => {
-                            ips.push(IpAddr::V6(*ip));
-                            DnsRecord::AAAA {
-                                domain: domain.to_string(),
-                                addr: *ip,
-                                ttl: TransientTtl(record.ttl()),
-                            }
-                        }
-                        _ => continue,
-                    };
-                    packet.answers.push(rdata)
+        let rules = self.rules();
+        let policy = rules.dns_for_domain(domain);
+        match rules.action_for_domain(domain) {
+            // Answer with a fake ip so the connection reaches the tun, where the rules about
+            // the process can be checked.
+            _ if self.inner.options.fake_ip && rules.depends_on_connection(domain) => {}
+            // The rule asks for an answer whatever its action.
+            _ if self.inner.options.fake_ip && policy == Some(&DnsPolicy::FakeIp) => {}
+            _ if matches!(policy, Some(DnsPolicy::Real) | Some(DnsPolicy::Resolver(_))) => {
+                return self.resolve_real_ip(domain).await
+            }
+            Some(Action::Direct) => match &self.inner.options.domestic_ips {
+                Some(domestic_ips) if self.inner.options.fake_ip => {
+                    if let Some(answer) = self.resolve_domestic(domain, domestic_ips).await {
+                        return answer;
+                    }
                 }
-
-                debug!("lookup host for direct domain: {}, ip: {:?}", domain, ips);
-                return Ok(packet);
+                _ => return self.resolve_real_ip(domain).await,
+            },
+            // Other reject modes answer at the tun, the connection has to reach it.
+            Some(Action::Reject)
+                if !self.inner.options.fake_ip
+                    || rules.rule_for_domain(domain).map(|r| r.reject)
+                        == Some(RejectMode::Reset) =>
+            {
+                return Ok((packet, AnswerSource::Rejected))
             }
-            Some(Action::Reject) => return Ok(packet),
+            _ if !self.inner.options.fake_ip => return self.resolve_real_ip(domain).await,
             _ => {}
         };
 
@@ -168,19 +456,79 @@ impl RuleBasedDnsResolver {
             addr: ip.parse().unwrap(),
             ttl: TransientTtl(5),
         });
-        Ok(packet)
+        Ok((packet, AnswerSource::FakeIp))
     }
 }
 
+/// Whether any address of `packet` is domestic or private. Answers without addresses are
+/// left alone, there is nothing to compare.
+fn is_domestic_answer(packet: &DnsPacket, domestic_ips: &IpSet) -> bool {
+    let ips: Vec<IpAddr> = packet
+        .answers
+        .iter()
+        .filter_map(|record| match record {
+            DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+            DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+            _ => None,
+        })
+        .collect();
+    ips.is_empty()
+        || ips
+            .iter()
+            .any(|ip| domestic_ips.contains(*ip) || is_private_ip(*ip))
+}
+
+/// DNS64: add an AAAA record in the NAT64 `prefix` for every A record of `packet` the
+/// gateway translates.
+fn synthesize_aaaa(domain: &str, prefix: Ipv6Addr, packet: &mut DnsPacket, ips: &mut Vec<IpAddr>) {
+    let synthesized: Vec<_> = packet
+        .answers
+        .iter()
+        .filter_map(|record| match record {
+            DnsRecord::A { addr, ttl, .. } if !nat64::is_special_use(*addr) => {
+                Some(DnsRecord::AAAA {
+                    domain: domain.to_string(),
+                    addr: nat64::synthesize(prefix, *addr),
+                    ttl: *ttl,
+                })
+            }
+            _ => None,
+        })
+        .collect();
+    for record in &synthesized {
+        if let DnsRecord::AAAA { addr, .. } = record {
+            ips.push(IpAddr::V6(*addr));
+        }
+    }
+    packet.answers.extend(synthesized);
+}
+
 #[async_trait]
 impl DnsResolver for RuleBasedDnsResolver {
-    async fn resolve(
-        &self,
-        domain: &str,
-        _qtype: QueryType,
-        _recursive: bool,
-    ) -> Result<DnsPacket> {
-        self.resolve(domain).await
+    async fn resolve(&self, domain: &str, qtype: QueryType, _recursive: bool) -> Result<DnsPacket> {
+        let start = Instant::now();
+        let mut lan_answer = None;
+        if (is_local_domain(domain) || is_reverse_zone(domain))
+            && self.inner.hosts.get(domain).is_none()
+        {
+            lan_answer = self.resolve_lan(domain, qtype).await;
+        }
+        let result = match lan_answer {
+            Some(packet) => Ok((packet, AnswerSource::Lan)),
+            None => self.lookup(domain).await,
+        };
+        let rule = self
+            .rules()
+            .action_for_domain(domain)
+            .map(|action| format!("{:?}", action));
+        self.inner.query_log.record(QueryLogEntry::new(
+            domain,
+            qtype,
+            &result,
+            start.elapsed(),
+            rule,
+        ));
+        result.map(|(packet, _)| packet)
     }
 
     fn as_any(&self) -> &dyn Any {
@@ -191,8 +539,9 @@ impl DnsResolver for RuleBasedDnsResolver {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::new_resolver;
+    use crate::tests::{new_resolver, stub_upstream};
     use async_std::task;
+    use trust_dns_proto::op::ResponseCode;
 
     #[test]
     fn test_inner_resolve_ip_and_lookup_host() {
@@ -205,18 +554,21 @@ mod tests {
                 dir.path(),
                 n,
                 ProxyRules::new(vec![]),
+                ResolverOptions::default(),
                 new_resolver(dns, 53).await,
+                HashMap::new(),
             )
             .await;
             assert_eq!(
-                resolver.resolve("baidu.com").await.unwrap().get_random_a(),
+                resolver.lookup("baidu.com").await.unwrap().0.get_random_a(),
                 Some("10.0.0.1".to_string())
             );
             assert_eq!(
                 resolver
-                    .resolve("www.ali.com")
+                    .lookup("www.ali.com")
                     .await
                     .unwrap()
+                    .0
                     .get_random_a(),
                 Some("10.0.0.2".to_string())
             );
@@ -227,4 +579,152 @@ mod tests {
             assert_eq!(resolver.lookup_host("10.1.0.1"), None);
         });
     }
+
+    #[test]
+    fn test_upstream_servfail() {
+        let dir = tempfile::tempdir().unwrap();
+        task::block_on(async {
+            let port = stub_upstream(ResponseCode::ServFail, None).await;
+            let resolver = RuleBasedDnsResolver::new(
+                dir.path(),
+                u32::from_be_bytes([10, 0, 0, 1]),
+                ProxyRules::new(vec![]),
+                ResolverOptions {
+                    fake_ip: false,
+                    ..ResolverOptions::default()
+                },
+                new_resolver("127.0.0.1".to_string(), port).await,
+                HashMap::new(),
+            )
+            .await;
+            // The DNS server answers errors with SERVFAIL.
+            assert!(resolver
+                .resolve("example.com", QueryType::A, true)
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn test_domestic_dns() {
+        use config::rule::Rule;
+        use std::str::FromStr;
+
+        let dir = tempfile::tempdir().unwrap();
+        task::block_on(async {
+            let port = stub_upstream(ResponseCode::NoError, Some([8, 8, 8, 8].into())).await;
+            let domestic_port =
+                stub_upstream(ResponseCode::NoError, Some([114, 114, 1, 1].into())).await;
+            let mut upstreams = HashMap::new();
+            let domestic = new_resolver("127.0.0.1".to_string(), domestic_port).await;
+            upstreams.insert("domestic".to_string(), domestic);
+            let resolver = RuleBasedDnsResolver::new(
+                dir.path(),
+                u32::from_be_bytes([10, 0, 0, 1]),
+                ProxyRules::new(vec![
+                    Rule::from_str("DOMAIN-SUFFIX,example.cn,DIRECT").unwrap(),
+                    Rule::from_str("MATCH,PROXY").unwrap(),
+                ]),
+                ResolverOptions {
+                    fake_ip: false,
+                    domestic_dns: Some("domestic".to_string()),
+                    ..ResolverOptions::default()
+                },
+                new_resolver("127.0.0.1".to_string(), port).await,
+                upstreams,
+            )
+            .await;
+            let (packet, _) = resolver.lookup("www.example.cn").await.unwrap();
+            assert_eq!(packet.get_random_a(), Some("114.114.1.1".to_string()));
+            // Without fake ips, proxied domains get the answer of the default upstream.
+            let (packet, _) = resolver.lookup("example.com").await.unwrap();
+            assert_eq!(packet.get_random_a(), Some("8.8.8.8".to_string()));
+        });
+    }
+
+    #[test]
+    fn test_rule_dns_policy() {
+        use config::rule::Rule;
+        use std::str::FromStr;
+
+        let dir = tempfile::tempdir().unwrap();
+        let rules = ProxyRules::new(vec![
+            Rule {
+                dns: Some(DnsPolicy::FakeIp),
+                ..Rule::from_str("DOMAIN-SUFFIX,example.cn,DIRECT").unwrap()
+            },
+            Rule {
+                dns: Some(DnsPolicy::Resolver("domestic".to_string())),
+                ..Rule::from_str("DOMAIN-SUFFIX,example.org,DIRECT").unwrap()
+            },
+        ]);
+        task::block_on(async {
+            let default = new_resolver("127.0.0.1".to_string(), 53).await;
+            let mut upstreams = HashMap::new();
+            upstreams.insert("domestic".to_string(), default.clone());
+            let resolver = RuleBasedDnsResolver::new(
+                dir.path(),
+                u32::from_be_bytes([10, 0, 0, 1]),
+                rules,
+                ResolverOptions::default(),
+                default,
+                upstreams,
+            )
+            .await;
+            // A DIRECT domain, answered without asking the upstream.
+            let (packet, source) = resolver.lookup("www.example.cn").await.unwrap();
+            assert_eq!(source, AnswerSource::FakeIp);
+            assert_eq!(packet.get_random_a(), Some("10.0.0.1".to_string()));
+
+            // Reloaded rules apply to the next query.
+            resolver.set_rules(ProxyRules::new(vec![Rule::from_str(
+                "DOMAIN-SUFFIX,example.cn,REJECT",
+            )
+            .unwrap()]));
+            let (_, source) = resolver.lookup("www.example.cn").await.unwrap();
+            assert_eq!(source, AnswerSource::Rejected);
+        });
+    }
+
+    #[test]
+    fn test_synthesize_aaaa() {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: TransientTtl(60),
+        });
+        let mut ips = vec![];
+        synthesize_aaaa(
+            "example.com",
+            "64:ff9b::".parse().unwrap(),
+            &mut packet,
+            &mut ips,
+        );
+        assert_eq!(ips, vec!["64:ff9b::c000:201".parse::<IpAddr>().unwrap()]);
+        assert_eq!(packet.answers.len(), 2);
+    }
+
+    #[test]
+    fn test_is_domestic_answer() {
+        let domestic_ips = IpSet::parse("114.114.0.0/16\n").unwrap();
+        let answer = |ips: &[&str]| {
+            let mut packet = DnsPacket::new();
+            for ip in ips {
+                packet.answers.push(DnsRecord::A {
+                    domain: "example.com".to_string(),
+                    addr: ip.parse().unwrap(),
+                    ttl: TransientTtl(60),
+                });
+            }
+            packet
+        };
+        assert!(is_domestic_answer(&answer(&[]), &domestic_ips));
+        assert!(is_domestic_answer(
+            &answer(&["8.8.8.8", "114.114.1.1"]),
+            &domestic_ips
+        ));
+        assert!(is_domestic_answer(&answer(&["192.168.1.1"]), &domestic_ips));
+        assert!(!is_domestic_answer(&answer(&["8.8.8.8"]), &domestic_ips));
+    }
 }
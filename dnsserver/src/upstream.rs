@@ -0,0 +1,493 @@
+//! Resolvers for the upstream DNS servers. Their sockets are marked like the other sockets
+//! seeker opens itself (see `sysconfig::mark_socket`), so queries leave through the network
+//! instead of the tun, also while the kill switch blocks everything else.
+use async_std::io::timeout;
+use async_std::net::UdpSocket;
+#[cfg(not(unix))]
+use async_std_resolver::AsyncStdResolver as Upstream;
+use config::trust_anchor::RootKey;
+#[cfg(unix)]
+use marked::Upstream;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+use trust_dns_proto::op::{Message, MessageType, Query, ResponseCode};
+use trust_dns_proto::rr::{Name, RecordType};
+use trust_dns_resolver::config::{Protocol, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::lookup_ip::LookupIp;
+use validated::Validator;
+
+/// Answers to plain queries without EDNS fit into this.
+const MAX_UDP_RESPONSE: usize = 512;
+
+/// A resolver for the servers of a `ResolverConfig`, used like the trust-dns resolver it
+/// wraps.
+#[derive(Clone)]
+pub struct Resolver {
+    resolver: Upstream,
+    /// The plain UDP servers, asked directly for what the resolver does not report.
+    udp_servers: Arc<Vec<SocketAddr>>,
+    timeout: Duration,
+    /// Looks up addresses instead of the resolver when answers are validated from
+    /// `dnssec_trust_anchor`.
+    validator: Option<Arc<Validator>>,
+}
+
+/// With `root_keys` and `validate` set in `options`, answers are validated from
+/// `root_keys` instead of the root keys built into trust-dns.
+pub async fn resolver(
+    config: ResolverConfig,
+    options: ResolverOpts,
+    root_keys: Option<&[RootKey]>,
+) -> Result<Resolver, ResolveError> {
+    let udp_servers = config
+        .name_servers()
+        .iter()
+        .filter(|server| server.protocol == Protocol::Udp)
+        .map(|server| server.socket_addr)
+        .collect::<Vec<_>>();
+    let timeout = options.timeout;
+    let (validator, options) = match root_keys {
+        Some(keys) if options.validate => {
+            let validator = Validator::new(keys, udp_servers.clone(), &options)?;
+            let options = ResolverOpts {
+                validate: false,
+                ..options
+            };
+            (Some(Arc::new(validator)), options)
+        }
+        _ => (None, options),
+    };
+    #[cfg(unix)]
+    let resolver = marked::resolver(config, options).await?;
+    #[cfg(not(unix))]
+    let resolver = async_std_resolver::resolver(config, options).await?;
+    Ok(Resolver {
+        resolver,
+        udp_servers: Arc::new(udp_servers),
+        timeout,
+        validator,
+    })
+}
+
+impl Deref for Resolver {
+    type Target = Upstream;
+
+    fn deref(&self) -> &Upstream {
+        &self.resolver
+    }
+}
+
+impl Resolver {
+    /// The addresses of `domain`, validated from the configured root keys when there are
+    /// some.
+    pub async fn lookup_ip(&self, domain: &str) -> Result<LookupIp, ResolveError> {
+        match &self.validator {
+            Some(validator) => validator.lookup_ip(domain).await,
+            None => self.resolver.lookup_ip(domain).await,
+        }
+    }
+
+    /// Whether the first UDP server answering says `domain` does not exist. The resolver
+    /// reports NXDOMAIN like an answer without records of the type asked for, so negative
+    /// answers are checked again with a query of their own. Without UDP servers the domain
+    /// is taken to exist.
+    pub async fn is_nxdomain(&self, domain: &str) -> bool {
+        for server in self.udp_servers.iter() {
+            match timeout(self.timeout, response_code(*server, domain)).await {
+                Ok(code) => return code == ResponseCode::NXDomain,
+                Err(e) => debug!(?e, %server, domain, "query response code"),
+            }
+        }
+        false
+    }
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// The response code `server` answers an A query for `domain` with.
+async fn response_code(server: SocketAddr, domain: &str) -> io::Result<ResponseCode> {
+    let name = Name::from_ascii(domain).map_err(invalid_data)?;
+    let mut query = Message::new();
+    query
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name, RecordType::A));
+    let socket = udp_socket(&server)?;
+    socket
+        .send_to(&query.to_vec().map_err(invalid_data)?, server)
+        .await?;
+    let mut buf = vec![0; MAX_UDP_RESPONSE];
+    loop {
+        let (size, from) = socket.recv_from(&mut buf).await?;
+        if from != server {
+            continue;
+        }
+        match Message::from_vec(&buf[..size]) {
+            Ok(response)
+                if response.id() == query.id()
+                    && response.message_type() == MessageType::Response =>
+            {
+                return Ok(response.response_code())
+            }
+            _ => continue,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn udp_socket(server: &SocketAddr) -> io::Result<UdpSocket> {
+    Ok(UdpSocket::from(sysconfig::marked_udp_socket(server)?))
+}
+
+#[cfg(not(unix))]
+fn udp_socket(server: &SocketAddr) -> io::Result<UdpSocket> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    let socket = if server.is_ipv4() {
+        std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?
+    } else {
+        std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?
+    };
+    Ok(UdpSocket::from(socket))
+}
+
+#[cfg(not(all(unix, feature = "dnssec")))]
+mod validated {
+    use config::trust_anchor::RootKey;
+    use std::net::SocketAddr;
+    use trust_dns_resolver::config::ResolverOpts;
+    use trust_dns_resolver::error::ResolveError;
+    use trust_dns_resolver::lookup_ip::LookupIp;
+
+    /// Validation from other root keys needs the `dnssec` feature and marked sockets.
+    pub enum Validator {}
+
+    impl Validator {
+        pub fn new(
+            _root_keys: &[RootKey],
+            _servers: Vec<SocketAddr>,
+            _options: &ResolverOpts,
+        ) -> Result<Self, ResolveError> {
+            Err(ResolveError::from(
+                "dnssec_trust_anchor needs seeker built with the dnssec feature on unix",
+            ))
+        }
+
+        pub async fn lookup_ip(&self, _domain: &str) -> Result<LookupIp, ResolveError> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "dnssec"))]
+mod validated {
+    use super::marked::{MarkedUdpSocket, Timer};
+    use config::trust_anchor::RootKey;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tracing::debug;
+    use trust_dns_proto::error::ProtoError;
+    use trust_dns_proto::op::{Query, ResponseCode};
+    use trust_dns_proto::rr::dnssec::{Algorithm, PublicKeyEnum, TrustAnchor};
+    use trust_dns_proto::rr::{Name, RecordType};
+    use trust_dns_proto::udp::UdpClientStream;
+    use trust_dns_proto::xfer::{DnsExchange, DnsHandle, DnsRequestOptions, SecureDnsHandle};
+    use trust_dns_resolver::config::{LookupIpStrategy, ResolverOpts};
+    use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+    use trust_dns_resolver::lookup::Lookup;
+    use trust_dns_resolver::lookup_ip::LookupIp;
+
+    /// Looks up addresses validated (RFC 4035) from root keys of the config. The resolver of
+    /// trust-dns only validates from the keys built into it, which go stale with a root
+    /// key rollover.
+    pub struct Validator {
+        root_keys: Vec<RootKey>,
+        servers: Vec<SocketAddr>,
+        timeout: Duration,
+        /// Queried in this order.
+        record_types: Vec<RecordType>,
+        /// Whether the records of all `record_types` are wanted, not just the first found.
+        all_types: bool,
+    }
+
+    fn trust_anchor(root_keys: &[RootKey]) -> Result<TrustAnchor, ProtoError> {
+        let mut trust_anchor = TrustAnchor::new();
+        for key in root_keys {
+            let algorithm = Algorithm::from_u8(key.algorithm)?;
+            let public_key = PublicKeyEnum::from_public_bytes(&key.public_key, algorithm)?;
+            trust_anchor.insert_trust_anchor(&public_key);
+        }
+        Ok(trust_anchor)
+    }
+
+    impl Validator {
+        pub fn new(
+            root_keys: &[RootKey],
+            servers: Vec<SocketAddr>,
+            options: &ResolverOpts,
+        ) -> Result<Self, ResolveError> {
+            if servers.is_empty() {
+                return Err(ResolveError::from(
+                    "dnssec_trust_anchor needs dns servers over udp",
+                ));
+            }
+            trust_anchor(root_keys)?;
+            let (record_types, all_types) = match options.ip_strategy {
+                LookupIpStrategy::Ipv4Only => (vec![RecordType::A], false),
+                LookupIpStrategy::Ipv6Only => (vec![RecordType::AAAA], false),
+                LookupIpStrategy::Ipv4AndIpv6 => (vec![RecordType::A, RecordType::AAAA], true),
+                LookupIpStrategy::Ipv4thenIpv6 => (vec![RecordType::A, RecordType::AAAA], false),
+                LookupIpStrategy::Ipv6thenIpv4 => (vec![RecordType::AAAA, RecordType::A], false),
+            };
+            Ok(Validator {
+                root_keys: root_keys.to_vec(),
+                servers,
+                timeout: options.timeout,
+                record_types,
+                all_types,
+            })
+        }
+
+        /// Answers that fail validation are errors, like those of a validating resolver.
+        /// The next server is asked when one fails.
+        pub async fn lookup_ip(&self, domain: &str) -> Result<LookupIp, ResolveError> {
+            let name = Name::from_ascii(domain)?;
+            let mut error = ResolveError::from("no dns servers");
+            for server in &self.servers {
+                match self.lookup_from(*server, &name).await {
+                    Ok(lookup) => return Ok(lookup),
+                    Err(e) => {
+                        if let ResolveErrorKind::NoRecordsFound { .. } = e.kind() {
+                            return Err(e);
+                        }
+                        debug!(?e, %server, domain, "validated lookup");
+                        error = e;
+                    }
+                }
+            }
+            Err(error)
+        }
+
+        async fn lookup_from(
+            &self,
+            server: SocketAddr,
+            name: &Name,
+        ) -> Result<LookupIp, ResolveError> {
+            let stream = UdpClientStream::<MarkedUdpSocket>::with_timeout(server, self.timeout);
+            let (exchange, background) = DnsExchange::connect::<_, _, Timer>(stream).await?;
+            // Runs until the handles of the exchange are dropped.
+            let _background = async_std::task::spawn(background);
+            let mut handle =
+                SecureDnsHandle::with_trust_anchor(exchange, trust_anchor(&self.root_keys)?);
+            let mut records = vec![];
+            for record_type in &self.record_types {
+                let query = Query::query(name.clone(), *record_type);
+                let response = handle.lookup(query, DnsRequestOptions::default()).await?;
+                match response.response_code() {
+                    ResponseCode::NoError | ResponseCode::NXDomain => {}
+                    code => {
+                        return Err(ResolveError::from(format!("{} answered {}", server, code)))
+                    }
+                }
+                let answers = response.answers().iter();
+                records.extend(answers.filter(|r| r.rr_type() == *record_type).cloned());
+                if !records.is_empty() && !self.all_types {
+                    break;
+                }
+            }
+            let query = Query::query(name.clone(), self.record_types[0]);
+            if records.is_empty() {
+                let valid_until = None;
+                return Err(ResolveErrorKind::NoRecordsFound { query, valid_until }.into());
+            }
+            let lookup = Lookup::new_with_max_ttl(query, Arc::new(records));
+            Ok(LookupIp::from(lookup))
+        }
+    }
+}
+
+#[cfg(unix)]
+mod marked {
+    use async_io::Async;
+    use async_std::io::{Read, Write};
+    use async_std::net::{TcpStream, UdpSocket};
+    use async_trait::async_trait;
+    use std::future::Future;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use trust_dns_proto::error::ProtoError;
+    use trust_dns_proto::tcp::Connect;
+    use trust_dns_proto::udp::UdpSocket as DnsUdpSocket;
+    use trust_dns_proto::Time;
+    use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+    use trust_dns_resolver::error::ResolveError;
+    use trust_dns_resolver::name_server::{
+        GenericConnection, GenericConnectionProvider, RuntimeProvider, Spawn,
+    };
+    use trust_dns_resolver::AsyncResolver;
+
+    pub type Upstream = AsyncResolver<GenericConnection, GenericConnectionProvider<Marked>>;
+
+    pub async fn resolver(
+        config: ResolverConfig,
+        options: ResolverOpts,
+    ) -> Result<Upstream, ResolveError> {
+        Upstream::new(config, options, Handle).await
+    }
+
+    /// The async-std runtime, with marked sockets.
+    #[derive(Clone, Copy)]
+    pub struct Marked;
+
+    impl RuntimeProvider for Marked {
+        type Handle = Handle;
+        type Tcp = MarkedTcpStream;
+        type Timer = Timer;
+        type Udp = MarkedUdpSocket;
+    }
+
+    #[derive(Clone, Copy)]
+    pub struct Handle;
+
+    impl Spawn for Handle {
+        fn spawn_bg<F>(&mut self, future: F)
+        where
+            F: Future<Output = Result<(), ProtoError>> + Send + 'static,
+        {
+            let _task = async_std::task::spawn(future);
+        }
+    }
+
+    pub struct Timer;
+
+    #[async_trait]
+    impl Time for Timer {
+        async fn delay_for(duration: Duration) {
+            async_std::task::sleep(duration).await
+        }
+
+        async fn timeout<F: 'static + Future + Send>(
+            duration: Duration,
+            future: F,
+        ) -> io::Result<F::Output> {
+            async_std::future::timeout(duration, future)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "future timed out"))
+        }
+    }
+
+    pub struct MarkedUdpSocket(UdpSocket);
+
+    #[async_trait]
+    impl DnsUdpSocket for MarkedUdpSocket {
+        /// The port of `addr` is left to the system, like for the other sockets of seeker.
+        async fn bind(addr: &SocketAddr) -> io::Result<Self> {
+            let socket = sysconfig::marked_udp_socket(addr)?;
+            Ok(MarkedUdpSocket(UdpSocket::from(socket)))
+        }
+
+        async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.0.recv_from(buf).await
+        }
+
+        async fn send_to(&mut self, buf: &[u8], target: &SocketAddr) -> io::Result<usize> {
+            self.0.send_to(buf, target).await
+        }
+    }
+
+    pub struct MarkedTcpStream(TcpStream);
+
+    #[async_trait]
+    impl Connect for MarkedTcpStream {
+        type Transport = MarkedTcpStream;
+
+        async fn connect(addr: SocketAddr) -> io::Result<Self::Transport> {
+            let stream = Async::new(sysconfig::marked_tcp_connect(&addr)?)?;
+            stream.writable().await?;
+            if let Some(e) = stream.get_ref().take_error()? {
+                return Err(e);
+            }
+            let stream = TcpStream::from(stream.into_inner()?);
+            stream.set_nodelay(true)?;
+            Ok(MarkedTcpStream(stream))
+        }
+    }
+
+    impl Read for MarkedTcpStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl Write for MarkedTcpStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_close(cx)
+        }
+    }
+}
+
+#[cfg(all(test, unix, feature = "dnssec"))]
+mod tests {
+    use super::*;
+    use crate::tests::stub_upstream;
+    use async_std::task;
+    use std::net::IpAddr;
+    use trust_dns_resolver::config::NameServerConfigGroup;
+
+    #[test]
+    fn test_bogus_answer() {
+        task::block_on(async {
+            let port = stub_upstream(ResponseCode::NoError, Some([1, 2, 3, 4].into())).await;
+            let servers =
+                NameServerConfigGroup::from_ips_clear(&["127.0.0.1".parse().unwrap()], port);
+            let config = ResolverConfig::from_parts(None, vec![], servers);
+            let options = ResolverOpts {
+                validate: true,
+                ..ResolverOpts::default()
+            };
+            let mut public_key = vec![3, 1, 0, 1];
+            public_key.extend_from_slice(&[0xc5; 256]);
+            let root_keys = [RootKey {
+                flags: 257,
+                algorithm: 8,
+                public_key,
+            }];
+            let validating = resolver(config.clone(), options, Some(&root_keys)).await;
+            // Unsigned answers do not chain up to the root keys.
+            assert!(validating.unwrap().lookup_ip("example.com").await.is_err());
+            let plain = resolver(config, ResolverOpts::default(), Some(&root_keys)).await;
+            let addrs = plain.unwrap().lookup_ip("example.com").await.unwrap();
+            assert_eq!(
+                addrs.iter().collect::<Vec<_>>(),
+                vec![IpAddr::from([1, 2, 3, 4])]
+            );
+        });
+    }
+}
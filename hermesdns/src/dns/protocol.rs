@@ -24,6 +24,7 @@ pub enum QueryType {
     NS,    // 2
     CNAME, // 5
     SOA,   // 6
+    PTR,   // 12
     MX,    // 15
     TXT,   // 16
     AAAA,  // 28
@@ -39,6 +40,7 @@ impl QueryType {
             QueryType::NS => 2,
             QueryType::CNAME => 5,
             QueryType::SOA => 6,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
             QueryType::TXT => 16,
             QueryType::AAAA => 28,
@@ -53,6 +55,7 @@ impl QueryType {
             2 => QueryType::NS,
             5 => QueryType::CNAME,
             6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
             16 => QueryType::TXT,
             28 => QueryType::AAAA,
@@ -132,6 +135,11 @@ pub enum DnsRecord {
         minimum: u32,
         ttl: TransientTtl,
     }, // 6
+    PTR {
+        domain: String,
+        host: String,
+        ttl: TransientTtl,
+    }, // 12
     MX {
         domain: String,
         priority: u16,
@@ -232,6 +240,16 @@ impl DnsRecord {
                     ttl: TransientTtl(ttl),
                 })
             }
+            QueryType::PTR => {
+                let mut ptr = String::new();
+                buffer.read_qname(&mut ptr)?;
+
+                Ok(DnsRecord::PTR {
+                    domain,
+                    host: ptr,
+                    ttl: TransientTtl(ttl),
+                })
+            }
             QueryType::SRV => {
                 let priority = buffer.read_u16()?;
                 let weight = buffer.read_u16()?;
@@ -402,6 +420,24 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                ttl: TransientTtl(ttl),
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
             DnsRecord::SRV {
                 ref domain,
                 priority,
@@ -506,6 +542,7 @@ impl DnsRecord {
             DnsRecord::AAAA { .. } => QueryType::AAAA,
             DnsRecord::NS { .. } => QueryType::NS,
             DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::PTR { .. } => QueryType::PTR,
             DnsRecord::SRV { .. } => QueryType::SRV,
             DnsRecord::MX { .. } => QueryType::MX,
             DnsRecord::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(qtype),
@@ -521,6 +558,7 @@ impl DnsRecord {
             | DnsRecord::AAAA { ref domain, .. }
             | DnsRecord::NS { ref domain, .. }
             | DnsRecord::CNAME { ref domain, .. }
+            | DnsRecord::PTR { ref domain, .. }
             | DnsRecord::SRV { ref domain, .. }
             | DnsRecord::MX { ref domain, .. }
             | DnsRecord::UNKNOWN { ref domain, .. }
@@ -548,6 +586,10 @@ impl DnsRecord {
                 ttl: TransientTtl(ttl),
                 ..
             }
+            | DnsRecord::PTR {
+                ttl: TransientTtl(ttl),
+                ..
+            }
             | DnsRecord::SRV {
                 ttl: TransientTtl(ttl),
                 ..
@@ -6,6 +6,8 @@ use crate::dns::protocol::{DnsPacket, DnsRecord, QueryType, ResultCode};
 use crate::dns::resolve::DnsResolver;
 use async_std::net::UdpSocket;
 use async_std::task::spawn;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
 
 macro_rules! return_or_report {
@@ -140,19 +142,35 @@ pub async fn execute_query(context: Arc<ServerContext>, request: &DnsPacket) ->
     packet
 }
 
+/// Runs the task answering a query, instead of `async_std::task::spawn`.
+pub type Spawner = Arc<dyn Fn(Pin<Box<dyn Future<Output = ()> + Send>>) + Send + Sync>;
+
 /// The UDP server
 ///
 /// Accepts DNS queries through UDP, and uses the `ServerContext` to determine
 /// how to service the request. Packets are read on a single thread, after which
 /// a new thread is spawned to service the request asynchronously.
+#[derive(Clone)]
 pub struct DnsUdpServer {
     context: Arc<ServerContext>,
+    spawner: Option<Spawner>,
 }
 
 impl DnsUdpServer {
     pub async fn new(listen: String, resolver: Box<dyn DnsResolver + Send + Sync>) -> DnsUdpServer {
         let context = Arc::new(ServerContext::new(listen, resolver).await);
-        DnsUdpServer { context }
+        DnsUdpServer {
+            context,
+            spawner: None,
+        }
+    }
+
+    /// Answer queries in tasks run by `spawner`.
+    pub fn with_spawner(self, spawner: Spawner) -> Self {
+        DnsUdpServer {
+            spawner: Some(spawner),
+            ..self
+        }
     }
 
     pub fn context(&self) -> Arc<ServerContext> {
@@ -181,7 +199,7 @@ impl DnsUdpServer {
             let context = self.context.clone();
             let socket_clone = socket.clone();
 
-            spawn(async move {
+            let task = async move {
                 // Parse it
                 let request = return_or_report!(
                     DnsPacket::from_buffer(&mut req_buffer),
@@ -212,7 +230,13 @@ impl DnsUdpServer {
                     socket_clone.send_to(data, src).await,
                     "Failed to send response packet"
                 );
-            });
+            };
+            match &self.spawner {
+                Some(spawner) => spawner(Box::pin(task)),
+                None => {
+                    spawn(task);
+                }
+            }
         }
     }
 }
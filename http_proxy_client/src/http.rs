@@ -17,7 +17,17 @@ impl HttpProxyTcpStream {
         username: Option<&str>,
         password: Option<&str>,
     ) -> Result<Self> {
-        let mut conn = TcpStream::connect(proxy_server).await?;
+        let conn = TcpStream::connect(proxy_server).await?;
+        HttpProxyTcpStream::connect_stream(conn, addr, username, password).await
+    }
+
+    /// Like `connect`, over `conn` already connected to the proxy server.
+    pub async fn connect_stream(
+        mut conn: TcpStream,
+        addr: Address,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<Self> {
         let authorization = match (username, password) {
             (Some(username), Some(password)) => {
                 base64::encode(format!("{}:{}", username, password))
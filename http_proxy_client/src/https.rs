@@ -23,8 +23,20 @@ impl HttpsProxyTcpStream {
         username: Option<&str>,
         password: Option<&str>,
     ) -> Result<Self> {
-        let connector = TlsConnector::default();
         let stream = TcpStream::connect(proxy_server).await?;
+        HttpsProxyTcpStream::connect_stream(stream, proxy_server_domain, addr, username, password)
+            .await
+    }
+
+    /// Like `connect`, over `stream` already connected to the proxy server.
+    pub async fn connect_stream(
+        stream: TcpStream,
+        proxy_server_domain: String,
+        addr: Address,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<Self> {
+        let connector = TlsConnector::default();
         let mut conn = connector.connect(proxy_server_domain, stream).await?;
         let authorization = match (username, password) {
             (Some(username), Some(password)) => {
@@ -0,0 +1,188 @@
+//! Domains advertising HTTP/3 through `Alt-Svc`, so their QUIC flows on UDP/443 can be
+//! routed like the TCP connection that advertised it, or blocked, see `Http3Policy`.
+//!
+//! Only responses seeker can read are learned from, i.e. plain HTTP relayed over port 80.
+use config::rule::Action;
+use config::Http3Policy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// `ma` when the header has none, per RFC 7838.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(86400);
+const CAPACITY: usize = 4096;
+
+struct Entry {
+    /// What the TCP connection carrying the advertisement was sent through.
+    tcp_action: Action,
+    expires: Instant,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdvertisedDomain {
+    pub domain: String,
+    pub tcp_action: String,
+    pub expires_in_secs: u64,
+}
+
+pub struct AltSvcCache {
+    policy: Http3Policy,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl AltSvcCache {
+    pub fn new(policy: Http3Policy) -> Self {
+        AltSvcCache {
+            policy,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether responses have to be inspected at all.
+    pub fn is_enabled(&self) -> bool {
+        self.policy != Http3Policy::Allow
+    }
+
+    /// Remember what the response starting with `head`, received for `domain` through
+    /// `tcp_action`, advertises.
+    pub fn learn(&self, domain: &str, tcp_action: Action, head: &[u8]) {
+        let advertised = match parse_alt_svc(head) {
+            Some(advertised) => advertised,
+            None => return,
+        };
+        let mut entries = self.entries.lock();
+        match advertised {
+            Some(max_age) => {
+                if entries.len() >= CAPACITY {
+                    let now = Instant::now();
+                    entries.retain(|_, e| e.expires > now);
+                }
+                let entry = Entry {
+                    tcp_action,
+                    expires: Instant::now() + max_age,
+                };
+                entries.insert(domain.to_string(), entry);
+            }
+            None => {
+                entries.remove(domain);
+            }
+        }
+    }
+
+    /// The action for a UDP/443 flow to `domain`, `None` leaves it to the rules.
+    pub fn udp_action(&self, domain: &str) -> Option<Action> {
+        let mut entries = self.entries.lock();
+        let entry = entries.get(domain)?;
+        if entry.expires <= Instant::now() {
+            entries.remove(domain);
+            return None;
+        }
+        match self.policy {
+            Http3Policy::Allow => None,
+            Http3Policy::FollowTcp => Some(entry.tcp_action),
+            Http3Policy::Block => Some(Action::Reject),
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<AdvertisedDomain> {
+        let now = Instant::now();
+        let mut domains: Vec<AdvertisedDomain> = self
+            .entries
+            .lock()
+            .iter()
+            .filter(|(_, e)| e.expires > now)
+            .map(|(domain, e)| AdvertisedDomain {
+                domain: domain.clone(),
+                tcp_action: e.tcp_action.to_string().to_uppercase(),
+                expires_in_secs: (e.expires - now).as_secs(),
+            })
+            .collect();
+        domains.sort_by(|a, b| a.domain.cmp(&b.domain));
+        domains
+    }
+}
+
+/// The `Alt-Svc` of an HTTP response head: `Some(Some(max_age))` when it advertises
+/// HTTP/3, `Some(None)` when it clears the alternatives, `None` without the header.
+fn parse_alt_svc(head: &[u8]) -> Option<Option<Duration>> {
+    let end = head
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .unwrap_or_else(|| head.len());
+    let head = String::from_utf8_lossy(&head[..end]);
+    if !head.starts_with("HTTP/1.") {
+        return None;
+    }
+    let mut found = None;
+    for line in head.lines().skip(1) {
+        let mut parts = line.splitn(2, ':');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name.trim(), value.trim()),
+            _ => continue,
+        };
+        if !name.eq_ignore_ascii_case("alt-svc") {
+            continue;
+        }
+        if value.eq_ignore_ascii_case("clear") {
+            return Some(None);
+        }
+        for alternative in value.split(',') {
+            let mut params = alternative.split(';').map(str::trim);
+            let protocol = params.next().unwrap_or_default();
+            if !protocol.starts_with("h3") {
+                continue;
+            }
+            let max_age = params
+                .filter_map(|p| p.strip_prefix("ma="))
+                .find_map(|ma| ma.trim_matches('"').parse().ok())
+                .map_or(DEFAULT_MAX_AGE, Duration::from_secs);
+            found = Some(Some(max_age));
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_alt_svc() {
+        let head = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nalt-svc: h3-29=\":443\"; ma=2592000, h3=\":443\"; ma=60\r\n\r\nbody";
+        assert_eq!(parse_alt_svc(head), Some(Some(Duration::from_secs(60))));
+        assert_eq!(
+            parse_alt_svc(b"HTTP/1.1 200 OK\r\nAlt-Svc: h3=\":443\"\r\n\r\n"),
+            Some(Some(DEFAULT_MAX_AGE))
+        );
+        assert_eq!(
+            parse_alt_svc(b"HTTP/1.1 200 OK\r\nAlt-Svc: clear\r\n\r\n"),
+            Some(None)
+        );
+        assert_eq!(
+            parse_alt_svc(b"HTTP/1.1 200 OK\r\nAlt-Svc: h2=\"alt.example.com:443\"\r\n\r\n"),
+            None
+        );
+        assert_eq!(parse_alt_svc(b"\x16\x03\x01\x02\x00"), None);
+    }
+
+    #[test]
+    fn test_udp_action() {
+        let head = b"HTTP/1.1 200 OK\r\nAlt-Svc: h3=\":443\"\r\n\r\n";
+        let cache = AltSvcCache::new(Http3Policy::FollowTcp);
+        cache.learn("example.com", Action::Proxy, head);
+        assert_eq!(cache.udp_action("example.com"), Some(Action::Proxy));
+        assert_eq!(cache.udp_action("example.org"), None);
+        cache.learn(
+            "example.com",
+            Action::Proxy,
+            b"HTTP/1.1 200 OK\r\nAlt-Svc: clear\r\n\r\n",
+        );
+        assert_eq!(cache.udp_action("example.com"), None);
+
+        let cache = AltSvcCache::new(Http3Policy::Block);
+        cache.learn("example.com", Action::Direct, head);
+        assert_eq!(cache.udp_action("example.com"), Some(Action::Reject));
+        assert_eq!(cache.snapshot()[0].tcp_action, "DIRECT");
+    }
+}
@@ -0,0 +1,650 @@
+//! A tiny HTTP/1.1 management API serving JSON.
+//!
+//! Besides seeker's own endpoints it answers the parts of Clash's external controller API
+//! dashboards use, see `clash_api`.
+
+use crate::alt_svc::AltSvcCache;
+use crate::clash_api;
+use crate::connections::{ConnectionTracker, RateMeter};
+use crate::features;
+use crate::interactive::{Decision, Prompter};
+use crate::introspect::{self, Introspect};
+use crate::metrics;
+use crate::reload::Reloader;
+use crate::rule_stats::RuleStats;
+use crate::server_chooser::ServerChooser;
+use async_std::io::Read;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task::{sleep, spawn_blocking};
+use crypto::digest::{self, Digest, DigestType};
+use dnsserver::query_log::QueryLog;
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info};
+
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+const MAX_BODY_SIZE: usize = 64 * 1024;
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Shortest `interval` in milliseconds of the `/connections` stream, snapshots are not free.
+const MIN_STREAM_INTERVAL: u64 = 100;
+
+/// Body of `PUT /profile`.
+#[derive(Debug, Deserialize)]
+struct SwitchProfile {
+    name: String,
+}
+
+/// Body and response of `/debug/ss-frames`.
+#[derive(Debug, Serialize, Deserialize)]
+struct FrameTrace {
+    enabled: bool,
+}
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of the header `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn is_websocket(&self) -> bool {
+        self.header("upgrade")
+            .map_or(false, |v| v.eq_ignore_ascii_case("websocket"))
+    }
+
+    /// Value of the query string parameter `name`, not percent-decoded.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.split('&').find_map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k == name => Some(v),
+                _ => None,
+            }
+        })
+    }
+}
+
+pub struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    allow_origin: Option<String>,
+}
+
+/// Headers letting pages of `origin` call the API from a browser, none without an origin.
+fn cors_headers(origin: Option<&str>) -> String {
+    match origin {
+        Some(origin) => format!(
+            "Access-Control-Allow-Origin: {}\r\nAccess-Control-Allow-Methods: GET, POST, PUT, PATCH, DELETE\r\nAccess-Control-Allow-Headers: Content-Type, Authorization\r\nVary: Origin\r\n",
+            origin
+        ),
+        None => String::new(),
+    }
+}
+
+impl Response {
+    pub fn json<T: Serialize>(value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response::bytes("application/json", body),
+            Err(e) => Response::error(500, &e.to_string()),
+        }
+    }
+
+    pub fn bytes(content_type: &'static str, body: Vec<u8>) -> Self {
+        Response {
+            status: 200,
+            content_type,
+            body,
+            allow_origin: None,
+        }
+    }
+
+    pub fn empty(status: u16) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: vec![],
+            allow_origin: None,
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: serde_json::json!({ "message": message })
+                .to_string()
+                .into_bytes(),
+            allow_origin: None,
+        }
+    }
+
+    /// Let the page of `origin`, one of `api_allowed_origins`, read the response.
+    pub fn allow_origin(mut self, origin: Option<&str>) -> Self {
+        self.allow_origin = origin.map(str::to_string);
+        self
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+            self.status,
+            self.reason(),
+            self.content_type,
+            self.body.len(),
+            cors_headers(self.allow_origin.as_deref())
+        )
+        .into_bytes();
+        buf.extend_from_slice(&self.body);
+        buf
+    }
+}
+
+pub struct ApiServer {
+    listen: String,
+    chooser: Arc<ServerChooser>,
+    reloader: Arc<Reloader>,
+    query_log: QueryLog,
+    prompter: Arc<Prompter>,
+    connections: Arc<ConnectionTracker>,
+    alt_svc: Arc<AltSvcCache>,
+    rule_stats: Arc<RuleStats>,
+    introspect: Introspect,
+    /// Clash's `secret`, required from every request when set.
+    secret: Option<String>,
+    allowed_origins: Vec<String>,
+}
+
+impl ApiServer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        listen: String,
+        chooser: Arc<ServerChooser>,
+        reloader: Arc<Reloader>,
+        query_log: QueryLog,
+        prompter: Arc<Prompter>,
+        connections: Arc<ConnectionTracker>,
+        alt_svc: Arc<AltSvcCache>,
+        rule_stats: Arc<RuleStats>,
+        introspect: Introspect,
+        secret: Option<String>,
+        allowed_origins: Vec<String>,
+    ) -> Self {
+        ApiServer {
+            listen,
+            chooser,
+            reloader,
+            query_log,
+            prompter,
+            connections,
+            alt_svc,
+            rule_stats,
+            introspect,
+            secret,
+            allowed_origins,
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let listener = TcpListener::bind(&self.listen).await?;
+        info!(listen = %self.listen, "Management API started");
+        let server = self;
+        let mut incoming = listener.incoming();
+        while let Some(Ok(conn)) = incoming.next().await {
+            let server = server.clone();
+            introspect::spawn("api_request", async move {
+                if let Err(e) = server.handle(conn).await {
+                    error!(?e, "api request error");
+                }
+            });
+        }
+        Ok(())
+    }
+
+    async fn handle(&self, mut conn: TcpStream) -> Result<()> {
+        let req = match read_request(&mut conn).await {
+            Ok(req) => req,
+            Err(e) => {
+                return conn
+                    .write_all(&Response::error(400, &e.to_string()).to_bytes())
+                    .await
+            }
+        };
+        // Browsers send simple requests and open websockets without asking the API first,
+        // so pages of other origins are refused outright.
+        let origin = match req.header("origin") {
+            Some(origin) if !self.allowed_origins.iter().any(|o| o == origin) => {
+                let response = Response::error(403, "origin not allowed");
+                return conn.write_all(&response.to_bytes()).await;
+            }
+            origin => origin,
+        };
+        let response = match (req.method.as_str(), req.path.as_str()) {
+            // The CORS preflight of an allowed dashboard, which carries no credentials.
+            ("OPTIONS", _) => Response::empty(204),
+            _ if !self.authorized(&req) => Response::error(401, "Unauthorized"),
+            ("GET", "/traffic") => return self.stream_traffic(conn, &req, origin).await,
+            ("GET", "/connections") if req.is_websocket() => {
+                return self.stream_connections(conn, &req, origin).await
+            }
+            // Loading the config and applying it blocks.
+            ("POST", "/config/reload") => {
+                let reloader = self.reloader.clone();
+                let status = spawn_blocking(move || reloader.reload()).await;
+                Response::json(&status)
+            }
+            ("PUT", "/profile") => match serde_json::from_slice::<SwitchProfile>(&req.body) {
+                Ok(switch) => {
+                    let reloader = self.reloader.clone();
+                    let status =
+                        spawn_blocking(move || reloader.switch_profile(&switch.name)).await;
+                    Response::json(&status)
+                }
+                Err(e) => Response::error(400, &e.to_string()),
+            },
+            // Clash's reload of the config file.
+            ("PUT", "/configs") => {
+                match clash_api::check_load_config(&req.body, self.reloader.path()) {
+                    Ok(()) => {
+                        let reloader = self.reloader.clone();
+                        let status = spawn_blocking(move || reloader.reload()).await;
+                        match status.error {
+                            None => Response::empty(204),
+                            Some(e) => Response::error(400, &e),
+                        }
+                    }
+                    Err(e) => Response::error(400, &e),
+                }
+            }
+            ("POST", "/prompts") => self.resolve_prompt(&req.body).await,
+            ("GET", path) if clash_api::delay_target(path).is_some() => {
+                let name = clash_api::delay_target(path).unwrap_or_default();
+                self.proxy_delay(&clash_api::percent_decode(name), &req)
+                    .await
+            }
+            _ => self.route(&req),
+        };
+        conn.write_all(&response.allow_origin(origin).to_bytes())
+            .await
+    }
+
+    /// Whether `req` carries the `secret`, as a bearer token or, for websockets which
+    /// browsers open without custom headers, as the `token` query parameter.
+    fn authorized(&self, req: &Request) -> bool {
+        let secret = match &self.secret {
+            Some(secret) => secret.as_str(),
+            None => return true,
+        };
+        let bearer = req
+            .header("authorization")
+            .and_then(|v| v.strip_prefix("Bearer "));
+        let token = req.query_param("token").map(clash_api::percent_decode);
+        let matches = |given: &str| constant_time_eq(given.as_bytes(), secret.as_bytes());
+        bearer.map_or(false, matches) || token.as_deref().map_or(false, matches)
+    }
+
+    /// Clash's `/traffic`: the rates of the last second, every second, until the client
+    /// goes away.
+    async fn stream_traffic(
+        &self,
+        conn: TcpStream,
+        req: &Request,
+        origin: Option<&str>,
+    ) -> Result<()> {
+        let mut stream = EventStream::start(conn, req, origin).await?;
+        let mut meter = RateMeter::new(&self.connections);
+        loop {
+            sleep(Duration::from_secs(1)).await;
+            let rate = meter.sample(&self.connections, 1);
+            stream.send(&serde_json::to_vec(&rate)?).await?;
+        }
+    }
+
+    /// Clash's websocket `/connections`: a snapshot every `interval` milliseconds, at least
+    /// `MIN_STREAM_INTERVAL`.
+    async fn stream_connections(
+        &self,
+        conn: TcpStream,
+        req: &Request,
+        origin: Option<&str>,
+    ) -> Result<()> {
+        let interval = req
+            .query_param("interval")
+            .and_then(|i| i.parse().ok())
+            .unwrap_or(1000)
+            .max(MIN_STREAM_INTERVAL);
+        let mut stream = EventStream::start(conn, req, origin).await?;
+        loop {
+            let snapshot = serde_json::to_vec(&self.connections.snapshot())?;
+            stream.send(&snapshot).await?;
+            sleep(Duration::from_millis(interval)).await;
+        }
+    }
+
+    /// Clash's `/proxies/:name/delay`: the time to fetch the `ping_urls` through the server
+    /// `name`, within the `timeout` in milliseconds. The `url` dashboards pass is not used.
+    async fn proxy_delay(&self, name: &str, req: &Request) -> Response {
+        let server = match self.chooser.servers().iter().find(|s| s.name() == name) {
+            Some(server) => server.clone(),
+            None => return Response::error(404, "resource not found"),
+        };
+        let ping_timeout = req
+            .query_param("timeout")
+            .and_then(|t| t.parse().ok())
+            .map_or(Duration::from_secs(5), Duration::from_millis);
+        match self.chooser.measure_latency(&server, ping_timeout).await {
+            Ok(latency) => Response::json(&serde_json::json!({
+                "delay": latency.as_millis() as u64
+            })),
+            Err(e) if e.kind() == ErrorKind::TimedOut => Response::error(408, "Timeout"),
+            Err(e) => Response::error(503, &e.to_string()),
+        }
+    }
+
+    /// Clash's `/proxies/:name`.
+    fn proxy(&self, name: &str) -> Response {
+        let mut proxies = clash_api::proxies(&self.reloader.running(), &self.chooser);
+        match proxies.proxies.remove(&clash_api::percent_decode(name)) {
+            Some(proxy) => Response::json(&proxy),
+            None => Response::error(404, "resource not found"),
+        }
+    }
+
+    /// Clash's `DELETE /connections/:id`.
+    fn close_connection(&self, id: &str) -> Response {
+        match id.parse() {
+            Ok(id) if self.connections.close(id) => Response::empty(204),
+            _ => Response::error(404, "connection not found"),
+        }
+    }
+
+    fn route(&self, req: &Request) -> Response {
+        match (req.method.as_str(), req.path.as_str()) {
+            ("GET", "/") => Response::json(&serde_json::json!({ "hello": "clash" })),
+            ("GET", "/proxies") => {
+                Response::json(&clash_api::proxies(&self.reloader.running(), &self.chooser))
+            }
+            ("GET", path) if path.starts_with("/proxies/") => {
+                self.proxy(&path["/proxies/".len()..])
+            }
+            ("GET", "/rules") => Response::json(&clash_api::rules(&self.reloader.running())),
+            ("GET", "/configs") => Response::json(&clash_api::configs(&self.reloader.running())),
+            ("DELETE", "/connections") => {
+                self.connections.close_all();
+                Response::empty(204)
+            }
+            ("DELETE", path) if path.starts_with("/connections/") => {
+                self.close_connection(&path["/connections/".len()..])
+            }
+            ("GET", "/quarantine") => Response::json(&self.chooser.quarantined_servers()),
+            ("GET", "/metrics") => Response::json(&metrics::snapshot()),
+            ("GET", "/config/diff") => Response::json(&self.reloader.diff()),
+            ("GET", "/config/reload") => Response::json(&self.reloader.status()),
+            ("GET", "/profiles") => Response::json(&self.reloader.profiles()),
+            ("GET", "/version") => Response::json(&features::build_info()),
+            ("GET", "/dns/queries") => {
+                Response::json(&self.query_log.entries(req.query_param("name")))
+            }
+            ("GET", "/prompts") => Response::json(&self.prompter.pending()),
+            ("GET", "/prompts/rules") => Response::json(&self.prompter.rules()),
+            ("GET", "/connections") => Response::json(&self.connections.snapshot()),
+            ("GET", "/alt-svc") => Response::json(&self.alt_svc.snapshot()),
+            ("GET", "/groups") => Response::json(&self.chooser.groups()),
+            ("GET", "/fail-closed") => Response::json(&self.chooser.fail_closed()),
+            ("GET", "/rules/hits") => Response::json(&self.rule_stats.hits()),
+            ("GET", "/rules/decisions") => {
+                Response::json(&self.rule_stats.decisions(req.query_param("host")))
+            }
+            ("GET", "/debug/ss-frames") => Response::json(&FrameTrace {
+                enabled: ssclient::frame_trace_enabled(),
+            }),
+            ("PUT", "/debug/ss-frames") => set_frame_trace(&req.body),
+            ("GET", "/debug/runtime") => Response::json(&self.introspect.snapshot()),
+            (_, "/quarantine")
+            | (_, "/metrics")
+            | (_, "/config/diff")
+            | (_, "/config/reload")
+            | (_, "/profiles")
+            | (_, "/profile")
+            | (_, "/version")
+            | (_, "/dns/queries")
+            | (_, "/prompts")
+            | (_, "/prompts/rules")
+            | (_, "/traffic")
+            | (_, "/connections")
+            | (_, "/alt-svc")
+            | (_, "/groups")
+            | (_, "/fail-closed")
+            | (_, "/rules/hits")
+            | (_, "/rules/decisions")
+            | (_, "/debug/ss-frames")
+            | (_, "/debug/runtime")
+            | (_, "/")
+            | (_, "/proxies")
+            | (_, "/rules")
+            | (_, "/configs") => Response::error(405, "method not allowed"),
+            _ => Response::error(404, "not found"),
+        }
+    }
+
+    async fn resolve_prompt(&self, body: &[u8]) -> Response {
+        let decision: Decision = match serde_json::from_slice(body) {
+            Ok(d) => d,
+            Err(e) => return Response::error(400, &e.to_string()),
+        };
+        match self.prompter.resolve(&decision).await {
+            Ok(true) => Response::empty(204),
+            Ok(false) => Response::error(404, "prompt not found or expired"),
+            Err(e) => Response::error(400, &e.to_string()),
+        }
+    }
+}
+
+/// Log the AEAD frames of shadowsocks connections or stop, see `ssclient::set_frame_trace`.
+fn set_frame_trace(body: &[u8]) -> Response {
+    match serde_json::from_slice::<FrameTrace>(body) {
+        Ok(trace) => {
+            info!("shadowsocks frame trace enabled: {}", trace.enabled);
+            ssclient::set_frame_trace(trace.enabled);
+            Response::json(&trace)
+        }
+        Err(e) => Response::error(400, &e.to_string()),
+    }
+}
+
+/// JSON messages pushed to a client, as websocket text frames when it asked for an upgrade
+/// and as chunks of a chunked response otherwise.
+struct EventStream {
+    conn: TcpStream,
+    websocket: bool,
+}
+
+impl EventStream {
+    async fn start(mut conn: TcpStream, req: &Request, origin: Option<&str>) -> Result<Self> {
+        let websocket = req.is_websocket();
+        let head = if websocket {
+            let key = req
+                .header("sec-websocket-key")
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing websocket key"))?;
+            format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                websocket_accept(key)
+            )
+        } else {
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n{}\r\n",
+                cors_headers(origin)
+            )
+        };
+        conn.write_all(head.as_bytes()).await?;
+        debug!(path = %req.path, websocket, "api stream started");
+        Ok(EventStream { conn, websocket })
+    }
+
+    async fn send(&mut self, message: &[u8]) -> Result<()> {
+        let buf = if self.websocket {
+            websocket_text_frame(message)
+        } else {
+            let mut buf = format!("{:x}\r\n", message.len() + 1).into_bytes();
+            buf.extend_from_slice(message);
+            buf.extend_from_slice(b"\n\r\n");
+            buf
+        };
+        self.conn.write_all(&buf).await
+    }
+}
+
+/// Compares every byte, so how long a wrong secret takes to refuse tells nothing about it.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn websocket_accept(key: &str) -> String {
+    let mut sha1 = digest::with_type(DigestType::Sha1);
+    sha1.update(key.trim().as_bytes());
+    sha1.update(WEBSOCKET_GUID.as_bytes());
+    let mut hash = Vec::with_capacity(20);
+    sha1.digest(&mut hash);
+    base64::encode(&hash)
+}
+
+/// An unmasked, unfragmented text frame, as sent by servers.
+fn websocket_text_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x81];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= 0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// A request for `path` of the management API on `api`, authorized with its `secret`.
+pub fn client_request(method: &str, api: &str, path: &str, secret: Option<&str>) -> ureq::Request {
+    let mut request = ureq::request(method, &format!("http://{}{}", api, path));
+    if let Some(secret) = secret {
+        request.set("Authorization", &format!("Bearer {}", secret));
+    }
+    request
+}
+
+pub async fn read_request<S: Read + Unpin>(conn: &mut S) -> Result<Request> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+    let header_end = loop {
+        let size = conn.read(&mut chunk).await?;
+        if size == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..size]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_HEADER_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "header too large"));
+        }
+    };
+
+    let header = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut request_line = header.lines().next().unwrap_or_default().split_whitespace();
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(m), Some(t)) => (m.to_string(), t.to_string()),
+        _ => return Err(Error::new(ErrorKind::InvalidData, "invalid request line")),
+    };
+    let mut target = target.splitn(2, '?');
+    let path = target.next().unwrap_or_default().to_string();
+    let query = target.next().unwrap_or_default().to_string();
+
+    let headers: Vec<(String, String)> = header
+        .lines()
+        .skip(1)
+        .filter_map(|l| {
+            let mut kv = l.splitn(2, ':');
+            Some((kv.next()?.to_string(), kv.next()?.trim().to_string()))
+        })
+        .collect();
+    let content_length = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    if content_length > MAX_BODY_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "body too large"));
+    }
+    let mut body = buf.split_off(header_end);
+    body.truncate(content_length);
+    if body.len() < content_length {
+        let read = body.len();
+        body.resize(content_length, 0);
+        conn.read_exact(&mut body[read..]).await?;
+    }
+    Ok(Request {
+        method,
+        path,
+        query,
+        headers,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_websocket_handshake() {
+        // The example of RFC 6455.
+        assert_eq!(
+            websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(websocket_text_frame(b"{}"), b"\x81\x02{}");
+        assert_eq!(
+            &websocket_text_frame(&[b'a'; 200])[..4],
+            b"\x81\x7e\x00\xc8"
+        );
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
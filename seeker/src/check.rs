@@ -0,0 +1,386 @@
+//! `seeker check`: load the config as seeker does at start and report what is wrong with it,
+//! with the line of the server or rule at fault.
+use config::rule::Rule;
+use config::{share_uri, Config, Format, ServerConfig};
+use serde_yaml::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    /// 1-based, `None` when the problem is not about one entry.
+    pub line: Option<usize>,
+    pub message: String,
+    /// seeker starts anyway.
+    pub warning: bool,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.warning {
+            write!(f, "warning: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+struct Problems {
+    /// Where the entries of the top-level lists start, by key.
+    entry_lines: HashMap<String, Vec<usize>>,
+    found: Vec<Problem>,
+}
+
+impl Problems {
+    /// The line of entry `index` of the top-level list `key`.
+    fn line(&self, key: &str, index: usize) -> Option<usize> {
+        self.entry_lines.get(key)?.get(index).copied()
+    }
+
+    fn push(&mut self, line: Option<usize>, message: String, warning: bool) {
+        self.found.push(Problem {
+            line,
+            message,
+            warning,
+        });
+    }
+}
+
+enum Container {
+    /// With the key of the value being parsed, and whether a key comes next.
+    Mapping {
+        key: Option<String>,
+        at_key: bool,
+    },
+    Sequence,
+}
+
+/// Collects the lines the YAML parser marks the entries of top-level lists with.
+#[derive(Default)]
+struct EntryLines {
+    stack: Vec<Container>,
+    lines: HashMap<String, Vec<usize>>,
+}
+
+impl EntryLines {
+    /// A node at the top of `stack` was parsed.
+    fn node_done(&mut self) {
+        if let Some(Container::Mapping { at_key, .. }) = self.stack.last_mut() {
+            *at_key = !*at_key;
+        }
+    }
+}
+
+impl MarkedEventReceiver for EntryLines {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        let starts_node = matches!(
+            event,
+            Event::Scalar(..)
+                | Event::Alias(..)
+                | Event::MappingStart(..)
+                | Event::SequenceStart(..)
+        );
+        if starts_node {
+            if let [Container::Mapping { key: Some(key), .. }, Container::Sequence] =
+                self.stack.as_slice()
+            {
+                self.lines.entry(key.clone()).or_default().push(mark.line());
+            }
+            if let Some(Container::Mapping { key, at_key: true }) = self.stack.last_mut() {
+                *key = match &event {
+                    Event::Scalar(value, ..) => Some(value.clone()),
+                    _ => None,
+                };
+            }
+        }
+        match event {
+            Event::Scalar(..) | Event::Alias(..) => self.node_done(),
+            Event::MappingStart(..) => self.stack.push(Container::Mapping {
+                key: None,
+                at_key: true,
+            }),
+            Event::SequenceStart(..) => self.stack.push(Container::Sequence),
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+                self.node_done();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Lines, from 1, where the entries of the top-level lists of `text` start, by key. JSON
+/// parses as YAML too. Empty when `text` does not parse.
+fn entry_lines(text: &str) -> HashMap<String, Vec<usize>> {
+    let mut receiver = EntryLines::default();
+    match Parser::new(text.chars()).load(&mut receiver, false) {
+        Ok(()) => receiver.lines,
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// Whether `addr` names a port, seeker uses port 80 otherwise.
+fn has_port(addr: &str) -> bool {
+    if addr.parse::<SocketAddr>().is_ok() {
+        return true;
+    }
+    match addr.rfind(':') {
+        Some(pos) => !addr[..pos].contains(':') && addr[pos + 1..].parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+fn check_servers(doc: &Value, problems: &mut Problems) {
+    let servers = match doc.get("servers").and_then(Value::as_sequence) {
+        Some(servers) => servers,
+        None => return,
+    };
+    let mut names = vec![];
+    for (i, value) in servers.iter().enumerate() {
+        let line = problems.line("servers", i);
+        let server = match value {
+            Value::String(uri) => share_uri::parse(uri),
+            value => {
+                let name = value.get("name").and_then(Value::as_str).unwrap_or("");
+                let server = serde_yaml::from_value::<ServerConfig>(value.clone())
+                    .map_err(|e| format!("server {}: {}", name, e));
+                if let Some(addr) = value.get("addr").and_then(Value::as_str) {
+                    if server.is_ok() && !has_port(addr) {
+                        let message = format!("server {}: addr has no port, 80 is used.", name);
+                        problems.push(line, message, true);
+                    }
+                }
+                server
+            }
+        };
+        let server = match server {
+            Ok(server) => server,
+            Err(e) => {
+                problems.push(line, e, false);
+                continue;
+            }
+        };
+        if let Err(e) = server.validate() {
+            problems.push(line, e, false);
+        }
+        if names.iter().any(|n| n == server.name()) {
+            let message = format!("server {} is defined twice.", server.name());
+            problems.push(line, message, false);
+        }
+        names.push(server.name().to_string());
+    }
+}
+
+fn check_rules(doc: &Value, problems: &mut Problems) {
+    let rules = match doc.get("rules").and_then(Value::as_sequence) {
+        Some(rules) => rules,
+        None => return,
+    };
+    let groups: HashSet<&str> = doc
+        .get("proxy_groups")
+        .and_then(Value::as_sequence)
+        .map(|groups| {
+            groups
+                .iter()
+                .filter_map(|g| g.get("name").and_then(Value::as_str))
+                .collect()
+        })
+        .unwrap_or_default();
+    for (i, value) in rules.iter().enumerate() {
+        let text = match value.as_str().or_else(|| value.get("rule")?.as_str()) {
+            Some(text) => text,
+            None => continue,
+        };
+        let line = problems.line("rules", i);
+        match Rule::from_str(text) {
+            Err(()) => problems.push(line, format!("invalid rule {}", text), false),
+            Ok(rule) => match &rule.group {
+                Some(group) if !groups.contains(group.as_str()) => {
+                    let message = format!(
+                        "rule {} targets {}, neither an action nor a proxy group.",
+                        text, group
+                    );
+                    problems.push(line, message, false);
+                }
+                _ => {}
+            },
+        }
+    }
+}
+
+/// Everything wrong with the config `text`, with includes relative to `dir`. Entries of the
+/// config itself are checked one by one first, the rest of the checks seeker does at start
+/// only run without errors in them.
+pub fn check(text: &str, format: Format, dir: &Path) -> Vec<Problem> {
+    // Expanding keeps the lines where they were.
+    let expanded = match config::env_vars::expand(text) {
+        Ok(expanded) => expanded,
+        Err(e) => {
+            return vec![Problem {
+                line: None,
+                message: e,
+                warning: false,
+            }]
+        }
+    };
+    let mut problems = Problems {
+        entry_lines: entry_lines(&expanded),
+        found: vec![],
+    };
+    let doc: Result<Value, (Option<usize>, String)> = match format {
+        Format::Yaml => serde_yaml::from_str(&expanded)
+            .map_err(|e| (e.location().map(|l| l.line()), e.to_string())),
+        Format::Json => {
+            serde_json::from_str(&expanded).map_err(|e| (Some(e.line()), e.to_string()))
+        }
+    };
+    let doc = match doc {
+        Ok(doc) => doc,
+        Err((line, message)) => {
+            problems.push(line, message, false);
+            return problems.found;
+        }
+    };
+    check_servers(&doc, &mut problems);
+    check_rules(&doc, &mut problems);
+    if problems.found.iter().all(|p| p.warning) {
+        if let Err(e) = Config::from_text_with_profile(text, format, dir, None) {
+            problems.push(None, e.to_string(), false);
+        }
+    }
+    problems.found
+}
+
+/// `seeker check`: print the problems of the config at `path`, failing on errors.
+pub fn run_check(path: &str) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(path)?;
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new("."));
+    let problems = check(&text, Format::of_path(path), dir);
+    for problem in &problems {
+        match problem.line {
+            Some(line) => println!("{}:{}: {}", path, line, problem),
+            None => println!("{}: {}", path, problem),
+        }
+    }
+    let errors = problems.iter().filter(|p| !p.warning).count();
+    if errors > 0 {
+        anyhow::bail!("{} error(s) in {}", errors, path);
+    }
+    println!("{} is valid", path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config::config_text;
+
+    /// The required settings take the first 8 lines.
+    const CONFIG: &str = r#"servers:
+  - name: server1
+    addr: 127.0.0.1:1080
+    protocol: Socks5
+  - name: server1
+    addr: ss.example.com
+    protocol: Shadowsocks
+    method: chacha20-ietf
+  - 'trojan://pass@example.com:443'
+rules:
+  - 'DOMAIN-SUFIX,google.com,PROXY'
+  - 'DOMAIN-SUFFIX,netflix.com,Streaming'
+  - 'MATCH,DIRECT'
+"#;
+
+    #[test]
+    fn test_check() {
+        let problems = check(&config_text(CONFIG), Format::Yaml, Path::new("."));
+        let lines: Vec<(Option<usize>, bool)> =
+            problems.iter().map(|p| (p.line, p.warning)).collect();
+        assert_eq!(
+            lines,
+            vec![
+                (Some(13), true),
+                (Some(13), false),
+                (Some(13), false),
+                (Some(17), false),
+                (Some(19), false),
+                (Some(20), false),
+            ]
+        );
+        assert_eq!(
+            problems[1].message,
+            "server server1: shadowsocks needs a password."
+        );
+        assert_eq!(problems[2].message, "server server1 is defined twice.");
+
+        let problems = check("servers: [", Format::Yaml, Path::new("."));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].line.is_some());
+    }
+
+    /// A valid config with `settings` added.
+    fn with_settings(settings: &str) -> String {
+        config_text(&format!(
+            "servers:\n  - {{name: hk, addr: 127.0.0.1:1080, protocol: Socks5}}\n\
+             rules: ['MATCH,DIRECT']\n{}",
+            settings
+        ))
+    }
+
+    /// The messages of the problems of `text`.
+    fn messages(text: &str) -> Vec<String> {
+        check(text, Format::Yaml, Path::new("."))
+            .into_iter()
+            .map(|p| p.message)
+            .collect()
+    }
+
+    #[test]
+    fn test_check_settings() {
+        assert!(messages(&with_settings("conn_hook: 127.0.0.1:9000\n")).is_empty());
+        let problems = messages(&with_settings("conn_hook: localhost:9000\n"));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("conn_hook localhost:9000 is not ip:port or unix:/path."));
+
+        let text = with_settings("").replace("tun_cidr: 11.0.0.0/16", "tun_cidr: 11.0.0.0");
+        let problems = messages(&text);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("invalid cidr 11.0.0.0"));
+        let problems = messages(&with_settings("sniff_timeout: fast\n"));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("invalid value: fast, expected 10s or 10ms"));
+    }
+
+    #[test]
+    fn test_entry_lines() {
+        // The broken rule is also in a comment and the name of the server in a group.
+        let text = "proxy_groups:\n  - name: g\n    proxies: [hk]\n\
+                    # - 'DOMAIN-SUFIX,google.com,PROXY'\n\
+                    servers:\n  - {name: hk, addr: 'ss.example.com:8388', protocol: Shadowsocks}\n\
+                    rules:\n  - 'MATCH,DIRECT'\n  - rule: 'DOMAIN-SUFIX,google.com,PROXY'\n";
+        let lines = entry_lines(text);
+        assert_eq!(lines["proxy_groups"], vec![2]);
+        assert_eq!(lines["servers"], vec![6]);
+        assert_eq!(lines["rules"], vec![8, 9]);
+        assert!(!lines.contains_key("proxies"));
+        let problems = check(&config_text(text), Format::Yaml, Path::new("."));
+        let lines: Vec<Option<usize>> = problems.iter().map(|p| p.line).collect();
+        assert_eq!(lines, vec![Some(14), Some(17)]);
+
+        let json = r#"{"rules": ["MATCH,DIRECT",
+  "DOMAIN-SUFIX,google.com,PROXY"]}"#;
+        assert_eq!(entry_lines(json)["rules"], vec![1, 2]);
+    }
+
+    #[test]
+    fn test_has_port() {
+        assert!(has_port("example.com:443"));
+        assert!(has_port("[2001:db8::1]:443"));
+        assert!(!has_port("example.com"));
+        assert!(!has_port("2001:db8::1"));
+    }
+}
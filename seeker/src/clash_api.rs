@@ -0,0 +1,348 @@
+//! The JSON shapes of Clash's external controller API for `/proxies`, `/rules` and
+//! `/configs`, so dashboards like yacd manage seeker through the management API. `/traffic`
+//! and `/connections` are in `connections.rs`.
+//!
+//! Servers are proxies, and `PROXY` and the proxy groups are groups of the Clash type of
+//! their strategy. None of them is a `Selector`, dashboards show the server in use but
+//! can't switch it. `GLOBAL` lists every target like in Clash, with `final` selected.
+use crate::connections::{rfc3339, rule_type_payload};
+use crate::server_chooser::ServerChooser;
+use config::rule::{Action, Rule};
+use config::{Config, GroupStrategy, ServerProtocol};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::iter::once;
+
+#[derive(Debug, Serialize)]
+pub struct Delay {
+    pub time: String,
+    /// Milliseconds.
+    pub delay: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Proxy {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// The member of a group in use.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub now: Option<String>,
+    /// Members of a group.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub all: Option<Vec<String>>,
+    /// The latency of the last ping, empty when it failed.
+    pub history: Vec<Delay>,
+    pub udp: bool,
+}
+
+/// Response of `GET /proxies`.
+#[derive(Debug, Serialize)]
+pub struct Proxies {
+    pub proxies: BTreeMap<String, Proxy>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClashRule {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub payload: String,
+    pub proxy: String,
+}
+
+/// Response of `GET /rules`.
+#[derive(Debug, Serialize)]
+pub struct Rules {
+    pub rules: Vec<ClashRule>,
+}
+
+/// Response of `GET /configs`, the ports are those of the tproxy and redirect listeners.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Configs {
+    pub port: u16,
+    pub socks_port: u16,
+    pub redir_port: u16,
+    pub tproxy_port: u16,
+    pub mixed_port: u16,
+    pub allow_lan: bool,
+    pub mode: &'static str,
+    pub log_level: &'static str,
+}
+
+/// Body of `PUT /configs`.
+#[derive(Debug, Default, Deserialize)]
+struct LoadConfig {
+    #[serde(default)]
+    path: String,
+    #[serde(default)]
+    payload: String,
+}
+
+fn server_kind(protocol: ServerProtocol) -> &'static str {
+    match protocol {
+        ServerProtocol::Shadowsocks => "Shadowsocks",
+        ServerProtocol::Socks5 => "Socks5",
+        ServerProtocol::Http | ServerProtocol::Https => "Http",
+    }
+}
+
+fn group_kind(strategy: GroupStrategy) -> &'static str {
+    match strategy {
+        GroupStrategy::Latency => "URLTest",
+        GroupStrategy::Fallback => "Fallback",
+        GroupStrategy::RoundRobin => "LoadBalance",
+    }
+}
+
+fn group(name: &str, kind: &'static str, now: Option<String>, all: Vec<String>) -> Proxy {
+    Proxy {
+        name: name.to_string(),
+        kind,
+        now,
+        all: Some(all),
+        history: vec![],
+        udp: true,
+    }
+}
+
+pub fn proxies(config: &Config, chooser: &ServerChooser) -> Proxies {
+    let servers = chooser.servers();
+    let mut proxies = BTreeMap::new();
+    for server in servers.iter() {
+        let history = chooser
+            .latency(server.name())
+            .map(|(time, latency)| Delay {
+                time: rfc3339(time),
+                delay: latency.as_millis() as u64,
+            })
+            .into_iter()
+            .collect();
+        let proxy = Proxy {
+            name: server.name().to_string(),
+            kind: server_kind(server.protocol()),
+            now: None,
+            all: None,
+            history,
+            // UDP is relayed through shadowsocks and socks5 servers only.
+            udp: matches!(
+                server.protocol(),
+                ServerProtocol::Shadowsocks | ServerProtocol::Socks5
+            ),
+        };
+        proxies.insert(proxy.name.clone(), proxy);
+    }
+    for (name, kind) in &[("DIRECT", "Direct"), ("REJECT", "Reject")] {
+        let proxy = Proxy {
+            name: name.to_string(),
+            kind: *kind,
+            now: None,
+            all: None,
+            history: vec![],
+            udp: true,
+        };
+        proxies.insert(proxy.name.clone(), proxy);
+    }
+
+    let names: Vec<String> = servers.iter().map(|s| s.name().to_string()).collect();
+    let current = chooser.current_server().map(|s| s.name().to_string());
+    proxies.insert(
+        "PROXY".to_string(),
+        group("PROXY", "URLTest", current, names.clone()),
+    );
+    let snapshots = chooser.groups();
+    for proxy_group in &config.proxy_groups {
+        // A group without a usable server passes connections on to its `final`.
+        let now = snapshots
+            .iter()
+            .find(|s| s.name == proxy_group.name)
+            .and_then(|s| s.current.clone())
+            .or_else(|| proxy_group.final_target.clone());
+        proxies.insert(
+            proxy_group.name.clone(),
+            group(
+                &proxy_group.name,
+                group_kind(proxy_group.strategy),
+                now,
+                proxy_group.servers.clone(),
+            ),
+        );
+    }
+
+    let all = once("PROXY".to_string())
+        .chain(config.proxy_groups.iter().map(|g| g.name.clone()))
+        .chain(names)
+        .chain(vec!["DIRECT".to_string(), "REJECT".to_string()])
+        .collect();
+    proxies.insert(
+        "GLOBAL".to_string(),
+        group("GLOBAL", "Selector", Some(config.final_target.clone()), all),
+    );
+    Proxies { proxies }
+}
+
+/// The target of `rule`: its group, or the action in capitals like `DIRECT`.
+fn rule_target(rule: &Rule) -> String {
+    match (rule.action, &rule.group) {
+        (Action::Proxy, Some(group)) => group.clone(),
+        (action, _) => action.to_string().to_uppercase(),
+    }
+}
+
+/// The rules and the final rule, in the order they are matched.
+pub fn rules(config: &Config) -> Rules {
+    let rules = config
+        .rules
+        .rules()
+        .iter()
+        .chain(once(config.rules.final_rule()))
+        .map(|rule| {
+            let (kind, payload) = rule_type_payload(rule);
+            ClashRule {
+                kind,
+                payload,
+                proxy: rule_target(rule),
+            }
+        })
+        .collect();
+    Rules { rules }
+}
+
+fn port(listen: &Option<String>) -> u16 {
+    listen
+        .as_deref()
+        .and_then(|l| l.rsplit(':').next())
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(0)
+}
+
+pub fn configs(config: &Config) -> Configs {
+    Configs {
+        port: 0,
+        socks_port: 0,
+        redir_port: port(&config.redirect_listen),
+        tproxy_port: port(&config.tproxy_listen),
+        mixed_port: 0,
+        allow_lan: false,
+        mode: "rule",
+        log_level: "info",
+    }
+}
+
+/// Check that the body of a `PUT /configs` asks for what seeker does: reload its own
+/// config file. Configs sent as `payload` are not loaded.
+pub fn check_load_config(body: &[u8], config_path: Option<&str>) -> Result<(), String> {
+    let load: LoadConfig = if body.is_empty() {
+        LoadConfig::default()
+    } else {
+        serde_json::from_slice(body).map_err(|e| e.to_string())?
+    };
+    if !load.payload.is_empty() {
+        return Err("seeker reloads its config file, it does not load payloads".to_string());
+    }
+    match config_path {
+        Some(path) if !load.path.is_empty() && load.path != path => {
+            Err(format!("seeker only reloads its config file {}", path))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The name in a `/proxies/:name/delay` path.
+pub fn delay_target(path: &str) -> Option<&str> {
+    path.strip_prefix("/proxies/")?.strip_suffix("/delay")
+}
+
+/// `s` with its `%XX` escapes decoded, dashboards escape names in paths.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| bytes[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config::config;
+
+    const CONFIG: &str = r#"redirect_listen: 0.0.0.0:7892
+servers:
+  - name: hk
+    addr: 127.0.0.1:1080
+    protocol: Socks5
+proxy_groups:
+  - name: Streaming
+    servers: [hk]
+rules:
+  - 'DOMAIN-SUFFIX,netflix.com,Streaming'
+  - 'IP-CIDR,192.168.0.0/16,DIRECT'
+final: PROXY
+"#;
+
+    #[test]
+    fn test_rules() {
+        let config = config(CONFIG);
+        let rules: Vec<(String, String, String)> = rules(&config)
+            .rules
+            .into_iter()
+            .map(|r| (r.kind, r.payload, r.proxy))
+            .collect();
+        let expected = [
+            ("DOMAIN-SUFFIX", "netflix.com", "Streaming"),
+            ("IP-CIDR", "192.168.0.0/16", "DIRECT"),
+            ("MATCH", "", "PROXY"),
+        ];
+        assert_eq!(rules.len(), expected.len());
+        for (rule, (kind, payload, proxy)) in rules.iter().zip(expected.iter()) {
+            assert_eq!(
+                rule,
+                &(kind.to_string(), payload.to_string(), proxy.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_configs() {
+        let config = config(CONFIG);
+        assert_eq!(configs(&config).redir_port, 7892);
+        assert_eq!(configs(&config).tproxy_port, 0);
+    }
+
+    #[test]
+    fn test_check_load_config() {
+        let path = Some("/etc/seeker.yml");
+        assert!(check_load_config(b"", path).is_ok());
+        assert!(check_load_config(br#"{"path": "", "payload": ""}"#, path).is_ok());
+        assert!(check_load_config(br#"{"path": "/etc/seeker.yml"}"#, path).is_ok());
+        assert!(check_load_config(br#"{"path": "/etc/clash.yml"}"#, path).is_err());
+        assert!(check_load_config(br#"{"payload": "rules: []"}"#, path).is_err());
+        assert!(check_load_config(b"{", path).is_err());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("hk"), "hk");
+        assert_eq!(percent_decode("%E9%A6%99%E6%B8%AF%2001"), "香港 01");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(delay_target("/proxies/hk/delay"), Some("hk"));
+        assert_eq!(delay_target("/proxies/hk"), None);
+    }
+}
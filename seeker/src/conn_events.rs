@@ -0,0 +1,250 @@
+//! JSON events for every new outbound connection, emitted before any data flows.
+//!
+//! Events are sent as one datagram each to `conn_events`. When `conn_hook` is set, it is
+//! asked over a stream connection whether to let the connection through: seeker writes the
+//! event as a line of JSON and reads back a line with `allow` or `deny`.
+use async_std::io::{timeout, BufReader};
+use async_std::net::{TcpStream, UdpSocket};
+#[cfg(unix)]
+use async_std::os::unix::net::{UnixDatagram, UnixStream};
+use async_std::prelude::*;
+use config::rule::Action;
+use config::{Address, Config};
+use serde::Serialize;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+/// `unix:/path/to/socket` or `ip:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    pub fn parse(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Endpoint::Unix(PathBuf::from(path)));
+        }
+        s.parse().map(Endpoint::Inet).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid endpoint {}, expected ip:port or unix:/path", s),
+            )
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionEvent {
+    /// Seconds since the unix epoch.
+    pub time: u64,
+    pub network: &'static str,
+    pub src: SocketAddr,
+    /// Domain and port when known, the destination address otherwise.
+    pub host: String,
+    /// Real address of `host`.
+    pub resolved: SocketAddr,
+    pub action: String,
+    pub tag: Option<String>,
+}
+
+impl ConnectionEvent {
+    pub fn new(
+        network: &'static str,
+        src: SocketAddr,
+        host: &Address,
+        resolved: SocketAddr,
+        action: Action,
+        tag: Option<String>,
+    ) -> Self {
+        ConnectionEvent {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            network,
+            src,
+            host: host.to_string(),
+            resolved,
+            action: action.to_string().to_uppercase(),
+            tag,
+        }
+    }
+}
+
+pub struct ConnectionEvents {
+    sink: Option<Endpoint>,
+    hook: Option<Endpoint>,
+    hook_timeout: Duration,
+    hook_fail_closed: bool,
+}
+
+impl ConnectionEvents {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(ConnectionEvents::new(
+            config
+                .conn_events
+                .as_deref()
+                .map(Endpoint::parse)
+                .transpose()?,
+            config
+                .conn_hook
+                .as_deref()
+                .map(Endpoint::parse)
+                .transpose()?,
+            config.conn_hook_timeout,
+            config.conn_hook_fail_closed,
+        ))
+    }
+
+    pub fn new(
+        sink: Option<Endpoint>,
+        hook: Option<Endpoint>,
+        hook_timeout: Duration,
+        hook_fail_closed: bool,
+    ) -> Self {
+        ConnectionEvents {
+            sink,
+            hook,
+            hook_timeout,
+            hook_fail_closed,
+        }
+    }
+
+    /// Publish `event` and ask the hook about it, returns whether the connection may proceed.
+    ///
+    /// Rejected connections are published but never sent to the hook.
+    pub async fn check(&self, event: &ConnectionEvent) -> bool {
+        if self.sink.is_none() && self.hook.is_none() {
+            return true;
+        }
+        let mut line = match serde_json::to_vec(event) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(?e, "serialize connection event");
+                return true;
+            }
+        };
+        if let Some(sink) = &self.sink {
+            if let Err(e) = send_datagram(sink, &line).await {
+                debug!(?e, ?sink, "send connection event");
+            }
+        }
+        let hook = match &self.hook {
+            Some(hook) if event.action != "REJECT" => hook,
+            _ => return true,
+        };
+        line.push(b'\n');
+        match timeout(self.hook_timeout, ask_hook(hook, &line)).await {
+            Ok(allow) => allow,
+            Err(e) => {
+                warn!(?e, ?hook, host = %event.host, "connection hook failed");
+                !self.hook_fail_closed
+            }
+        }
+    }
+}
+
+async fn send_datagram(endpoint: &Endpoint, buf: &[u8]) -> Result<()> {
+    match endpoint {
+        Endpoint::Inet(addr) => {
+            let bind: SocketAddr = if addr.is_ipv6() {
+                "[::]:0".parse().expect("never error")
+            } else {
+                "0.0.0.0:0".parse().expect("never error")
+            };
+            UdpSocket::bind(bind).await?.send_to(buf, addr).await?;
+        }
+        #[cfg(unix)]
+        Endpoint::Unix(path) => {
+            UnixDatagram::unbound()?.send_to(buf, path).await?;
+        }
+        #[cfg(not(unix))]
+        Endpoint::Unix(_) => return Err(ErrorKind::Other.into()),
+    }
+    Ok(())
+}
+
+async fn ask_hook(endpoint: &Endpoint, line: &[u8]) -> Result<bool> {
+    let reply = match endpoint {
+        Endpoint::Inet(addr) => request_line(TcpStream::connect(addr).await?, line).await?,
+        #[cfg(unix)]
+        Endpoint::Unix(path) => request_line(UnixStream::connect(path).await?, line).await?,
+        #[cfg(not(unix))]
+        Endpoint::Unix(_) => return Err(ErrorKind::Other.into()),
+    };
+    match reply.trim() {
+        "allow" => Ok(true),
+        "deny" => Ok(false),
+        other => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid hook reply: {}", other),
+        )),
+    }
+}
+
+async fn request_line<S: Read + Write + Unpin>(mut conn: S, line: &[u8]) -> Result<String> {
+    conn.write_all(line).await?;
+    let mut reply = String::new();
+    BufReader::new(conn).read_line(&mut reply).await?;
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use async_std::task::{block_on, spawn};
+
+    fn event(action: Action) -> ConnectionEvent {
+        let addr: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let host = Address::DomainNameAddress("example.com".to_string(), 443);
+        ConnectionEvent::new("tcp", addr, &host, addr, action, None)
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(
+            Endpoint::parse("unix:/run/seeker.sock").unwrap(),
+            Endpoint::Unix(PathBuf::from("/run/seeker.sock"))
+        );
+        assert_eq!(
+            Endpoint::parse("127.0.0.1:9999").unwrap(),
+            Endpoint::Inet("127.0.0.1:9999".parse().unwrap())
+        );
+        assert!(Endpoint::parse("localhost").is_err());
+    }
+
+    #[test]
+    fn test_hook() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let hook = Endpoint::Inet(listener.local_addr().unwrap());
+            spawn(async move {
+                let mut incoming = listener.incoming();
+                while let Some(Ok(conn)) = incoming.next().await {
+                    let mut line = String::new();
+                    BufReader::new(&conn).read_line(&mut line).await.unwrap();
+                    let reply = if line.contains("\"PROXY\"") {
+                        "deny\n"
+                    } else {
+                        "allow\n"
+                    };
+                    (&conn).write_all(reply.as_bytes()).await.unwrap();
+                }
+            });
+            let events = ConnectionEvents::new(None, Some(hook), Duration::from_secs(1), true);
+            assert!(events.check(&event(Action::Direct)).await);
+            assert!(!events.check(&event(Action::Proxy)).await);
+
+            let unreachable = Endpoint::Inet("127.0.0.1:1".parse().unwrap());
+            let events =
+                ConnectionEvents::new(None, Some(unreachable), Duration::from_secs(1), true);
+            assert!(!events.check(&event(Action::Direct)).await);
+        });
+    }
+}
@@ -0,0 +1,364 @@
+//! Live connections and traffic totals, reported in the JSON shapes of Clash's `/traffic`
+//! and `/connections` so Clash dashboards work against the management API.
+use crate::proxy_connection::ProxyConnection;
+use crate::traffic::Traffic;
+use config::rule::Rule;
+use config::Address;
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a connection is, fixed when it is tracked.
+pub struct ConnectionInfo {
+    pub network: &'static str,
+    pub src: SocketAddr,
+    pub dest: SocketAddr,
+    pub host: Address,
+    /// Name of the upstream server, `None` for direct connections.
+    pub server: Option<String>,
+    pub rule: Option<Rule>,
+    pub process_path: Option<String>,
+}
+
+struct Entry {
+    info: ConnectionInfo,
+    traffic: Traffic,
+    start: SystemTime,
+    conn: Box<dyn ProxyConnection + Send + Sync>,
+}
+
+#[derive(Default)]
+pub struct ConnectionTracker {
+    next_id: AtomicU64,
+    live: RwLock<HashMap<u64, Entry>>,
+    /// Traffic of connections already closed.
+    closed_upload: AtomicU64,
+    closed_download: AtomicU64,
+}
+
+/// Keeps a connection listed until dropped.
+pub struct Tracked {
+    tracker: Arc<ConnectionTracker>,
+    id: u64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Some(entry) = self.tracker.live.write().remove(&self.id) {
+            self.tracker
+                .closed_upload
+                .fetch_add(entry.traffic.sent_bytes() as u64, Ordering::Relaxed);
+            self.tracker
+                .closed_download
+                .fetch_add(entry.traffic.received_bytes() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// `/traffic`: bytes per second.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct TrafficRate {
+    pub up: u64,
+    pub down: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Connections {
+    pub download_total: u64,
+    pub upload_total: u64,
+    pub connections: Vec<ConnectionSnapshot>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionSnapshot {
+    pub id: String,
+    pub metadata: Metadata,
+    pub upload: u64,
+    pub download: u64,
+    pub start: String,
+    pub chains: Vec<String>,
+    pub rule: String,
+    pub rule_payload: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+    pub network: &'static str,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    #[serde(rename = "sourceIP")]
+    pub source_ip: String,
+    #[serde(rename = "destinationIP")]
+    pub destination_ip: String,
+    pub source_port: String,
+    pub destination_port: String,
+    pub host: String,
+    pub dns_mode: &'static str,
+    pub process_path: String,
+}
+
+impl ConnectionTracker {
+    /// List the connection to the upstream `conn` until the returned `Tracked` is dropped.
+    pub fn track(
+        self: &Arc<Self>,
+        info: ConnectionInfo,
+        conn: Box<dyn ProxyConnection + Send + Sync>,
+    ) -> Tracked {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let entry = Entry {
+            info,
+            traffic: conn.traffic(),
+            start: SystemTime::now(),
+            conn,
+        };
+        self.live.write().insert(id, entry);
+        Tracked {
+            tracker: self.clone(),
+            id,
+        }
+    }
+
+    /// Number of live connections.
+    pub fn live(&self) -> usize {
+        self.live.read().len()
+    }
+
+    /// Shut the upstream of connection `id` down, its relay ends at the next read or write.
+    /// False when there is no such connection.
+    pub fn close(&self, id: u64) -> bool {
+        match self.live.read().get(&id) {
+            Some(entry) => {
+                entry.conn.shutdown();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn close_all(&self) {
+        self.live.read().values().for_each(|e| e.conn.shutdown());
+    }
+
+    /// Bytes uploaded and downloaded since start, including live connections.
+    pub fn totals(&self) -> (u64, u64) {
+        let live = self.live.read();
+        let upload = live
+            .values()
+            .map(|e| e.traffic.sent_bytes() as u64)
+            .sum::<u64>()
+            + self.closed_upload.load(Ordering::Relaxed);
+        let download = live
+            .values()
+            .map(|e| e.traffic.received_bytes() as u64)
+            .sum::<u64>()
+            + self.closed_download.load(Ordering::Relaxed);
+        (upload, download)
+    }
+
+    pub fn snapshot(&self) -> Connections {
+        let (upload_total, download_total) = self.totals();
+        let mut connections: Vec<(u64, ConnectionSnapshot)> = self
+            .live
+            .read()
+            .iter()
+            .map(|(id, entry)| (*id, snapshot(*id, entry)))
+            .collect();
+        connections.sort_by_key(|(id, _)| *id);
+        Connections {
+            download_total,
+            upload_total,
+            connections: connections.into_iter().map(|(_, c)| c).collect(),
+        }
+    }
+}
+
+/// Turns totals sampled at a fixed interval into per second rates.
+pub struct RateMeter {
+    last: (u64, u64),
+}
+
+impl RateMeter {
+    pub fn new(tracker: &ConnectionTracker) -> Self {
+        RateMeter {
+            last: tracker.totals(),
+        }
+    }
+
+    /// Rate since the previous sample, taken `secs` ago.
+    pub fn sample(&mut self, tracker: &ConnectionTracker, secs: u64) -> TrafficRate {
+        let now = tracker.totals();
+        let rate = TrafficRate {
+            up: now.0.saturating_sub(self.last.0) / secs.max(1),
+            down: now.1.saturating_sub(self.last.1) / secs.max(1),
+        };
+        self.last = now;
+        rate
+    }
+}
+
+/// The type and payload of the matcher of `rule`, as Clash reports them.
+pub fn rule_type_payload(rule: &Rule) -> (String, String) {
+    // Matchers print as `TYPE,payload`, `MATCH` or `AND((...),(...))`.
+    let matcher = rule.matcher.to_string();
+    match matcher.find(|c| c == ',' || c == '(') {
+        Some(pos) => (
+            matcher[..pos].to_string(),
+            matcher[pos..].trim_start_matches(',').to_string(),
+        ),
+        None => (matcher, String::new()),
+    }
+}
+
+fn snapshot(id: u64, entry: &Entry) -> ConnectionSnapshot {
+    let info = &entry.info;
+    let host = match &info.host {
+        Address::DomainNameAddress(domain, _) => domain.clone(),
+        Address::SocketAddress(_) => String::new(),
+    };
+    let (rule, rule_payload) = match &info.rule {
+        Some(rule) => rule_type_payload(rule),
+        // Connections to ips matched by no IP rule go through the proxy, see `get_action_for_addr`.
+        None => ("MATCH".to_string(), String::new()),
+    };
+    ConnectionSnapshot {
+        id: id.to_string(),
+        metadata: Metadata {
+            network: info.network,
+            kind: "Tun",
+            source_ip: info.src.ip().to_string(),
+            destination_ip: info.dest.ip().to_string(),
+            source_port: info.src.port().to_string(),
+            destination_port: info.dest.port().to_string(),
+            host,
+            dns_mode: "fake-ip",
+            process_path: info.process_path.clone().unwrap_or_default(),
+        },
+        upload: entry.traffic.sent_bytes() as u64,
+        download: entry.traffic.received_bytes() as u64,
+        start: rfc3339(entry.start),
+        chains: vec![info.server.clone().unwrap_or_else(|| "DIRECT".to_string())],
+        rule,
+        rule_payload,
+    }
+}
+
+/// Format `time` as RFC 3339 in UTC, e.g. `2020-09-01T08:00:00Z`.
+pub fn rfc3339(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ServerConfig;
+    use std::str::FromStr;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    struct Conn {
+        traffic: Traffic,
+        alive: Arc<AtomicBool>,
+    }
+
+    impl ProxyConnection for Conn {
+        fn traffic(&self) -> Traffic {
+            self.traffic.clone()
+        }
+
+        fn config(&self) -> Option<&ServerConfig> {
+            None
+        }
+
+        fn has_config(&self, config: Option<&ServerConfig>) -> bool {
+            config.is_none()
+        }
+
+        fn shutdown(&self) {
+            self.alive.store(false, Ordering::SeqCst);
+        }
+
+        fn strong_count(&self) -> usize {
+            Arc::strong_count(&self.alive)
+        }
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_secs(1_583_020_800 + 3661)),
+            "2020-03-01T01:01:01Z"
+        );
+    }
+
+    #[test]
+    fn test_track() {
+        let tracker = Arc::new(ConnectionTracker::default());
+        let upstream = Conn {
+            alive: Arc::new(AtomicBool::new(true)),
+            ..Conn::default()
+        };
+        let traffic = upstream.traffic.clone();
+        let info = ConnectionInfo {
+            network: "tcp",
+            src: "10.0.0.2:50000".parse().unwrap(),
+            dest: "1.2.3.4:443".parse().unwrap(),
+            host: Address::DomainNameAddress("example.com".to_string(), 443),
+            server: None,
+            rule: Some(Rule::from_str("DOMAIN-SUFFIX,example.com,DIRECT").unwrap()),
+            process_path: None,
+        };
+        let tracked = tracker.track(info, Box::new(upstream.clone()));
+        let mut meter = RateMeter::new(&tracker);
+        traffic.send(10);
+        traffic.recv(100);
+
+        let connections = tracker.snapshot();
+        assert_eq!(connections.upload_total, 10);
+        let conn = &connections.connections[0];
+        assert_eq!(conn.rule, "DOMAIN-SUFFIX");
+        assert_eq!(conn.rule_payload, "example.com");
+        assert_eq!(conn.chains, vec!["DIRECT"]);
+        assert_eq!(conn.metadata.host, "example.com");
+
+        assert!(!tracker.close(2));
+        assert!(tracker.close(1));
+        assert!(!upstream.alive.load(Ordering::SeqCst));
+
+        drop(tracked);
+        assert!(tracker.snapshot().connections.is_empty());
+        assert_eq!(tracker.totals(), (10, 100));
+        assert_eq!(meter.sample(&tracker, 1), TrafficRate { up: 10, down: 100 });
+    }
+}
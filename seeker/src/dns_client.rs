@@ -1,20 +1,64 @@
+use crate::metrics;
+use async_std::task;
 use async_std_resolver::config::{
-    NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts,
+    LookupIpStrategy, NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig,
+    ResolverOpts,
 };
-use async_std_resolver::{resolver, AsyncStdResolver};
-use config::{Address, DnsServerAddr};
+use async_std_resolver::lookup_ip::LookupIp;
+use config::dns_ttl::DnsTtl;
+use config::trust_anchor::RootKey;
+use config::{nat64, Address, DnsServerAddr, Ipv6Policy};
+use dnsserver::upstream::{resolver, Resolver};
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Result};
-use std::net::IpAddr;
-use std::net::SocketAddr;
-use std::time::Duration;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::debug;
+#[cfg(not(feature = "dnssec"))]
+use tracing::warn;
+
+/// Domains looked up at least this many times since their last refresh are prefetched.
+const HOT_HITS: u32 = 3;
+/// Refresh hot domains this long before their records expire.
+const PREFETCH_AHEAD: Duration = Duration::from_secs(5);
+const PREFETCH_INTERVAL: Duration = Duration::from_secs(1);
+
+struct CacheEntry {
+    ips: Vec<IpAddr>,
+    valid_until: Instant,
+    hits: u32,
+}
 
 #[derive(Clone)]
 pub struct DnsClient {
-    resolver: AsyncStdResolver,
+    /// Shared by all clones, a config reload swaps in new upstreams with `replace`.
+    resolver: Arc<RwLock<Resolver>>,
+    /// Resolver without cache, so prefetching gets fresh records before the old ones expire.
+    uncached_resolver: Arc<RwLock<Resolver>>,
+    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
+    ipv6_policy: Ipv6Policy,
+    nat64_prefix: Option<Ipv6Addr>,
 }
 
 impl DnsClient {
-    pub async fn new(dns_servers: &[DnsServerAddr], timeout: Duration) -> Self {
+    /// With `validate` set, answers that fail DNSSEC validation are reported as errors,
+    /// which the DNS server turns into SERVFAIL. They are validated from `root_keys` when
+    /// set, otherwise from the root keys built into the resolver.
+    ///
+    /// With `nat64_prefix` set, `dial_address` reaches IPv4 addresses through NAT64.
+    ///
+    /// Cached answers are kept for the global TTL clamps of `ttl`.
+    pub async fn new(
+        dns_servers: &[DnsServerAddr],
+        timeout: Duration,
+        validate: bool,
+        root_keys: Option<&[RootKey]>,
+        ipv6_policy: Ipv6Policy,
+        nat64_prefix: Option<Ipv6Addr>,
+        ttl: &DnsTtl,
+    ) -> Self {
         let mut name_servers = NameServerConfigGroup::with_capacity(dns_servers.len());
 
         for addr in dns_servers {
@@ -43,36 +87,142 @@ impl DnsClient {
             }
         }
 
+        #[cfg(not(feature = "dnssec"))]
+        let validate = {
+            if validate {
+                warn!("DNSSEC validation needs seeker built with the dnssec feature, disabled");
+            }
+            false
+        };
         let num_concurrent_reqs = name_servers.len();
+        let ip_strategy = match ipv6_policy {
+            Ipv6Policy::Off => LookupIpStrategy::Ipv4Only,
+            Ipv6Policy::PreferV4 => LookupIpStrategy::Ipv4thenIpv6,
+            Ipv6Policy::PreferV6 => LookupIpStrategy::Ipv6thenIpv4,
+            Ipv6Policy::OnlyMatched => LookupIpStrategy::Ipv4AndIpv6,
+        };
 
-        // Construct a new Resolver with default configuration options
-        let resolver = resolver(
-            ResolverConfig::from_parts(None, Vec::new(), name_servers),
+        let resolver_config = ResolverConfig::from_parts(None, Vec::new(), name_servers);
+        let opts = ResolverOpts {
+            timeout,
+            num_concurrent_reqs,
+            validate,
+            edns0: validate,
+            ip_strategy,
+            positive_min_ttl: ttl.min,
+            positive_max_ttl: ttl.max,
+            ..Default::default()
+        };
+        let uncached_resolver = resolver(
+            resolver_config.clone(),
             ResolverOpts {
-                timeout,
-                num_concurrent_reqs,
-                ..Default::default()
+                cache_size: 0,
+                ..opts
             },
+            root_keys,
         )
         .await
         .expect("failed to create resolver");
+        let resolver = resolver(resolver_config, opts, root_keys)
+            .await
+            .expect("failed to create resolver");
 
-        DnsClient { resolver }
+        DnsClient {
+            resolver: Arc::new(RwLock::new(resolver)),
+            uncached_resolver: Arc::new(RwLock::new(uncached_resolver)),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            ipv6_policy,
+            nat64_prefix,
+        }
+    }
+
+    pub fn resolver(&self) -> Resolver {
+        self.resolver.read().clone()
     }
 
-    pub fn resolver(&self) -> AsyncStdResolver {
-        self.resolver.clone()
+    /// Resolve through the upstreams of `other` from now on, in every clone of this client.
+    /// Cached answers of the old upstreams are dropped.
+    pub fn replace(&self, other: &DnsClient) {
+        *self.resolver.write() = other.resolver();
+        *self.uncached_resolver.write() = other.uncached_resolver.read().clone();
+        self.cache.lock().clear();
     }
     pub async fn lookup(&self, domain: &str) -> Result<IpAddr> {
+        let ips = self.lookup_ips(domain).await?;
+        let prefer_v6 = self.ipv6_policy == Ipv6Policy::PreferV6;
+        ips.iter()
+            .find(|ip| ip.is_ipv6() == prefer_v6)
+            .or_else(|| ips.first())
+            .copied()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} not resolved", domain)))
+    }
+
+    async fn lookup_ips(&self, domain: &str) -> Result<Vec<IpAddr>> {
+        if let Some(entry) = self.cache.lock().get_mut(domain) {
+            if entry.valid_until > Instant::now() {
+                entry.hits += 1;
+                metrics::incr("dns_cache{result=\"hit\"}");
+                return Ok(entry.ips.clone());
+            }
+        }
+        metrics::incr("dns_cache{result=\"miss\"}");
         let response = self
-            .resolver
+            .resolver()
             .lookup_ip(domain)
             .await
             .map_err(|_| Error::new(ErrorKind::NotFound, format!("{} not resolved", domain)))?;
-        response
-            .iter()
-            .next()
-            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} not resolved", domain)))
+        Ok(self.store(domain, &response, 1))
+    }
+
+    /// Cache `response`, adding `hits` to the hits of the domain.
+    fn store(&self, domain: &str, response: &LookupIp, hits: u32) -> Vec<IpAddr> {
+        let ips: Vec<IpAddr> = response.iter().collect();
+        let mut cache = self.cache.lock();
+        let hits = cache.get(domain).map_or(0, |e| e.hits) + hits;
+        cache.insert(
+            domain.to_string(),
+            CacheEntry {
+                ips: ips.clone(),
+                valid_until: response.valid_until(),
+                hits,
+            },
+        );
+        ips
+    }
+
+    /// Refresh hot domains shortly before their records expire, so connections to them
+    /// never wait on upstream.
+    pub async fn prefetch_forever(&self) {
+        loop {
+            task::sleep(PREFETCH_INTERVAL).await;
+            let now = Instant::now();
+            let domains: Vec<String> = {
+                let mut cache = self.cache.lock();
+                cache.retain(|_, e| e.valid_until > now || e.hits >= HOT_HITS);
+                cache
+                    .iter_mut()
+                    .filter(|(_, e)| e.hits >= HOT_HITS && e.valid_until <= now + PREFETCH_AHEAD)
+                    .map(|(domain, e)| {
+                        // Domains must be used again to stay hot.
+                        e.hits = 0;
+                        domain.clone()
+                    })
+                    .collect()
+            };
+            for domain in domains {
+                let resolver = self.uncached_resolver.read().clone();
+                match resolver.lookup_ip(domain.as_str()).await {
+                    Ok(response) => {
+                        self.store(&domain, &response, 0);
+                        metrics::incr("dns_prefetch{result=\"ok\"}");
+                    }
+                    Err(e) => {
+                        debug!(?e, %domain, "prefetch error");
+                        metrics::incr("dns_prefetch{result=\"error\"}");
+                    }
+                }
+            }
+        }
     }
 
     pub async fn lookup_address(&self, addr: &Address) -> Result<SocketAddr> {
@@ -84,4 +234,37 @@ impl DnsClient {
             }
         }
     }
+
+    /// The address to connect to for `addr`, mapped into the NAT64 prefix if one is set.
+    pub async fn dial_address(&self, addr: &Address) -> Result<SocketAddr> {
+        Ok(self.translate(self.lookup_address(addr).await?))
+    }
+
+    /// Like `dial_address`, for the address of a proxy server.
+    ///
+    /// Servers may only have AAAA records, so they are looked up over IPv6 even when
+    /// `dns_ipv6` is off and no A record is found.
+    pub async fn dial_server(&self, addr: &Address) -> Result<SocketAddr> {
+        let err = match self.lookup_address(addr).await {
+            Ok(addr) => return Ok(self.translate(addr)),
+            Err(e) => e,
+        };
+        match addr {
+            Address::DomainNameAddress(domain, port) if self.ipv6_policy == Ipv6Policy::Off => {
+                let ip = self
+                    .resolver()
+                    .ipv6_lookup(domain.as_str())
+                    .await
+                    .ok()
+                    .and_then(|lookup| lookup.iter().next().copied())
+                    .ok_or(err)?;
+                Ok(SocketAddr::new(IpAddr::V6(ip), *port))
+            }
+            _ => Err(err),
+        }
+    }
+
+    pub fn translate(&self, addr: SocketAddr) -> SocketAddr {
+        nat64::translate(self.nat64_prefix, addr)
+    }
 }
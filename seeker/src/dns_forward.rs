@@ -0,0 +1,77 @@
+//! Forward DNS queries arriving over other transports (hijacked tun traffic, DoT, DoH)
+//! to seeker's own UDP DNS server, so they all follow the same rules.
+
+use async_std::io::{timeout, Read, Write};
+use async_std::net::{SocketAddr, UdpSocket};
+use async_std::prelude::*;
+use std::io::{ErrorKind, Result};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+/// Address to reach the local DNS server listening on `dns_listen`.
+pub fn local_dns_addr(dns_listen: &str) -> Option<SocketAddr> {
+    let mut addr: SocketAddr = dns_listen.parse().ok()?;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(Ipv4Addr::LOCALHOST.into());
+    }
+    Some(addr)
+}
+
+/// Serve length-prefixed DNS queries (RFC 1035 TCP framing, also used by DoT) from `conn`
+/// by forwarding each of them to the local DNS server.
+pub async fn forward_stream_queries<S: Read + Write + Unpin>(
+    mut conn: S,
+    dns_addr: SocketAddr,
+    dns_timeout: Duration,
+) -> Result<()> {
+    let socket = connect_local(dns_addr).await?;
+    let mut len_buf = [0; 2];
+    let mut buf = vec![0; u16::MAX as usize];
+    loop {
+        match conn.read_exact(&mut len_buf).await {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            r => r?,
+        }
+        let len = u16::from_be_bytes(len_buf) as usize;
+        conn.read_exact(&mut buf[..len]).await?;
+        socket.send(&buf[..len]).await?;
+        let size = timeout(dns_timeout, socket.recv(&mut buf)).await?;
+        conn.write_all(&(size as u16).to_be_bytes()).await?;
+        conn.write_all(&buf[..size]).await?;
+    }
+}
+
+/// Forward a single query to the local DNS server and return its answer.
+pub async fn forward_query(
+    query: &[u8],
+    dns_addr: SocketAddr,
+    dns_timeout: Duration,
+) -> Result<Vec<u8>> {
+    let socket = connect_local(dns_addr).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0; u16::MAX as usize];
+    let size = timeout(dns_timeout, socket.recv(&mut buf)).await?;
+    buf.truncate(size);
+    Ok(buf)
+}
+
+async fn connect_local(dns_addr: SocketAddr) -> Result<UdpSocket> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(dns_addr).await?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_dns_addr() {
+        assert_eq!(local_dns_addr("0.0.0.0:53"), "127.0.0.1:53".parse().ok());
+        assert_eq!(
+            local_dns_addr("192.168.1.2:5353"),
+            "192.168.1.2:5353".parse().ok()
+        );
+        assert_eq!(local_dns_addr("invalid"), None);
+    }
+}
@@ -0,0 +1,122 @@
+//! DNS-over-TLS and DNS-over-HTTPS listeners for other devices on the LAN. Queries are
+//! answered by the local DNS server, so they follow seeker's split-DNS rules.
+
+use crate::api::{read_request, Response};
+use crate::dns_forward::{forward_query, forward_stream_queries};
+use async_std::io::{timeout, Read, Write};
+use async_std::net::{SocketAddr, TcpListener};
+use async_std::prelude::*;
+use async_std::task::spawn;
+use async_tls::TlsAcceptor;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, ServerConfig};
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info};
+
+const DNS_MESSAGE: &str = "application/dns-message";
+/// Clients get this long for the TLS handshake, and DoH clients for sending their request,
+/// so stalled ones do not hold a task forever.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Load a PEM certificate chain and private key for the DoT and DoH listeners.
+pub fn load_tls_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidInput, msg.to_string());
+    let certs = certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|_| invalid("invalid tls certificate"))?;
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|_| invalid("invalid tls key"))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(|_| invalid("invalid tls key"))?;
+    }
+    let key = keys.pop().ok_or_else(|| invalid("no tls key found"))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(|e| invalid(&e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Serve DNS over TLS (RFC 7858) on `listen`.
+pub async fn run_dot_server(
+    listen: String,
+    acceptor: TlsAcceptor,
+    dns_addr: SocketAddr,
+    dns_timeout: Duration,
+) -> Result<()> {
+    let listener = TcpListener::bind(&listen).await?;
+    info!(%listen, "DoT server started");
+    let mut incoming = listener.incoming();
+    while let Some(Ok(conn)) = incoming.next().await {
+        let acceptor = acceptor.clone();
+        spawn(async move {
+            let ret = match timeout(CLIENT_TIMEOUT, acceptor.accept(conn)).await {
+                Ok(stream) => forward_stream_queries(stream, dns_addr, dns_timeout).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = ret {
+                debug!(?e, "DoT connection error");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Serve DNS over HTTPS (RFC 8484 wire format) on `listen`, one query per connection.
+pub async fn run_doh_server(
+    listen: String,
+    acceptor: TlsAcceptor,
+    dns_addr: SocketAddr,
+    dns_timeout: Duration,
+) -> Result<()> {
+    let listener = TcpListener::bind(&listen).await?;
+    info!(%listen, "DoH server started");
+    let mut incoming = listener.incoming();
+    while let Some(Ok(conn)) = incoming.next().await {
+        let acceptor = acceptor.clone();
+        spawn(async move {
+            let ret = match timeout(CLIENT_TIMEOUT, acceptor.accept(conn)).await {
+                Ok(stream) => serve_doh(stream, dns_addr, dns_timeout).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = ret {
+                debug!(?e, "DoH connection error");
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn serve_doh<S: Read + Write + Unpin>(
+    mut conn: S,
+    dns_addr: SocketAddr,
+    dns_timeout: Duration,
+) -> Result<()> {
+    let response = match timeout(CLIENT_TIMEOUT, read_request(&mut conn)).await {
+        Ok(req) => {
+            let query = match (req.method.as_str(), req.path.as_str()) {
+                ("GET", "/dns-query") => req
+                    .query_param("dns")
+                    .and_then(|q| base64::decode_config(q, base64::URL_SAFE_NO_PAD).ok()),
+                ("POST", "/dns-query") => Some(req.body),
+                _ => None,
+            };
+            match query {
+                Some(query) => match forward_query(&query, dns_addr, dns_timeout).await {
+                    Ok(answer) => Response::bytes(DNS_MESSAGE, answer),
+                    Err(e) => Response::error(500, &e.to_string()),
+                },
+                None if req.path == "/dns-query" => Response::error(400, "missing dns query"),
+                None => Response::error(404, "not found"),
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::TimedOut => return Err(e),
+        Err(e) => Response::error(400, &e.to_string()),
+    };
+    conn.write_all(&response.to_bytes()).await?;
+    conn.flush().await
+}
@@ -0,0 +1,189 @@
+//! `seeker export --format clash`: the servers, proxy groups and rules of the config as a
+//! Clash config, for devices that run Clash next to seeker.
+//!
+//! `PROXY` becomes a `url-test` group of all servers, like seeker picks the fastest one.
+//! Rules Clash does not have, like `GEOSITE` or `UID`, and rules seeker applies only some
+//! of the time, like those with a `time` window, are left out as comments.
+use crate::import::quote;
+use config::rule::{Action, Matcher, Rule};
+use config::{Address, Config, GroupStrategy, ServerConfig, ServerProtocol};
+use std::fmt::Write;
+
+const TEST_URL: &str = "http://www.gstatic.com/generate_204";
+const TEST_INTERVAL: u32 = 300;
+
+fn host_port(addr: &Address) -> (String, u16) {
+    match addr {
+        Address::SocketAddress(addr) => (addr.ip().to_string(), addr.port()),
+        Address::DomainNameAddress(domain, port) => (domain.clone(), *port),
+    }
+}
+
+fn write_proxy(out: &mut String, server: &ServerConfig) {
+    let (host, port) = host_port(server.addr());
+    let kind = match server.protocol() {
+        ServerProtocol::Shadowsocks => "ss",
+        ServerProtocol::Socks5 => "socks5",
+        ServerProtocol::Http | ServerProtocol::Https => "http",
+    };
+    writeln!(out, "  - name: {}", quote(server.name())).unwrap();
+    writeln!(out, "    type: {}", kind).unwrap();
+    writeln!(out, "    server: {}", quote(&host)).unwrap();
+    writeln!(out, "    port: {}", port).unwrap();
+    if let Some(method) = server.method() {
+        writeln!(out, "    cipher: {}", method).unwrap();
+    }
+    if let Some(username) = server.username() {
+        writeln!(out, "    username: {}", quote(username)).unwrap();
+    }
+    if let Some(password) = server.password() {
+        writeln!(out, "    password: {}", quote(password)).unwrap();
+    }
+    match server.protocol() {
+        ServerProtocol::Shadowsocks => writeln!(out, "    udp: true").unwrap(),
+        ServerProtocol::Https => writeln!(out, "    tls: true").unwrap(),
+        _ => {}
+    }
+}
+
+fn write_group(out: &mut String, name: &str, strategy: GroupStrategy, servers: &[&str]) {
+    writeln!(out, "  - name: {}", quote(name)).unwrap();
+    match strategy {
+        GroupStrategy::Latency => writeln!(out, "    type: url-test").unwrap(),
+        GroupStrategy::Fallback => writeln!(out, "    type: fallback").unwrap(),
+        GroupStrategy::RoundRobin => {
+            writeln!(out, "    type: load-balance").unwrap();
+            writeln!(out, "    strategy: round-robin").unwrap();
+        }
+    }
+    writeln!(out, "    url: {}", TEST_URL).unwrap();
+    writeln!(out, "    interval: {}", TEST_INTERVAL).unwrap();
+    writeln!(out, "    proxies:").unwrap();
+    for server in servers {
+        writeln!(out, "      - {}", quote(server)).unwrap();
+    }
+}
+
+/// `rule` in Clash syntax, `None` when Clash has no such rule.
+fn clash_rule(rule: &Rule) -> Option<String> {
+    if rule.time.is_some() {
+        return None;
+    }
+    let target = match (rule.action, &rule.group) {
+        (Action::Proxy, Some(group)) => group.as_str(),
+        (Action::Proxy, None) => "PROXY",
+        (Action::Direct, _) => "DIRECT",
+        (Action::Reject, _) => "REJECT",
+        (Action::Probe, _) | (Action::Script, _) => return None,
+    };
+    match &rule.matcher {
+        Matcher::Domain(_)
+        | Matcher::DomainSuffix(_)
+        | Matcher::DomainKeyword(_)
+        | Matcher::ProcessName(_)
+        | Matcher::ProcessPath(_)
+        | Matcher::DstPort(_)
+        | Matcher::Match => Some(format!("{},{}", rule.matcher, target)),
+        // seeker never resolves domains for IP rules.
+        Matcher::IpCidr(_) | Matcher::IpCidr6(_) => {
+            Some(format!("{},{},no-resolve", rule.matcher, target))
+        }
+        _ => None,
+    }
+}
+
+pub fn clash_yaml(config: &Config) -> String {
+    let mut out = String::from("# Exported by `seeker export --format clash`.\nproxies:\n");
+    for server in config.servers.iter() {
+        write_proxy(&mut out, server);
+    }
+
+    out.push_str("proxy-groups:\n");
+    let all: Vec<&str> = config.servers.iter().map(|s| s.name()).collect();
+    write_group(&mut out, "PROXY", GroupStrategy::Latency, &all);
+    for group in &config.proxy_groups {
+        let servers: Vec<&str> = group.servers.iter().map(String::as_str).collect();
+        write_group(&mut out, &group.name, group.strategy, &servers);
+    }
+
+    out.push_str("rules:\n");
+    for rule in config.rules.rules() {
+        match clash_rule(rule) {
+            Some(rule) => writeln!(out, "  - {}", quote(&rule)).unwrap(),
+            None => writeln!(out, "  # not supported by Clash: {}", rule).unwrap(),
+        }
+    }
+    match clash_rule(config.rules.final_rule()) {
+        Some(rule) => writeln!(out, "  - {}", quote(&rule)).unwrap(),
+        None => {
+            writeln!(
+                out,
+                "  # not supported by Clash: final {}",
+                config.final_target
+            )
+            .unwrap();
+            writeln!(out, "  - {}", quote("MATCH,PROXY")).unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config::config;
+
+    const CONFIG: &str = r#"servers:
+  - 'ss://YWVzLTI1Ni1nY206cGFzcw@hk.example.com:8388#hk'
+  - name: us
+    addr: 10.0.0.1:1080
+    protocol: Socks5
+proxy_groups:
+  - name: Streaming
+    servers: [us, hk]
+    strategy: fallback
+rules:
+  - 'DOMAIN-SUFFIX,netflix.com,Streaming'
+  - 'IP-CIDR,192.168.0.0/16,DIRECT'
+  - 'UID,501,DIRECT'
+  - 'DOMAIN-SUFFIX,ads.example.com,REJECT'
+final: PROXY
+"#;
+
+    #[test]
+    fn test_clash_yaml() {
+        let config = config(CONFIG);
+        let yaml = clash_yaml(&config);
+        let doc: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+
+        let proxies = doc["proxies"].as_sequence().unwrap();
+        assert_eq!(proxies.len(), 2);
+        assert_eq!(proxies[0]["type"], "ss".into());
+        assert_eq!(proxies[0]["server"], "hk.example.com".into());
+        assert_eq!(proxies[0]["port"], 8388.into());
+        assert_eq!(proxies[0]["cipher"], "aes-256-gcm".into());
+        assert_eq!(proxies[1]["type"], "socks5".into());
+
+        let groups = doc["proxy-groups"].as_sequence().unwrap();
+        assert_eq!(groups[0]["name"], "PROXY".into());
+        assert_eq!(groups[1]["type"], "fallback".into());
+        assert_eq!(groups[1]["proxies"][0], "us".into());
+
+        let rules: Vec<&str> = doc["rules"]
+            .as_sequence()
+            .unwrap()
+            .iter()
+            .map(|r| r.as_str().unwrap())
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                "DOMAIN-SUFFIX,netflix.com,Streaming",
+                "IP-CIDR,192.168.0.0/16,DIRECT,no-resolve",
+                "DOMAIN-SUFFIX,ads.example.com,REJECT",
+                "MATCH,PROXY",
+            ]
+        );
+        assert!(yaml.contains("# not supported by Clash: UID,501,DIRECT"));
+    }
+}
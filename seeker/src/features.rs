@@ -0,0 +1,49 @@
+//! What this binary was built with, for `seeker features` and the `/version` endpoint.
+
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct Feature {
+    pub name: &'static str,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub protocols: Vec<&'static str>,
+    pub features: Vec<Feature>,
+}
+
+macro_rules! features {
+    ($($name:literal),+ $(,)?) => {
+        vec![$(Feature { name: $name, enabled: cfg!(feature = $name) }),+]
+    };
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        protocols: vec!["http", "https", "socks5", "shadowsocks"],
+        features: features![
+            "dnssec",
+            "dns-inbound",
+            "openssl-ciphers",
+            "script",
+            "heap-stats"
+        ],
+    }
+}
+
+pub fn print_features() {
+    let info = build_info();
+    println!("seeker {}", info.version);
+    println!("protocols: {}", info.protocols.join(", "));
+    for feature in info.features {
+        println!(
+            "{}: {}",
+            feature.name,
+            if feature.enabled { "on" } else { "off" }
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! Bytes held on the heap, counted by a wrapper around the system allocator installed with
+//! the `heap-stats` feature. Counting costs two atomic operations per allocation.
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Serialize)]
+pub struct HeapStats {
+    pub allocated_bytes: usize,
+    pub peak_bytes: usize,
+}
+
+pub fn stats() -> Option<HeapStats> {
+    if !cfg!(feature = "heap-stats") {
+        return None;
+    }
+    Some(HeapStats {
+        allocated_bytes: ALLOCATED.load(Ordering::Relaxed),
+        peak_bytes: PEAK.load(Ordering::Relaxed),
+    })
+}
+
+#[cfg(feature = "heap-stats")]
+pub use counting::CountingAllocator;
+
+#[cfg(feature = "heap-stats")]
+mod counting {
+    use super::{ALLOCATED, PEAK};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::Ordering;
+
+    pub struct CountingAllocator;
+
+    fn grow(n: usize) {
+        let now = ALLOCATED.fetch_add(n, Ordering::Relaxed) + n;
+        PEAK.fetch_max(now, Ordering::Relaxed);
+    }
+
+    fn shrink(n: usize) {
+        ALLOCATED.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc(layout);
+            if !ptr.is_null() {
+                grow(layout.size());
+            }
+            ptr
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc_zeroed(layout);
+            if !ptr.is_null() {
+                grow(layout.size());
+            }
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+            shrink(layout.size());
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let new_ptr = System.realloc(ptr, layout, new_size);
+            if !new_ptr.is_null() {
+                shrink(layout.size());
+                grow(new_size);
+            }
+            new_ptr
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! `seeker init`: ask a few questions and write a starter config for this platform, with a
+//! server, the usual DNS settings and rules keeping LAN traffic direct.
+use crate::import::servers_yaml;
+use config::share_uri;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+/// macOS only creates tun devices named `utunN`.
+const DEFAULT_TUN_NAME: &str = "utun4";
+
+/// systemd-resolved holds 127.0.0.53:53 on Linux, `resolv.conf` points at 127.0.0.1.
+#[cfg(target_os = "linux")]
+const DEFAULT_DNS_LISTEN: &str = "127.0.0.1:53";
+#[cfg(not(target_os = "linux"))]
+const DEFAULT_DNS_LISTEN: &str = "0.0.0.0:53";
+
+const DEFAULT_DNS_SERVER: &str = "223.5.5.5:53";
+
+const SAMPLE_SERVER: &str = r#"servers:
+  - name: server1
+    addr: domain-to-ss-server.com:8388  # the address and port of your shadowsocks server
+    method: chacha20-ietf
+    password: password
+    protocol: Shadowsocks
+"#;
+
+const RULES: &str = r#"rules:
+  - 'DOMAIN-SUFFIX,local,DIRECT'
+  - 'IP-CIDR,10.0.0.0/8,DIRECT'
+  - 'IP-CIDR,172.16.0.0/12,DIRECT'
+  - 'IP-CIDR,192.168.0.0/16,DIRECT'
+  - 'IP-CIDR,127.0.0.0/8,DIRECT'
+  - 'MATCH,PROXY'
+"#;
+
+pub struct Answers {
+    pub tun_name: String,
+    pub dns_listen: String,
+    pub dns_server: String,
+    /// A share URI, the sample server when `None`.
+    pub server: Option<String>,
+}
+
+impl Default for Answers {
+    fn default() -> Self {
+        Answers {
+            tun_name: DEFAULT_TUN_NAME.to_string(),
+            dns_listen: DEFAULT_DNS_LISTEN.to_string(),
+            dns_server: DEFAULT_DNS_SERVER.to_string(),
+            server: None,
+        }
+    }
+}
+
+pub fn generate(answers: &Answers) -> Result<String, String> {
+    let servers = match &answers.server {
+        Some(uri) => servers_yaml(&[share_uri::parse(uri)?]),
+        None => SAMPLE_SERVER.to_string(),
+    };
+    Ok(format!(
+        r#"# Generated by `seeker init`, the README documents all settings.
+dns_start_ip: 11.0.0.10
+dns_servers:
+  - {dns_server}
+dns_timeout: 1s
+tun_name: {tun_name}
+tun_ip: 11.0.0.1
+tun_cidr: 11.0.0.0/16
+dns_listen: {dns_listen}
+ping_timeout: 2s
+connect_timeout: 2s
+read_timeout: 300s
+write_timeout: 300s
+max_connect_errors: 2
+
+{servers}
+{rules}"#,
+        dns_server = answers.dns_server,
+        tun_name = answers.tun_name,
+        dns_listen = answers.dns_listen,
+        servers = servers,
+        rules = RULES,
+    ))
+}
+
+/// The answer to `question`, `default` for an empty line or at the end of the input.
+fn ask<R: BufRead>(input: &mut R, question: &str, default: &str) -> io::Result<String> {
+    print!("{} [{}]: ", question, default);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { default } else { answer }.to_string())
+}
+
+fn ask_all<R: BufRead>(input: &mut R) -> io::Result<Answers> {
+    let mut answers = Answers {
+        tun_name: ask(input, "tun device name", DEFAULT_TUN_NAME)?,
+        dns_listen: ask(input, "DNS listen address", DEFAULT_DNS_LISTEN)?,
+        dns_server: ask(input, "upstream DNS server", DEFAULT_DNS_SERVER)?,
+        server: None,
+    };
+    loop {
+        let uri = ask(
+            input,
+            "server share URI (ss://, socks5://, http://), none for a sample",
+            "none",
+        )?;
+        if uri == "none" {
+            return Ok(answers);
+        }
+        match share_uri::parse(&uri) {
+            Ok(_) => {
+                answers.server = Some(uri);
+                return Ok(answers);
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+/// `seeker init`: write a starter config to `path`, with the defaults when `defaults`.
+pub fn run_init(path: &str, force: bool, defaults: bool) -> anyhow::Result<()> {
+    if Path::new(path).exists() && !force {
+        anyhow::bail!("{} exists, pass --force to overwrite it", path);
+    }
+    let answers = if defaults {
+        Answers::default()
+    } else {
+        ask_all(&mut io::stdin().lock())?
+    };
+    let text = generate(&answers).map_err(anyhow::Error::msg)?;
+    std::fs::write(path, text)?;
+    println!("wrote {}", path);
+    if answers.server.is_none() {
+        println!("replace the sample server in it with yours");
+    }
+    println!(
+        "check it with `seeker --config {0} check`, start with `sudo seeker --config {0}`",
+        path
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+
+    #[test]
+    fn test_generate() {
+        let text = generate(&Answers::default()).unwrap();
+        let config = Config::from_reader(text.as_bytes()).unwrap();
+        assert_eq!(config.tun_name, DEFAULT_TUN_NAME);
+        assert_eq!(config.servers[0].name(), "server1");
+
+        let mut input = io::Cursor::new("\n\n\ntrojan://a@b.com:443\nsocks5://10.0.0.1:1080#lan\n");
+        let answers = ask_all(&mut input).unwrap();
+        assert_eq!(answers.dns_listen, DEFAULT_DNS_LISTEN);
+        let config = Config::from_reader(generate(&answers).unwrap().as_bytes()).unwrap();
+        assert_eq!(config.servers[0].name(), "lan");
+    }
+}
@@ -0,0 +1,354 @@
+//! Interactive mode: connections no explicit rule matches are held until a prompt client,
+//! talking to the management API, decides what to do with them.
+//!
+//! Decisions can be remembered. Remembered decisions apply to later connections and are
+//! appended to `interactive_rules_file` as `DOMAIN,example.com,DIRECT` lines, or
+//! `IP-CIDR,1.2.3.4/32,DIRECT` for connections to ips, ready to be moved into the rules of
+//! the config.
+use crate::api::client_request;
+use async_std::channel::{bounded, Sender};
+use async_std::io::timeout;
+use async_std::task::spawn_blocking;
+use config::rule::Action;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Prompt {
+    pub id: u64,
+    pub src: SocketAddr,
+    /// Domain of the connection, its ip when it has none.
+    pub domain: String,
+    pub port: u16,
+    /// What the rules would do with the connection.
+    pub rule_action: String,
+    pub waiting_ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Decision {
+    pub id: u64,
+    /// `DIRECT`, `PROXY`, `PROBE` or `REJECT`.
+    pub action: String,
+    #[serde(default)]
+    pub remember: bool,
+}
+
+struct Pending {
+    prompt: Prompt,
+    since: Instant,
+    reply: Sender<Action>,
+}
+
+pub struct Prompter {
+    enabled: bool,
+    timeout: Duration,
+    rules_file: Option<String>,
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, Pending>>,
+    remembered: Mutex<HashMap<String, Action>>,
+}
+
+pub fn parse_action(s: &str) -> Option<Action> {
+    match s {
+        "DIRECT" => Some(Action::Direct),
+        "PROXY" => Some(Action::Proxy),
+        "PROBE" => Some(Action::Probe),
+        "REJECT" => Some(Action::Reject),
+        _ => None,
+    }
+}
+
+fn rule_line(host: &str, action: Action) -> String {
+    let action = action.to_string().to_uppercase();
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => format!("IP-CIDR,{}/32,{}", ip, action),
+        Ok(IpAddr::V6(ip)) => format!("IP-CIDR6,{}/128,{}", ip, action),
+        Err(_) => format!("DOMAIN,{},{}", host, action),
+    }
+}
+
+/// The host and action of a line written by `rule_line`.
+fn parse_rule_line(line: &str) -> Option<(&str, Action)> {
+    let segments: Vec<&str> = line.trim().split(',').collect();
+    let (host, action) = match segments.as_slice() {
+        ["DOMAIN", domain, action] => (*domain, action),
+        ["IP-CIDR", cidr, action] => (cidr.strip_suffix("/32")?, action),
+        ["IP-CIDR6", cidr, action] => (cidr.strip_suffix("/128")?, action),
+        _ => return None,
+    };
+    Some((host, parse_action(action)?))
+}
+
+impl Prompter {
+    pub fn new(enabled: bool, timeout: Duration, rules_file: Option<String>) -> Self {
+        let mut remembered = HashMap::new();
+        if let Some(path) = &rules_file {
+            match std::fs::read_to_string(path) {
+                Ok(content) => {
+                    for (host, action) in content.lines().filter_map(parse_rule_line) {
+                        remembered.insert(host.to_string(), action);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => error!(?e, %path, "read interactive rules"),
+            }
+        }
+        Prompter {
+            enabled,
+            timeout,
+            rules_file,
+            next_id: AtomicU64::new(1),
+            pending: Mutex::new(HashMap::new()),
+            remembered: Mutex::new(remembered),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Wait for a decision about the connection from `src` to `domain`, or to an ip.
+    ///
+    /// Connections nobody decides on within the timeout are rejected.
+    pub async fn decide(
+        &self,
+        src: SocketAddr,
+        domain: &str,
+        port: u16,
+        rule_action: Action,
+    ) -> Action {
+        if let Some(action) = self.remembered.lock().get(domain) {
+            return *action;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = bounded(1);
+        let prompt = Prompt {
+            id,
+            src,
+            domain: domain.to_string(),
+            port,
+            rule_action: rule_action.to_string().to_uppercase(),
+            waiting_ms: 0,
+        };
+        self.pending.lock().insert(
+            id,
+            Pending {
+                prompt,
+                since: Instant::now(),
+                reply: sender,
+            },
+        );
+        let decision = timeout(self.timeout, async {
+            receiver
+                .recv()
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+        })
+        .await;
+        self.pending.lock().remove(&id);
+        decision.unwrap_or(Action::Reject)
+    }
+
+    /// Connections waiting for a decision, oldest first.
+    pub fn pending(&self) -> Vec<Prompt> {
+        let mut prompts: Vec<Prompt> = self
+            .pending
+            .lock()
+            .values()
+            .map(|p| Prompt {
+                waiting_ms: p.since.elapsed().as_millis() as u64,
+                ..p.prompt.clone()
+            })
+            .collect();
+        prompts.sort_by_key(|p| p.id);
+        prompts
+    }
+
+    /// Apply `decision`, returns false if the prompt is unknown or already expired.
+    pub async fn resolve(&self, decision: &Decision) -> io::Result<bool> {
+        let action = parse_action(&decision.action).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid action: {}", decision.action),
+            )
+        })?;
+        let pending = match self.pending.lock().remove(&decision.id) {
+            Some(p) => p,
+            None => return Ok(false),
+        };
+        let _ = pending.reply.try_send(action);
+        if decision.remember {
+            self.remember(&pending.prompt.domain, action).await?;
+        }
+        Ok(true)
+    }
+
+    async fn remember(&self, domain: &str, action: Action) -> io::Result<()> {
+        self.remembered.lock().insert(domain.to_string(), action);
+        let rule = rule_line(domain, action);
+        info!(%rule, "interactive decision remembered");
+        if let Some(path) = self.rules_file.clone() {
+            spawn_blocking(move || {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", rule)
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Remembered decisions as rules.
+    pub fn rules(&self) -> Vec<String> {
+        let mut rules: Vec<String> = self
+            .remembered
+            .lock()
+            .iter()
+            .map(|(domain, action)| rule_line(domain, *action))
+            .collect();
+        rules.sort();
+        rules
+    }
+}
+
+/// `seeker prompt`: ask on the terminal about the connections waiting at the API on `api`.
+///
+/// Answer `d`, `p` or `r` for DIRECT, PROXY or REJECT, uppercase to remember the decision.
+pub fn run_prompt_client(api: &str, secret: Option<&str>) -> anyhow::Result<()> {
+    let stdin = io::stdin();
+    loop {
+        let body = client_request("GET", api, "/prompts", secret)
+            .call()
+            .into_string()?;
+        let prompts: Vec<Prompt> = serde_json::from_str(&body)?;
+        if prompts.is_empty() {
+            sleep(Duration::from_millis(500));
+            continue;
+        }
+        for prompt in prompts {
+            print!(
+                "{} -> {}:{} (rules: {}) [d]irect/[p]roxy/[r]eject, uppercase to remember: ",
+                prompt.src, prompt.domain, prompt.port, prompt.rule_action
+            );
+            io::stdout().flush()?;
+            let mut answer = String::new();
+            if stdin.lock().read_line(&mut answer)? == 0 {
+                return Ok(());
+            }
+            let answer = answer.trim();
+            let action = match answer.to_lowercase().as_str() {
+                "d" => "DIRECT",
+                "p" => "PROXY",
+                "r" => "REJECT",
+                _ => {
+                    println!("skipped");
+                    continue;
+                }
+            };
+            let decision = serde_json::json!({
+                "id": prompt.id,
+                "action": action,
+                "remember": answer.chars().all(|c| c.is_uppercase()),
+            });
+            let resp =
+                client_request("POST", api, "/prompts", secret).send_string(&decision.to_string());
+            if resp.status() == 404 {
+                println!("expired");
+            } else if !resp.ok() {
+                println!("error: {}", resp.into_string()?);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::{block_on, spawn};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_decide() {
+        let prompter = Arc::new(Prompter::new(true, Duration::from_secs(5), None));
+        let src: SocketAddr = "10.0.0.2:50000".parse().unwrap();
+        block_on(async {
+            let p = prompter.clone();
+            let held = spawn(async move { p.decide(src, "example.com", 443, Action::Proxy).await });
+            while prompter.pending().is_empty() {
+                async_std::task::yield_now().await;
+            }
+            let prompt = &prompter.pending()[0];
+            assert_eq!(prompt.domain, "example.com");
+            assert_eq!(prompt.rule_action, "PROXY");
+            let decision = Decision {
+                id: prompt.id,
+                action: "DIRECT".to_string(),
+                remember: true,
+            };
+            assert!(prompter.resolve(&decision).await.unwrap());
+            assert_eq!(held.await, Action::Direct);
+            assert!(!prompter.resolve(&decision).await.unwrap());
+            assert_eq!(prompter.rules(), vec!["DOMAIN,example.com,DIRECT"]);
+            assert_eq!(
+                prompter.decide(src, "example.com", 80, Action::Proxy).await,
+                Action::Direct
+            );
+        });
+
+        let prompter = Prompter::new(true, Duration::from_millis(10), None);
+        let action = block_on(prompter.decide(src, "example.org", 443, Action::Proxy));
+        assert_eq!(action, Action::Reject);
+        assert!(prompter.pending().is_empty());
+    }
+
+    #[test]
+    fn test_rules_file() {
+        let path = std::env::temp_dir().join(format!("seeker-prompt-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        let prompter = Arc::new(Prompter::new(
+            true,
+            Duration::from_secs(5),
+            Some(path.clone()),
+        ));
+        let src: SocketAddr = "10.0.0.2:50000".parse().unwrap();
+        block_on(async {
+            let p = prompter.clone();
+            let held = spawn(async move { p.decide(src, "example.com", 443, Action::Proxy).await });
+            while prompter.pending().is_empty() {
+                async_std::task::yield_now().await;
+            }
+            let decision = Decision {
+                id: prompter.pending()[0].id,
+                action: "REJECT".to_string(),
+                remember: true,
+            };
+            assert!(prompter.resolve(&decision).await.unwrap());
+            assert_eq!(held.await, Action::Reject);
+        });
+        block_on(prompter.remember("1.2.3.4", Action::Direct)).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            content,
+            "DOMAIN,example.com,REJECT\nIP-CIDR,1.2.3.4/32,DIRECT\n"
+        );
+        let reloaded = Prompter::new(true, Duration::from_secs(5), Some(path.clone()));
+        assert_eq!(
+            reloaded.rules(),
+            vec!["DOMAIN,example.com,REJECT", "IP-CIDR,1.2.3.4/32,DIRECT"]
+        );
+        assert_eq!(
+            block_on(reloaded.decide(src, "1.2.3.4", 443, Action::Proxy)),
+            Action::Direct
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+//! What a running seeker holds, for `GET /debug/runtime`, to find where the memory of a
+//! long-running instance goes.
+use crate::connections::ConnectionTracker;
+use crate::heap::{self, HeapStats};
+use crate::udp_queue::UdpQueue;
+use async_std::task::JoinHandle;
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::Arc;
+use tun_nat::SessionManager;
+
+static TASKS: Lazy<Mutex<BTreeMap<&'static str, u64>>> = Lazy::new(Default::default);
+
+/// Counts a running task of `kind` until dropped.
+pub struct TaskGuard(&'static str);
+
+impl TaskGuard {
+    pub fn new(kind: &'static str) -> Self {
+        *TASKS.lock().entry(kind).or_insert(0) += 1;
+        TaskGuard(kind)
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if let Some(n) = TASKS.lock().get_mut(self.0) {
+            *n -= 1;
+        }
+    }
+}
+
+/// `async_std::task::spawn`, counting the task under `kind` while it runs.
+pub fn spawn<F>(kind: &'static str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let guard = TaskGuard::new(kind);
+    async_std::task::spawn(async move {
+        let _guard = guard;
+        future.await
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuntimeStats {
+    /// Running tasks by kind.
+    pub tasks: BTreeMap<&'static str, u64>,
+    /// Entries of the tun NAT table.
+    pub nat_sessions: usize,
+    pub udp_associations: usize,
+    /// Datagrams waiting in the send queues of the UDP associations.
+    pub udp_queued_datagrams: usize,
+    pub live_connections: usize,
+    /// `None` unless built with the `heap-stats` feature.
+    pub heap: Option<HeapStats>,
+}
+
+#[derive(Clone)]
+pub struct Introspect {
+    session_manager: SessionManager,
+    udp_manager: Arc<RwLock<HashMap<u16, UdpQueue>>>,
+    connections: Arc<ConnectionTracker>,
+}
+
+impl Introspect {
+    pub fn new(
+        session_manager: SessionManager,
+        udp_manager: Arc<RwLock<HashMap<u16, UdpQueue>>>,
+        connections: Arc<ConnectionTracker>,
+    ) -> Self {
+        Introspect {
+            session_manager,
+            udp_manager,
+            connections,
+        }
+    }
+
+    pub fn snapshot(&self) -> RuntimeStats {
+        let (udp_associations, udp_queued_datagrams) = {
+            let udp = self.udp_manager.read();
+            (udp.len(), udp.values().map(UdpQueue::queued).sum())
+        };
+        RuntimeStats {
+            tasks: TASKS.lock().clone(),
+            nat_sessions: self.session_manager.len(),
+            udp_associations,
+            udp_queued_datagrams,
+            live_connections: self.connections.live(),
+            heap: heap::stats(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+
+    #[test]
+    fn test_task_count() {
+        let count = || TASKS.lock().get("test_task_count").copied();
+        let guard = TaskGuard::new("test_task_count");
+        assert_eq!(count(), Some(1));
+        block_on(spawn("test_task_count", async move {
+            assert_eq!(count(), Some(2));
+        }));
+        drop(guard);
+        assert_eq!(count(), Some(0));
+    }
+}
@@ -1,10 +1,18 @@
 use file_rotate::{FileRotate, RotationMode};
+use std::collections::HashMap;
 use std::error::Error;
 use std::io;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::callsite::Identifier;
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
 #[derive(Clone)]
 struct TracingWriter {
     file_rotate: Arc<Mutex<FileRotate>>,
@@ -28,12 +36,122 @@ impl io::Write for TracingWriter {
     }
 }
 
-pub fn setup_logger(log_path: Option<&str>) -> Result<(), Box<dyn Error>> {
-    let env_filter = EnvFilter::new("seeker=trace")
-        .add_directive("dnsserver=debug".parse()?)
-        .add_directive("seeker=trace".parse()?)
-        .add_directive("sysconfig=info".parse()?)
-        .add_directive("tun_nat=info".parse()?);
+/// Events of one callsite in the current window.
+struct Window {
+    start: Instant,
+    count: usize,
+    suppressed: usize,
+    /// Suppressed in the previous window and not reported yet.
+    unreported: usize,
+}
+
+impl Window {
+    fn new(now: Instant) -> Self {
+        Window {
+            start: now,
+            count: 0,
+            suppressed: 0,
+            unreported: 0,
+        }
+    }
+
+    /// Whether one more event fits in `limit` per window.
+    fn allow(&mut self, now: Instant, limit: usize) -> bool {
+        if now.duration_since(self.start) >= RATE_WINDOW {
+            self.unreported += self.suppressed;
+            self.start = now;
+            self.count = 0;
+            self.suppressed = 0;
+        }
+        if self.count < limit {
+            self.count += 1;
+            true
+        } else {
+            self.suppressed += 1;
+            false
+        }
+    }
+}
+
+/// Lets through at most `limit` debug and trace events per second from each callsite, so
+/// turning on per-packet logs on a busy gateway does not slow it down. How many were
+/// dropped is logged after the next event of the callsite let through.
+struct RateLimit {
+    limit: usize,
+    windows: Mutex<HashMap<Identifier, Window>>,
+}
+
+impl RateLimit {
+    fn new(limit: usize) -> Self {
+        RateLimit {
+            limit,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_limited(&self, metadata: &Metadata<'_>) -> bool {
+        self.limit > 0
+            && metadata.is_event()
+            && matches!(*metadata.level(), Level::DEBUG | Level::TRACE)
+    }
+}
+
+impl<S: Subscriber> Layer<S> for RateLimit {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if self.is_limited(metadata) {
+            // Ask for every event, the answer changes over time.
+            Interest::sometimes()
+        } else {
+            Interest::always()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        if !self.is_limited(metadata) {
+            return true;
+        }
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        windows
+            .entry(metadata.callsite())
+            .or_insert_with(|| Window::new(now))
+            .allow(now, self.limit)
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if !self.is_limited(metadata) {
+            return;
+        }
+        let suppressed = match self.windows.lock().unwrap().get_mut(&metadata.callsite()) {
+            Some(window) => std::mem::take(&mut window.unreported),
+            None => 0,
+        };
+        if suppressed > 0 {
+            tracing::info!(
+                callsite = metadata.name(),
+                suppressed,
+                "suppressed messages over the log rate limit"
+            );
+        }
+    }
+}
+
+/// Log to `log_path`, or the terminal, with the filter `log_level` instead of the defaults.
+pub fn setup_logger(
+    log_path: Option<&str>,
+    log_level: Option<&str>,
+    rate_limit: usize,
+) -> Result<(), Box<dyn Error>> {
+    let env_filter = match log_level {
+        Some(filter) => EnvFilter::try_new(filter)?,
+        None => EnvFilter::new("seeker=trace")
+            .add_directive("dnsserver=debug".parse()?)
+            .add_directive("seeker=trace".parse()?)
+            .add_directive("ssclient::frames=info".parse()?)
+            .add_directive("sysconfig=info".parse()?)
+            .add_directive("tun_nat=info".parse()?),
+    };
 
     if let Some(log_path) = log_path {
         if let Some(path) = PathBuf::from(log_path).parent() {
@@ -48,14 +166,16 @@ pub fn setup_logger(log_path: Option<&str>) -> Result<(), Box<dyn Error>> {
             .with_env_filter(env_filter)
             .with_ansi(false)
             .with_writer(move || TracingWriter::new(logger.clone()))
-            .finish();
+            .finish()
+            .with(RateLimit::new(rate_limit));
         tracing::subscriber::set_global_default(my_subscriber)
             .expect("setting tracing default failed");
     } else {
         let subscriber = FmtSubscriber::builder()
             .with_env_filter(env_filter)
             .compact()
-            .finish();
+            .finish()
+            .with(RateLimit::new(rate_limit));
 
         tracing::subscriber::set_global_default(subscriber)
             .expect("setting tracing default failed");
@@ -88,3 +208,23 @@ pub fn setup_logger(log_path: Option<&str>) -> Result<(), Box<dyn Error>> {
     } // only for #[cfg]
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_window() {
+        let start = Instant::now();
+        let mut window = Window::new(start);
+        assert!(window.allow(start, 2));
+        assert!(window.allow(start, 2));
+        assert!(!window.allow(start, 2));
+        assert!(!window.allow(start + Duration::from_millis(999), 2));
+        assert_eq!(window.suppressed, 2);
+
+        assert!(window.allow(start + RATE_WINDOW, 2));
+        assert_eq!(window.suppressed, 0);
+        assert_eq!(window.unreported, 2);
+    }
+}
@@ -21,3 +21,21 @@ macro_rules! retry_timeout {
         }
     };
 }
+
+/// Like `retry_timeout!` for futures that time out by themselves.
+macro_rules! retry {
+    ($retries: expr, $fut: expr) => {
+        async {
+            let mut retries: usize = $retries;
+            loop {
+                match $fut.await {
+                    Err(e) if e.kind() == std::io::ErrorKind::TimedOut && retries > 0 => {
+                        tracing::warn!("retry: {}", $retries - retries);
+                    }
+                    ret => break ret,
+                }
+                retries -= 1;
+            }
+        }
+    };
+}
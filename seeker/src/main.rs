@@ -1,29 +1,79 @@
 #![type_length_limit = "2374570"]
 #[macro_use]
 mod macros;
+mod alt_svc;
+mod api;
+mod check;
+mod clash_api;
 mod config_encryptor;
+mod conn_events;
+mod connections;
 mod dns_client;
+mod dns_forward;
+#[cfg(feature = "dns-inbound")]
+mod dns_inbound;
+mod export;
+mod features;
+mod heap;
+mod import;
+mod init;
+mod interactive;
+mod introspect;
 mod logger;
+mod metrics;
+#[cfg(unix)]
+mod mtu;
+#[cfg(unix)]
+mod network_watch;
+mod outbound;
+mod priority;
+mod process_lookup;
 mod proxy_client;
 mod proxy_connection;
+mod proxy_group;
 mod proxy_tcp_stream;
 mod proxy_udp_socket;
+mod quarantine;
+mod reject;
+mod relay;
+mod reload;
+mod remote;
+mod rule_providers;
+mod rule_stats;
+mod rule_test;
+mod script;
 mod server_chooser;
+mod shutdown;
+mod sniff;
+mod supervisor;
+#[cfg(test)]
+mod test_config;
 mod traffic;
+mod udp_queue;
 
 use std::error::Error;
 
 use crate::logger::setup_logger;
-use crate::proxy_client::ProxyClient;
+use crate::proxy_client::{run_dns_only, ProxyClient};
+use crate::shutdown::shutdown_signal;
 use anyhow::Context;
-use async_signals::Signals;
-use async_std::prelude::{FutureExt, StreamExt};
+use async_std::prelude::FutureExt;
 use async_std::task::block_on;
-use clap::{App, Arg};
-use config::Config;
+use clap::{App, Arg, SubCommand};
+use config::{error_code, Address, Config, DnsServerAddr, Format, Mode, Overrides};
 use crypto::CipherType;
 use std::fs::File;
-use sysconfig::{set_rlimit_no_file, DNSSetup, IpForward};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::sync::Arc;
+#[cfg(unix)]
+use sysconfig::{set_rlimit_no_file, IpForward};
+use sysconfig::{AutoRoute, DNSSetup, KillSwitchFirewall};
+
+#[cfg(feature = "heap-stats")]
+#[global_allocator]
+static ALLOCATOR: heap::CountingAllocator = heap::CountingAllocator;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let version = env!("CARGO_PKG_VERSION");
@@ -43,7 +93,50 @@ fn main() -> Result<(), Box<dyn Error>> {
             Arg::with_name("config-url")
                 .long("config-url")
                 .value_name("CONFIG_URL")
-                .help("URL to config")
+                .help("URL to config, decrypted with --key when given")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("config-cache")
+                .long("config-cache")
+                .value_name("FILE")
+                .help("Keep the config downloaded from --config-url here, to start with it when the download fails")
+                .requires("config-url")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .value_name("NAME")
+                .help("Start with this one of the config's profiles instead of the one it names")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("tun-name")
+                .long("tun-name")
+                .value_name("NAME")
+                .help("Use this tun device instead of the config's tun_name")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("tun-fd")
+                .long("tun-fd")
+                .value_name("FD|PATH")
+                .help("Use the tun opened as this file descriptor, or received over this unix socket")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("dns-listen")
+                .long("dns-listen")
+                .value_name("ADDR")
+                .help("Serve DNS on this address instead of the config's dns_listen")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("group")
+                .long("group")
+                .value_name("GROUP")
+                .help("Send connections no rule matches through this proxy group instead of the config's final")
                 .required(false),
         )
         .arg(
@@ -75,8 +168,239 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .help("Log file")
                 .required(false),
         )
+        .arg(
+            Arg::with_name("api-secret")
+                .long("api-secret")
+                .value_name("SECRET")
+                .help("api_secret of the management API, for the subcommands calling it")
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("FILTER")
+                .help("Log filter like `info` or `seeker=debug,dnsserver=info`, instead of the defaults")
+                .required(false),
+        )
+        .subcommand(
+            SubCommand::with_name("features")
+                .about("Print the protocols and optional features this binary was built with"),
+        )
+        .subcommand(
+            SubCommand::with_name("schema")
+                .about("Print the JSON Schema of the config, for editors to validate configs with"),
+        )
+        .subcommand(
+            SubCommand::with_name("prompt")
+                .about("Decide on the connections held by interactive mode")
+                .arg(
+                    Arg::with_name("api")
+                        .long("api")
+                        .value_name("ADDR")
+                        .help("Management API address")
+                        .default_value("127.0.0.1:9000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rules")
+                .about("Print how often each rule matched, or the recent routing decisions")
+                .arg(
+                    Arg::with_name("api")
+                        .long("api")
+                        .value_name("ADDR")
+                        .help("Management API address")
+                        .default_value("127.0.0.1:9000"),
+                )
+                .arg(
+                    Arg::with_name("decisions")
+                        .long("decisions")
+                        .help("Print the recent decisions instead of the hit counts"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .long("host")
+                        .value_name("HOST")
+                        .help("Only decisions for hosts containing HOST")
+                        .requires("decisions"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("explain")
+                .about("Explain an error code like SEEKER-E1001, or list all codes")
+                .arg(
+                    Arg::with_name("code")
+                        .value_name("CODE")
+                        .help("Error code, with or without the SEEKER-E prefix"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Check the config file given by --config and print its errors with their lines"),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Ask a few questions and write a starter config to --config, config.yml by default")
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Overwrite the file when it exists"),
+                )
+                .arg(
+                    Arg::with_name("defaults")
+                        .long("defaults")
+                        .help("Write the defaults without asking"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Print the servers, proxy groups and rules of the config in another format")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["clash"])
+                        .default_value("clash")
+                        .help("Format to export to"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Print servers entries for share URIs, or for a subscription read from stdin")
+                .arg(
+                    Arg::with_name("uri")
+                        .value_name("URI")
+                        .help("ss://, socks5://, http:// or https:// URI")
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("reload")
+                .about("Reload the config file of the running seeker, keeping the running config when it fails")
+                .arg(
+                    Arg::with_name("api")
+                        .long("api")
+                        .value_name("ADDR")
+                        .help("Management API address")
+                        .default_value("127.0.0.1:9000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("profile")
+                .about("Print the profiles of the running seeker, or switch it to another one")
+                .arg(
+                    Arg::with_name("name")
+                        .value_name("NAME")
+                        .help("Profile to switch to"),
+                )
+                .arg(
+                    Arg::with_name("api")
+                        .long("api")
+                        .value_name("ADDR")
+                        .help("Management API address")
+                        .default_value("127.0.0.1:9000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rule-test")
+                .about("Print the rule and outbound a connection would get, without starting the tun")
+                .arg(
+                    Arg::with_name("target")
+                        .value_name("TARGET")
+                        .help("Domain or ip, optionally with :port")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("port")
+                        .long("port")
+                        .value_name("PORT")
+                        .help("Destination port"),
+                )
+                .arg(
+                    Arg::with_name("process")
+                        .long("process")
+                        .value_name("NAME")
+                        .help("Name of the process making the connection"),
+                )
+                .arg(
+                    Arg::with_name("uid")
+                        .long("uid")
+                        .value_name("UID")
+                        .help("User id of the process making the connection"),
+                )
+                .arg(
+                    Arg::with_name("network")
+                        .long("network")
+                        .value_name("NETWORK")
+                        .possible_values(&["tcp", "udp"])
+                        .help("Transport of the connection"),
+                ),
+        )
         .get_matches();
 
+    if matches.subcommand_matches("features").is_some() {
+        features::print_features();
+        return Ok(());
+    }
+    if matches.subcommand_matches("schema").is_some() {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&config::schema::schema())?
+        );
+        return Ok(());
+    }
+    if let Some(matches) = matches.subcommand_matches("prompt") {
+        interactive::run_prompt_client(
+            matches.value_of("api").unwrap(),
+            matches.value_of("api-secret"),
+        )?;
+        return Ok(());
+    }
+    if let Some(matches) = matches.subcommand_matches("rules") {
+        rule_stats::run_rules_client(
+            matches.value_of("api").unwrap(),
+            matches.value_of("api-secret"),
+            matches.is_present("decisions"),
+            matches.value_of("host"),
+        )?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("explain") {
+        match matches.value_of("code") {
+            Some(code) => match error_code::lookup(code) {
+                Some(entry) => print!("{}", entry.explain()),
+                None => return Err(format!("unknown error code {}", code).into()),
+            },
+            None => {
+                for entry in error_code::CATALOG {
+                    println!("{}  {}", entry.code, entry.summary);
+                }
+            }
+        }
+        return Ok(());
+    }
+    if let Some(matches) = matches.subcommand_matches("import") {
+        let uris: Vec<&str> = matches.values_of("uri").into_iter().flatten().collect();
+        import::run_import(&uris)?;
+        return Ok(());
+    }
+    if let Some(matches) = matches.subcommand_matches("reload") {
+        reload::run_reload_client(
+            matches.value_of("api").unwrap(),
+            matches.value_of("api-secret"),
+        )?;
+        return Ok(());
+    }
+    if let Some(matches) = matches.subcommand_matches("profile") {
+        reload::run_profile_client(
+            matches.value_of("api").unwrap(),
+            matches.value_of("api-secret"),
+            matches.value_of("name"),
+        )?;
+        return Ok(());
+    }
+
     let path = matches.value_of("config");
     let key = matches.value_of("key");
     let to_encrypt = matches.is_present("encrypt");
@@ -87,19 +411,132 @@ fn main() -> Result<(), Box<dyn Error>> {
         );
         return Ok(());
     }
+    if let Some(matches) = matches.subcommand_matches("init") {
+        init::run_init(
+            path.unwrap_or("config.yml"),
+            matches.is_present("force"),
+            matches.is_present("defaults"),
+        )?;
+        return Ok(());
+    }
+    if matches.subcommand_matches("check").is_some() {
+        let path = path.ok_or("seeker check needs --config")?;
+        check::run_check(path)?;
+        return Ok(());
+    }
     let config_url = matches.value_of("config-url");
-    let config = load_config(path, config_url, key)?;
+    let profile = matches.value_of("profile");
+    let config_cache = matches.value_of("config-cache");
+    let overrides = Overrides {
+        tun_name: matches.value_of("tun-name").map(str::to_string),
+        tun_fd: matches
+            .value_of("tun-fd")
+            .map(|fd| fd.parse().expect("parse tun fd")),
+        dns_listen: matches.value_of("dns-listen").map(str::to_string),
+        group: matches.value_of("group").map(str::to_string),
+    };
+    let config = load_config(path, config_url, key, config_cache, profile)?;
+    let mut config = overrides
+        .apply(config)
+        .context("Apply command line settings error")?;
+
+    if let Some(matches) = matches.subcommand_matches("rule-test") {
+        let target = rule_test::Target {
+            host: matches.value_of("target").unwrap(),
+            port: matches.value_of("port").map(str::parse).transpose()?,
+            process_name: matches.value_of("process"),
+            uid: matches.value_of("uid").map(str::parse).transpose()?,
+            network: matches.value_of("network"),
+        };
+        print!("{}", rule_test::explain(&config, &target));
+        return Ok(());
+    }
+    if matches.subcommand_matches("export").is_some() {
+        print!("{}", export::clash_yaml(&config));
+        return Ok(());
+    }
 
     let uid = matches.value_of("user_id").map(|uid| uid.parse().unwrap());
     let log_path = matches.value_of("log");
+    let log_level = matches.value_of("log-level");
 
-    setup_logger(log_path)?;
+    setup_logger(log_path, log_level, config.log_rate_limit)?;
 
-    let mut signals = Signals::new(vec![libc::SIGINT, libc::SIGTERM]).unwrap();
+    let shutdown = shutdown_signal()?;
 
+    #[cfg(unix)]
     set_rlimit_no_file(10240)?;
 
-    let _dns_setup = DNSSetup::new("".to_string());
+    if config.mode == Mode::DnsOnly {
+        block_on(run_dns_only(config).race(shutdown));
+        println!("Stop server. Bye bye...");
+        return Ok(());
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    if config.mode == Mode::Tproxy || config.mode == Mode::Redirect {
+        return Err(
+            anyhow::anyhow!("modes tproxy and redirect are only supported on Linux").into(),
+        );
+    }
+    #[cfg(not(target_os = "linux"))]
+    if config.tun_persistent || config.user.is_some() {
+        return Err(anyhow::anyhow!("tun_persistent and user are only supported on Linux").into());
+    }
+    #[cfg(not(target_os = "linux"))]
+    if config.tun_queues > 1 {
+        return Err(anyhow::anyhow!("tun_queues is only supported on Linux").into());
+    }
+    #[cfg(windows)]
+    if config.gateway_mode {
+        return Err(anyhow::anyhow!("gateway_mode is not supported on Windows").into());
+    }
+    // With tproxy seeker runs on the router, which keeps its own DNS settings, and whoever
+    // set up a preconfigured tun points the DNS at seeker.
+    let takes_over_dns =
+        config.system_dns && config.mode != Mode::Tproxy && !config.tun_preconfigured();
+    let resolved_link = if takes_over_dns {
+        resolved_link(&config)
+    } else {
+        None
+    };
+    let dns_setup = if takes_over_dns && resolved_link.is_none() {
+        Some(DNSSetup::new("".to_string()))
+    } else {
+        None
+    };
+    let original_dns = match (&dns_setup, &resolved_link) {
+        (Some(dns_setup), _) => dns_setup.original_dns().to_vec(),
+        (None, Some(_)) => resolved_upstream_dns(),
+        (None, None) => vec![],
+    };
+    if config.lan_dns.is_empty() {
+        // Keep LAN names resolvable by the resolvers the network handed out.
+        config.lan_dns = original_dns
+            .iter()
+            .filter_map(|ip| ip.parse::<IpAddr>().ok())
+            .map(|ip| SocketAddr::new(ip, 53))
+            .collect();
+    }
+    // Route seeker's own marked sockets around the tun.
+    #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd"))]
+    let _bypass_rule = sysconfig::BypassRule::new();
+    #[cfg(target_os = "linux")]
+    let _tproxy_route = if config.mode == Mode::Tproxy {
+        Some(sysconfig::TproxyRoute::new())
+    } else {
+        None
+    };
+    bind_outbound_interface();
+    let _kill_switch = if config.kill_switch {
+        Some(KillSwitchFirewall::new(
+            &config.tun_name,
+            &direct_ips(&config),
+        )?)
+    } else {
+        None
+    };
+    #[cfg(unix)]
     let _ip_forward = if config.gateway_mode {
         // In gateway mode, dns server need be accessible from the network.
         Some(IpForward::new())
@@ -107,48 +544,224 @@ fn main() -> Result<(), Box<dyn Error>> {
         None
     };
 
+    // The routes need the tun, which the client creates.
+    let auto_route_config = if config.auto_route {
+        Some(config.clone())
+    } else {
+        None
+    };
+    #[cfg(target_os = "linux")]
+    let user = config.user.clone();
+
     block_on(async {
-        let client = ProxyClient::new(config, uid).await;
+        let client = Arc::new(
+            ProxyClient::new(
+                config,
+                path.map(str::to_string),
+                profile.map(str::to_string),
+                overrides,
+                uid,
+            )
+            .await?,
+        );
+        let _auto_route = auto_route_config
+            .as_ref()
+            .map(auto_route)
+            .transpose()
+            .map_err(|e| error_code::ROUTE_SETUP.wrap(e))?;
+        #[cfg(target_os = "linux")]
+        let _resolved = match resolved_link {
+            Some((link, server)) => Some(
+                sysconfig::ResolvedDNS::new(&link, server)
+                    .context("Setup DNS with systemd-resolved error")?,
+            ),
+            None => None,
+        };
+        // Everything needing root is set up by now.
+        #[cfg(target_os = "linux")]
+        if let Some(user) = &user {
+            sysconfig::drop_privileges(user)
+                .with_context(|| format!("Drop privileges to user {}", user))?;
+        }
         client
             .run()
             .race(async {
-                signals.next().await.unwrap();
+                shutdown.await;
+                Ok(())
             })
-            .await;
-    });
+            .await?;
+        Ok::<_, anyhow::Error>(())
+    })?;
 
     println!("Stop server. Bye bye...");
     Ok(())
 }
 
+/// The tun and the address of seeker's DNS server to hand to systemd-resolved, when it
+/// manages `/etc/resolv.conf` and would undo changes to it. Set up once the tun exists.
+#[cfg(target_os = "linux")]
+fn resolved_link(config: &Config) -> Option<(String, SocketAddr)> {
+    if !config.mode.uses_tun() || !sysconfig::resolved_manages_dns() {
+        return None;
+    }
+    let mut server: SocketAddr = config.dns_listen.parse().ok()?;
+    // resolved sends queries for the link out of it, the address of the tun is local.
+    if server.ip().is_unspecified() {
+        server.set_ip(config.tun_ip.into());
+    }
+    Some((config.tun_name.clone(), server))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resolved_link(_config: &Config) -> Option<(String, SocketAddr)> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn resolved_upstream_dns() -> Vec<String> {
+    sysconfig::ResolvedDNS::upstream_dns()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resolved_upstream_dns() -> Vec<String> {
+    vec![]
+}
+
+/// On macOS seeker's sockets are marked by binding them to the default interface.
+#[cfg(target_os = "macos")]
+fn bind_outbound_interface() {
+    let interface = match sysconfig::default_interface() {
+        Some(interface) => interface,
+        None => {
+            tracing::warn!("no default route, outbound sockets are not bound to an interface");
+            return;
+        }
+    };
+    if let Err(e) = sysconfig::set_outbound_interface(&interface) {
+        tracing::warn!(?e, "can not bind outbound sockets to the default interface");
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn bind_outbound_interface() {}
+
 fn load_config(
     path: Option<&str>,
     url: Option<&str>,
     decrypt_key: Option<&str>,
+    cache: Option<&str>,
+    profile: Option<&str>,
 ) -> anyhow::Result<Config> {
     match (path, url, decrypt_key) {
-        (Some(p), ..) => Config::from_config_file(p).context("Load config from path error"),
-        (_, Some(url), Some(key)) => {
-            let resp = ureq::get(url)
-                .timeout_read(5000)
-                .timeout_connect(5000)
-                .timeout_write(5000)
-                .call();
-            if !resp.ok() {
-                return Err(anyhow::anyhow!(
-                    "Load config from remote host error: {}",
-                    resp.into_string().unwrap()
-                ));
-            }
-            let config =
-                config_encryptor::decrypt_config(resp.into_reader(), CipherType::ChaCha20Ietf, key)
-                    .context("Decrypt remote config error")?;
-            Config::from_reader(config.as_slice()).context("Load Config error")
+        (Some(p), ..) => {
+            reload::load_with_subscription(|| Config::from_config_file_with_profile(p, profile))
+                .context("Load config from path error")
+        }
+        (_, Some(url), key) => {
+            let body =
+                remote::fetch_config(url, cache).context("Load config from remote host error")?;
+            // The cache keeps the config as downloaded, encrypted with a key.
+            let config = match key {
+                Some(key) => {
+                    config_encryptor::decrypt_config(body.as_slice(), CipherType::ChaCha20Ietf, key)
+                        .context("Decrypt remote config error")?
+                }
+                None => body,
+            };
+            let config = String::from_utf8(config).context("Decrypt remote config error")?;
+            reload::load_with_subscription(|| {
+                Config::from_text_with_profile(
+                    &config,
+                    Format::of_path(url),
+                    Path::new("."),
+                    profile,
+                )
+            })
+            .context("Load Config error")
         }
         _ => Err(anyhow::anyhow!("Parameters error")),
     }
 }
 
+/// Private, link-local and CGNAT networks, which stay off the tun with `auto_route`.
+const LAN_NETWORKS: &[&str] = &[
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+/// Loopback and multicast networks. Routes can not take them off the tun, the system already
+/// has routes of its own for them, but the Linux policy rules and firewall rules have to
+/// leave them out, the `output` hook of redirect would catch localhost connections.
+#[cfg(target_os = "linux")]
+const LOCAL_NETWORKS: &[&str] = &["127.0.0.0/8", "224.0.0.0/4", "::1/128", "ff00::/8"];
+
+/// Routes into the tun, or the firewall rules of the tproxy and redirect modes, leaving
+/// out the addresses seeker talks to directly and the LAN. The tun routes of the fake ips
+/// are more specific than the LAN networks, so those still go through the tun.
+fn auto_route(config: &Config) -> io::Result<AutoRoute> {
+    let mut excluded: Vec<String> = direct_ips(config)
+        .iter()
+        .map(|ip| match ip {
+            IpAddr::V4(_) => format!("{}/32", ip),
+            IpAddr::V6(_) => format!("{}/128", ip),
+        })
+        .collect();
+    excluded.extend(LAN_NETWORKS.iter().map(|net| net.to_string()));
+    #[cfg(target_os = "linux")]
+    excluded.extend(LOCAL_NETWORKS.iter().map(|net| net.to_string()));
+    excluded.extend(config.auto_route_exclude.iter().cloned());
+    #[cfg(target_os = "linux")]
+    let port = |listen: &Option<String>| {
+        listen
+            .as_deref()
+            .and_then(|l| l.parse::<SocketAddr>().ok())
+            .map_or(0, |addr| addr.port())
+    };
+    match config.mode {
+        #[cfg(target_os = "linux")]
+        Mode::Tproxy => AutoRoute::tproxy(port(&config.tproxy_listen), &excluded),
+        #[cfg(target_os = "linux")]
+        Mode::Redirect => AutoRoute::redirect(port(&config.redirect_listen), &excluded),
+        _ => AutoRoute::tun(&config.tun_name, config.tun_ip6.is_some(), &excluded),
+    }
+}
+
+/// Addresses of the proxy servers, upstream and LAN DNS servers, which seeker talks to directly.
+fn direct_ips(config: &Config) -> Vec<IpAddr> {
+    let mut ips = vec![];
+    for server in config.servers.iter() {
+        match server.addr() {
+            Address::SocketAddress(addr) => ips.push(addr.ip()),
+            Address::DomainNameAddress(domain, port) => {
+                if let Ok(addrs) = (domain.as_str(), *port).to_socket_addrs() {
+                    ips.extend(addrs.map(|a| a.ip()))
+                }
+            }
+        }
+    }
+    for dns in &config.dns_servers {
+        match dns {
+            DnsServerAddr::UdpSocketAddr(addr) => ips.push(addr.ip()),
+            DnsServerAddr::TcpSocketAddr(url) => {
+                if let Some(ip) = url.host_str().and_then(|h| h.parse().ok()) {
+                    ips.push(ip)
+                }
+            }
+        }
+    }
+    ips.extend(config.lan_dns.iter().map(|addr| addr.ip()));
+    // The mDNS group, which never leaves the local link.
+    ips.push(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)));
+    ips.sort();
+    ips.dedup();
+    ips
+}
+
 fn encrypt_config(path: Option<&str>, encrypt_key: Option<&str>) -> anyhow::Result<String> {
     if let (Some(path), Some(key)) = (path, encrypt_key) {
         let file = File::open(&path).context("Open config error")?;
@@ -0,0 +1,24 @@
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+
+static COUNTERS: Lazy<Mutex<BTreeMap<String, u64>>> = Lazy::new(Default::default);
+
+/// Increase the counter `name` by one.
+pub fn incr(name: &str) {
+    add(name, 1)
+}
+
+pub fn add(name: &str, n: u64) {
+    let mut counters = COUNTERS.lock();
+    match counters.get_mut(name) {
+        Some(v) => *v += n,
+        None => {
+            counters.insert(name.to_string(), n);
+        }
+    }
+}
+
+pub fn snapshot() -> BTreeMap<String, u64> {
+    COUNTERS.lock().clone()
+}
@@ -0,0 +1,94 @@
+//! Size the tun after the path MTU to the servers.
+//!
+//! Relayed UDP datagrams are wrapped in the headers of the upstream protocol, a tun MTU
+//! larger than the path allows makes them too big for links like PPPoE or tunnels, and
+//! large uploads stall. The path MTU to each of the first servers is probed with datagrams
+//! that may not be fragmented on the way, see `sysconfig::probe_path_mtu`.
+//!
+//! The stack of the tun keeps to the same MTU, clamping the MSS of TCP handshakes and
+//! answering larger UDP packets with ICMP, also when seeker does not own the tun.
+use crate::dns_client::DnsClient;
+use async_std::io::timeout;
+use async_std::task::{sleep, spawn_blocking};
+use config::ServerConfig;
+use std::io::Result;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::{info, warn};
+use tun_nat::SessionManager;
+
+/// Only the first servers of the config are probed.
+const PROBED_SERVERS: usize = 3;
+/// Each probe waits for ICMP errors for a few of the sizes it tries.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Paths change with the network, they are probed again this often.
+const PROBE_INTERVAL: Duration = Duration::from_secs(600);
+/// Room for the headers, address and cipher overhead of relayed UDP datagrams.
+const ENCAPSULATION: u32 = 64;
+const MIN_MTU: u32 = 1280;
+const MAX_MTU: u32 = 1500;
+
+/// The path MTU to `addr`.
+async fn probe(addr: SocketAddr) -> Result<u32> {
+    timeout(
+        PROBE_TIMEOUT,
+        spawn_blocking(move || sysconfig::probe_path_mtu(addr)),
+    )
+    .await
+}
+
+/// The MTU of the tun for the narrowest of `path_mtus`.
+fn tun_mtu(path_mtus: &[u32]) -> Option<u32> {
+    let path = path_mtus.iter().min()?;
+    Some(path.saturating_sub(ENCAPSULATION).max(MIN_MTU).min(MAX_MTU))
+}
+
+/// Probe the servers and set the MTU of the tun, when there is a `tun_name` seeker owns, and
+/// of the stack when it changed, every `PROBE_INTERVAL`.
+pub async fn probe_forever(
+    tun_name: Option<String>,
+    servers: Vec<ServerConfig>,
+    dns_client: DnsClient,
+    session_manager: SessionManager,
+) -> Result<()> {
+    let mut current = None;
+    loop {
+        let mut path_mtus = vec![];
+        for server in servers.iter().take(PROBED_SERVERS) {
+            let result = match dns_client.dial_server(server.addr()).await {
+                Ok(addr) => probe(addr).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(mtu) => path_mtus.push(mtu),
+                Err(e) => warn!(?e, server = server.name(), "probe path mtu"),
+            }
+        }
+        if let Some(mtu) = tun_mtu(&path_mtus) {
+            if current != Some(mtu) {
+                info!(mtu, tun = ?tun_name, "set tun mtu");
+                if let Some(tun_name) = &tun_name {
+                    if let Err(e) = sysconfig::set_mtu(tun_name, mtu) {
+                        warn!(?e, mtu, "set tun mtu");
+                    }
+                }
+                session_manager.set_path_mtu(mtu);
+                current = Some(mtu);
+            }
+        }
+        sleep(PROBE_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tun_mtu() {
+        assert_eq!(tun_mtu(&[]), None);
+        assert_eq!(tun_mtu(&[1500, 1492]), Some(1428));
+        assert_eq!(tun_mtu(&[9000]), Some(MAX_MTU));
+        assert_eq!(tun_mtu(&[576]), Some(MIN_MTU));
+    }
+}
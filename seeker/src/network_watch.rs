@@ -0,0 +1,88 @@
+//! Recovering from network changes, like moving from Wi-Fi to LTE or a VPN coming up, and
+//! from waking up after sleep. Until then proxy connections hang on sockets of the old
+//! network until their TCP timeouts, and cached DNS answers and server latencies are
+//! those of the old network.
+use crate::metrics;
+use async_std::task::{sleep, spawn_blocking};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use sysconfig::{network_state, NetworkMonitor};
+use tracing::info;
+
+/// A change comes as a burst of messages, like the old address going away and the new one
+/// arriving, it is looked at once they settled.
+const SETTLE: Duration = Duration::from_secs(2);
+
+/// How often the clocks are compared to notice sleep.
+const WAKE_CHECK: Duration = Duration::from_secs(5);
+/// Clocks drifting apart by less are NTP adjustments rather than sleep.
+const MIN_SLEEP: Duration = Duration::from_secs(10);
+
+/// Call `recover` whenever the addresses of the interfaces other than the tun changed.
+pub async fn watch_network(
+    tun_name: String,
+    recover: Arc<dyn Fn() + Send + Sync>,
+) -> io::Result<()> {
+    let monitor = Arc::new(NetworkMonitor::new()?);
+    let mut state = network_state(&tun_name);
+    loop {
+        let waiting = monitor.clone();
+        spawn_blocking(move || waiting.wait()).await?;
+        sleep(SETTLE).await;
+        monitor.drain();
+        let current = network_state(&tun_name);
+        if current == state {
+            continue;
+        }
+        info!(
+            ?current,
+            "network changed, start over with DNS and proxy connections"
+        );
+        metrics::incr("network_changes");
+        state = current;
+        let recover = recover.clone();
+        spawn_blocking(move || recover()).await;
+    }
+}
+
+/// Call `recover` after the machine woke up from sleep. The monotonic clock stops while
+/// the machine sleeps and the wall clock does not, so sleep shows as the wall clock
+/// running ahead. That works the same on every platform, without power notifications.
+pub async fn watch_wake(recover: Arc<dyn Fn() + Send + Sync>) -> io::Result<()> {
+    let mut last = (Instant::now(), SystemTime::now());
+    loop {
+        sleep(WAKE_CHECK).await;
+        let now = (Instant::now(), SystemTime::now());
+        let wall = now.1.duration_since(last.1).unwrap_or_default();
+        if let Some(slept) = slept(now.0 - last.0, wall) {
+            info!(?slept, "woke up, start over with DNS and proxy connections");
+            metrics::incr("wakeups");
+            let recover = recover.clone();
+            spawn_blocking(move || recover()).await;
+        }
+        last = now;
+    }
+}
+
+fn slept(monotonic: Duration, wall: Duration) -> Option<Duration> {
+    let slept = wall.checked_sub(monotonic)?;
+    if slept < MIN_SLEEP {
+        return None;
+    }
+    Some(slept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slept() {
+        let secs = Duration::from_secs;
+        assert_eq!(slept(secs(5), secs(5)), None);
+        assert_eq!(slept(secs(5), secs(8)), None);
+        assert_eq!(slept(secs(5), secs(3)), None);
+        assert_eq!(slept(secs(5), secs(605)), Some(secs(600)));
+    }
+}
@@ -0,0 +1,39 @@
+//! Sockets for traffic seeker originates itself, to upstream proxies or direct targets.
+//!
+//! They are marked (see `sysconfig::mark_socket`) so routing rules and the kill switch
+//! exempt them, without pinning routes to every server address.
+use async_io::Async;
+use async_std::net::{SocketAddr, TcpStream, UdpSocket};
+use config::ServerConfig;
+use std::io::Result;
+use sysconfig::AsSocket;
+use tracing::warn;
+
+pub async fn connect(addr: SocketAddr) -> Result<TcpStream> {
+    let stream = Async::new(sysconfig::marked_tcp_connect(&addr)?)?;
+    stream.writable().await?;
+    if let Some(e) = stream.get_ref().take_error()? {
+        return Err(e);
+    }
+    Ok(TcpStream::from(stream.into_inner()?))
+}
+
+/// Connect to the proxy `server` at `addr`, with the keepalive configured for it.
+///
+/// None of the supported protocols has ping frames of its own, TCP keepalives keep idle
+/// connections, like the control connection of a SOCKS5 UDP association, alive through NAT.
+pub async fn connect_server(addr: SocketAddr, server: &ServerConfig) -> Result<TcpStream> {
+    let stream = connect(addr).await?;
+    if let (Some(idle), Some(interval)) = (server.keepalive(), server.keepalive_interval()) {
+        let count = server.keepalive_count();
+        if let Err(e) = sysconfig::set_tcp_keepalive(stream.raw_socket(), idle, interval, count) {
+            warn!(?e, server = server.name(), "set tcp keepalive");
+        }
+    }
+    Ok(stream)
+}
+
+/// A UDP socket for talking to `peer`.
+pub fn bind_udp(peer: SocketAddr) -> Result<UdpSocket> {
+    Ok(UdpSocket::from(sysconfig::marked_udp_socket(&peer)?))
+}
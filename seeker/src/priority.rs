@@ -0,0 +1,44 @@
+//! A separate executor for latency sensitive work: DNS queries, health probes and proxy
+//! handshakes.
+//!
+//! Relays run on the global async-std executor, whose threads can be kept busy by a few
+//! heavy downloads. Tasks spawned here run on threads of their own, so resolving and
+//! connecting stay fast while bulk data is copied. Only short tasks belong here.
+use async_executor::{Executor, Task};
+use futures_util::future::pending;
+use once_cell::sync::Lazy;
+use std::future::Future;
+use std::panic::catch_unwind;
+use std::thread;
+
+const THREADS: usize = 2;
+
+static EXECUTOR: Lazy<Executor<'static>> = Lazy::new(|| {
+    for i in 0..THREADS {
+        thread::Builder::new()
+            .name(format!("seeker-priority-{}", i))
+            .spawn(|| loop {
+                // Panics of tasks end up in their `Task`, keep the thread running anyway.
+                let _ = catch_unwind(|| async_io::block_on(EXECUTOR.run(pending::<()>())));
+            })
+            .expect("spawn priority executor thread");
+    }
+    Executor::new()
+});
+
+/// Run `future` on the priority executor, it is cancelled when the returned task is dropped.
+pub fn spawn<T: Send + 'static>(future: impl Future<Output = T> + Send + 'static) -> Task<T> {
+    EXECUTOR.spawn(future)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+
+    #[test]
+    fn test_spawn() {
+        let name = block_on(spawn(async { thread::current().name().map(String::from) }));
+        assert!(name.unwrap().starts_with("seeker-priority-"));
+    }
+}
@@ -0,0 +1,97 @@
+//! Find the local process behind a connection from the tun, for `PROCESS-NAME`,
+//! `PROCESS-PATH` and `UID` rules.
+//!
+//! The source address of a tun connection is the address the process bound, so the
+//! owning process is found through the socket tables of the system. Walking them is slow,
+//! results are cached for a few seconds per source address.
+use async_std::task::spawn_blocking;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use sysconfig::ProcessInfo;
+use tracing::debug;
+
+const CACHE_TTL: Duration = Duration::from_secs(5);
+const CACHE_CAPACITY: usize = 4096;
+
+type Key = (SocketAddr, bool);
+
+#[derive(Clone)]
+pub struct ProcessLookup {
+    enabled: Arc<AtomicBool>,
+    cache: Arc<Mutex<HashMap<Key, (Instant, Option<ProcessInfo>)>>>,
+}
+
+impl ProcessLookup {
+    /// Lookups are only done when `enabled`, i.e. the rules match on processes.
+    pub fn new(enabled: bool) -> Self {
+        ProcessLookup {
+            enabled: Arc::new(AtomicBool::new(enabled)),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Start or stop the lookups, when reloaded rules gain or lose process rules.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The process that owns the socket bound to `src`, if it can be found.
+    pub async fn process(&self, src: SocketAddr, udp: bool) -> Option<ProcessInfo> {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let key = (src, udp);
+        if let Some((at, process)) = self.cache.lock().get(&key) {
+            if at.elapsed() < CACHE_TTL {
+                return process.clone();
+            }
+        }
+        let process = spawn_blocking(move || find_process(src, udp)).await;
+        let mut cache = self.cache.lock();
+        if cache.len() >= CACHE_CAPACITY {
+            cache.retain(|_, (at, _)| at.elapsed() < CACHE_TTL);
+        }
+        cache.insert(key, (Instant::now(), process.clone()));
+        process
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn find_process(src: SocketAddr, udp: bool) -> Option<ProcessInfo> {
+    match sysconfig::find_socket_process(src, udp) {
+        Ok(process) => process,
+        Err(e) => {
+            debug!(?e, %src, "find socket process");
+            None
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn find_process(src: SocketAddr, _udp: bool) -> Option<ProcessInfo> {
+    debug!(%src, "process lookup is not supported on this platform");
+    None
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "macos")))]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+
+    #[test]
+    fn test_process_name() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let src = socket.local_addr().unwrap();
+        let lookup = ProcessLookup::new(true);
+        let process = block_on(lookup.process(src, true)).unwrap();
+        assert_eq!(process.pid, std::process::id() as i32);
+        drop(socket);
+        assert_eq!(block_on(lookup.process(src, true)), Some(process));
+
+        assert_eq!(block_on(ProcessLookup::new(false).process(src, true)), None);
+    }
+}
@@ -1,49 +1,176 @@
+use crate::alt_svc::AltSvcCache;
+use crate::api::ApiServer;
+use crate::conn_events::{ConnectionEvent, ConnectionEvents};
+use crate::connections::{ConnectionInfo, ConnectionTracker};
 use crate::dns_client::DnsClient;
+use crate::dns_forward::{forward_stream_queries, local_dns_addr};
+#[cfg(feature = "dns-inbound")]
+use crate::dns_inbound::{load_tls_acceptor, run_doh_server, run_dot_server};
+use crate::interactive::Prompter;
+use crate::introspect::{self, Introspect};
+use crate::metrics;
+#[cfg(unix)]
+use crate::mtu;
+use crate::outbound;
+use crate::priority;
+use crate::process_lookup::ProcessLookup;
+use crate::proxy_connection::ProxyConnection;
 use crate::proxy_tcp_stream::ProxyTcpStream;
 use crate::proxy_udp_socket::ProxyUdpSocket;
+use crate::quarantine::{is_reset, EARLY_RESET_WINDOW};
+use crate::reject;
+use crate::relay::{tunnel_tcp_stream, CloseReason, Inspect};
+use crate::reload::{self, Reloader};
+use crate::rule_providers;
+use crate::rule_stats::{Route, RuleStats};
+use crate::script::{RuleScript, ScriptConnection};
 use crate::server_chooser::ServerChooser;
-use async_std::io::{timeout, Read, Write};
+use crate::sniff;
+use crate::supervisor::Supervisor;
+use crate::udp_queue::UdpQueue;
+use async_std::io::timeout;
 use async_std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
 use async_std::prelude::*;
-use async_std::task::spawn;
-use async_std_resolver::AsyncStdResolver;
-use config::rule::Action;
-use config::{Address, Config};
+use config::error_code::{CONFIG_INVALID, TUN_SETUP};
+use config::rule::{Action, ConnectionMeta, DnsPolicy, ProxyRules, Rule, TUN_INBOUND};
+use config::{Address, Config, DnsServerAddr, Mode, Overrides, ServerConfig, TunFd, TunStack};
 use dnsserver::create_dns_server;
-use dnsserver::resolver::RuleBasedDnsResolver;
+use dnsserver::resolver::{ResolverOptions, RuleBasedDnsResolver};
+use dnsserver::upstream::Resolver;
 use parking_lot::RwLock;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::io::Result;
+use std::net::IpAddr;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
-use tracing::{error, trace, trace_span};
+use std::time::Instant;
+use sysconfig::AsSocket;
+use tracing::{debug, error, info, trace, trace_span, warn};
 use tracing_futures::Instrument;
-use tun_nat::{run_nat, SessionManager};
+use tun_nat::{run_nat, SessionManager, StackKind, TunOpen};
+
+/// The parts of the config a reload swaps in while seeker runs, see `reloader`.
+#[derive(Clone)]
+struct Live {
+    rules: ProxyRules,
+    dns_servers: Vec<DnsServerAddr>,
+    /// The `dns_resolvers` named by `dns:` of a rule.
+    dns_upstreams: HashMap<String, DnsClient>,
+    /// Addresses of the servers, always connected to directly.
+    extra_directly_servers: Vec<String>,
+    lan_bypass: bool,
+}
+
+impl Live {
+    async fn new(config: &Config) -> Self {
+        Live {
+            rules: config.rules.clone(),
+            dns_servers: config.dns_servers.clone(),
+            dns_upstreams: dns_upstreams(config).await,
+            extra_directly_servers: config
+                .servers
+                .iter()
+                .map(|s| s.addr().to_string())
+                .collect(),
+            lan_bypass: config.lan_bypass,
+        }
+    }
+}
 
 pub struct ProxyClient {
+    /// The config seeker started with, settings a reload can change are read from `live`.
     config: Config,
+    live: Arc<RwLock<Arc<Live>>>,
     uid: Option<u32>,
     session_manager: SessionManager,
-    udp_manager: Arc<RwLock<HashMap<u16, (ProxyUdpSocket, SocketAddr)>>>,
+    udp_manager: Arc<RwLock<HashMap<u16, UdpQueue>>>,
     resolver: RuleBasedDnsResolver,
     dns_client: DnsClient,
-    extra_directly_servers: Vec<String>,
     server_chooser: Arc<ServerChooser>,
+    hijacked_dns_addr: Option<SocketAddr>,
+    conn_events: ConnectionEvents,
+    prompter: Arc<Prompter>,
+    process_lookup: ProcessLookup,
+    script: Option<Arc<RuleScript>>,
+    connections: Arc<ConnectionTracker>,
+    alt_svc: Arc<AltSvcCache>,
+    rule_stats: Arc<RuleStats>,
 }
 
-impl ProxyClient {
-    pub async fn new(config: Config, uid: Option<u32>) -> Self {
-        let session_manager =
-            run_nat(&config.tun_name, config.tun_ip, config.tun_cidr, 1300).expect("run nat");
-        let dns_client = DnsClient::new(&config.dns_servers, config.dns_timeout).await;
+/// How to get the tun, the descriptor of `tun_fd` is received over its socket when it is
+/// a path.
+fn tun_open(config: &Config) -> Result<TunOpen> {
+    let open = match &config.tun_fd {
+        Some(TunFd::Fd(fd)) => TunOpen::Fd(*fd),
+        #[cfg(unix)]
+        Some(TunFd::Socket(path)) => TunOpen::Fd(tun_nat::receive_fd(path)?),
+        #[cfg(not(unix))]
+        Some(TunFd::Socket(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tun_fd sockets are only supported on unix",
+            ))
+        }
+        None if config.tun_persistent => TunOpen::Persistent,
+        None => TunOpen::Create,
+    };
+    Ok(open)
+}
 
-        let resolver = run_dns_resolver(&config, dns_client.resolver()).await;
+impl ProxyClient {
+    pub async fn new(
+        config: Config,
+        config_path: Option<String>,
+        profile: Option<String>,
+        overrides: Overrides,
+        uid: Option<u32>,
+    ) -> Result<Self> {
+        sysconfig::set_tcp_buffer_size(config.tcp_socket_buffer.unwrap_or(0));
+        let stack = match config.tun_stack {
+            TunStack::Nat => StackKind::Nat,
+        };
+        let session_manager = if !config.mode.uses_tun() {
+            SessionManager::default()
+        } else {
+            let tun_open = tun_open(&config).map_err(|e| TUN_SETUP.wrap(e))?;
+            run_nat(
+                &config.tun_name,
+                tun_open,
+                config.tun_offload,
+                config.tun_queues,
+                config.tun_ip,
+                config.tun_cidr,
+                config.tun_ip6.zip(config.tun_cidr6),
+                1300,
+                stack,
+            )
+            .map_err(|e| TUN_SETUP.wrap(e))?
+        };
+        let dns_client = DnsClient::new(
+            &config.dns_servers,
+            config.dns_timeout,
+            config.dnssec,
+            config.dnssec_root_keys.as_deref(),
+            config.dns_ipv6,
+            config.nat64_prefix,
+            &config.dns_ttl,
+        )
+        .await;
+        let live = Live::new(&config).await;
 
-        let extra_directly_servers = config
-            .servers
-            .iter()
-            .map(|s| s.addr().to_string())
-            .collect();
+        let supervisor = Supervisor::new(config.task_max_failures);
+        rule_providers::spawn_refreshers(supervisor, &config);
+        let resolver = run_dns_resolver(&config, dns_client.resolver(), &live.dns_upstreams).await;
+        let prefetch_client = dns_client.clone();
+        supervisor.spawn("dns_prefetch", move || {
+            let client = prefetch_client.clone();
+            async move {
+                client.prefetch_forever().await;
+                Ok(())
+            }
+        });
 
         let ping_url = vec![
             (
@@ -69,85 +196,513 @@ impl ProxyClient {
                 dns_client.clone(),
                 ping_url,
                 config.ping_timeout,
+                config.quarantine_duration,
+                config.proxy_groups.clone(),
+                config.proxy_fail_closed,
             )
             .await,
         );
         let chooser_clone = chooser.clone();
-        if config.servers.len() > 1 {
-            let _ = spawn(async move { chooser_clone.ping_servers_forever().await.unwrap() });
+        supervisor.spawn("ping_servers", move || {
+            let chooser = chooser_clone.clone();
+            async move { chooser.ping_servers_forever().await }
+        });
+        match config.tun_mtu {
+            _ if !config.mode.uses_tun() => {}
+            Some(mtu) => {
+                // Whoever set up the tun owns its MTU, the stack still keeps to it.
+                if !config.tun_preconfigured() {
+                    if let Err(e) = sysconfig::set_mtu(&config.tun_name, mtu) {
+                        warn!(?e, mtu, "set tun mtu");
+                    }
+                }
+                session_manager.set_path_mtu(mtu);
+            }
+            #[cfg(unix)]
+            None => {
+                let tun_name =
+                    Some(config.tun_name.clone()).filter(|_| !config.tun_preconfigured());
+                let servers = config.servers.clone();
+                let mtu_dns_client = dns_client.clone();
+                let mtu_session_manager = session_manager.clone();
+                supervisor.spawn("mtu_probe", move || {
+                    mtu::probe_forever(
+                        tun_name.clone(),
+                        servers.clone(),
+                        mtu_dns_client.clone(),
+                        mtu_session_manager.clone(),
+                    )
+                });
+            }
+            // There is no probe of the path MTU on Windows, the tun keeps the MTU it has.
+            #[cfg(not(unix))]
+            None => {}
         }
+        let udp_manager = Arc::new(RwLock::new(HashMap::new()));
+        let prompter = Arc::new(Prompter::new(
+            config.interactive,
+            config.interactive_timeout,
+            config.interactive_rules_file.clone(),
+        ));
+        let connections = Arc::new(ConnectionTracker::default());
+        let alt_svc = Arc::new(AltSvcCache::new(config.http3));
+        let rule_stats = Arc::new(RuleStats::new(
+            config.rules.rules(),
+            config.rule_decision_log_size,
+        ));
+        if config.interactive && config.api_listen.is_none() {
+            error!("interactive mode needs api_listen for prompt clients");
+        }
+
+        let conn_events =
+            ConnectionEvents::from_config(&config).map_err(|e| CONFIG_INVALID.wrap(e))?;
+
+        let script = config
+            .rule_script
+            .as_ref()
+            .and_then(|path| match RuleScript::load(path) {
+                Ok(script) => Some(Arc::new(script)),
+                Err(e) => {
+                    error!(?e, "load rule script, SCRIPT rules use the default action");
+                    None
+                }
+            });
+        let process_lookup =
+            ProcessLookup::new(config.rules.has_process_rules() || script.is_some());
 
-        Self {
+        let hijacked_dns_addr = if config.dns_hijack {
+            local_dns_addr(&config.dns_listen)
+        } else {
+            None
+        };
+
+        let live = Arc::new(RwLock::new(Arc::new(live)));
+        #[cfg(unix)]
+        if config.watch_network {
+            let tun_name = config.tun_name.clone();
+            let recover = network_recovery(&config, &live, &dns_client, &resolver, &chooser);
+            let wake_recover = recover.clone();
+            supervisor.spawn("watch_network", move || {
+                crate::network_watch::watch_network(tun_name.clone(), recover.clone())
+            });
+            supervisor.spawn("watch_wake", move || {
+                crate::network_watch::watch_wake(wake_recover.clone())
+            });
+        }
+        let reloader = Arc::new(reloader(
+            Reloader::new(config_path, config.clone())
+                .with_profile(profile)
+                .with_overrides(overrides),
+            &config,
+            &live,
+            &dns_client,
+            &resolver,
+            &chooser,
+            &rule_stats,
+            &process_lookup,
+            script.is_some(),
+        ));
+        #[cfg(unix)]
+        {
+            let signal_reloader = reloader.clone();
+            supervisor.spawn("reload_on_sighup", move || {
+                reload::reload_on_signal(signal_reloader.clone())
+            });
+        }
+        if config.watch_config {
+            let watch_reloader = reloader.clone();
+            supervisor.spawn("watch_config", move || {
+                reload::reload_on_change(watch_reloader.clone())
+            });
+        }
+        if let (Some(_), Some(interval)) = (&config.subscription_url, config.subscription_interval)
+        {
+            let subscription_reloader = reloader.clone();
+            supervisor.spawn("subscription", move || {
+                reload::reload_every(subscription_reloader.clone(), interval)
+            });
+        }
+        if let Some(listen) = config.api_listen.clone() {
+            let api = Arc::new(ApiServer::new(
+                listen,
+                chooser.clone(),
+                reloader.clone(),
+                resolver.query_log(),
+                prompter.clone(),
+                connections.clone(),
+                alt_svc.clone(),
+                rule_stats.clone(),
+                Introspect::new(
+                    session_manager.clone(),
+                    udp_manager.clone(),
+                    connections.clone(),
+                ),
+                config.api_secret.clone(),
+                config.api_allowed_origins.clone(),
+            ));
+            supervisor.spawn("management_api", move || api.clone().run());
+        }
+
+        Ok(Self {
+            hijacked_dns_addr,
+            conn_events,
+            prompter,
+            process_lookup,
+            script,
+            connections,
+            alt_svc,
+            rule_stats,
             resolver,
-            extra_directly_servers,
-            udp_manager: Arc::new(RwLock::new(HashMap::new())),
+            udp_manager,
             dns_client,
+            live,
             config,
             uid,
             session_manager,
             server_chooser: chooser,
+        })
+    }
+
+    fn live(&self) -> Arc<Live> {
+        self.live.read().clone()
+    }
+
+    /// Whether `lan_bypass`, as the running config sets it, sends connections to `ip`
+    /// directly when no IP rule matches it.
+    fn lan_bypasses(&self, ip: IpAddr) -> bool {
+        self.live().lan_bypass && self.config.is_lan(ip)
+    }
+
+    fn rule_for_host(&self, host: &Address) -> Option<Rule> {
+        let rules = &self.live().rules;
+        match host {
+            Address::DomainNameAddress(domain, _) => rules.rule_for_domain(domain).cloned(),
+            Address::SocketAddress(addr) => rules.rule_for_ip(addr.ip()).cloned(),
+        }
+    }
+
+    /// The client resolving `host`, the resolver named by its rule, the `domestic_dns` for
+    /// DIRECT domains, or the default one.
+    fn dns_client_for(&self, host: &Address) -> DnsClient {
+        let live = self.live();
+        let name = match host {
+            Address::DomainNameAddress(domain, _) => match live.rules.dns_for_domain(domain) {
+                Some(DnsPolicy::Resolver(name)) => Some(name),
+                _ if live.rules.action_for_domain(domain) == Some(Action::Direct) => {
+                    self.config.domestic_dns.as_ref()
+                }
+                _ => None,
+            },
+            Address::SocketAddress(_) => None,
+        };
+        name.and_then(|name| live.dns_upstreams.get(name))
+            .unwrap_or(&self.dns_client)
+            .clone()
+    }
+
+    /// The rule `route` was matched by, as the rules in use have it.
+    fn rule_of(&self, route: &Route) -> Option<Rule> {
+        let index = route.rule?;
+        self.live().rules.rules().get(index).cloned()
+    }
+
+    /// Tag of the rule matching `host`, used to label logs and metrics.
+    fn tag_for_host(&self, host: &Address) -> Option<String> {
+        self.rule_for_host(host)?.tag
+    }
+
+    /// Learn the `Alt-Svc` of plain HTTP responses, see `AltSvcCache`.
+    fn alt_svc_inspector(&self, host: &Address, conn: &ProxyTcpStream) -> Option<Inspect> {
+        let domain = match host {
+            Address::DomainNameAddress(domain, 80) if self.alt_svc.is_enabled() => domain.clone(),
+            _ => return None,
+        };
+        let action = if conn.config().is_some() {
+            Action::Proxy
+        } else {
+            Action::Direct
+        };
+        let alt_svc = self.alt_svc.clone();
+        Some(Box::new(move |head: &[u8]| {
+            alt_svc.learn(&domain, action, head)
+        }))
+    }
+
+    /// Describe a relayed connection, routed by `rule`, for the management API.
+    async fn connection_info(
+        &self,
+        network: &'static str,
+        src: SocketAddr,
+        dest: SocketAddr,
+        host: &Address,
+        rule: Option<Rule>,
+        conn: &dyn ProxyConnection,
+    ) -> ConnectionInfo {
+        let process_path = self
+            .process_lookup
+            .process(src, network == "udp")
+            .await
+            .and_then(|p| p.path)
+            .map(|p| p.to_string_lossy().into_owned());
+        ConnectionInfo {
+            network,
+            src,
+            dest,
+            host: host.clone(),
+            server: conn.config().map(|c| c.name().to_string()),
+            rule,
+            process_path,
+        }
+    }
+
+    /// The local DNS server address if traffic to `real_dest` should be answered by it.
+    fn hijacked_dns_addr(&self, real_dest: SocketAddr) -> Option<SocketAddr> {
+        if real_dest.port() != 53 {
+            return None;
         }
+        // Queries from the upstream resolvers themselves must not loop back to us.
+        let is_upstream = self.live().dns_servers.iter().any(|s| match s {
+            DnsServerAddr::UdpSocketAddr(addr) => *addr == real_dest,
+            DnsServerAddr::TcpSocketAddr(_) => false,
+        });
+        if is_upstream {
+            return None;
+        }
+        self.hijacked_dns_addr
     }
 
+    /// The action for a connection to `addr`, with the proxy group when the rule names one.
     async fn get_action_for_addr(
         &self,
+        network: &'static str,
+        inbound: &'static str,
         original_addr: SocketAddr,
         socket_addr: SocketAddr,
         addr: &Address,
-    ) -> Result<Action> {
+    ) -> Result<Route> {
+        let live = self.live();
+        let rules = &live.rules;
         let mut pass_proxy = false;
-        let domain = match &addr {
-            // 如果是 IP 说明是用户手动改了路由表，必须要走代理。
-            Address::SocketAddress(_) => {
-                return Ok(Action::Proxy);
+        let (domain, ip, port) = match &addr {
+            Address::SocketAddress(addr) => {
+                // Misrouted LAN traffic, no proxy can reach these unless an IP rule says
+                // otherwise.
+                if rules.index_for_ip(addr.ip()).is_none() && self.lan_bypasses(addr.ip()) {
+                    trace!(?addr, "lan bypass");
+                    return Ok(Route {
+                        action: Action::Direct,
+                        group: None,
+                        rule: None,
+                    });
+                }
+                (None, Some(addr.ip()), addr.port())
             }
-            Address::DomainNameAddress(domain, _port) => domain.to_string(),
+            Address::DomainNameAddress(domain, port) => (Some(domain.to_string()), None, *port),
         };
-        if self.extra_directly_servers.contains(&domain) {
-            pass_proxy = true;
+        if let Some(domain) = &domain {
+            if live.extra_directly_servers.contains(domain) {
+                pass_proxy = true;
+            }
         }
         if let Some(uid) = self.uid {
             if !socket_addr_belong_to_user(original_addr, uid)? {
                 pass_proxy = true;
             }
         }
-        let mut action = if pass_proxy {
-            Action::Direct
+        let process = if pass_proxy {
+            None
         } else {
-            self.config
-                .rules
-                .action_for_domain(&domain)
-                .unwrap_or_else(|| self.config.rules.default_action())
+            self.process_lookup
+                .process(original_addr, network == "udp")
+                .await
         };
+        let conn = ConnectionMeta {
+            domain: domain.as_deref(),
+            process_name: process.as_ref().map(|p| p.name.as_str()),
+            process_path: process
+                .as_ref()
+                .and_then(|p| p.path.as_ref())
+                .and_then(|p| p.to_str()),
+            uid: process.as_ref().and_then(|p| p.uid),
+            port: Some(port),
+            network: Some(network),
+            inbound: Some(inbound),
+            ip,
+        };
+        trace!(?conn, "match rules");
+        let rule = if pass_proxy {
+            None
+        } else {
+            rules.index_for_connection(&conn)
+        };
+        let (mut action, group) = match rule.map(|i| &rules.rules()[i]) {
+            Some(rule) => (rule.action, rule.group.clone()),
+            None if pass_proxy => (Action::Direct, None),
+            None => {
+                let final_rule = rules.final_rule();
+                (final_rule.action, final_rule.group.clone())
+            }
+        };
+        // NETWORK rules may treat QUIC differently from TCP on purpose, and so may rules
+        // naming the domain or the app.
+        let follow_alt_svc = !rules.has_network_rules();
+        if !pass_proxy
+            && follow_alt_svc
+            && network == "udp"
+            && port == 443
+            && !rules.is_connection_explicitly_matched(&conn)
+        {
+            if let Some(action) = domain.as_deref().and_then(|d| self.alt_svc.udp_action(d)) {
+                trace!(?action, ?domain, "quic flow follows alt-svc");
+                return Ok(Route {
+                    action,
+                    group,
+                    rule,
+                });
+            }
+        }
+        if action == Action::Script {
+            let script_conn = ScriptConnection {
+                domain: domain.as_deref().unwrap_or(""),
+                ip: socket_addr.ip(),
+                port,
+                network,
+                process_name: conn.process_name,
+                process_path: conn.process_path,
+                uid: conn.uid,
+            };
+            action = self.script_action(&script_conn);
+        }
+        if !pass_proxy
+            && self.prompter.is_enabled()
+            && !rules.is_connection_explicitly_matched(&conn)
+        {
+            let host = domain
+                .clone()
+                .unwrap_or_else(|| socket_addr.ip().to_string());
+            action = self
+                .prompter
+                .decide(original_addr, &host, port, action)
+                .await;
+        }
+
+        // The domestic resolver answered with foreign addresses, have the proxy resolve it.
+        let geo_demoted = domain
+            .as_deref()
+            .map_or(false, |domain| self.resolver.is_geo_demoted(domain));
+        if action == Action::Direct && !pass_proxy && geo_demoted {
+            action = Action::Proxy;
+        }
 
         if action == Action::Probe {
-            if self.probe_connectivity(socket_addr).await {
-                action = Action::Direct;
-            } else {
-                action = Action::Proxy;
+            action = self.probe_action(socket_addr).await;
+        }
+
+        Ok(Route {
+            action,
+            group,
+            rule,
+        })
+    }
+
+    /// The action the rule script picks for `conn`, the default action when it fails. A
+    /// default action of `SCRIPT` itself falls back to the proxy.
+    fn script_action(&self, conn: &ScriptConnection) -> Action {
+        let default_action = match self.live().rules.default_action() {
+            Action::Script => Action::Proxy,
+            action => action,
+        };
+        match self.script.as_ref().map(|script| script.route(conn)) {
+            Some(Ok(action)) => action,
+            Some(Err(e)) => {
+                error!(?e, ?conn, "rule script failed, use the default action");
+                default_action
             }
+            None => default_action,
         }
+    }
 
-        Ok(action)
+    /// `DIRECT` when `addr` is reachable without the proxy, else `PROXY`.
+    async fn probe_action(&self, addr: SocketAddr) -> Action {
+        if self.probe_connectivity(addr).await {
+            Action::Direct
+        } else {
+            Action::Proxy
+        }
     }
 
+    /// Publish the new connection and fail it if the connection hook denies it.
+    async fn check_outbound(
+        &self,
+        network: &'static str,
+        original_addr: SocketAddr,
+        sock_addr: SocketAddr,
+        remote_addr: &Address,
+        action: Action,
+    ) -> Result<()> {
+        let event = ConnectionEvent::new(
+            network,
+            original_addr,
+            remote_addr,
+            sock_addr,
+            action,
+            self.tag_for_host(remote_addr),
+        );
+        if self.conn_events.check(&event).await {
+            return Ok(());
+        }
+        metrics::incr("conn_hook_denied");
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} denied by connection hook", remote_addr),
+        ))
+    }
+
+    /// Connect to `connect_addr` as the rules decide for `remote_addr`, with the route they
+    /// picked. They only differ when the domain of a connection to a bare ip was sniffed.
     async fn choose_proxy_tcp_stream(
         &self,
+        inbound: &'static str,
         original_addr: SocketAddr,
         sock_addr: SocketAddr,
         remote_addr: &Address,
-    ) -> Result<ProxyTcpStream> {
-        let action = self
-            .get_action_for_addr(original_addr, sock_addr, &remote_addr)
+        connect_addr: &Address,
+    ) -> Result<(ProxyTcpStream, Route)> {
+        let route = self
+            .get_action_for_addr("tcp", inbound, original_addr, sock_addr, &remote_addr)
             .await?;
-        trace!(?action, "selected action");
-        retry_timeout!(
-            self.config.connect_timeout,
-            self.config.max_connect_errors,
-            self.server_chooser
-                .candidate_tcp_stream(remote_addr.clone(), action)
-        )
-        .await
+        trace!(?route, "selected route");
+        let action = route.action;
+        let result: Result<ProxyTcpStream> = async {
+            self.check_outbound("tcp", original_addr, sock_addr, remote_addr, action)
+                .await?;
+            if action == Action::Reject {
+                return Err(reject::rejected());
+            }
+            // Handshakes run on the priority executor so busy relays do not slow them down.
+            // Servers can have their own connect timeout, the chooser applies it.
+            let chooser = &self.server_chooser;
+            let connect_timeout = self.config.connect_timeout;
+            retry!(self.config.max_connect_errors, {
+                let chooser = chooser.clone();
+                let connect_addr = connect_addr.clone();
+                let group = route.group.clone();
+                priority::spawn(async move {
+                    chooser
+                        .candidate_tcp_stream(
+                            connect_addr,
+                            action,
+                            group.as_deref(),
+                            connect_timeout,
+                        )
+                        .await
+                })
+            })
+            .await
+        }
+        .await;
+        self.record_route("tcp", remote_addr, &route, &result);
+        result.map(|conn| (conn, route))
     }
 
     async fn choose_proxy_udp_socket(
@@ -155,27 +710,75 @@ impl ProxyClient {
         original_addr: SocketAddr,
         sock_addr: SocketAddr,
         remote_addr: &Address,
-    ) -> Result<ProxyUdpSocket> {
-        let action = self
-            .get_action_for_addr(original_addr, sock_addr, &remote_addr)
+    ) -> Result<(ProxyUdpSocket, Route)> {
+        let route = self
+            .get_action_for_addr("udp", TUN_INBOUND, original_addr, sock_addr, &remote_addr)
             .await?;
+        let action = route.action;
+        let result: Result<ProxyUdpSocket> = async {
+            self.check_outbound("udp", original_addr, sock_addr, remote_addr, action)
+                .await?;
+            // Datagrams are dropped whatever the reject mode.
+            if action == Action::Reject {
+                return Err(reject::rejected());
+            }
 
-        retry_timeout!(
-            self.config.connect_timeout,
-            self.config.max_connect_errors,
-            self.server_chooser.candidate_udp_socket(action)
-        )
-        .await
+            let chooser = &self.server_chooser;
+            retry_timeout!(
+                self.config.connect_timeout,
+                self.config.max_connect_errors,
+                {
+                    let chooser = chooser.clone();
+                    let group = route.group.clone();
+                    priority::spawn(async move {
+                        chooser.candidate_udp_socket(action, group.as_deref()).await
+                    })
+                }
+            )
+            .await
+        }
+        .await;
+        self.record_route("udp", remote_addr, &route, &result);
+        result.map(|socket| (socket, route))
+    }
+
+    /// Count the rule of `route` and log the decision for the management API.
+    fn record_route<C: ProxyConnection>(
+        &self,
+        network: &str,
+        remote_addr: &Address,
+        route: &Route,
+        result: &Result<C>,
+    ) {
+        let (server, error) = match result {
+            Ok(conn) => (conn.config().map(|c| c.name()), None),
+            Err(e) => (None, Some(e)),
+        };
+        self.rule_stats
+            .record(network, remote_addr, route, server, error);
     }
 
     async fn probe_connectivity(&self, addr: SocketAddr) -> bool {
-        timeout(self.config.probe_timeout, TcpStream::connect(addr))
-            .await
-            .is_ok()
+        let addr = self.dns_client.translate(addr);
+        let probe = timeout(self.config.probe_timeout, outbound::connect(addr));
+        priority::spawn(probe).await.is_ok()
     }
 
-    async fn run_tcp_relay_server(&self) -> Result<()> {
-        let listener = TcpListener::bind((self.config.tun_ip, 1300)).await?;
+    /// Connections `listener` accepts get the buffers of `tcp_socket_buffer`.
+    fn size_socket_buffers(&self, listener: &TcpListener) {
+        if let Some(size) = self.config.tcp_socket_buffer {
+            if let Err(e) = sysconfig::set_tcp_buffers(listener.raw_socket(), size) {
+                warn!(?e, size, "set tcp socket buffers");
+            }
+        }
+    }
+
+    /// Each connection is relayed by a task of its own, so sniffing its first bytes and
+    /// connecting do not hold up the next accept. The same goes for the TPROXY and REDIRECT
+    /// listeners.
+    async fn run_tcp_relay_server(self: Arc<Self>, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        self.size_socket_buffers(&listener);
         let mut incoming = listener.incoming();
         while let Some(Ok(conn)) = incoming.next().await {
             let peer_addr = conn.peer_addr()?;
@@ -184,65 +787,320 @@ impl ProxyClient {
                 None => continue,
             };
 
-            async {
-                let ip = real_dest.ip().to_string();
-                let host = self
-                    .resolver
-                    .lookup_host(&ip)
-                    .map(|s| Address::DomainNameAddress(s, real_dest.port()))
-                    .unwrap_or_else(|| Address::SocketAddress(real_dest));
-
-                trace!(dest_host = ?host, "new relay connection");
-
-                let sock_addr = match self.dns_client.lookup_address(&host).await {
-                    Ok(a) => a,
-                    Err(e) => {
-                        error!(?e, ?host, "error resolve dns");
-                        return;
-                    }
-                };
-
-                trace!(ip = ?ip, host = ?host, "lookup host");
+            let client = self.clone();
+            introspect::spawn("tcp_connect", async move {
+                client
+                    .relay_tcp_connection(conn, TUN_INBOUND, real_src, real_dest)
+                    .instrument(trace_span!(
+                        "tcp connection",
+                        ?peer_addr,
+                        ?real_src,
+                        ?real_dest
+                    ))
+                    .await
+            });
+        }
+        Ok(())
+    }
 
-                match self
-                    .choose_proxy_tcp_stream(real_src, sock_addr, &host)
+    /// Relay connections redirected by TPROXY rules, their local address is where they
+    /// were going.
+    #[cfg(target_os = "linux")]
+    async fn run_tproxy_server(self: Arc<Self>, listen: &str) -> Result<()> {
+        let addr: SocketAddr = listen.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid tproxy_listen {}", listen),
+            )
+        })?;
+        let listener = TcpListener::from(sysconfig::tproxy_tcp_listener(&addr)?);
+        self.size_socket_buffers(&listener);
+        info!(%addr, "listening for tproxy connections");
+        let mut incoming = listener.incoming();
+        while let Some(conn) = incoming.next().await {
+            // A failed connection is no reason to stop taking the others.
+            let conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    debug!(?e, "accept tproxy connection");
+                    continue;
+                }
+            };
+            let (real_src, real_dest) = match (conn.peer_addr(), conn.local_addr()) {
+                (Ok(src), Ok(dest)) => (src, dest),
+                (Err(e), _) | (_, Err(e)) => {
+                    debug!(?e, "tproxy connection closed before relaying");
+                    continue;
+                }
+            };
+            let client = self.clone();
+            introspect::spawn("tcp_connect", async move {
+                client
+                    .relay_tcp_connection(conn, config::rule::TPROXY_INBOUND, real_src, real_dest)
+                    .instrument(trace_span!("tproxy connection", ?real_src, ?real_dest))
                     .await
-                {
-                    Ok(remote_conn) => {
-                        trace!("connect successfully");
-                        spawn(async move { tunnel_tcp_stream(conn, remote_conn).await });
-                    }
-                    Err(e) => {
-                        error!(?e, "connect error");
-                    }
-                };
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn run_tproxy_server(self: Arc<Self>, _listen: &str) -> Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "mode tproxy is only supported on Linux",
+        ))
+    }
+
+    /// Relay connections sent to `listen` by REDIRECT rules, conntrack knows where they
+    /// were going.
+    #[cfg(target_os = "linux")]
+    async fn run_redirect_server(self: Arc<Self>, listen: &str) -> Result<()> {
+        let listener = TcpListener::bind(listen).await?;
+        self.size_socket_buffers(&listener);
+        let local_addr = listener.local_addr()?;
+        info!(%local_addr, "listening for redirected connections");
+        let mut incoming = listener.incoming();
+        while let Some(conn) = incoming.next().await {
+            // A failed connection is no reason to stop taking the others.
+            let conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    debug!(?e, "accept redirected connection");
+                    continue;
+                }
+            };
+            let real_src = match conn.peer_addr() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    debug!(?e, "redirected connection closed before relaying");
+                    continue;
+                }
+            };
+            let real_dest = match sysconfig::original_dst(conn.as_raw_fd(), real_src.is_ipv6()) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    debug!(?e, ?real_src, "no original destination");
+                    continue;
+                }
+            };
+            // Connections made to the listener itself were not redirected, relaying them
+            // would connect back to it.
+            if conn.local_addr().map_or(true, |addr| addr == real_dest) {
+                continue;
             }
-            .instrument(trace_span!(
-                "tcp connection",
-                ?peer_addr,
-                ?real_src,
-                ?real_dest
-            ))
-            .await
+            let client = self.clone();
+            introspect::spawn("tcp_connect", async move {
+                client
+                    .relay_tcp_connection(conn, config::rule::REDIRECT_INBOUND, real_src, real_dest)
+                    .instrument(trace_span!("redirect connection", ?real_src, ?real_dest))
+                    .await
+            });
         }
-        Ok::<(), io::Error>(())
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn run_redirect_server(self: Arc<Self>, _listen: &str) -> Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "mode redirect is only supported on Linux",
+        ))
     }
 
-    pub async fn run(&self) {
-        self.run_tcp_relay_server()
-            .race(self.run_udp_relay_server())
+    /// Relay `conn` from `real_src` as the rules decide for `real_dest`, where it was going.
+    async fn relay_tcp_connection(
+        &self,
+        conn: TcpStream,
+        inbound: &'static str,
+        real_src: SocketAddr,
+        real_dest: SocketAddr,
+    ) {
+        if let Some(dns_addr) = self.hijacked_dns_addr(real_dest) {
+            trace!(?real_src, ?real_dest, "hijack tcp dns query");
+            let dns_timeout = self.config.dns_timeout;
+            introspect::spawn("dns_hijack", async move {
+                if let Err(e) = forward_stream_queries(conn, dns_addr, dns_timeout).await {
+                    debug!(?e, ?real_dest, "hijack tcp dns query error");
+                }
+            });
+            return;
+        }
+
+        let ip = real_dest.ip().to_string();
+        let mut host = self
+            .resolver
+            .lookup_host(&ip)
+            .map(|s| Address::DomainNameAddress(s, real_dest.port()))
+            .unwrap_or_else(|| Address::SocketAddress(real_dest));
+        // Connect to the ip the app resolved unless `sniff_override` is set, so
+        // proxies resolve the sniffed domain near their exit. LAN connections keep their
+        // ip, a sniffed domain would take them past the bypass.
+        let mut connect_addr = host.clone();
+        let sniff = match &host {
+            Address::SocketAddress(addr) => !self.lan_bypasses(addr.ip()),
+            Address::DomainNameAddress(..) => false,
+        };
+        if let (true, Some(wait)) = (sniff, self.config.sniff_timeout) {
+            if let Some(domain) = sniff::peek_host(&conn, wait).await {
+                trace!(%domain, ?real_dest, "sniffed domain");
+                metrics::incr("sniffed_connections");
+                host = Address::DomainNameAddress(domain, real_dest.port());
+                if self.config.sniff_override {
+                    connect_addr = host.clone();
+                }
+            }
+        }
+
+        let tag = self.tag_for_host(&host);
+        trace!(dest_host = ?host, ?tag, "new relay connection");
+
+        let sock_addr = match self
+            .dns_client_for(&connect_addr)
+            .lookup_address(&connect_addr)
+            .await
+        {
+            Ok(a) => a,
+            Err(e) => {
+                error!(?e, ?host, "error resolve dns");
+                return;
+            }
+        };
+
+        trace!(ip = ?ip, host = ?host, "lookup host");
+
+        match self
+            .choose_proxy_tcp_stream(inbound, real_src, sock_addr, &host, &connect_addr)
             .await
-            .unwrap();
+        {
+            Ok((remote_conn, route)) => {
+                trace!("connect successfully");
+                let chooser = self.server_chooser.clone();
+                let server = remote_conn.config();
+                let read_timeout = server
+                    .and_then(ServerConfig::read_timeout)
+                    .unwrap_or(self.config.read_timeout);
+                let write_timeout = server
+                    .and_then(ServerConfig::write_timeout)
+                    .unwrap_or(self.config.write_timeout);
+                let rule = self.rule_of(&route);
+                let coalesce = rule.as_ref().and_then(|r| r.coalesce);
+                let buffer_size = self.config.tcp_relay_buffer;
+                let info = self
+                    .connection_info("tcp", real_src, sock_addr, &host, rule, &remote_conn)
+                    .await;
+                let tracked = self.connections.track(info, Box::new(remote_conn.clone()));
+                let inspect = self.alt_svc_inspector(&host, &remote_conn);
+                introspect::spawn(
+                    "tcp_relay",
+                    async move {
+                        let _tracked = tracked;
+                        let connected_at = Instant::now();
+                        let reason = tunnel_tcp_stream(
+                            conn,
+                            remote_conn.clone(),
+                            buffer_size,
+                            read_timeout,
+                            write_timeout,
+                            coalesce,
+                            inspect,
+                        )
+                        .await;
+                        let traffic = remote_conn.traffic();
+                        metrics::incr(&format!("relay_close{{reason=\"{}\"}}", reason.as_str()));
+                        if let Some(tag) = &tag {
+                            metrics::incr(&format!("tag_connections{{tag=\"{}\"}}", tag));
+                            metrics::add(
+                                &format!("tag_sent_bytes{{tag=\"{}\"}}", tag),
+                                traffic.sent_bytes() as u64,
+                            );
+                            metrics::add(
+                                &format!("tag_recv_bytes{{tag=\"{}\"}}", tag),
+                                traffic.received_bytes() as u64,
+                            );
+                        }
+                        debug!(
+                            reason = reason.as_str(),
+                            ?tag,
+                            sent_bytes = traffic.sent_bytes(),
+                            recv_bytes = traffic.received_bytes(),
+                            "relay closed"
+                        );
+                        if let (CloseReason::UpstreamError(e), Some(config)) =
+                            (&reason, remote_conn.config())
+                        {
+                            if is_reset(e)
+                                && traffic.received_bytes() == 0
+                                && connected_at.elapsed() < EARLY_RESET_WINDOW
+                            {
+                                chooser.report_early_reset(config);
+                            }
+                        }
+                    }
+                    .in_current_span(),
+                );
+            }
+            // The mode of the rule of the host, rejections by process rules, scripts or
+            // prompts answer like it too.
+            Err(e) if reject::is_rejected(&e) => {
+                let mode = self
+                    .rule_for_host(&host)
+                    .map(|r| r.reject)
+                    .unwrap_or_default();
+                trace!(?mode, "rejected");
+                introspect::spawn(
+                    "tcp_reject",
+                    reject::reject_tcp(conn, mode, self.config.read_timeout),
+                );
+            }
+            Err(e) => {
+                error!(?e, "connect error");
+            }
+        };
     }
 
-    fn get_udp_socket_and_dest_addr(&self, port: u16) -> Option<(ProxyUdpSocket, SocketAddr)> {
+    /// Relay connections until an error stops the listeners, like failing to bind them.
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        match self.config.mode {
+            Mode::Tproxy => {
+                let listen = self.config.tproxy_listen.as_deref().unwrap_or_default();
+                return self.clone().run_tproxy_server(listen).await;
+            }
+            Mode::Redirect => {
+                let listen = self.config.redirect_listen.as_deref().unwrap_or_default();
+                return self.clone().run_redirect_server(listen).await;
+            }
+            _ => {}
+        }
+        let relay = self
+            .clone()
+            .run_tcp_relay_server((self.config.tun_ip, 1300).into())
+            .race(self.run_udp_relay_server(([0, 0, 0, 0], 1300).into()));
+        match self.config.tun_ip6 {
+            // The NAT stack sends IPv6 connections to tun_ip6, the wildcard address would
+            // clash with the IPv4 UDP relay on dual-stack sockets.
+            Some(ip6) => {
+                relay
+                    .race(self.clone().run_tcp_relay_server((ip6, 1300).into()))
+                    .race(self.run_udp_relay_server((ip6, 1300).into()))
+                    .await
+            }
+            None => relay.await,
+        }
+    }
+
+    fn get_udp_queue(&self, port: u16) -> Option<UdpQueue> {
         let (real_src, real_dest) = self.session_manager.get_by_port(port)?;
         trace!(?real_src, ?real_dest, "new udp relay packet");
 
         self.udp_manager.read().get(&port).cloned()
     }
 
-    async fn new_udp_socket(&self, port: u16) -> Result<(ProxyUdpSocket, SocketAddr)> {
+    /// The socket for the session of `port`, with its destination and the rule routing it.
+    async fn new_udp_socket(
+        &self,
+        port: u16,
+    ) -> Result<(ProxyUdpSocket, Address, SocketAddr, Option<Rule>)> {
         let (real_src, real_dest) = match self.session_manager.get_by_port(port) {
             Some(s) => s,
             None => return Err(io::ErrorKind::AddrNotAvailable.into()),
@@ -250,8 +1108,10 @@ impl ProxyClient {
 
         trace!(?real_src, ?real_dest, "new udp relay packet");
 
-        if let Some(r) = self.udp_manager.read().get(&port) {
-            return Ok(r.clone());
+        if let Some(dns_addr) = self.hijacked_dns_addr(real_dest) {
+            trace!(?real_src, ?real_dest, "hijack udp dns query");
+            let socket = ProxyUdpSocket::new(None, self.dns_client.clone()).await?;
+            return Ok((socket, Address::SocketAddress(dns_addr), dns_addr, None));
         }
 
         let ip = real_dest.ip().to_string();
@@ -260,117 +1120,408 @@ impl ProxyClient {
             .lookup_host(&ip)
             .map(|s| Address::DomainNameAddress(s, real_dest.port()))
             .unwrap_or_else(|| Address::SocketAddress(real_dest));
-        let sock_addr = self.dns_client.lookup_address(&host).await?;
-        let socket = self
+        let tag = self.tag_for_host(&host);
+        trace!(dest_host = ?host, ?tag, "new udp relay");
+        if let Some(tag) = &tag {
+            metrics::incr(&format!("tag_udp_sessions{{tag=\"{}\"}}", tag));
+        }
+        let sock_addr = self.dns_client_for(&host).lookup_address(&host).await?;
+        let (socket, route) = self
             .choose_proxy_udp_socket(real_src, sock_addr, &host)
             .await?;
-        self.udp_manager
-            .write()
-            .insert(port, (socket.clone(), sock_addr));
-        Ok((socket, sock_addr))
+        let rule = self.rule_of(&route);
+        Ok((socket, host, sock_addr, rule))
     }
 
-    async fn run_udp_relay_server(&self) -> Result<()> {
-        let udp_listener = Arc::new(UdpSocket::bind("0.0.0.0:1300").await?);
+    async fn run_udp_relay_server(&self, addr: SocketAddr) -> Result<()> {
+        let udp_listener = Arc::new(UdpSocket::bind(addr).await?);
         let recv_timeout = self.config.read_timeout;
         let write_timeout = self.config.write_timeout;
         let mut buf = vec![0; 2000];
         loop {
             let (size, peer_addr) = udp_listener.recv_from(&mut buf).await?;
             assert!(size < 2000);
-            let (socket, dest_addr) = match self.get_udp_socket_and_dest_addr(peer_addr.port()) {
+            let queue = match self.get_udp_queue(peer_addr.port()) {
                 None => {
-                    let (socket, dest_addr) = match self.new_udp_socket(peer_addr.port()).await {
-                        Ok(r) => r,
-                        Err(e) => {
-                            error!(?e, "new udp socket");
-                            continue;
-                        }
-                    };
+                    let (socket, dest_host, dest_addr, rule) =
+                        match self.new_udp_socket(peer_addr.port()).await {
+                            Ok(r) => r,
+                            Err(e) => {
+                                error!(?e, "new udp socket");
+                                continue;
+                            }
+                        };
+                    let queue = UdpQueue::new(self.config.udp_queue_size);
+                    self.udp_manager
+                        .write()
+                        .insert(peer_addr.port(), queue.clone());
+
                     let socket_clone = socket.clone();
-                    let udp_listener_clone = udp_listener.clone();
+                    let queue_clone = queue.clone();
+                    introspect::spawn("udp_send", async move {
+                        while let Some(packet) = queue_clone.pop().await {
+                            if let Err(e) = timeout(
+                                write_timeout,
+                                socket_clone.send_to(&packet, &dest_host, dest_addr),
+                            )
+                            .await
+                            {
+                                error!(?e, "send to {}", dest_addr);
+                            }
+                        }
+                    });
 
+                    let queue_clone = queue.clone();
+                    let udp_listener_clone = udp_listener.clone();
                     let udp_manager = self.udp_manager.clone();
-                    spawn(async move {
+                    let real_src = self
+                        .session_manager
+                        .get_by_port(peer_addr.port())
+                        .map_or(peer_addr, |(src, _)| src);
+                    let info = self
+                        .connection_info("udp", real_src, dest_addr, &dest_host, rule, &socket)
+                        .await;
+                    let tracked = self.connections.track(info, Box::new(socket.clone()));
+                    introspect::spawn("udp_relay", async move {
+                        let _tracked = tracked;
                         let _: Result<()> = async {
                             let mut buf = vec![0; 2000];
                             loop {
                                 let (recv_size, _peer) =
-                                    timeout(recv_timeout, socket_clone.recv_from(&mut buf)).await?;
+                                    timeout(recv_timeout, socket.recv_from(&mut buf)).await?;
                                 assert!(recv_size < 2000);
                                 let send_size = timeout(
                                     write_timeout,
-                                    udp_listener_clone.send_to(&buf[..size], peer_addr),
+                                    udp_listener_clone.send_to(&buf[..recv_size], peer_addr),
                                 )
                                 .await?;
-                                assert_eq!(send_size, size);
+                                assert_eq!(send_size, recv_size);
                             }
                         }
                         .await;
                         let _ = udp_manager.write().remove(&peer_addr.port());
+                        queue_clone.close();
                     });
-                    (socket, dest_addr)
+                    queue
                 }
                 Some(r) => r,
             };
-            match timeout(write_timeout, socket.send_to(&buf[..size], dest_addr)).await {
-                Ok(send_size) => {
-                    assert_eq!(size, send_size);
-                }
-                Err(e) => error!(?e, "send to {}", dest_addr),
-            };
+            let dropped = queue.push(buf[..size].to_vec());
+            if dropped > 0 {
+                metrics::add("udp_queue_dropped", dropped as u64);
+            }
         }
     }
 }
 
-async fn tunnel_tcp_stream<T1: Read + Write + Unpin + Clone, T2: Read + Write + Unpin + Clone>(
-    mut conn1: T1,
-    mut conn2: T2,
-) -> Result<()> {
-    let mut conn1_clone = conn1.clone();
-    let mut conn2_clone = conn2.clone();
-    let f1 = async {
-        let mut buf = vec![0; 1500];
-        loop {
-            let size = conn1.read(&mut buf).await?;
-            if size == 0 {
-                break Ok(());
+/// Settings the rules applier handles, `rules_order` is a change of the order of the rules
+/// alone.
+pub(crate) const RULES_SETTINGS: &[&str] = &["rules", "final_target", "rules_order"];
+
+/// `base` with the appliers of the settings seeker changes while running: the DNS
+/// upstreams, the servers and groups, the rules and `lan_bypass`. Connections already relayed keep the
+/// route they got.
+#[allow(clippy::too_many_arguments)]
+fn reloader(
+    base: Reloader,
+    config: &Config,
+    live: &Arc<RwLock<Arc<Live>>>,
+    dns_client: &DnsClient,
+    resolver: &RuleBasedDnsResolver,
+    chooser: &Arc<ServerChooser>,
+    rule_stats: &Arc<RuleStats>,
+    process_lookup: &ProcessLookup,
+    has_script: bool,
+) -> Reloader {
+    let (dns_live, client, dns_resolver) = (live.clone(), dns_client.clone(), resolver.clone());
+    let (servers_live, chooser) = (live.clone(), chooser.clone());
+    let started_servers = config.servers.clone();
+    let kill_switch = config.kill_switch;
+    let (rules_live, rules_resolver) = (live.clone(), resolver.clone());
+    let bypass_live = live.clone();
+    let (rule_stats, process_lookup) = (rule_stats.clone(), process_lookup.clone());
+    base.with_applier(
+        "dns",
+        &[
+            "dns_servers",
+            "dns_resolvers",
+            "dnssec",
+            "dnssec_trust_anchor",
+        ],
+        move |new| {
+            let (fresh, upstreams) = async_std::task::block_on(async {
+                let fresh = DnsClient::new(
+                    &new.dns_servers,
+                    new.dns_timeout,
+                    new.dnssec,
+                    new.dnssec_root_keys.as_deref(),
+                    new.dns_ipv6,
+                    new.nat64_prefix,
+                    &new.dns_ttl,
+                )
+                .await;
+                (fresh, dns_upstreams(new).await)
+            });
+            client.replace(&fresh);
+            dns_resolver.set_upstreams(client.resolver(), upstream_resolvers(&upstreams));
+            update_live(&dns_live, |live| {
+                live.dns_servers = new.dns_servers.clone();
+                live.dns_upstreams = upstreams;
+            });
+            Ok(())
+        },
+    )
+    .with_applier(
+        "servers",
+        &[
+            "servers",
+            "subscription_url",
+            "subscription_path",
+            "proxy_groups",
+        ],
+        move |new| {
+            if kill_switch && new.servers != started_servers {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "kill_switch pins the servers seeker started with, restart to change them",
+                ));
             }
-            conn2.write_all(&buf[..size]).await?;
+            chooser.set_servers(new.servers.clone(), new.proxy_groups.clone());
+            update_live(&servers_live, |live| {
+                live.extra_directly_servers =
+                    new.servers.iter().map(|s| s.addr().to_string()).collect();
+            });
+            Ok(())
+        },
+    )
+    // The profile is only a name, the settings it changes have appliers of their own.
+    .with_applier("profile", &["profile"], |_| Ok(()))
+    .with_applier("rules", RULES_SETTINGS, move |new| {
+        let running = rules_live.read().rules.clone();
+        let names = |rules: &ProxyRules| rules.rule_set_names().into_iter().collect::<HashSet<_>>();
+        if names(&new.rules) != names(&running) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "restart seeker to change the RULE-SET lists the rules use",
+            ));
         }
-    };
-    let f2 = async {
-        let mut buf = vec![0; 1500];
-        loop {
-            let size = conn2_clone.read(&mut buf).await?;
-            if size == 0 {
-                break Ok(());
+        // The refreshers of `rule_providers` update the lists of the running rules.
+        let rules = new.rules.clone().with_rule_sets_of(&running);
+        rules_resolver.set_rules(rules.clone());
+        rule_stats.set_rules(rules.rules());
+        process_lookup.set_enabled(rules.has_process_rules() || has_script);
+        update_live(&rules_live, |live| live.rules = rules);
+        Ok(())
+    })
+    .with_applier("lan_bypass", &["lan_bypass"], move |new| {
+        update_live(&bypass_live, |live| live.lan_bypass = new.lan_bypass);
+        Ok(())
+    })
+}
+
+/// Start over after the network changed: a new client for the running `dns_servers`, which
+/// drops the cached answers, proxy connections closed so clients reconnect right away, and
+/// the servers pinged through the new network. Direct connections are left to time out.
+#[cfg(unix)]
+fn network_recovery(
+    config: &Config,
+    live: &Arc<RwLock<Arc<Live>>>,
+    dns_client: &DnsClient,
+    resolver: &RuleBasedDnsResolver,
+    chooser: &Arc<ServerChooser>,
+) -> Arc<dyn Fn() + Send + Sync> {
+    let (config, live, client) = (config.clone(), live.clone(), dns_client.clone());
+    let (resolver, chooser) = (resolver.clone(), chooser.clone());
+    Arc::new(move || {
+        // Without a default route yet the sockets stay bound to the previous interface.
+        #[cfg(target_os = "macos")]
+        if let Some(interface) = sysconfig::default_interface() {
+            if let Err(e) = sysconfig::set_outbound_interface(&interface) {
+                tracing::warn!(?e, "can not bind outbound sockets to the default interface");
             }
-            conn1_clone.write_all(&buf[..size]).await?;
         }
-    };
-    f1.race(f2).await
+        let running = live.read().clone();
+        async_std::task::block_on(async {
+            let fresh = DnsClient::new(
+                &running.dns_servers,
+                config.dns_timeout,
+                config.dnssec,
+                config.dnssec_root_keys.as_deref(),
+                config.dns_ipv6,
+                config.nat64_prefix,
+                &config.dns_ttl,
+            )
+            .await;
+            client.replace(&fresh);
+            resolver.set_upstreams(
+                client.resolver(),
+                upstream_resolvers(&running.dns_upstreams),
+            );
+            chooser.shutdown_connections();
+            chooser.ping_servers().await;
+        });
+    })
+}
+
+fn update_live(live: &RwLock<Arc<Live>>, f: impl FnOnce(&mut Live)) {
+    let mut live = live.write();
+    let mut next = (**live).clone();
+    f(&mut next);
+    *live = Arc::new(next);
+}
+
+/// A client for each of `dns_resolvers`, with the settings of the default one.
+async fn dns_upstreams(config: &Config) -> HashMap<String, DnsClient> {
+    let mut upstreams = HashMap::new();
+    for (name, servers) in &config.dns_resolvers {
+        let client = DnsClient::new(
+            servers,
+            config.dns_timeout,
+            config.dnssec,
+            config.dnssec_root_keys.as_deref(),
+            config.dns_ipv6,
+            config.nat64_prefix,
+            &config.dns_ttl,
+        )
+        .await;
+        upstreams.insert(name.clone(), client);
+    }
+    upstreams
+}
+
+fn upstream_resolvers(upstreams: &HashMap<String, DnsClient>) -> HashMap<String, Resolver> {
+    upstreams
+        .iter()
+        .map(|(name, client)| (name.clone(), client.resolver()))
+        .collect()
 }
 
-async fn run_dns_resolver(config: &Config, resolver: AsyncStdResolver) -> RuleBasedDnsResolver {
+async fn run_dns_resolver(
+    config: &Config,
+    resolver: Resolver,
+    upstreams: &HashMap<String, DnsClient>,
+) -> RuleBasedDnsResolver {
     let (dns_server, resolver) = create_dns_server(
         "dns.db",
         config.dns_listen.clone(),
         config.dns_start_ip,
         config.rules.clone(),
+        resolver_options(config, true),
         resolver,
+        upstream_resolvers(upstreams),
     )
     .await;
     println!("Spawn DNS server");
-    spawn(
-        dns_server
-            .run_server()
-            .instrument(trace_span!("dns_server.run_server")),
-    );
+    // Queries are answered on the priority executor, ahead of relayed data.
+    let dns_server = dns_server.with_spawner(Arc::new(|task| priority::spawn(task).detach()));
+    Supervisor::new(config.task_max_failures).spawn("dns_server", move || {
+        let server = dns_server.clone();
+        async move {
+            let run = server
+                .run_server()
+                .instrument(trace_span!("dns_server.run_server"));
+            priority::spawn(run).await;
+            Ok(())
+        }
+    });
+    spawn_dns_inbound(config);
     resolver
 }
 
-#[cfg(target_arch = "x86_64")]
+fn resolver_options(config: &Config, fake_ip: bool) -> ResolverOptions {
+    ResolverOptions {
+        ipv6_policy: config.dns_ipv6,
+        fake_ip,
+        rebind_protection: config.dns_rebind_protection,
+        rebind_allowlist: config.dns_rebind_allowlist.clone(),
+        lan_dns: config.lan_dns.clone(),
+        query_log_size: config.dns_query_log_size,
+        nat64_prefix: config.nat64_prefix,
+        domestic_ips: config.domestic_ips.clone(),
+        domestic_dns: config.domestic_dns.clone(),
+        ttl: config.dns_ttl.clone(),
+    }
+}
+
+#[cfg(not(feature = "dns-inbound"))]
+fn spawn_dns_inbound(config: &Config) {
+    if config.dot_listen.is_some() || config.doh_listen.is_some() {
+        error!("DoT/DoH listeners need seeker built with the dns-inbound feature");
+    }
+}
+
+/// Start the DoT/DoH listeners configured in `config`.
+#[cfg(feature = "dns-inbound")]
+fn spawn_dns_inbound(config: &Config) {
+    if config.dot_listen.is_none() && config.doh_listen.is_none() {
+        return;
+    }
+    let dns_addr = match local_dns_addr(&config.dns_listen) {
+        Some(addr) => addr,
+        None => return,
+    };
+    let dns_timeout = config.dns_timeout;
+    let supervisor = Supervisor::new(config.task_max_failures);
+    let acceptor = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => load_tls_acceptor(cert, key),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "dot_listen and doh_listen require tls_cert and tls_key",
+        )),
+    };
+    let acceptor = match acceptor {
+        Ok(acceptor) => acceptor,
+        Err(e) => {
+            error!(?e, "DoT/DoH server error");
+            return;
+        }
+    };
+    if let Some(listen) = config.dot_listen.clone() {
+        let acceptor = acceptor.clone();
+        supervisor.spawn("dot_server", move || {
+            run_dot_server(listen.clone(), acceptor.clone(), dns_addr, dns_timeout)
+        });
+    }
+    if let Some(listen) = config.doh_listen.clone() {
+        supervisor.spawn("doh_server", move || {
+            run_doh_server(listen.clone(), acceptor.clone(), dns_addr, dns_timeout)
+        });
+    }
+}
+
+/// Run only the rules-aware DNS server without tun. Domains are resolved to their real
+/// addresses instead of fake ips since there is no relay to map them back.
+pub async fn run_dns_only(config: Config) {
+    let dns_client = DnsClient::new(
+        &config.dns_servers,
+        config.dns_timeout,
+        config.dnssec,
+        config.dnssec_root_keys.as_deref(),
+        config.dns_ipv6,
+        config.nat64_prefix,
+        &config.dns_ttl,
+    )
+    .await;
+    let (dns_server, _resolver) = create_dns_server(
+        "dns.db",
+        config.dns_listen.clone(),
+        config.dns_start_ip,
+        config.rules.clone(),
+        resolver_options(&config, false),
+        dns_client.resolver(),
+        upstream_resolvers(&dns_upstreams(&config).await),
+    )
+    .await;
+    println!("Spawn DNS server");
+    rule_providers::spawn_refreshers(Supervisor::new(config.task_max_failures), &config);
+    spawn_dns_inbound(&config);
+    dns_server
+        .run_server()
+        .instrument(trace_span!("dns_server.run_server"))
+        .await
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 fn socket_addr_belong_to_user(addr: SocketAddr, uid: u32) -> Result<bool> {
     use sysconfig::SocketInfo;
     let user_socks: HashMap<i32, Vec<SocketInfo>> = sysconfig::list_user_proc_socks(uid)?;
@@ -379,7 +1530,7 @@ fn socket_addr_belong_to_user(addr: SocketAddr, uid: u32) -> Result<bool> {
         .any(|sockets| sockets.iter().any(|s| s.local == addr)))
 }
 
-#[cfg(not(target_arch = "x86_64"))]
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
 fn socket_addr_belong_to_user(_addr: SocketAddr, _uid: u32) -> Result<bool> {
     Ok(true)
 }
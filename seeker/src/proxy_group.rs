@@ -0,0 +1,166 @@
+//! Named groups of servers that rules send connections to, each picking one of its
+//! servers by its own strategy.
+use config::{GroupStrategy, ProxyGroup, ServerConfig};
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct Group {
+    config: ProxyGroup,
+    /// Turn of `GroupStrategy::RoundRobin`.
+    next: AtomicUsize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupSnapshot {
+    pub name: String,
+    pub strategy: String,
+    pub servers: Vec<String>,
+    /// Server the next connection would use, `None` when it falls through to `final`.
+    pub current: Option<String>,
+    #[serde(rename = "final")]
+    pub final_target: Option<String>,
+}
+
+impl Group {
+    pub fn new(config: ProxyGroup) -> Self {
+        Group {
+            config,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Choose a server of the group. `candidates` are the servers that answered the last
+    /// ping, best first, and `usable` says whether one is not quarantined. When no member
+    /// answered, the first one listed is used anyway, unless the group has a `final` target
+    /// to fall through to.
+    pub fn pick(
+        &self,
+        servers: &[ServerConfig],
+        candidates: &[ServerConfig],
+        usable: impl Fn(&ServerConfig) -> bool,
+    ) -> Option<ServerConfig> {
+        let turn = self.next.fetch_add(1, Ordering::Relaxed);
+        self.choose(servers, candidates, usable, turn)
+    }
+
+    /// Where connections go when `pick` finds no usable server.
+    pub fn final_target(&self) -> Option<&str> {
+        self.config.final_target.as_deref()
+    }
+
+    /// Describe the group and the server `pick` would choose next.
+    pub fn snapshot(
+        &self,
+        servers: &[ServerConfig],
+        candidates: &[ServerConfig],
+        usable: impl Fn(&ServerConfig) -> bool,
+    ) -> GroupSnapshot {
+        let turn = self.next.load(Ordering::Relaxed);
+        GroupSnapshot {
+            name: self.config.name.clone(),
+            strategy: format!("{:?}", self.config.strategy),
+            servers: self.config.servers.clone(),
+            current: self
+                .choose(servers, candidates, usable, turn)
+                .map(|s| s.name().to_string()),
+            final_target: self.config.final_target.clone(),
+        }
+    }
+
+    fn choose(
+        &self,
+        servers: &[ServerConfig],
+        candidates: &[ServerConfig],
+        usable: impl Fn(&ServerConfig) -> bool,
+        turn: usize,
+    ) -> Option<ServerConfig> {
+        let reachable: Vec<&ServerConfig> = match self.config.strategy {
+            GroupStrategy::Latency => candidates
+                .iter()
+                .filter(|c| self.config.servers.iter().any(|s| s == c.name()))
+                .collect(),
+            GroupStrategy::Fallback | GroupStrategy::RoundRobin => self
+                .config
+                .servers
+                .iter()
+                .filter_map(|s| candidates.iter().find(|c| c.name() == s))
+                .collect(),
+        };
+        let mut pool: Vec<&ServerConfig> =
+            reachable.iter().copied().filter(|c| usable(c)).collect();
+        if pool.is_empty() {
+            if self.config.final_target.is_some() {
+                return None;
+            }
+            pool = reachable;
+        }
+        let chosen = match self.config.strategy {
+            GroupStrategy::RoundRobin if !pool.is_empty() => Some(pool[turn % pool.len()]),
+            _ => pool.first().copied(),
+        };
+        chosen
+            .or_else(|| {
+                let first = self.config.servers.first()?;
+                servers.iter().find(|s| s.name() == first)
+            })
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(name: &str) -> ServerConfig {
+        serde_yaml::from_str(&format!(
+            "{{name: {}, addr: '127.0.0.1:1080', protocol: Socks5}}",
+            name
+        ))
+        .unwrap()
+    }
+
+    fn group(strategy: GroupStrategy) -> Group {
+        Group::new(ProxyGroup {
+            name: "Streaming".to_string(),
+            servers: vec!["us".to_string(), "jp".to_string(), "hk".to_string()],
+            strategy,
+            final_target: None,
+        })
+    }
+
+    #[test]
+    fn test_pick() {
+        let servers = vec![server("hk"), server("jp"), server("us"), server("sg")];
+        // Ranked by latency: sg, hk, jp, us is unreachable.
+        let candidates = vec![server("sg"), server("hk"), server("jp")];
+        let pick = |g: &Group, usable: &dyn Fn(&ServerConfig) -> bool| {
+            g.pick(&servers, &candidates, usable)
+                .map(|s| s.name().to_string())
+        };
+
+        let latency = group(GroupStrategy::Latency);
+        assert_eq!(pick(&latency, &|_| true).as_deref(), Some("hk"));
+        assert_eq!(pick(&latency, &|s| s.name() != "hk").as_deref(), Some("jp"));
+
+        let fallback = group(GroupStrategy::Fallback);
+        assert_eq!(pick(&fallback, &|_| true).as_deref(), Some("jp"));
+        // Quarantined servers are still used when nothing else is left.
+        assert_eq!(pick(&fallback, &|_| false).as_deref(), Some("jp"));
+
+        let round_robin = group(GroupStrategy::RoundRobin);
+        let picks: Vec<_> = (0..3)
+            .map(|_| pick(&round_robin, &|_| true).unwrap())
+            .collect();
+        assert_eq!(picks, vec!["jp", "hk", "jp"]);
+
+        assert_eq!(latency.pick(&servers, &[], |_| true).unwrap().name(), "us");
+
+        let with_final = Group::new(ProxyGroup {
+            final_target: Some("DIRECT".to_string()),
+            ..group(GroupStrategy::Fallback).config
+        });
+        assert_eq!(pick(&with_final, &|_| true).as_deref(), Some("jp"));
+        assert_eq!(pick(&with_final, &|_| false), None);
+        assert!(with_final.pick(&servers, &[], |_| true).is_none());
+    }
+}
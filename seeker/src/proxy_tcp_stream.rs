@@ -1,6 +1,7 @@
 use async_std::io::{Read, Write};
 use async_std::net::TcpStream;
-use config::{Address, ServerConfig, ServerProtocol};
+use config::error_code::{CONFIG_INVALID, HANDSHAKE, SERVER_UNREACHABLE};
+use config::{Address, AddressPreference, ServerConfig, ServerProtocol};
 use http_proxy_client::{HttpProxyTcpStream, HttpsProxyTcpStream};
 use socks5_client::Socks5TcpStream;
 use ssclient::SSTcpStream;
@@ -9,12 +10,14 @@ use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use crate::dns_client::DnsClient;
+use crate::outbound;
 use crate::proxy_connection::ProxyConnection;
 use crate::traffic::Traffic;
 use async_std::task::ready;
 use std::io::{Error, ErrorKind};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use tracing::debug;
 
 #[derive(Clone)]
 enum ProxyTcpStreamInner {
@@ -42,12 +45,16 @@ impl ProxyTcpStream {
     ) -> Result<ProxyTcpStream> {
         let remote_addr_clone = remote_addr.clone();
         let stream = if let Some(config) = config {
+            let remote_addr = proxy_target(config, remote_addr, &dns_client).await;
             match config.protocol() {
                 ServerProtocol::Https => {
-                    let proxy_socket_addr = dns_client.lookup_address(config.addr()).await?;
+                    let proxy_socket_addr = dns_client
+                        .dial_server(config.addr())
+                        .await
+                        .map_err(|e| SERVER_UNREACHABLE.wrap(e))?;
                     let proxy_hostname = match config.addr().hostname() {
                         None => {
-                            return Err(Error::new(
+                            return Err(CONFIG_INVALID.error(
                                 ErrorKind::InvalidData,
                                 "proxy domain must not be empty for https protocol.",
                             ))
@@ -55,53 +62,84 @@ impl ProxyTcpStream {
                         Some(s) => s,
                     };
                     ProxyTcpStreamInner::HttpsProxy(
-                        HttpsProxyTcpStream::connect(
-                            proxy_socket_addr,
+                        HttpsProxyTcpStream::connect_stream(
+                            outbound::connect_server(proxy_socket_addr, config)
+                                .await
+                                .map_err(|e| SERVER_UNREACHABLE.wrap(e))?,
                             proxy_hostname.to_string(),
                             remote_addr,
                             config.username(),
                             config.password(),
                         )
-                        .await?,
+                        .await
+                        .map_err(|e| HANDSHAKE.wrap(e))?,
                     )
                 }
                 ServerProtocol::Http => {
-                    let proxy_socket_addr = dns_client.lookup_address(config.addr()).await?;
+                    let proxy_socket_addr = dns_client
+                        .dial_server(config.addr())
+                        .await
+                        .map_err(|e| SERVER_UNREACHABLE.wrap(e))?;
                     ProxyTcpStreamInner::HttpProxy(
-                        HttpProxyTcpStream::connect(
-                            proxy_socket_addr,
+                        HttpProxyTcpStream::connect_stream(
+                            outbound::connect_server(proxy_socket_addr, config)
+                                .await
+                                .map_err(|e| SERVER_UNREACHABLE.wrap(e))?,
                             remote_addr,
                             config.username(),
                             config.password(),
                         )
-                        .await?,
+                        .await
+                        .map_err(|e| HANDSHAKE.wrap(e))?,
                     )
                 }
                 ServerProtocol::Socks5 => {
-                    let proxy_socket_addr = dns_client.lookup_address(config.addr()).await?;
+                    let proxy_socket_addr = dns_client
+                        .dial_server(config.addr())
+                        .await
+                        .map_err(|e| SERVER_UNREACHABLE.wrap(e))?;
+                    let conn = outbound::connect_server(proxy_socket_addr, config)
+                        .await
+                        .map_err(|e| SERVER_UNREACHABLE.wrap(e))?;
                     ProxyTcpStreamInner::Socks5(
-                        Socks5TcpStream::connect(proxy_socket_addr, remote_addr).await?,
+                        Socks5TcpStream::connect_stream(conn, remote_addr)
+                            .await
+                            .map_err(|e| HANDSHAKE.wrap(e))?,
                     )
                 }
                 ServerProtocol::Shadowsocks => {
-                    let proxy_socket_addr = dns_client.lookup_address(config.addr()).await?;
+                    let proxy_socket_addr = dns_client
+                        .dial_server(config.addr())
+                        .await
+                        .map_err(|e| SERVER_UNREACHABLE.wrap(e))?;
                     let (method, key) = match (config.method(), config.key()) {
                         (Some(m), Some(k)) => (m, k),
                         _ => {
-                            return Err(Error::new(
+                            return Err(CONFIG_INVALID.error(
                                 ErrorKind::InvalidData,
                                 "method and password must be set for ss protocol.",
                             ))
                         }
                     };
+                    let conn = outbound::connect_server(proxy_socket_addr, config)
+                        .await
+                        .map_err(|e| SERVER_UNREACHABLE.wrap(e))?;
                     ProxyTcpStreamInner::Shadowsocks(
-                        SSTcpStream::connect(proxy_socket_addr, remote_addr, method, key).await?,
+                        SSTcpStream::connect_stream_with_salt_size(
+                            conn,
+                            remote_addr,
+                            method,
+                            key,
+                            config.salt_size(),
+                        )
+                        .await
+                        .map_err(|e| HANDSHAKE.wrap(e))?,
                     )
                 }
             }
         } else {
-            let socket_addr = dns_client.lookup_address(&remote_addr).await?;
-            ProxyTcpStreamInner::Direct(TcpStream::connect(socket_addr).await?)
+            let socket_addr = dns_client.dial_address(&remote_addr).await?;
+            ProxyTcpStreamInner::Direct(outbound::connect(socket_addr).await?)
         };
 
         Ok(ProxyTcpStream {
@@ -114,6 +152,26 @@ impl ProxyTcpStream {
     }
 }
 
+/// The target sent to the proxy in `config` for `remote_addr`.
+async fn proxy_target(
+    config: &ServerConfig,
+    remote_addr: Address,
+    dns_client: &DnsClient,
+) -> Address {
+    if config.address_preference() != AddressPreference::IpFirst {
+        return remote_addr;
+    }
+    if let Address::DomainNameAddress(domain, _) = &remote_addr {
+        match dns_client.lookup_address(&remote_addr).await {
+            Ok(addr) => return Address::SocketAddress(addr),
+            Err(e) => {
+                debug!(?e, %domain, "resolve target for ip-first server, send domain instead")
+            }
+        }
+    }
+    remote_addr
+}
+
 impl ProxyConnection for ProxyTcpStream {
     fn traffic(&self) -> Traffic {
         self.traffic.clone()
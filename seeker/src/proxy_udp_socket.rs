@@ -1,14 +1,21 @@
 use crate::dns_client::DnsClient;
+use crate::outbound;
 use crate::proxy_connection::ProxyConnection;
 use crate::traffic::Traffic;
+use async_std::io::timeout;
 use async_std::net::{SocketAddr, UdpSocket};
-use config::{ServerConfig, ServerProtocol};
+use config::error_code::UDP_PROXY;
+use config::{Address, AddressPreference, ServerConfig, ServerProtocol};
 use socks5_client::Socks5UdpSocket;
 use ssclient::SSUdpSocket;
 use std::io;
 use std::io::{Error, ErrorKind};
+use std::net::Ipv4Addr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+
+const SOCKS5_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 enum ProxyUdpSocketInner {
@@ -25,38 +32,55 @@ pub struct ProxyUdpSocket {
     traffic: Traffic,
 }
 
-impl ProxyUdpSocket {
-    pub async fn new(config: Option<&ServerConfig>, dns_client: DnsClient) -> io::Result<Self> {
-        let socket = if let Some(config) = config {
-            match config.protocol() {
-                ServerProtocol::Socks5 => {
-                    let server = dns_client.lookup_address(&config.addr()).await?;
-                    ProxyUdpSocketInner::Socks5(Arc::new(Socks5UdpSocket::new(server).await?))
-                }
-                ServerProtocol::Shadowsocks => {
-                    let server = dns_client.lookup_address(&config.addr()).await?;
-                    let (method, key) = match (config.method(), config.key()) {
-                        (Some(m), Some(k)) => (m, k),
-                        _ => {
-                            return Err(Error::new(
-                                ErrorKind::InvalidData,
-                                "method and password must be set for ss protocol.",
-                            ))
-                        }
-                    };
-
-                    let udp = SSUdpSocket::new(server, method, key).await?;
-                    ProxyUdpSocketInner::Shadowsocks(Arc::new(udp))
-                }
-                protocol => {
+/// A UDP relay through the server of `config`.
+async fn relay_through(
+    config: &ServerConfig,
+    dns_client: &DnsClient,
+) -> io::Result<ProxyUdpSocketInner> {
+    let socket = match config.protocol() {
+        ServerProtocol::Socks5 => {
+            let server = dns_client.dial_server(config.addr()).await?;
+            let conn = outbound::connect_server(server, config);
+            let conn = timeout(SOCKS5_CONNECT_TIMEOUT, conn).await?;
+            let udp = Socks5UdpSocket::associate(outbound::bind_udp(server)?, conn).await?;
+            ProxyUdpSocketInner::Socks5(Arc::new(udp))
+        }
+        ServerProtocol::Shadowsocks => {
+            let server = dns_client.dial_server(config.addr()).await?;
+            let (method, key) = match (config.method(), config.key()) {
+                (Some(m), Some(k)) => (m, k),
+                _ => {
                     return Err(Error::new(
-                        ErrorKind::ConnectionRefused,
-                        format!("udp not supported for {:?}.", protocol),
+                        ErrorKind::InvalidData,
+                        "method and password must be set for ss protocol.",
                     ))
                 }
-            }
+            };
+
+            let socket = outbound::bind_udp(server)?;
+            socket.connect(server).await?;
+            let udp = SSUdpSocket::bind(socket, method, key).with_salt_size(config.salt_size());
+            ProxyUdpSocketInner::Shadowsocks(Arc::new(udp))
+        }
+        protocol => {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("udp not supported for {:?}.", protocol),
+            ))
+        }
+    };
+    Ok(socket)
+}
+
+impl ProxyUdpSocket {
+    pub async fn new(config: Option<&ServerConfig>, dns_client: DnsClient) -> io::Result<Self> {
+        let socket = if let Some(config) = config {
+            relay_through(config, &dns_client)
+                .await
+                .map_err(|e| UDP_PROXY.wrap(e))?
         } else {
-            ProxyUdpSocketInner::Direct(Arc::new(UdpSocket::bind("0.0.0.0:0").await?))
+            let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+            ProxyUdpSocketInner::Direct(Arc::new(outbound::bind_udp(unspecified)?))
         };
         Ok(ProxyUdpSocket {
             inner: socket,
@@ -66,7 +90,10 @@ impl ProxyUdpSocket {
         })
     }
 
-    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+    /// Send `buf` to `host`, which resolved to `addr`.
+    ///
+    /// Proxies get the domain of `host` unless the server prefers ips.
+    pub async fn send_to(&self, buf: &[u8], host: &Address, addr: SocketAddr) -> io::Result<usize> {
         if !self.alive.load(Ordering::SeqCst) {
             return Err(Error::new(
                 ErrorKind::BrokenPipe,
@@ -75,8 +102,21 @@ impl ProxyUdpSocket {
         }
         match &self.inner {
             ProxyUdpSocketInner::Direct(socket) => socket.send_to(buf, addr).await,
-            ProxyUdpSocketInner::Socks5(socket) => socket.send_to(buf, addr).await,
-            ProxyUdpSocketInner::Shadowsocks(socket) => socket.send_to(buf, addr).await,
+            ProxyUdpSocketInner::Socks5(socket) => {
+                socket.send_to(buf, self.target(host, addr)).await
+            }
+            ProxyUdpSocketInner::Shadowsocks(socket) => {
+                socket.send_to(buf, self.target(host, addr)).await
+            }
+        }
+    }
+
+    fn target(&self, host: &Address, addr: SocketAddr) -> Address {
+        let prefer_domain = self.config.as_ref().map(|c| c.address_preference())
+            == Some(AddressPreference::DomainFirst);
+        match host {
+            Address::DomainNameAddress(_, _) if prefer_domain => host.clone(),
+            _ => Address::SocketAddress(addr),
         }
     }
 
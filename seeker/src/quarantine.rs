@@ -0,0 +1,175 @@
+use config::ServerConfig;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Window after a successful handshake in which a reset counts as an early reset.
+pub const EARLY_RESET_WINDOW: Duration = Duration::from_secs(2);
+/// Number of early resets within `duration` before a server is quarantined.
+const EARLY_RESET_THRESHOLD: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum QuarantineReason {
+    /// Handshake succeeded but the server reset the connection before sending any data.
+    EarlyReset,
+    /// The TLS certificate presented by the server does not match its name.
+    TlsMismatch,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QuarantinedServer {
+    pub name: String,
+    pub addr: String,
+    pub reason: QuarantineReason,
+    pub remaining_secs: u64,
+}
+
+struct Entry {
+    name: String,
+    strikes: usize,
+    last_strike: Instant,
+    until: Option<Instant>,
+    reason: QuarantineReason,
+}
+
+/// Servers that look alive to probes but misbehave on real traffic.
+///
+/// Quarantined servers are skipped by the chooser until the quarantine expires.
+pub struct Quarantine {
+    entries: Mutex<HashMap<String, Entry>>,
+    duration: Duration,
+}
+
+impl Quarantine {
+    pub fn new(duration: Duration) -> Self {
+        Quarantine {
+            entries: Mutex::new(HashMap::new()),
+            duration,
+        }
+    }
+
+    /// Record a misbehaviour of `config`. Returns true if the server got quarantined.
+    pub fn strike(&self, config: &ServerConfig, reason: QuarantineReason) -> bool {
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        let entry = entries
+            .entry(config.addr().to_string())
+            .or_insert_with(|| Entry {
+                name: config.name().to_string(),
+                strikes: 0,
+                last_strike: now,
+                until: None,
+                reason,
+            });
+        if now.duration_since(entry.last_strike) > self.duration {
+            entry.strikes = 0;
+        }
+        entry.strikes += 1;
+        entry.last_strike = now;
+        entry.reason = reason;
+
+        let threshold = match reason {
+            QuarantineReason::TlsMismatch => 1,
+            QuarantineReason::EarlyReset => EARLY_RESET_THRESHOLD,
+        };
+        if entry.strikes < threshold || entry.until.map(|t| t > now) == Some(true) {
+            return false;
+        }
+        entry.until = Some(now + self.duration);
+        warn!(
+            name = config.name(),
+            server = ?config.addr(),
+            ?reason,
+            "Quarantine server"
+        );
+        true
+    }
+
+    pub fn is_quarantined(&self, config: &ServerConfig) -> bool {
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        let key = config.addr().to_string();
+        match entries.get(&key).and_then(|e| e.until) {
+            Some(until) if until > now => true,
+            Some(_) => {
+                entries.remove(&key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn list(&self) -> Vec<QuarantinedServer> {
+        let now = Instant::now();
+        self.entries
+            .lock()
+            .iter()
+            .filter_map(|(addr, e)| {
+                let until = e.until.filter(|t| *t > now)?;
+                Some(QuarantinedServer {
+                    name: e.name.clone(),
+                    addr: addr.clone(),
+                    reason: e.reason,
+                    remaining_secs: until.duration_since(now).as_secs(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Whether a connect error means the server presented a certificate we can not accept.
+pub fn is_tls_mismatch(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::InvalidData && e.to_string().contains("WebPKIError")
+}
+
+/// Whether a relay error looks like the server killed the connection with a RST.
+pub fn is_reset(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> ServerConfig {
+        serde_yaml::from_str(
+            r#"
+name: server1
+addr: 127.0.0.1:1080
+protocol: Socks5
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_early_reset_threshold() {
+        let quarantine = Quarantine::new(Duration::from_secs(60));
+        let config = server();
+        assert!(!quarantine.strike(&config, QuarantineReason::EarlyReset));
+        assert!(!quarantine.strike(&config, QuarantineReason::EarlyReset));
+        assert!(!quarantine.is_quarantined(&config));
+        assert!(quarantine.strike(&config, QuarantineReason::EarlyReset));
+        assert!(quarantine.is_quarantined(&config));
+        assert_eq!(quarantine.list().len(), 1);
+    }
+
+    #[test]
+    fn test_tls_mismatch_and_expire() {
+        let quarantine = Quarantine::new(Duration::from_millis(10));
+        let config = server();
+        assert!(quarantine.strike(&config, QuarantineReason::TlsMismatch));
+        assert!(quarantine.is_quarantined(&config));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!quarantine.is_quarantined(&config));
+        assert!(quarantine.list().is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+//! Answer TCP connections rejected by a rule as its `RejectMode` says.
+use async_std::io::{self, timeout};
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use config::rule::RejectMode;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+use sysconfig::AsSocket;
+
+const FORBIDDEN: &[u8] =
+    b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+#[derive(Debug)]
+struct Rejected;
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rejected by rule")
+    }
+}
+
+impl Error for Rejected {}
+
+pub fn rejected() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, Rejected)
+}
+
+pub fn is_rejected(e: &io::Error) -> bool {
+    e.get_ref().map_or(false, |e| e.is::<Rejected>())
+}
+
+/// Answer `conn`, waiting at most `idle` for the client to send or close.
+pub async fn reject_tcp(conn: TcpStream, mode: RejectMode, idle: Duration) {
+    match mode {
+        RejectMode::Reset => reset(conn),
+        RejectMode::Drop => {
+            let _ = timeout(idle, io::copy(&mut &conn, &mut io::sink())).await;
+        }
+        RejectMode::Http403 => {
+            let mut buf = vec![0; 1024];
+            match timeout(idle, (&conn).read(&mut buf)).await {
+                Ok(size) if is_http_request(&buf[..size]) => {
+                    let _ = (&conn).write_all(FORBIDDEN).await;
+                }
+                _ => reset(conn),
+            }
+        }
+    }
+}
+
+/// Close with a RST instead of a FIN.
+fn reset(conn: TcpStream) {
+    let _ = sysconfig::reset_on_close(conn.raw_socket());
+}
+
+fn is_http_request(data: &[u8]) -> bool {
+    let line = data.split(|b| *b == b'\n').next().unwrap_or_default();
+    line.windows(8).any(|w| w == b" HTTP/1.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use async_std::task::{block_on, spawn};
+
+    #[test]
+    fn test_is_http_request() {
+        assert!(is_http_request(
+            b"GET / HTTP/1.1\r\nHost: ads.example.com\r\n"
+        ));
+        assert!(!is_http_request(b"\x16\x03\x01\x02\x00\x01"));
+        assert!(!is_http_request(b""));
+    }
+
+    #[test]
+    fn test_reject_http() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let h = spawn(async move {
+                let (conn, _) = listener.accept().await.unwrap();
+                reject_tcp(conn, RejectMode::Http403, Duration::from_secs(1)).await;
+            });
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            let mut resp = vec![];
+            client.read_to_end(&mut resp).await.unwrap();
+            assert_eq!(resp, FORBIDDEN);
+            h.await;
+        })
+    }
+
+    #[test]
+    fn test_reject_reset() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let h = spawn(async move {
+                let (conn, _) = listener.accept().await.unwrap();
+                reject_tcp(conn, RejectMode::Reset, Duration::from_secs(1)).await;
+            });
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            h.await;
+            let mut buf = [0; 1];
+            let err = client.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        })
+    }
+}
@@ -0,0 +1,256 @@
+use async_std::io::{timeout, Read, Write};
+use async_std::prelude::*;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Why a relayed TCP flow was torn down.
+#[derive(Debug)]
+pub enum CloseReason {
+    ClientClosed,
+    UpstreamClosed,
+    /// The client did not drain the data sent by upstream within `write_timeout`.
+    ClientStall,
+    /// Upstream did not drain the data sent by the client within `write_timeout`.
+    UpstreamStall,
+    /// No bytes in either direction for `read_timeout`.
+    Idle,
+    ClientError(io::Error),
+    UpstreamError(io::Error),
+}
+
+impl CloseReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::ClientClosed => "client_closed",
+            CloseReason::UpstreamClosed => "upstream_closed",
+            CloseReason::ClientStall => "client_stall",
+            CloseReason::UpstreamStall => "upstream_stall",
+            CloseReason::Idle => "idle",
+            CloseReason::ClientError(_) => "client_error",
+            CloseReason::UpstreamError(_) => "upstream_error",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    /// client -> upstream
+    Upload,
+    /// upstream -> client
+    Download,
+}
+
+impl Direction {
+    fn src_closed(self) -> CloseReason {
+        match self {
+            Direction::Upload => CloseReason::ClientClosed,
+            Direction::Download => CloseReason::UpstreamClosed,
+        }
+    }
+
+    fn src_error(self, e: io::Error) -> CloseReason {
+        match self {
+            Direction::Upload => CloseReason::ClientError(e),
+            Direction::Download => CloseReason::UpstreamError(e),
+        }
+    }
+
+    fn dst_stall(self) -> CloseReason {
+        match self {
+            Direction::Upload => CloseReason::UpstreamStall,
+            Direction::Download => CloseReason::ClientStall,
+        }
+    }
+
+    fn dst_error(self, e: io::Error) -> CloseReason {
+        match self {
+            Direction::Upload => CloseReason::UpstreamError(e),
+            Direction::Download => CloseReason::ClientError(e),
+        }
+    }
+}
+
+/// Last time any byte moved in either direction.
+struct Activity {
+    start: Instant,
+    last_millis: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            start: Instant::now(),
+            last_millis: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last_millis.store(now, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let now = self.start.elapsed().as_millis() as u64;
+        Duration::from_millis(now.saturating_sub(self.last_millis.load(Ordering::Relaxed)))
+    }
+}
+
+/// Called with the first bytes received from upstream.
+pub type Inspect = Box<dyn FnOnce(&[u8]) + Send>;
+
+/// Upper bound of the delay used to coalesce small writes.
+pub const MAX_COALESCE_DELAY: Duration = Duration::from_millis(1);
+/// Coalescing stops once this much is gathered, more would only add delay.
+const MAX_COALESCE_SIZE: usize = 16 * 1024;
+
+#[allow(clippy::too_many_arguments)]
+async fn copy<R: Read + Unpin, W: Write + Unpin>(
+    mut src: R,
+    mut dst: W,
+    direction: Direction,
+    activity: &Activity,
+    buffer_size: usize,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    coalesce: Option<Duration>,
+    mut inspect: Option<Inspect>,
+) -> CloseReason {
+    let mut buf = vec![0; buffer_size.max(1)];
+    let coalesce_size = buf.len().min(MAX_COALESCE_SIZE);
+    loop {
+        let mut size = match timeout(read_timeout, src.read(&mut buf)).await {
+            Ok(0) => break direction.src_closed(),
+            Ok(size) => size,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                // The other direction may still be busy, only give up when both are quiet.
+                if activity.idle_for() >= read_timeout {
+                    break CloseReason::Idle;
+                }
+                continue;
+            }
+            Err(e) => break direction.src_error(e),
+        };
+        if let Some(delay) = coalesce {
+            // Gather what else arrives shortly, so the upstream gets one write (and one
+            // proxy frame) instead of many tiny ones.
+            while size < coalesce_size {
+                match timeout(delay, src.read(&mut buf[size..coalesce_size])).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => size += n,
+                }
+            }
+        }
+        if let Some(inspect) = inspect.take() {
+            inspect(&buf[..size]);
+        }
+        activity.touch();
+        match timeout(write_timeout, dst.write_all(&buf[..size])).await {
+            Ok(()) => activity.touch(),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => break direction.dst_stall(),
+            Err(e) => break direction.dst_error(e),
+        }
+    }
+}
+
+/// Relay bytes between `client` and `upstream` until either side closes or stalls, reading
+/// up to `buffer_size` bytes at a time in each direction.
+///
+/// With `coalesce` set, writes to upstream wait up to that long for more client data.
+/// `inspect_download` sees the first bytes upstream sends.
+pub async fn tunnel_tcp_stream<
+    T1: Read + Write + Unpin + Clone,
+    T2: Read + Write + Unpin + Clone,
+>(
+    client: T1,
+    upstream: T2,
+    buffer_size: usize,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    coalesce: Option<Duration>,
+    inspect_download: Option<Inspect>,
+) -> CloseReason {
+    let activity = Activity::new();
+    let coalesce = coalesce.map(|d| d.min(MAX_COALESCE_DELAY));
+    let upload = copy(
+        client.clone(),
+        upstream.clone(),
+        Direction::Upload,
+        &activity,
+        buffer_size,
+        read_timeout,
+        write_timeout,
+        coalesce,
+        None,
+    );
+    let download = copy(
+        upstream,
+        client,
+        Direction::Download,
+        &activity,
+        buffer_size,
+        read_timeout,
+        write_timeout,
+        None,
+        inspect_download,
+    );
+    upload.race(download).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task::{block_on, spawn};
+
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = spawn(async move { TcpStream::connect(addr).await.unwrap() });
+        let (accepted, _) = listener.accept().await.unwrap();
+        (connect.await, accepted)
+    }
+
+    #[test]
+    fn test_idle_close() {
+        block_on(async {
+            let (_client, client_side) = socket_pair().await;
+            let (upstream_side, _upstream) = socket_pair().await;
+            let reason = tunnel_tcp_stream(
+                client_side,
+                upstream_side,
+                16 * 1024,
+                Duration::from_millis(100),
+                Duration::from_millis(100),
+                None,
+                None,
+            )
+            .await;
+            assert!(matches!(reason, CloseReason::Idle));
+        });
+    }
+
+    #[test]
+    fn test_client_closed() {
+        block_on(async {
+            let (mut client, client_side) = socket_pair().await;
+            let (upstream_side, mut upstream) = socket_pair().await;
+            let relay = spawn(tunnel_tcp_stream(
+                client_side,
+                upstream_side,
+                16 * 1024,
+                Duration::from_secs(5),
+                Duration::from_secs(5),
+                Some(MAX_COALESCE_DELAY),
+                None,
+            ));
+            client.write_all(b"pi").await.unwrap();
+            client.write_all(b"ng").await.unwrap();
+            let mut buf = [0; 4];
+            upstream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            drop(client);
+            assert!(matches!(relay.await, CloseReason::ClientClosed));
+        });
+    }
+}
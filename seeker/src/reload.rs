@@ -0,0 +1,482 @@
+//! Reload the config file as one transaction: the new config is loaded and checked before
+//! anything changes, and when applying one part fails the parts already applied go back to
+//! the running config. The outcome of the last reload is kept for `seeker reload` and the
+//! management API.
+//!
+//! Reloads are started through the API, by SIGHUP on unix, when `watch_config` sees the file
+//! change, or every `subscription_interval` to refresh the subscription. Switching the
+//! profile is a reload with another profile active, which stays active for later reloads.
+use crate::api::client_request;
+#[cfg(unix)]
+use async_signals::Signals;
+#[cfg(unix)]
+use async_std::prelude::*;
+use async_std::task::{sleep, spawn_blocking};
+use config::error_code::CONFIG_DATA_FILE;
+use config::{subscription, Config, ConfigDiff, Overrides};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Error, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+type Apply = Box<dyn Fn(&Config) -> io::Result<()> + Send + Sync>;
+
+/// Applies a few settings of a new config to the running seeker.
+struct Applier {
+    name: &'static str,
+    /// Names of the settings, as in `ConfigDiff::settings_changed`, `rules` and `servers`.
+    settings: &'static [&'static str],
+    apply: Apply,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReloadStatus {
+    /// Seconds since the unix epoch.
+    pub time: u64,
+    pub applied: bool,
+    /// Why the reload failed, the running config was kept.
+    pub error: Option<String>,
+    pub diff: Option<ConfigDiff>,
+}
+
+/// Response of `/profiles`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profiles {
+    pub active: Option<String>,
+    pub profiles: Vec<String>,
+}
+
+pub struct Reloader {
+    path: Option<String>,
+    /// The profile chosen with `--profile` or switched to, instead of the config's `profile`.
+    profile: RwLock<Option<String>>,
+    /// Settings of the command line, replacing those of every loaded config.
+    overrides: Overrides,
+    /// Held for the whole of a reload, so reloads run one at a time.
+    reloading: Mutex<()>,
+    /// Swapped once a reload is applied, readers never wait for a reload.
+    running: RwLock<Arc<Config>>,
+    appliers: Vec<Applier>,
+    status: RwLock<Option<ReloadStatus>>,
+    /// Changes made by the last applied config.
+    diff: RwLock<Option<ConfigDiff>>,
+}
+
+impl Reloader {
+    /// Reload from `path`, `None` when the config did not come from a file.
+    pub fn new(path: Option<String>, running: Config) -> Self {
+        Reloader {
+            path,
+            profile: RwLock::new(None),
+            overrides: Overrides::default(),
+            reloading: Mutex::new(()),
+            running: RwLock::new(Arc::new(running)),
+            appliers: vec![],
+            status: RwLock::new(None),
+            diff: RwLock::new(None),
+        }
+    }
+
+    /// Load the config with `profile` active, whatever profile the file names.
+    pub fn with_profile(self, profile: Option<String>) -> Self {
+        *self.profile.write() = profile;
+        self
+    }
+
+    /// Apply `overrides` to every loaded config.
+    pub fn with_overrides(mut self, overrides: Overrides) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Apply changes of `settings` with `apply`, called with the old config again to roll back.
+    ///
+    /// A change to a setting no applier handles needs a restart.
+    pub fn with_applier<F>(
+        mut self,
+        name: &'static str,
+        settings: &'static [&'static str],
+        apply: F,
+    ) -> Self
+    where
+        F: Fn(&Config) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.appliers.push(Applier {
+            name,
+            settings,
+            apply: Box::new(apply),
+        });
+        self
+    }
+
+    pub fn status(&self) -> Option<ReloadStatus> {
+        self.status.read().clone()
+    }
+
+    pub fn diff(&self) -> Option<ConfigDiff> {
+        self.diff.read().clone()
+    }
+
+    /// The config file reloads read, `None` when the config did not come from a file.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// The running config, as applied by the last reload.
+    pub fn running(&self) -> Arc<Config> {
+        self.running.read().clone()
+    }
+
+    pub fn profiles(&self) -> Profiles {
+        let running = self.running();
+        Profiles {
+            active: running.profile.clone(),
+            profiles: running.profiles.clone(),
+        }
+    }
+
+    /// Reload with the profile `name` active. When that fails the profile active before is
+    /// kept, along with the running config.
+    pub fn switch_profile(&self, name: &str) -> ReloadStatus {
+        let previous = self.profile.write().replace(name.to_string());
+        let status = self.reload();
+        if !status.applied {
+            *self.profile.write() = previous;
+        }
+        status
+    }
+
+    /// Load the config file again and apply it, or keep the running config.
+    pub fn reload(&self) -> ReloadStatus {
+        let _reloading = self.reloading.lock();
+        let running = self.running();
+        let result = match &self.path {
+            Some(path) => load_with_subscription(|| {
+                Config::from_config_file_with_profile(path, self.profile.read().as_deref())
+            })
+            .and_then(|new| self.overrides.apply(new)),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                "the config was not loaded from a file",
+            )),
+        };
+        let result = result.and_then(|new| Ok((self.apply(&running, &new)?, new)));
+        let status = match result {
+            Ok((diff, new)) => {
+                info!(
+                    summary = %diff,
+                    rules_added = ?diff.rules_added,
+                    rules_removed = ?diff.rules_removed,
+                    servers_added = ?diff.servers_added,
+                    servers_removed = ?diff.servers_removed,
+                    "Config applied"
+                );
+                *self.running.write() = Arc::new(new);
+                *self.diff.write() = Some(diff.clone());
+                ReloadStatus {
+                    time: now(),
+                    applied: true,
+                    error: None,
+                    diff: Some(diff),
+                }
+            }
+            Err(e) => {
+                error!(%e, "reload config, keep the running config");
+                ReloadStatus {
+                    time: now(),
+                    applied: false,
+                    error: Some(e.to_string()),
+                    diff: None,
+                }
+            }
+        };
+        *self.status.write() = Some(status.clone());
+        status
+    }
+
+    /// Apply what changed from `old` to `new`, all of it or nothing.
+    fn apply(&self, old: &Config, new: &Config) -> io::Result<ConfigDiff> {
+        let diff = ConfigDiff::between(old, new);
+        let mut changed = diff.settings_changed.clone();
+        if !diff.rules_added.is_empty() || !diff.rules_removed.is_empty() {
+            changed.push("rules".to_string());
+        }
+        if !diff.servers_added.is_empty() || !diff.servers_removed.is_empty() {
+            changed.push("servers".to_string());
+        }
+        let handled = |setting: &String| {
+            self.appliers
+                .iter()
+                .any(|a| a.settings.contains(&setting.as_str()))
+        };
+        let restart: Vec<&str> = changed
+            .iter()
+            .filter(|s| !handled(s))
+            .map(String::as_str)
+            .collect();
+        if !restart.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("restart seeker to change {}", restart.join(", ")),
+            ));
+        }
+
+        let affected: Vec<&Applier> = self
+            .appliers
+            .iter()
+            .filter(|a| changed.iter().any(|s| a.settings.contains(&s.as_str())))
+            .collect();
+        for (i, applier) in affected.iter().enumerate() {
+            if let Err(e) = (applier.apply)(new) {
+                for done in affected[..i].iter().rev() {
+                    if let Err(e) = (done.apply)(old) {
+                        warn!(applier = done.name, %e, "roll back config");
+                    }
+                }
+                return Err(Error::new(e.kind(), format!("{}: {}", applier.name, e)));
+            }
+        }
+        Ok(diff)
+    }
+}
+
+/// How often `watch_config` looks at the config file.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reload on every SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_signal(reloader: Arc<Reloader>) -> io::Result<()> {
+    let mut signals = Signals::new(vec![libc::SIGHUP])?;
+    while signals.next().await.is_some() {
+        info!("SIGHUP, reload config");
+        let reloader = reloader.clone();
+        spawn_blocking(move || reloader.reload()).await;
+    }
+    Ok(())
+}
+
+/// Reload whenever the modification time of the config file changes. A file being written
+/// may not parse yet: the reload fails, keeps the running config and the next change tries
+/// again.
+pub async fn reload_on_change(reloader: Arc<Reloader>) -> io::Result<()> {
+    let path = match &reloader.path {
+        Some(path) => path.clone(),
+        None => return Ok(()),
+    };
+    let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last = modified(&path);
+    loop {
+        sleep(WATCH_INTERVAL).await;
+        let current = modified(&path);
+        if current.is_none() || current == last {
+            continue;
+        }
+        last = current;
+        info!(%path, "config file changed, reload config");
+        let reloader = reloader.clone();
+        spawn_blocking(move || reloader.reload()).await;
+    }
+}
+
+/// Load the config with `load`, after downloading its `subscription_url` to the
+/// `subscription_path` the config reads its servers from. When the download fails the copy
+/// saved before is used.
+pub fn load_with_subscription(load: impl Fn() -> io::Result<Config>) -> io::Result<Config> {
+    let config = load()?;
+    let (url, cache) = match (&config.subscription_url, &config.subscription_path) {
+        (Some(url), Some(cache)) => (url, cache),
+        _ => return Ok(config),
+    };
+    match subscription::download(url, cache) {
+        Ok(()) => load(),
+        Err(e) if config.servers.is_empty() => Err(CONFIG_DATA_FILE.wrap(e)),
+        Err(e) => {
+            warn!(%e, "download subscription, keep the servers saved before");
+            Ok(config)
+        }
+    }
+}
+
+/// Reload every `interval`, to download the `subscription_url` again.
+pub async fn reload_every(reloader: Arc<Reloader>, interval: Duration) -> io::Result<()> {
+    loop {
+        sleep(interval).await;
+        let reloader = reloader.clone();
+        spawn_blocking(move || reloader.reload()).await;
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// `seeker reload`: reload the config of the running seeker through the management API.
+pub fn run_reload_client(api: &str, secret: Option<&str>) -> anyhow::Result<()> {
+    let body = client_request("POST", api, "/config/reload", secret)
+        .call()
+        .into_string()?;
+    let status: ReloadStatus = serde_json::from_str(&body)?;
+    match (status.error, status.diff) {
+        (Some(e), _) => anyhow::bail!("reload failed, the running config is kept: {}", e),
+        (None, Some(diff)) => println!("reloaded: {}", diff),
+        (None, None) => println!("reloaded"),
+    }
+    Ok(())
+}
+
+/// `seeker profile`: print the profiles of the running seeker, or switch to `name`.
+pub fn run_profile_client(
+    api: &str,
+    secret: Option<&str>,
+    name: Option<&str>,
+) -> anyhow::Result<()> {
+    let name = match name {
+        Some(name) => name,
+        None => {
+            let body = client_request("GET", api, "/profiles", secret)
+                .call()
+                .into_string()?;
+            let profiles: Profiles = serde_json::from_str(&body)?;
+            for profile in &profiles.profiles {
+                let mark = if Some(profile) == profiles.active.as_ref() {
+                    "*"
+                } else {
+                    " "
+                };
+                println!("{} {}", mark, profile);
+            }
+            return Ok(());
+        }
+    };
+    let body = client_request("PUT", api, "/profile", secret)
+        .send_string(&serde_json::json!({ "name": name }).to_string())
+        .into_string()?;
+    let status: ReloadStatus = serde_json::from_str(&body)?;
+    match (status.error, status.diff) {
+        (Some(e), _) => anyhow::bail!(
+            "switch to profile {} failed, the running config is kept: {}",
+            name,
+            e
+        ),
+        (None, Some(diff)) => println!("switched to profile {}: {}", name, diff),
+        (None, None) => println!("switched to profile {}", name),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const SERVERS_AND_RULES: &str = r#"servers:
+  - name: server1
+    addr: 127.0.0.1:1080
+    protocol: Socks5
+rules:
+  - 'MATCH,DIRECT'
+"#;
+
+    fn config(dns_timeout: &str, mode: &str) -> Config {
+        test_config::config(&format!(
+            "dns_timeout: {}\nmode: {}\n{}",
+            dns_timeout, mode, SERVERS_AND_RULES
+        ))
+    }
+
+    #[test]
+    fn test_apply() {
+        let applied = Arc::new(AtomicUsize::new(0));
+        let count = applied.clone();
+        let reloader = Reloader::new(None, config("1s", "tun"))
+            .with_applier("dns", &["dns_timeout"], move |_| {
+                count.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .with_applier("broken", &["dns_timeout"], |_| {
+                Err(Error::new(ErrorKind::AddrInUse, "address in use"))
+            });
+        let old = config("1s", "tun");
+
+        // Rolled back: applied with the new config, then the old one.
+        let err = reloader.apply(&old, &config("2s", "tun")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        assert!(err.to_string().starts_with("broken:"));
+        assert_eq!(applied.load(Ordering::SeqCst), 2);
+
+        let err = reloader.apply(&old, &config("1s", "dns-only")).unwrap_err();
+        assert_eq!(err.to_string(), "restart seeker to change mode");
+        assert_eq!(applied.load(Ordering::SeqCst), 2);
+
+        assert!(reloader.apply(&old, &old).is_ok());
+        let status = reloader.reload();
+        assert!(!status.applied);
+        assert!(reloader.status().unwrap().error.is_some());
+    }
+
+    #[test]
+    fn test_reorder_rules() {
+        let rules = |first: &str, second: &str| {
+            test_config::config(&format!(
+                "servers:\n  - name: server1\n    addr: 127.0.0.1:1080\n    protocol: Socks5\n\
+                 rules:\n  - '{}'\n  - '{}'\n  - 'MATCH,DIRECT'\n",
+                first, second
+            ))
+        };
+        let old = rules("DOMAIN,a.com,PROXY", "DOMAIN-SUFFIX,a.com,DIRECT");
+        let new = rules("DOMAIN-SUFFIX,a.com,DIRECT", "DOMAIN,a.com,PROXY");
+        let applied = Arc::new(AtomicUsize::new(0));
+        let count = applied.clone();
+        let reloader = Reloader::new(None, old.clone()).with_applier(
+            "rules",
+            crate::proxy_client::RULES_SETTINGS,
+            move |_| {
+                count.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            },
+        );
+
+        let diff = reloader.apply(&old, &new).unwrap();
+        assert_eq!(diff.settings_changed, vec!["rules_order"]);
+        assert_eq!(applied.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_switch_profile() {
+        let path = std::env::temp_dir().join(format!("seeker-profiles-{}.yml", std::process::id()));
+        let profiles = r#"profile: home
+profiles:
+  home: {}
+  work: {dns_timeout: 2s}
+"#;
+        let text = test_config::config_text(&format!(
+            "dns_timeout: 1s\n{}{}",
+            SERVERS_AND_RULES, profiles
+        ));
+        std::fs::write(&path, text).unwrap();
+        let path_str = path.to_str().unwrap().to_string();
+        let running = Config::from_config_file(&path_str).unwrap();
+        assert_eq!(running.profile.as_deref(), Some("home"));
+        let reloader = Reloader::new(Some(path_str), running)
+            .with_applier("dns", &["dns_timeout"], |_| Ok(()))
+            .with_applier("profile", &["profile"], |_| Ok(()));
+
+        let status = reloader.switch_profile("work");
+        assert!(status.applied, "{:?}", status.error);
+        assert_eq!(reloader.profiles().active.as_deref(), Some("work"));
+        assert_eq!(reloader.profiles().profiles, vec!["home", "work"]);
+
+        // An unknown profile keeps the running one, for later reloads too.
+        assert!(!reloader.switch_profile("travel").applied);
+        assert!(reloader.reload().applied);
+        assert_eq!(reloader.profiles().active.as_deref(), Some("work"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
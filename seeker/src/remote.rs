@@ -0,0 +1,97 @@
+//! Files seeker downloads, `--config-url` and the lists of `rule_providers`, kept on disk
+//! with their `ETag`: the next download only transfers the file when it changed, and a
+//! failed one keeps the copy on disk, so seeker still starts offline.
+use std::fs::{self, File};
+use std::io::{self, Error, ErrorKind};
+
+#[derive(Debug, PartialEq)]
+pub enum Downloaded {
+    Updated,
+    /// The server answered 304, the copy on disk is current.
+    NotModified,
+}
+
+fn etag_path(path: &str) -> String {
+    format!("{}.etag", path)
+}
+
+/// Save `url` to `path`, through a temporary file so a failed download keeps the old one.
+pub fn download(url: &str, path: &str) -> io::Result<Downloaded> {
+    let mut request = ureq::get(url);
+    request.timeout_connect(5000).timeout_read(30000);
+    let etag = fs::read_to_string(etag_path(path)).ok();
+    if let Some(etag) = etag.as_deref().filter(|_| fs::metadata(path).is_ok()) {
+        request.set("If-None-Match", etag.trim());
+    }
+    let resp = request.call();
+    if resp.status() == 304 {
+        return Ok(Downloaded::NotModified);
+    }
+    if !resp.ok() {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("download {}: {}", url, resp.status_line()),
+        ));
+    }
+    let etag = resp.header("ETag").map(str::to_string);
+    let tmp = format!("{}.tmp", path);
+    io::copy(&mut resp.into_reader(), &mut File::create(&tmp)?)?;
+    fs::rename(&tmp, path)?;
+    match etag {
+        Some(etag) => fs::write(etag_path(path), etag)?,
+        None => {
+            let _ = fs::remove_file(etag_path(path));
+        }
+    }
+    Ok(Downloaded::Updated)
+}
+
+/// The config at `url`, downloaded to `cache` when given. The cached copy is used when the
+/// download fails.
+pub fn fetch_config(url: &str, cache: Option<&str>) -> io::Result<Vec<u8>> {
+    let cache = match cache {
+        Some(cache) => cache,
+        None => {
+            let resp = ureq::get(url)
+                .timeout_read(5000)
+                .timeout_connect(5000)
+                .timeout_write(5000)
+                .call();
+            if !resp.ok() {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("download {}: {}", url, resp.into_string()?),
+                ));
+            }
+            let mut body = vec![];
+            io::copy(&mut resp.into_reader(), &mut body)?;
+            return Ok(body);
+        }
+    };
+    match download(url, cache) {
+        Ok(_) => {}
+        // The config is loaded before the logger is set up.
+        Err(e) if fs::metadata(cache).is_ok() => {
+            eprintln!("{}, use the cached copy {}", e, cache)
+        }
+        Err(e) => return Err(e),
+    }
+    fs::read(cache)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fetch_config_offline() {
+        let cache = std::env::temp_dir().join(format!("seeker-remote-{}.yml", std::process::id()));
+        let cache = cache.to_str().unwrap();
+        fs::write(cache, "dns_timeout: 1s\n").unwrap();
+        // Nothing listens on port 9 of localhost, the download fails at once.
+        let body = fetch_config("http://127.0.0.1:9/config.yml", Some(cache)).unwrap();
+        assert_eq!(body, b"dns_timeout: 1s\n");
+        fs::remove_file(cache).unwrap();
+        assert!(fetch_config("http://127.0.0.1:9/config.yml", Some(cache)).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+//! Keep the lists of the `rule_providers` fresh: those with a `url` are downloaded at start
+//! and every `interval`, local ones are read again every `interval`.
+//!
+//! A failed refresh keeps the list in use, an unchanged download is not read again.
+use crate::remote::{download, Downloaded};
+use crate::supervisor::Supervisor;
+use async_std::task::{sleep, spawn_blocking};
+use config::rule::ProxyRules;
+use config::rule_set::RuleProvider;
+use config::Config;
+use tracing::{debug, info, warn};
+
+pub fn spawn_refreshers(supervisor: Supervisor, config: &Config) {
+    for name in config.rules.rule_set_names() {
+        let provider = match config.rule_providers.get(&name) {
+            Some(provider) if provider.url.is_some() || provider.interval.is_some() => {
+                provider.clone()
+            }
+            _ => continue,
+        };
+        let rules = config.rules.clone();
+        supervisor.spawn("rule_provider", move || {
+            let (name, provider, rules) = (name.clone(), provider.clone(), rules.clone());
+            async move {
+                // Lists on disk were read with the config, downloaded ones may be missing.
+                if provider.url.is_some() {
+                    refresh(&name, &provider, &rules).await;
+                }
+                let interval = match provider.interval {
+                    Some(interval) => interval,
+                    None => return Ok(()),
+                };
+                loop {
+                    sleep(interval).await;
+                    refresh(&name, &provider, &rules).await;
+                }
+            }
+        });
+    }
+}
+
+async fn refresh(name: &str, provider: &RuleProvider, rules: &ProxyRules) {
+    let p = provider.clone();
+    let loaded = spawn_blocking(move || {
+        if let Some(url) = &p.url {
+            if download(url, &p.path)? == Downloaded::NotModified {
+                return Ok(None);
+            }
+        }
+        p.load().map(Some)
+    })
+    .await;
+    match loaded {
+        Ok(None) => debug!(provider = name, "rule provider not modified"),
+        Ok(Some(list)) => {
+            info!(
+                provider = name,
+                entries = list.len(),
+                "rule provider loaded"
+            );
+            rules.rule_sets().replace(name, list);
+        }
+        Err(e) => warn!(
+            provider = name,
+            ?e,
+            "refresh rule provider, keep the old list"
+        ),
+    }
+}
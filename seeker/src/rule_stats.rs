@@ -0,0 +1,264 @@
+//! How often each rule matched and the latest routing decisions, to find rules that never
+//! match and see why a connection went where it did.
+use crate::api::client_request;
+use config::rule::{Action, Rule};
+use config::Address;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the rules sent a connection.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub action: Action,
+    pub group: Option<String>,
+    /// Index of the matched rule, `None` when no rule matched or the rules were bypassed.
+    pub rule: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteDecision {
+    /// Seconds since the unix epoch.
+    pub time: u64,
+    pub network: String,
+    pub host: String,
+    pub rule: Option<String>,
+    /// The final action, after scripts, prompts and probes.
+    pub action: String,
+    pub group: Option<String>,
+    /// Name of the upstream server, `None` for direct and failed connections.
+    pub server: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RuleHits {
+    pub index: usize,
+    pub rule: String,
+    pub hits: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HitCounts {
+    /// In the order of the config.
+    pub rules: Vec<RuleHits>,
+    /// Connections no rule matched.
+    pub unmatched: u64,
+}
+
+struct Counter {
+    rule: String,
+    hits: AtomicU64,
+}
+
+pub struct RuleStats {
+    /// One per rule, in the order of the config.
+    counters: RwLock<Vec<Counter>>,
+    unmatched: AtomicU64,
+    recent: Mutex<VecDeque<RouteDecision>>,
+    capacity: usize,
+}
+
+impl RuleStats {
+    /// Counters for `rules`, keeping the last `capacity` decisions.
+    pub fn new(rules: &[Rule], capacity: usize) -> Self {
+        RuleStats {
+            counters: RwLock::new(counters(rules, &[])),
+            unmatched: AtomicU64::new(0),
+            recent: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Count the hits of `rules` from now on. Rules that stay keep their count.
+    pub fn set_rules(&self, rules: &[Rule]) {
+        let mut current = self.counters.write();
+        *current = counters(rules, &current);
+    }
+
+    /// Count the rule of `route` and remember where the connection to `host` went,
+    /// through `server` or failing with `error`.
+    pub fn record(
+        &self,
+        network: &str,
+        host: &Address,
+        route: &Route,
+        server: Option<&str>,
+        error: Option<&io::Error>,
+    ) {
+        let counters = self.counters.read();
+        let counter = route.rule.and_then(|i| counters.get(i));
+        match counter {
+            Some(counter) => counter.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.unmatched.fetch_add(1, Ordering::Relaxed),
+        };
+        if self.capacity == 0 {
+            return;
+        }
+        let decision = RouteDecision {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            network: network.to_string(),
+            host: host.to_string(),
+            rule: counter.map(|c| c.rule.clone()),
+            action: route.action.to_string().to_uppercase(),
+            group: route.group.clone(),
+            server: server.map(str::to_string),
+            error: error.map(|e| e.to_string()),
+        };
+        let mut recent = self.recent.lock();
+        if recent.len() >= self.capacity {
+            let _ = recent.pop_front();
+        }
+        recent.push_back(decision);
+    }
+
+    pub fn hits(&self) -> HitCounts {
+        HitCounts {
+            rules: self
+                .counters
+                .read()
+                .iter()
+                .enumerate()
+                .map(|(index, counter)| RuleHits {
+                    index,
+                    rule: counter.rule.clone(),
+                    hits: counter.hits.load(Ordering::Relaxed),
+                })
+                .collect(),
+            unmatched: self.unmatched.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Recent decisions whose host contains `filter`, oldest first.
+    pub fn decisions(&self, filter: Option<&str>) -> Vec<RouteDecision> {
+        self.recent
+            .lock()
+            .iter()
+            .filter(|d| filter.map_or(true, |f| d.host.contains(f)))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Counters for `rules`, starting from the counts in `old` of the same rules.
+fn counters(rules: &[Rule], old: &[Counter]) -> Vec<Counter> {
+    rules
+        .iter()
+        .map(|rule| {
+            let rule = rule.to_string();
+            let hits = old
+                .iter()
+                .find(|c| c.rule == rule)
+                .map_or(0, |c| c.hits.load(Ordering::Relaxed));
+            Counter {
+                rule,
+                hits: AtomicU64::new(hits),
+            }
+        })
+        .collect()
+}
+
+/// `seeker rules`: print the hit counts, or the recent decisions, from the management API.
+pub fn run_rules_client(
+    api: &str,
+    secret: Option<&str>,
+    decisions: bool,
+    host: Option<&str>,
+) -> anyhow::Result<()> {
+    if decisions {
+        let mut path = "/rules/decisions".to_string();
+        if let Some(host) = host {
+            path = format!("{}?host={}", path, host);
+        }
+        let body = client_request("GET", api, &path, secret)
+            .call()
+            .into_string()?;
+        let decisions: Vec<RouteDecision> = serde_json::from_str(&body)?;
+        for d in decisions {
+            println!(
+                "{} {} {} -> {} {} via {}{}",
+                d.time,
+                d.network,
+                d.host,
+                d.rule.as_deref().unwrap_or("(no rule)"),
+                d.action,
+                d.server.as_deref().unwrap_or("-"),
+                d.error
+                    .map(|e| format!(" error: {}", e))
+                    .unwrap_or_default(),
+            );
+        }
+        return Ok(());
+    }
+    let body = client_request("GET", api, "/rules/hits", secret)
+        .call()
+        .into_string()?;
+    let counts: HitCounts = serde_json::from_str(&body)?;
+    for r in counts.rules {
+        println!("{:>10} {:>5} {}", r.hits, r.index, r.rule);
+    }
+    println!("{:>10}       (no rule)", counts.unmatched);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_record() {
+        let rules = vec![
+            Rule::from_str("DOMAIN-SUFFIX,example.com,PROXY").unwrap(),
+            Rule::from_str("MATCH,DIRECT").unwrap(),
+        ];
+        let stats = RuleStats::new(&rules, 2);
+        let host = |d: &str| Address::DomainNameAddress(d.to_string(), 443);
+        let proxied = Route {
+            action: Action::Proxy,
+            group: None,
+            rule: Some(0),
+        };
+        for domain in &["a.example.com", "b.example.com"] {
+            stats.record("tcp", &host(domain), &proxied, Some("server1"), None);
+        }
+        let bypassed = Route {
+            action: Action::Direct,
+            group: None,
+            rule: None,
+        };
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        stats.record("udp", &host("c.org"), &bypassed, None, Some(&refused));
+
+        let counts = stats.hits();
+        assert_eq!(counts.rules[0].hits, 2);
+        assert_eq!(counts.rules[1].hits, 0);
+        assert_eq!(counts.unmatched, 1);
+
+        let decisions = stats.decisions(None);
+        assert_eq!(decisions.len(), 2);
+        assert_eq!(decisions[0].host, "b.example.com:443");
+        assert_eq!(
+            decisions[0].rule.as_deref(),
+            Some("DOMAIN-SUFFIX,example.com,PROXY")
+        );
+        assert_eq!(decisions[1].action, "DIRECT");
+        assert!(decisions[1].error.is_some());
+        assert_eq!(stats.decisions(Some("example")).len(), 1);
+
+        stats.set_rules(&[
+            Rule::from_str("DOMAIN,example.org,REJECT").unwrap(),
+            rules[0].clone(),
+        ]);
+        let counts = stats.hits();
+        assert_eq!(counts.rules[0].hits, 0);
+        assert_eq!(counts.rules[1].rule, "DOMAIN-SUFFIX,example.com,PROXY");
+        assert_eq!(counts.rules[1].hits, 2);
+    }
+}
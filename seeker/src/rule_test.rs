@@ -0,0 +1,219 @@
+//! `seeker rule-test`: which rule and outbound a connection would get, without starting
+//! the tun.
+use config::rule::{Action, ConnectionMeta, TUN_INBOUND};
+use config::{Config, GroupStrategy};
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+
+/// What is known about the connection to test.
+#[derive(Debug, Default)]
+pub struct Target<'a> {
+    pub host: &'a str,
+    pub port: Option<u16>,
+    pub process_name: Option<&'a str>,
+    pub uid: Option<u32>,
+    /// `tcp` or `udp`.
+    pub network: Option<&'a str>,
+}
+
+/// Split `host:port`, `[v6]:port`, a bare ip or a bare domain.
+fn split_host(target: &str) -> (String, Option<u16>) {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return (addr.ip().to_string(), Some(addr.port()));
+    }
+    if target.parse::<IpAddr>().is_ok() {
+        return (target.to_string(), None);
+    }
+    match target.rfind(':') {
+        Some(pos) => match target[pos + 1..].parse() {
+            Ok(port) => (target[..pos].to_string(), Some(port)),
+            Err(_) => (target.to_string(), None),
+        },
+        None => (target.to_string(), None),
+    }
+}
+
+/// Describe how `target` would be routed by the rules of `config`.
+pub fn explain(config: &Config, target: &Target) -> String {
+    let (host, parsed_port) = split_host(target.host);
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let port = target.port.or(parsed_port);
+    let rules = &config.rules;
+    let mut out = String::new();
+
+    let addr = host.parse::<IpAddr>().ok();
+    let ip = addr.is_some();
+    let conn = ConnectionMeta {
+        domain: if ip { None } else { Some(&host) },
+        process_name: target.process_name,
+        uid: target.uid,
+        port,
+        network: target.network,
+        inbound: Some(TUN_INBOUND),
+        ip: addr,
+        ..Default::default()
+    };
+    let index = rules.index_for_connection(&conn);
+    let rule = index.map(|i| &rules.rules()[i]);
+    let matched = match (index, rule) {
+        (Some(i), Some(rule)) => format!("#{} {}", i, rule),
+        _ => format!("none, final {} applies", config.final_target),
+    };
+    writeln!(out, "rule:   {}", matched).unwrap();
+    let action = match rule {
+        Some(rule) => rule.action,
+        None => rules.default_action(),
+    };
+    writeln!(out, "action: {}", action.to_string().to_uppercase()).unwrap();
+
+    match action {
+        Action::Proxy => {
+            let group = match rule {
+                Some(rule) => rule.group.as_deref(),
+                None => rules.final_rule().group.as_deref(),
+            };
+            let group = group.and_then(|name| config.proxy_groups.iter().find(|g| g.name == name));
+            let line = match group {
+                Some(group) => format!(
+                    "group {} ({}): {}{}",
+                    group.name,
+                    strategy_name(group.strategy),
+                    group.servers.join(", "),
+                    group
+                        .final_target
+                        .as_ref()
+                        .map(|t| format!(", then {} when none is usable", t))
+                        .unwrap_or_default()
+                ),
+                None => {
+                    let names: Vec<&str> = config.servers.iter().map(|s| s.name()).collect();
+                    format!("fastest of {}", names.join(", "))
+                }
+            };
+            writeln!(out, "via:    {}", line).unwrap();
+        }
+        Action::Reject => {
+            let mode = rule.map(|r| r.reject).unwrap_or_default();
+            writeln!(out, "reject: {:?}", mode).unwrap();
+        }
+        Action::Probe => {
+            writeln!(out, "via:    direct, or the proxy when the probe times out").unwrap();
+        }
+        Action::Script => {
+            writeln!(out, "via:    decided by rule_script when connecting").unwrap();
+        }
+        Action::Direct => {}
+    }
+    if let Some(tag) = rule.and_then(|r| r.tag.as_deref()) {
+        writeln!(out, "tag:    {}", tag).unwrap();
+    }
+    if let Some(dns) = rule.and_then(|r| r.dns.as_ref()) {
+        writeln!(out, "dns:    {:?}", dns).unwrap();
+    }
+    if let Some(window) = rule.and_then(|r| r.time) {
+        writeln!(out, "time:   only {}, matched at the current time", window).unwrap();
+    }
+    let no_process = target.process_name.is_none() && target.uid.is_none();
+    if !ip && no_process && rules.depends_on_connection(&host) {
+        writeln!(
+            out,
+            "note:   rules about processes come first, pass --process or --uid to match them"
+        )
+        .unwrap();
+    }
+    if !ip && target.network.is_none() && rules.has_network_rules() {
+        writeln!(
+            out,
+            "note:   NETWORK rules only match with --network tcp or udp"
+        )
+        .unwrap();
+    }
+    out
+}
+
+fn strategy_name(strategy: GroupStrategy) -> &'static str {
+    match strategy {
+        GroupStrategy::Latency => "latency",
+        GroupStrategy::Fallback => "fallback",
+        GroupStrategy::RoundRobin => "round-robin",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config::config;
+
+    const CONFIG: &str = r#"servers:
+  - name: server1
+    addr: 127.0.0.1:1080
+    protocol: Socks5
+  - name: server2
+    addr: 127.0.0.1:1081
+    protocol: Socks5
+proxy_groups:
+  - name: Streaming
+    servers: [server2]
+    strategy: fallback
+rules:
+  - 'PROCESS-NAME,curl,REJECT'
+  - 'DOMAIN-SUFFIX,netflix.com,Streaming'
+  - 'DST-PORT,22,PROXY'
+  - 'IP-CIDR,10.0.0.0/8,DIRECT'
+  - 'MATCH,DIRECT'
+"#;
+
+    fn explain_str(host: &str) -> String {
+        let config = config(CONFIG);
+        explain(
+            &config,
+            &Target {
+                host,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_split_host() {
+        assert_eq!(split_host("example.com"), ("example.com".to_string(), None));
+        assert_eq!(
+            split_host("example.com:443"),
+            ("example.com".to_string(), Some(443))
+        );
+        assert_eq!(split_host("[::1]:53"), ("::1".to_string(), Some(53)));
+        assert_eq!(split_host("::1"), ("::1".to_string(), None));
+    }
+
+    #[test]
+    fn test_explain() {
+        let out = explain_str("www.Netflix.com");
+        assert!(out.contains("#1 DOMAIN-SUFFIX,netflix.com,Streaming"));
+        assert!(out.contains("group Streaming (fallback): server2"));
+        assert!(out.contains("note:"));
+
+        assert!(explain_str("example.com:22").contains("fastest of server1, server2"));
+        assert!(explain_str("10.1.2.3").contains("action: DIRECT"));
+        assert!(explain_str("1.2.3.4:443").contains("#4 MATCH,DIRECT"));
+        assert!(explain_str("1.2.3.4:22").contains("#2 DST-PORT,22,PROXY"));
+    }
+
+    #[test]
+    fn test_explain_final() {
+        let config = config(&format!(
+            "{}final: Streaming\n",
+            CONFIG.replace("  - 'MATCH,DIRECT'\n", "")
+        ));
+        for host in &["example.com", "1.2.3.4"] {
+            let out = explain(
+                &config,
+                &Target {
+                    host,
+                    ..Default::default()
+                },
+            );
+            assert!(out.contains("none, final Streaming applies"));
+            assert!(out.contains("group Streaming (fallback): server2"));
+        }
+    }
+}
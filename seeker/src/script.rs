@@ -0,0 +1,147 @@
+//! Rules with the `SCRIPT` action ask a user provided Rhai script for the action.
+//!
+//! The script defines `fn route(conn)`, `conn` is a map with `domain`, `ip`, `port`,
+//! `network`, `process_name`, `process_path` and `uid`, unknown fields are `()`. It
+//! returns `"DIRECT"`, `"PROXY"`, `"PROBE"` or `"REJECT"`.
+use std::net::IpAddr;
+
+/// What a script gets to know about a connection.
+#[derive(Debug)]
+pub struct ScriptConnection<'a> {
+    pub domain: &'a str,
+    /// Address the connection was made to, a fake ip for domains in fake-ip mode.
+    pub ip: IpAddr,
+    pub port: u16,
+    pub network: &'static str,
+    pub process_name: Option<&'a str>,
+    pub process_path: Option<&'a str>,
+    pub uid: Option<u32>,
+}
+
+#[cfg(feature = "script")]
+pub use self::rhai_script::RuleScript;
+
+#[cfg(feature = "script")]
+mod rhai_script {
+    use super::ScriptConnection;
+    use crate::interactive::parse_action;
+    use config::rule::Action;
+    use rhai::{Dynamic, Engine, ImmutableString, Map, Scope, AST};
+    use std::io::{Error, ErrorKind, Result};
+
+    /// Scripts running longer than this many operations are stopped.
+    const MAX_OPERATIONS: u64 = 100_000;
+
+    pub struct RuleScript {
+        engine: Engine,
+        ast: AST,
+    }
+
+    impl RuleScript {
+        pub fn load(path: &str) -> Result<Self> {
+            let source = std::fs::read_to_string(path)
+                .map_err(|e| Error::new(e.kind(), format!("read rule script {}: {}", path, e)))?;
+            RuleScript::compile(&source)
+        }
+
+        pub fn compile(source: &str) -> Result<Self> {
+            let mut engine = Engine::new();
+            engine.set_max_operations(MAX_OPERATIONS);
+            let ast = engine
+                .compile(source)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("rule script: {}", e)))?;
+            Ok(RuleScript { engine, ast })
+        }
+
+        pub fn route(&self, conn: &ScriptConnection) -> Result<Action> {
+            let optional =
+                |s: Option<&str>| s.map_or(Dynamic::UNIT, |s| Dynamic::from(s.to_string()));
+            let mut map = Map::new();
+            map.insert("domain".into(), Dynamic::from(conn.domain.to_string()));
+            map.insert("ip".into(), Dynamic::from(conn.ip.to_string()));
+            map.insert("port".into(), Dynamic::from(conn.port as i64));
+            map.insert("network".into(), Dynamic::from(conn.network.to_string()));
+            map.insert("process_name".into(), optional(conn.process_name));
+            map.insert("process_path".into(), optional(conn.process_path));
+            map.insert(
+                "uid".into(),
+                conn.uid
+                    .map_or(Dynamic::UNIT, |uid| Dynamic::from(uid as i64)),
+            );
+            let action: ImmutableString = self
+                .engine
+                .call_fn(&mut Scope::new(), &self.ast, "route", (map,))
+                .map_err(|e| Error::new(ErrorKind::Other, format!("rule script: {}", e)))?;
+            parse_action(action.as_str()).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("rule script returned an unknown action: {}", action),
+                )
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_route() {
+            let script = RuleScript::compile(
+                r#"
+fn route(conn) {
+    if conn.network == "udp" && conn.port == 443 {
+        return "REJECT";
+    }
+    if conn.process_name == () {
+        return "PROXY";
+    }
+    "DIRECT"
+}
+"#,
+            )
+            .unwrap();
+            let mut conn = ScriptConnection {
+                domain: "www.google.com",
+                ip: "10.0.0.1".parse().unwrap(),
+                port: 443,
+                network: "udp",
+                process_name: None,
+                process_path: None,
+                uid: None,
+            };
+            assert_eq!(script.route(&conn).unwrap(), Action::Reject);
+            conn.network = "tcp";
+            assert_eq!(script.route(&conn).unwrap(), Action::Proxy);
+            conn.process_name = Some("curl");
+            assert_eq!(script.route(&conn).unwrap(), Action::Direct);
+
+            let script = RuleScript::compile(r#"fn route(conn) { "BLOCK" }"#).unwrap();
+            assert!(script.route(&conn).is_err());
+            assert!(RuleScript::compile("fn route(conn) {").is_err());
+        }
+    }
+}
+
+/// Stand-in when seeker is built without the `script` feature, loading always fails.
+#[cfg(not(feature = "script"))]
+pub struct RuleScript;
+
+#[cfg(not(feature = "script"))]
+impl RuleScript {
+    pub fn load(_path: &str) -> std::io::Result<Self> {
+        Err(unsupported())
+    }
+
+    pub fn route(&self, _conn: &ScriptConnection) -> std::io::Result<config::rule::Action> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(not(feature = "script"))]
+fn unsupported() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "rule scripts need seeker built with the script feature",
+    )
+}
@@ -1,28 +1,75 @@
 use crate::dns_client::DnsClient;
+use crate::priority;
 use crate::proxy_connection::ProxyConnection;
+use crate::proxy_group::{Group, GroupSnapshot};
 use crate::proxy_tcp_stream::ProxyTcpStream;
 use crate::proxy_udp_socket::ProxyUdpSocket;
+use crate::quarantine::{is_tls_mismatch, Quarantine, QuarantineReason, QuarantinedServer};
+use crate::reject::rejected;
 use async_std::io::timeout;
 use async_std::prelude::*;
-use async_std::task::{sleep, spawn};
+use async_std::task::sleep;
 use config::rule::Action;
-use config::{Address, ServerConfig};
+use config::time_window::local_minute_of_day;
+use config::{Address, ProxyGroup, ServerConfig};
 use futures_util::stream::FuturesUnordered;
 use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::io::Result;
+use std::io::{ErrorKind, Result};
+use std::sync::atomic::{self, AtomicBool};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tracing::info;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, trace, warn};
+
+/// Where a connection sent to a proxy group goes.
+enum Pick {
+    Server(ServerConfig),
+    /// The group and its `final` targets had no usable server.
+    Direct,
+    Reject,
+}
+
+/// Response of `GET /fail-closed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FailClosed {
+    /// `proxy_fail_closed` is set.
+    pub enabled: bool,
+    /// No server is reachable and connections to proxies are rejected.
+    pub engaged: bool,
+}
+
+/// The servers and groups of the config, swapped as a whole by `set_servers`.
+struct Pool {
+    servers: Arc<Vec<ServerConfig>>,
+    groups: HashMap<String, Group>,
+}
+
+impl Pool {
+    fn new(servers: Arc<Vec<ServerConfig>>, proxy_groups: Vec<ProxyGroup>) -> Self {
+        let groups = proxy_groups
+            .into_iter()
+            .map(|g| (g.name.clone(), Group::new(g)))
+            .collect();
+        Pool { servers, groups }
+    }
+}
 
 #[derive(Clone)]
 pub struct ServerChooser {
     ping_url: Vec<(Address, String)>,
     ping_timeout: Duration,
-    servers: Arc<Vec<ServerConfig>>,
+    pool: Arc<RwLock<Arc<Pool>>>,
     candidates: Arc<Mutex<Vec<ServerConfig>>>,
     dns_client: DnsClient,
     live_connections: Arc<RwLock<Vec<Box<dyn ProxyConnection + Sync + Send>>>>,
+    quarantine: Arc<Quarantine>,
+    fail_closed: bool,
+    /// The last ping reached none of the servers.
+    all_down: Arc<AtomicBool>,
+    /// When each server last answered a ping, and how fast, by name.
+    latencies: Arc<RwLock<HashMap<String, (SystemTime, Duration)>>>,
 }
 
 impl ServerChooser {
@@ -31,19 +78,59 @@ impl ServerChooser {
         dns_client: DnsClient,
         ping_url: Vec<(Address, String)>,
         ping_timeout: Duration,
+        quarantine_duration: Duration,
+        proxy_groups: Vec<ProxyGroup>,
+        fail_closed: bool,
     ) -> Self {
         let chooser = ServerChooser {
             ping_url,
             ping_timeout,
+            quarantine: Arc::new(Quarantine::new(quarantine_duration)),
             candidates: Arc::new(Mutex::new(servers.iter().cloned().collect())),
-            servers,
+            pool: Arc::new(RwLock::new(Arc::new(Pool::new(servers, proxy_groups)))),
             dns_client,
             live_connections: Arc::new(RwLock::new(vec![])),
+            fail_closed,
+            all_down: Arc::new(AtomicBool::new(false)),
+            latencies: Arc::new(RwLock::new(HashMap::new())),
         };
         chooser.ping_servers().await;
         chooser
     }
 
+    fn pool(&self) -> Arc<Pool> {
+        self.pool.read().clone()
+    }
+
+    /// Use `servers` and `proxy_groups` from now on. Connections through the old servers
+    /// keep running. Servers that stay keep their rank, new ones come last until the next
+    /// ping.
+    pub fn set_servers(&self, servers: Arc<Vec<ServerConfig>>, proxy_groups: Vec<ProxyGroup>) {
+        {
+            let mut candidates = self.candidates.lock();
+            let mut next: Vec<ServerConfig> = candidates
+                .iter()
+                .filter(|c| servers.contains(c))
+                .cloned()
+                .collect();
+            let added: Vec<ServerConfig> = servers
+                .iter()
+                .filter(|s| !next.contains(s))
+                .cloned()
+                .collect();
+            next.extend(added);
+            *candidates = next;
+        }
+        *self.pool.write() = Arc::new(Pool::new(servers, proxy_groups));
+    }
+
+    /// Shut every proxy connection down, like after the network they were made on went away.
+    pub fn shutdown_connections(&self) {
+        let mut live_connections = self.live_connections.write();
+        live_connections.iter().for_each(|stream| stream.shutdown());
+        live_connections.retain(|stream| stream.strong_count() > 1);
+    }
+
     fn set_server_down(&self, config: &ServerConfig) {
         let mut live_connections = self.live_connections.write();
         live_connections
@@ -53,25 +140,188 @@ impl ServerChooser {
         live_connections.retain(|stream| stream.strong_count() > 1);
     }
 
+    /// The first candidate that is not quarantined. If every candidate is quarantined,
+    /// the first one is used anyway.
+    fn current_candidate(&self) -> ServerConfig {
+        self.current_server().unwrap()
+    }
+
+    /// The server connections without a group go through, `None` without servers.
+    pub fn current_server(&self) -> Option<ServerConfig> {
+        let candidates = self.candidates.lock();
+        candidates
+            .iter()
+            .find(|c| !self.quarantine.is_quarantined(c))
+            .or_else(|| candidates.first())
+            .cloned()
+    }
+
+    pub fn servers(&self) -> Arc<Vec<ServerConfig>> {
+        self.pool().servers.clone()
+    }
+
+    /// When the server `name` last answered a ping and its latency, `None` when it did not
+    /// answer the last one.
+    pub fn latency(&self, name: &str) -> Option<(SystemTime, Duration)> {
+        self.latencies.read().get(name).copied()
+    }
+
+    /// The server for a connection to `group`, the current candidate without one. A group
+    /// without a usable server passes the connection on to its `final` target.
+    fn candidate_for(&self, group: Option<&str>) -> Pick {
+        let pool = self.pool();
+        let mut name = group;
+        // The config rejects cycles of `final`, the bound is only a safeguard.
+        for _ in 0..=pool.groups.len() {
+            let group = match name.and_then(|name| pool.groups.get(name)) {
+                Some(group) => group,
+                None => break,
+            };
+            let picked = group.pick(&pool.servers, &self.candidates.lock(), |c| {
+                !self.quarantine.is_quarantined(c)
+            });
+            if let Some(server) = picked {
+                return Pick::Server(server);
+            }
+            trace!(group = ?name, target = ?group.final_target(), "group falls through");
+            match group.final_target() {
+                Some("DIRECT") => return Pick::Direct,
+                Some("REJECT") => return Pick::Reject,
+                Some("PROXY") | None => break,
+                Some(next) => name = Some(next),
+            }
+        }
+        Pick::Server(self.current_candidate())
+    }
+
+    /// With `proxy_fail_closed`, whether no server is reachable, because the last ping
+    /// failed for all of them or all are quarantined.
+    fn fail_closed_engaged(&self) -> bool {
+        if !self.fail_closed {
+            return false;
+        }
+        self.all_down.load(atomic::Ordering::SeqCst)
+            || self
+                .pool()
+                .servers
+                .iter()
+                .all(|c| self.quarantine.is_quarantined(c))
+    }
+
+    pub fn fail_closed(&self) -> FailClosed {
+        FailClosed {
+            enabled: self.fail_closed,
+            engaged: self.fail_closed_engaged(),
+        }
+    }
+
+    /// `action` with the server for `Action::Proxy`, after the fallthrough of `group`.
+    /// Without a reachable server `proxy_fail_closed` rejects it, fallthrough to `DIRECT`
+    /// included.
+    fn resolve(&self, action: Action, group: Option<&str>) -> (Action, Option<ServerConfig>) {
+        match action {
+            Action::Proxy if self.fail_closed_engaged() => {
+                trace!(?group, "no server reachable, fail closed");
+                (Action::Reject, None)
+            }
+            Action::Proxy => match self.candidate_for(group) {
+                Pick::Server(config) => (Action::Proxy, Some(config)),
+                Pick::Direct => (Action::Direct, None),
+                Pick::Reject => (Action::Reject, None),
+            },
+            action => (action, None),
+        }
+    }
+
+    pub fn groups(&self) -> Vec<GroupSnapshot> {
+        let pool = self.pool();
+        let candidates = self.candidates.lock();
+        let mut groups: Vec<GroupSnapshot> = pool
+            .groups
+            .values()
+            .map(|g| {
+                g.snapshot(&pool.servers, &candidates, |c| {
+                    !self.quarantine.is_quarantined(c)
+                })
+            })
+            .collect();
+        groups.sort_by(|a, b| a.name.cmp(&b.name));
+        groups
+    }
+
+    /// Report that `config` accepted a connection and reset it before sending anything back.
+    pub fn report_early_reset(&self, config: &ServerConfig) {
+        if self.quarantine.strike(config, QuarantineReason::EarlyReset) {
+            self.set_server_down(config);
+        }
+    }
+
+    pub fn quarantined_servers(&self) -> Vec<QuarantinedServer> {
+        self.quarantine.list()
+    }
+
+    /// Connect through `config`, each try within its `connect_timeout` or `default_timeout`,
+    /// trying `retries` more times before the server counts as failed. Errors of the config
+    /// and TLS mismatches are not tried again.
+    async fn connect_server(
+        &self,
+        remote_addr: Address,
+        config: &ServerConfig,
+        default_timeout: Duration,
+    ) -> Result<ProxyTcpStream> {
+        let connect_timeout = config.connect_timeout().unwrap_or(default_timeout);
+        let mut tries = 0;
+        loop {
+            let connect =
+                ProxyTcpStream::connect(remote_addr.clone(), Some(config), self.dns_client.clone());
+            match timeout(connect_timeout, connect).await {
+                Err(e)
+                    if tries < config.retries()
+                        && e.kind() != ErrorKind::InvalidData
+                        && !is_tls_mismatch(&e) =>
+                {
+                    tries += 1;
+                    debug!(
+                        server = config.name(),
+                        ?e,
+                        tries,
+                        "connect server, try again"
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// A connection to `remote_addr` as `action` and `group` decide, connecting within
+    /// `connect_timeout` unless the server has a timeout of its own.
     pub async fn candidate_tcp_stream(
         &self,
         remote_addr: Address,
         action: Action,
+        group: Option<&str>,
+        connect_timeout: Duration,
     ) -> Result<ProxyTcpStream> {
-        let stream = match action {
-            Action::Proxy => {
-                let config = self.candidates.lock().first().cloned().unwrap();
-                let stream =
-                    ProxyTcpStream::connect(remote_addr, Some(&config), self.dns_client.clone())
-                        .await;
-                if stream.is_err() {
-                    self.take_down_current_and_move_next();
+        let stream = match self.resolve(action, group) {
+            (Action::Proxy, Some(config)) => {
+                let stream = self
+                    .connect_server(remote_addr, &config, connect_timeout)
+                    .await;
+                match &stream {
+                    Err(e) if is_tls_mismatch(e) => {
+                        self.quarantine
+                            .strike(&config, QuarantineReason::TlsMismatch);
+                    }
+                    Err(_) => self.take_down_and_move_next(&config),
+                    Ok(_) => {}
                 }
                 stream?
             }
-            Action::Direct => {
-                ProxyTcpStream::connect(remote_addr, None, self.dns_client.clone()).await?
+            (Action::Direct, _) => {
+                let connect = ProxyTcpStream::connect(remote_addr, None, self.dns_client.clone());
+                timeout(connect_timeout, connect).await?
             }
+            (Action::Reject, _) => return Err(rejected()),
             _ => unreachable!(),
         };
 
@@ -82,17 +332,21 @@ impl ServerChooser {
         Ok(stream)
     }
 
-    pub async fn candidate_udp_socket(&self, action: Action) -> Result<ProxyUdpSocket> {
-        let socket = match action {
-            Action::Direct => ProxyUdpSocket::new(None, self.dns_client.clone()).await?,
-            Action::Proxy => {
-                let config = self.candidates.lock().first().cloned().unwrap();
+    pub async fn candidate_udp_socket(
+        &self,
+        action: Action,
+        group: Option<&str>,
+    ) -> Result<ProxyUdpSocket> {
+        let socket = match self.resolve(action, group) {
+            (Action::Direct, _) => ProxyUdpSocket::new(None, self.dns_client.clone()).await?,
+            (Action::Proxy, Some(config)) => {
                 let socket = ProxyUdpSocket::new(Some(&config), self.dns_client.clone()).await;
                 if socket.is_err() {
-                    self.take_down_current_and_move_next();
+                    self.take_down_and_move_next(&config);
                 }
                 socket?
             }
+            (Action::Reject, _) => return Err(rejected()),
             _ => unreachable!(),
         };
         let socket_clone = socket.clone();
@@ -100,13 +354,18 @@ impl ServerChooser {
         Ok(socket)
     }
 
-    pub fn take_down_current_and_move_next(&self) {
+    /// Stop using `config` until the next ping finds it reachable again.
+    pub fn take_down_and_move_next(&self, config: &ServerConfig) {
         // make sure `candidates` drop after block ends to avoid deadlock.
         let mut candidates = self.candidates.lock();
         if candidates.len() <= 1 {
             return;
         }
-        let removed = candidates.remove(0);
+        let pos = match candidates.iter().position(|c| c == config) {
+            Some(pos) => pos,
+            None => return,
+        };
+        let removed = candidates.remove(pos);
         self.set_server_down(&removed);
         let new = &candidates[0];
         info!(
@@ -120,8 +379,11 @@ impl ServerChooser {
 
     pub async fn ping_servers_forever(&self) -> Result<()> {
         loop {
-            self.ping_servers().await;
-            self.print_connection_stats();
+            // A single server is used whether it answers or not, unless failing closed.
+            if self.pool().servers.len() > 1 || self.fail_closed {
+                self.ping_servers().await;
+                self.print_connection_stats();
+            }
             sleep(Duration::from_secs(30)).await;
         }
     }
@@ -155,13 +417,13 @@ impl ServerChooser {
 
     pub async fn ping_servers(&self) {
         let mut candidates = vec![];
-        let mut fut: FuturesUnordered<_> = self
-            .servers
+        let servers = self.pool().servers.clone();
+        let mut fut: FuturesUnordered<_> = servers
             .iter()
             .map(|config| {
                 let self_clone = self.clone();
                 let config_clone = config.clone();
-                spawn(async move {
+                priority::spawn(async move {
                     let duration = self_clone
                         .ping_server(config_clone.clone())
                         .await
@@ -179,7 +441,10 @@ impl ServerChooser {
                         latency = %duration.as_millis(),
                         "Ping shadowsocks server"
                     );
-                    candidates.push(config);
+                    self.latencies
+                        .write()
+                        .insert(config.name().to_string(), (SystemTime::now(), duration));
+                    candidates.push((config, duration));
                 }
                 Err(config) => {
                     info!(
@@ -187,20 +452,51 @@ impl ServerChooser {
                         server = ?config.addr(),
                         "Ping shadowsocks server error"
                     );
+                    self.latencies.write().remove(config.name());
                 }
             }
         }
+        // The servers may have been replaced while they were pinged.
+        let servers = self.pool().servers.clone();
+        candidates.retain(|(c, _)| servers.contains(c));
+        let all_down = candidates.is_empty() && !servers.is_empty();
+        if self.fail_closed && self.all_down.swap(all_down, atomic::Ordering::SeqCst) != all_down {
+            if all_down {
+                warn!("no server reachable, rejecting proxied connections");
+            } else {
+                info!("servers reachable again, stop rejecting proxied connections");
+            }
+        }
         if !candidates.is_empty() {
-            *self.candidates.lock() = candidates;
+            rank_servers(&mut candidates, local_minute_of_day());
+            *self.candidates.lock() = candidates.into_iter().map(|(c, _)| c).collect();
         }
     }
 
     async fn ping_server(&self, config: ServerConfig) -> Result<Duration> {
+        let ret = self.measure_latency(&config, self.ping_timeout).await;
+        if let Err(e) = &ret {
+            if is_tls_mismatch(e) {
+                self.quarantine
+                    .strike(&config, QuarantineReason::TlsMismatch);
+            }
+            self.set_server_down(&config);
+        }
+        ret
+    }
+
+    /// Time to fetch the ping urls through `config`, each within `ping_timeout`. Unlike a
+    /// ping, failing leaves the candidates alone.
+    pub async fn measure_latency(
+        &self,
+        config: &ServerConfig,
+        ping_timeout: Duration,
+    ) -> Result<Duration> {
         let instant = Instant::now();
         for (host, path) in &self.ping_url {
-            let ret: Result<_> = timeout(self.ping_timeout, async {
+            timeout(ping_timeout, async {
                 let mut conn =
-                    ProxyTcpStream::connect(host.clone(), Some(&config), self.dns_client.clone())
+                    ProxyTcpStream::connect(host.clone(), Some(config), self.dns_client.clone())
                         .await?;
                 conn.write_all(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes())
                     .await?;
@@ -208,12 +504,63 @@ impl ServerChooser {
                 let _size = conn.read(&mut buf).await?;
                 Ok(())
             })
-            .await;
-            if let Err(e) = ret {
-                self.set_server_down(&config);
-                return Err(e);
-            }
+            .await?;
         }
         Ok(instant.elapsed())
     }
 }
+
+/// Order servers by latency divided by their weight at `minute_of_day`, best first.
+///
+/// Servers with a weight of 0 or less are only used when nothing else is reachable.
+fn rank_servers(servers: &mut Vec<(ServerConfig, Duration)>, minute_of_day: u16) {
+    let score = |(config, latency): &(ServerConfig, Duration)| {
+        let weight = config.weight_at(minute_of_day);
+        if weight > 0.0 {
+            latency.as_secs_f64() / weight
+        } else {
+            f64::INFINITY
+        }
+    };
+    // The sort is stable, so equal scores keep the order pings completed in.
+    servers.sort_by(|a, b| score(a).partial_cmp(&score(b)).unwrap_or(Ordering::Equal));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rank_servers() {
+        let servers: Vec<ServerConfig> = serde_yaml::from_str(
+            r#"
+- name: a
+  addr: 127.0.0.1:1080
+  protocol: Socks5
+- name: b
+  addr: 127.0.0.1:1081
+  protocol: Socks5
+  weights:
+    - time: 19:00-23:00
+      weight: 4
+    - time: 02:00-06:00
+      weight: 0
+"#,
+        )
+        .unwrap();
+        let mut ranked = vec![
+            (servers[0].clone(), Duration::from_millis(100)),
+            (servers[1].clone(), Duration::from_millis(200)),
+        ];
+        let names = |ranked: &[(ServerConfig, Duration)]| -> Vec<String> {
+            ranked.iter().map(|(c, _)| c.name().to_string()).collect()
+        };
+        rank_servers(&mut ranked, 12 * 60);
+        assert_eq!(names(&ranked), vec!["a", "b"]);
+        rank_servers(&mut ranked, 20 * 60);
+        assert_eq!(names(&ranked), vec!["b", "a"]);
+        ranked.reverse();
+        rank_servers(&mut ranked, 3 * 60);
+        assert_eq!(names(&ranked), vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,51 @@
+//! Stop seeker on SIGINT and SIGTERM, or on Ctrl-C and the console closing on Windows, so
+//! the routes, DNS and firewall rules are put back on the way out.
+use std::future::Future;
+use std::io;
+
+#[cfg(unix)]
+pub fn shutdown_signal() -> io::Result<impl Future<Output = ()>> {
+    use async_signals::Signals;
+    use async_std::prelude::StreamExt;
+
+    let mut signals = Signals::new(vec![libc::SIGINT, libc::SIGTERM])?;
+    Ok(async move {
+        signals.next().await;
+    })
+}
+
+#[cfg(windows)]
+pub fn shutdown_signal() -> io::Result<impl Future<Output = ()>> {
+    use async_std::channel::{bounded, Sender};
+    use once_cell::sync::OnceCell;
+    use std::time::Duration;
+    use winapi::shared::minwindef::{BOOL, DWORD, FALSE, TRUE};
+    use winapi::um::consoleapi::SetConsoleCtrlHandler;
+    use winapi::um::wincon::{CTRL_BREAK_EVENT, CTRL_C_EVENT};
+
+    static STOP: OnceCell<Sender<()>> = OnceCell::new();
+
+    /// Runs on a thread of its own. Windows ends the process once it returns from the
+    /// closing of the console, logoff or shutdown, so it waits for seeker to clean up and
+    /// exit first, within the few seconds Windows allows.
+    unsafe extern "system" fn handler(ctrl_type: DWORD) -> BOOL {
+        let stop = match STOP.get() {
+            Some(stop) => stop,
+            None => return FALSE,
+        };
+        let _ = stop.try_send(());
+        if ctrl_type != CTRL_C_EVENT && ctrl_type != CTRL_BREAK_EVENT {
+            std::thread::sleep(Duration::from_secs(4));
+        }
+        TRUE
+    }
+
+    let (sender, receiver) = bounded(1);
+    let _ = STOP.set(sender);
+    if unsafe { SetConsoleCtrlHandler(Some(handler), TRUE) } == FALSE {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(async move {
+        let _ = receiver.recv().await;
+    })
+}
@@ -0,0 +1,224 @@
+//! Find the domain of a TCP connection to a bare ip from its first bytes: the SNI of a TLS
+//! ClientHello or the `Host` of an HTTP request.
+//!
+//! Apps resolving names themselves, e.g. over DoH, bypass the fake ips of the DNS server,
+//! their connections would otherwise only be matched by IP rules.
+use async_std::io::timeout;
+use async_std::net::TcpStream;
+use async_std::task::sleep;
+use std::time::{Duration, Instant};
+
+/// A TLS record header and the largest record, ClientHellos with post-quantum key shares
+/// need more than the first few KB.
+const PEEK_SIZE: usize = 5 + 16 * 1024;
+/// Pause between peeks while the rest of the first bytes is on its way.
+const PEEK_RETRY: Duration = Duration::from_millis(5);
+
+/// The domain in the first bytes the client sends, waiting at most `wait` for them.
+///
+/// The bytes are only peeked, they are still relayed. Peeks are repeated until the first TLS
+/// record or the HTTP request head is complete, they may arrive in several segments.
+pub async fn peek_host(conn: &TcpStream, wait: Duration) -> Option<String> {
+    let deadline = Instant::now() + wait;
+    let mut buf = vec![0; PEEK_SIZE];
+    loop {
+        let left = deadline.checked_duration_since(Instant::now())?;
+        let size = timeout(left, conn.peek(&mut buf)).await.ok()?;
+        let data = &buf[..size];
+        if size == 0 {
+            return None;
+        }
+        let host = sniff_host(data);
+        if host.is_some() || size == PEEK_SIZE || is_complete(data) {
+            return host;
+        }
+        sleep(PEEK_RETRY.min(left)).await;
+    }
+}
+
+fn contains(data: &[u8], pattern: &[u8]) -> bool {
+    data.windows(pattern.len()).any(|w| w == pattern)
+}
+
+/// Whether the non-empty `data` holds all of the first TLS record or HTTP request head it
+/// starts, or can not start either.
+fn is_complete(data: &[u8]) -> bool {
+    if data[0] == 0x16 {
+        let mut r = Reader(data);
+        r.take(3);
+        return match r.u16() {
+            Some(len) => data.len() >= 5 + len,
+            None => false,
+        };
+    }
+    match data.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => !contains(&data[..end], b" HTTP/1.") || contains(data, b"\r\n\r\n"),
+        // Still in the request line.
+        None => !data.iter().all(|b| b.is_ascii_graphic() || *b == b' '),
+    }
+}
+
+pub fn sniff_host(data: &[u8]) -> Option<String> {
+    let host = tls_server_name(data).or_else(|| http_host(data))?;
+    let host = host
+        .trim_matches(|c| c == '[' || c == ']')
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    if host.is_empty() || host.parse::<std::net::IpAddr>().is_ok() {
+        return None;
+    }
+    Some(host)
+}
+
+/// Reads big endian integers off the front of a slice, `None` once it runs out.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        Some(self.take(1)?[0] as usize)
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        let b = self.take(2)?;
+        Some((b[0] as usize) << 8 | b[1] as usize)
+    }
+
+    /// A vector prefixed by its length of `len_size` bytes.
+    fn vec(&mut self, len_size: usize) -> Option<Reader<'a>> {
+        let len = match len_size {
+            1 => self.u8()?,
+            _ => self.u16()?,
+        };
+        Some(Reader(self.take(len)?))
+    }
+}
+
+/// The `server_name` extension of a TLS ClientHello.
+fn tls_server_name(data: &[u8]) -> Option<String> {
+    let mut r = Reader(data);
+    // Record header: handshake, version, length.
+    if r.u8()? != 0x16 {
+        return None;
+    }
+    r.take(4)?;
+    // Handshake header: client hello, 24 bit length.
+    if r.u8()? != 0x01 {
+        return None;
+    }
+    r.take(3)?;
+    // Version, random, session id, cipher suites, compression methods.
+    r.take(2 + 32)?;
+    r.vec(1)?;
+    r.vec(2)?;
+    r.vec(1)?;
+    let mut extensions = r.vec(2)?;
+    while let Some(kind) = extensions.u16() {
+        let mut ext = extensions.vec(2)?;
+        if kind != 0 {
+            continue;
+        }
+        let mut names = ext.vec(2)?;
+        while let Some(name_type) = names.u8() {
+            let name = names.vec(2)?.0;
+            if name_type == 0 {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+/// The `Host` header of an HTTP/1 request, without the port.
+fn http_host(data: &[u8]) -> Option<String> {
+    let head = std::str::from_utf8(data)
+        .or_else(|e| std::str::from_utf8(&data[..e.valid_up_to()]))
+        .ok()?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next()?;
+    if !request_line.contains(" HTTP/1.") {
+        return None;
+    }
+    lines
+        .take_while(|l| !l.is_empty())
+        .filter_map(|l| {
+            let mut parts = l.splitn(2, ':');
+            Some((parts.next()?, parts.next()?))
+        })
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| {
+            let value = value.trim();
+            match value.rfind(':') {
+                Some(pos) if !value.ends_with(']') => value[..pos].to_string(),
+                _ => value.to_string(),
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ClientHello with only the `server_name` extension.
+    fn client_hello(server_name: &[u8]) -> Vec<u8> {
+        let mut sni = vec![0x00];
+        sni.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(server_name);
+        let mut ext = vec![0x00, 0x00];
+        ext.extend_from_slice(&(sni.len() as u16 + 2).to_be_bytes());
+        ext.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        ext.extend_from_slice(&sni);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0; 32]);
+        hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        hello.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&ext);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(hello.len() as u16 + 4).to_be_bytes());
+        record.push(0x01);
+        record.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        record.extend_from_slice(&hello);
+        record
+    }
+
+    #[test]
+    fn test_sniff_tls() {
+        let hello = client_hello(b"Example.com");
+        assert_eq!(sniff_host(&hello), Some("example.com".to_string()));
+        assert_eq!(sniff_host(&hello[..hello.len() - 3]), None);
+        assert_eq!(sniff_host(&client_hello(b"1.2.3.4")), None);
+    }
+
+    #[test]
+    fn test_is_complete() {
+        let hello = client_hello(b"example.com");
+        assert!(is_complete(&hello));
+        assert!(!is_complete(&hello[..hello.len() - 3]));
+        assert!(!is_complete(&hello[..4]));
+        assert!(!is_complete(b"GET / HT"));
+        assert!(!is_complete(b"GET / HTTP/1.1\r\nUser-Agent: curl\r\n"));
+        assert!(is_complete(b"GET / HTTP/1.1\r\nUser-Agent: curl\r\n\r\n"));
+        assert!(is_complete(b"SSH-2.0-OpenSSH_8.2\r\n"));
+        assert!(is_complete(&[0x00, 0x01]));
+    }
+
+    #[test]
+    fn test_sniff_http() {
+        assert_eq!(
+            sniff_host(b"GET / HTTP/1.1\r\nUser-Agent: curl\r\nHost: example.com:8080\r\n\r\n"),
+            Some("example.com".to_string())
+        );
+        assert_eq!(sniff_host(b"GET / HTTP/1.1\r\nHost: [::1]\r\n\r\n"), None);
+        assert_eq!(sniff_host(b"SSH-2.0-OpenSSH_8.2\r\n"), None);
+    }
+}
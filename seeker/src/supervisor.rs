@@ -0,0 +1,117 @@
+//! Keep background tasks running: tasks that fail or panic are restarted with backoff.
+//!
+//! Restarts are counted in the `task_restarts{task="..."}` metric. With `task_max_failures`
+//! set, seeker exits once a task failed that many times, so fail-fast deployments can let
+//! their service manager restart the whole process instead.
+use crate::introspect::TaskGuard;
+use crate::metrics;
+use async_std::task::{sleep, spawn};
+use futures_util::FutureExt;
+use std::future::Future;
+use std::io::Result;
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A task running this long before failing counts as healthy again.
+const HEALTHY_AFTER: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Supervisor {
+    max_failures: Option<u32>,
+}
+
+impl Supervisor {
+    pub fn new(max_failures: Option<u32>) -> Self {
+        Supervisor { max_failures }
+    }
+
+    /// Spawn the task built by `make`, building a new one whenever it fails.
+    ///
+    /// A task returning `Ok` is done and not restarted.
+    pub fn spawn<F, Fut>(self, name: &'static str, make: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        spawn(async move { self.run(name, make).await });
+    }
+
+    async fn run<F, Fut>(self, name: &'static str, make: F)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let _task = TaskGuard::new(name);
+        let mut failures = 0;
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let started = Instant::now();
+            let reason = match AssertUnwindSafe(make()).catch_unwind().await {
+                Ok(Ok(())) => {
+                    info!(task = name, "background task finished");
+                    return;
+                }
+                Ok(Err(e)) => e.to_string(),
+                Err(panic) => panic_message(&*panic),
+            };
+            if started.elapsed() >= HEALTHY_AFTER {
+                failures = 0;
+                backoff = INITIAL_BACKOFF;
+            }
+            failures += 1;
+            error!(task = name, %reason, failures, "background task failed");
+            if let Some(max) = self.max_failures {
+                if failures >= max {
+                    error!(task = name, "background task failed too often, exiting");
+                    std::process::exit(1);
+                }
+            }
+            metrics::incr(&format!("task_restarts{{task=\"{}\"}}", name));
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        format!("panic: {}", s)
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        format!("panic: {}", s)
+    } else {
+        "panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+    use std::io::{Error, ErrorKind};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_restart() {
+        let runs = Arc::new(AtomicU32::new(0));
+        let r = runs.clone();
+        block_on(Supervisor::new(None).run("test_restart", move || {
+            let r = r.clone();
+            async move {
+                match r.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(Error::new(ErrorKind::Other, "failed")),
+                    1 => panic!("boom"),
+                    _ => Ok(()),
+                }
+            }
+        }));
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            metrics::snapshot().get("task_restarts{task=\"test_restart\"}"),
+            Some(&2)
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use async_std::channel::{bounded, Receiver, Sender, TrySendError};
+
+/// Bounded queue of datagrams waiting to be sent upstream by one UDP association.
+///
+/// When the upstream is slower than the client, the oldest datagrams are dropped instead
+/// of buffering without limit; UDP protocols cope with loss far better than with delay.
+#[derive(Clone)]
+pub struct UdpQueue {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+}
+
+impl UdpQueue {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, receiver) = bounded(capacity.max(1));
+        UdpQueue { sender, receiver }
+    }
+
+    /// Queue `packet`, returning how many old datagrams were dropped to make room.
+    pub fn push(&self, mut packet: Vec<u8>) -> usize {
+        let mut dropped = 0;
+        loop {
+            match self.sender.try_send(packet) {
+                Ok(()) | Err(TrySendError::Closed(_)) => return dropped,
+                Err(TrySendError::Full(p)) => {
+                    if self.receiver.try_recv().is_ok() {
+                        dropped += 1;
+                    }
+                    packet = p;
+                }
+            }
+        }
+    }
+
+    /// Next datagram to send, `None` once the queue is closed.
+    pub async fn pop(&self) -> Option<Vec<u8>> {
+        self.receiver.recv().await.ok()
+    }
+
+    /// Datagrams waiting to be sent.
+    pub fn queued(&self) -> usize {
+        self.receiver.len()
+    }
+
+    pub fn close(&self) {
+        self.sender.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+
+    #[test]
+    fn test_drop_oldest() {
+        let queue = UdpQueue::new(2);
+        assert_eq!(queue.push(vec![1]), 0);
+        assert_eq!(queue.push(vec![2]), 0);
+        assert_eq!(queue.push(vec![3]), 1);
+        queue.close();
+        block_on(async {
+            assert_eq!(queue.pop().await, Some(vec![2]));
+            assert_eq!(queue.pop().await, Some(vec![3]));
+            assert_eq!(queue.pop().await, None);
+        });
+    }
+}
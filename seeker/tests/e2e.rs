@@ -0,0 +1,143 @@
+//! End-to-end flows through the tun and real proxy servers, see `tests/e2e`.
+//!
+//! The servers run in Docker, so the tests are ignored by default. Run them with
+//!
+//! ```text
+//! cargo test -p seeker --test e2e -- --ignored --test-threads 1
+//! ```
+//!
+//! The seeker binary is mounted into a Debian container, build it on a compatible glibc
+//! or for `x86_64-unknown-linux-musl`.
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const READY_TIMEOUT: Duration = Duration::from_secs(20);
+const WEB_CONTENT: &str = "seeker e2e\n";
+
+fn compose(args: &[&str]) -> Output {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../tests/e2e");
+    Command::new("docker-compose")
+        .current_dir(dir)
+        .env("SEEKER_BIN", env!("CARGO_BIN_EXE_seeker"))
+        .args(args)
+        .output()
+        .expect("run docker-compose")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+/// The containers of `tests/e2e`, removed when dropped.
+struct Harness;
+
+impl Harness {
+    fn up() -> Self {
+        let output = compose(&["up", "-d", "--build"]);
+        assert!(
+            output.status.success(),
+            "docker-compose up: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Harness
+    }
+
+    /// Run `cmd` in the client container.
+    fn exec(&self, cmd: &str) -> Output {
+        compose(&["exec", "-T", "client", "sh", "-c", cmd])
+    }
+
+    /// Start seeker with `configs/<config>.yml` and wait until names resolve through it.
+    fn start_seeker(&self, config: &str) {
+        let output = compose(&[
+            "exec",
+            "-d",
+            "client",
+            "seeker",
+            "-c",
+            &format!("/e2e/configs/{}.yml", config),
+            "-l",
+            &format!("/tmp/seeker-{}.log", config),
+        ]);
+        assert!(output.status.success(), "start seeker with {}", config);
+        let start = Instant::now();
+        // Fake ips come from `dns_start_ip`, 11.0.0.0/16.
+        while !stdout(&self.exec("getent hosts web")).starts_with("11.0.") {
+            assert!(
+                start.elapsed() < READY_TIMEOUT,
+                "seeker with {} not ready:\n{}",
+                config,
+                self.log(config)
+            );
+            sleep(Duration::from_millis(500));
+        }
+    }
+
+    fn stop_seeker(&self) {
+        self.exec("pkill -INT seeker; while pgrep seeker >/dev/null; do sleep 0.1; done");
+    }
+
+    fn log(&self, config: &str) -> String {
+        stdout(&self.exec(&format!("cat /tmp/seeker-{}.log*", config)))
+    }
+
+    /// Fetch a page from `web` over TCP.
+    fn check_tcp(&self, config: &str) {
+        let output = self.exec("curl -sf --max-time 10 http://web/e2e.txt");
+        assert_eq!(
+            stdout(&output),
+            WEB_CONTENT,
+            "tcp through {}:\n{}",
+            config,
+            self.log(config)
+        );
+    }
+
+    /// Send a datagram to `udp-echo` and read it back.
+    fn check_udp(&self, config: &str) {
+        let output = self.exec("echo ping | socat -t 5 - UDP:udp-echo:9000");
+        assert_eq!(
+            stdout(&output),
+            "ping\n",
+            "udp through {}:\n{}",
+            config,
+            self.log(config)
+        );
+    }
+
+    fn run(&self, config: &str, udp: bool) {
+        self.start_seeker(config);
+        self.check_tcp(config);
+        if udp {
+            self.check_udp(config);
+        }
+        self.stop_seeker();
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = compose(&["down", "-v"]);
+    }
+}
+
+#[test]
+#[ignore]
+fn test_shadowsocks() {
+    Harness::up().run("shadowsocks", true);
+}
+
+#[test]
+#[ignore]
+fn test_socks5() {
+    Harness::up().run("socks5", true);
+}
+
+#[test]
+#[ignore]
+fn test_http() {
+    // HTTP proxies only relay TCP.
+    Harness::up().run("http", false);
+}
@@ -15,7 +15,12 @@ pub struct Socks5TcpStream {
 
 impl Socks5TcpStream {
     pub async fn connect(socks5_server: SocketAddr, addr: Address) -> Result<Self> {
-        let mut conn = TcpStream::connect(socks5_server).await?;
+        let conn = TcpStream::connect(socks5_server).await?;
+        Socks5TcpStream::connect_stream(conn, addr).await
+    }
+
+    /// Like `connect`, over `conn` already connected to the socks5 server.
+    pub async fn connect_stream(mut conn: TcpStream, addr: Address) -> Result<Self> {
         let handshake_req = HandshakeRequest::new(vec![SOCKS5_AUTH_METHOD_NONE]);
         handshake_req.write_to(&mut conn).await?;
         let handshake_resp = HandshakeResponse::read_from(&mut conn).await?;
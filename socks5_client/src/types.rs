@@ -11,7 +11,7 @@ use std::{
     error,
     fmt::{self, Debug, Formatter},
     io::{self, Cursor},
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
     str::FromStr,
     u8, vec,
 };
@@ -378,22 +378,22 @@ impl FromStr for Address {
     type Err = AddressError;
 
     fn from_str(s: &str) -> Result<Address, AddressError> {
-        match s.parse::<SocketAddr>() {
-            Ok(addr) => Ok(Address::SocketAddress(addr)),
-            Err(..) => {
-                let mut sp = s.split(':');
-                match (sp.next(), sp.next()) {
-                    (Some(dn), Some(port)) => match port.parse::<u16>() {
-                        Ok(port) => Ok(Address::DomainNameAddress(dn.to_owned(), port)),
-                        Err(..) => Err(AddressError),
-                    },
-                    (Some(dn), None) => {
-                        // Assume it is 80 (http's default port)
-                        Ok(Address::DomainNameAddress(dn.to_owned(), 80))
-                    }
-                    _ => Err(AddressError),
-                }
-            }
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Address::SocketAddress(addr));
+        }
+        // Assume it is 80 (http's default port) when the port is omitted
+        let host = s.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Address::SocketAddress(SocketAddr::new(ip, 80)));
+        }
+        let mut sp = s.rsplitn(2, ':');
+        match (sp.next(), sp.next()) {
+            (Some(port), Some(dn)) if !dn.contains(':') => match port.parse::<u16>() {
+                Ok(port) => Ok(Address::DomainNameAddress(dn.to_owned(), port)),
+                Err(..) => Err(AddressError),
+            },
+            (Some(dn), None) => Ok(Address::DomainNameAddress(dn.to_owned(), 80)),
+            _ => Err(AddressError),
         }
     }
 }
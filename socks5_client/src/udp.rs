@@ -5,9 +5,18 @@ use crate::types::{
 use async_std::io;
 use async_std::net::{TcpStream, UdpSocket};
 use std::io::{Error, ErrorKind, Result};
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::time::Duration;
 
+/// The wildcard address of the same family as `addr`.
+fn unspecified_addr(addr: SocketAddr) -> SocketAddr {
+    let ip = match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    SocketAddr::new(ip, 0)
+}
+
 #[derive(Debug)]
 pub struct Socks5UdpSocket {
     socket: UdpSocket,
@@ -17,9 +26,14 @@ pub struct Socks5UdpSocket {
 
 impl Socks5UdpSocket {
     pub async fn new(socks5_server: SocketAddr) -> Result<Self> {
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        let mut conn =
-            io::timeout(Duration::from_secs(1), TcpStream::connect(socks5_server)).await?;
+        let socket = UdpSocket::bind(unspecified_addr(socks5_server)).await?;
+        let conn = io::timeout(Duration::from_secs(1), TcpStream::connect(socks5_server)).await?;
+        Socks5UdpSocket::associate(socket, conn).await
+    }
+
+    /// Like `new`, with an unconnected `socket` and `conn` connected to the socks5 server.
+    pub async fn associate(socket: UdpSocket, mut conn: TcpStream) -> Result<Self> {
+        let socks5_server = conn.peer_addr()?;
         let handshake_req = HandshakeRequest::new(vec![SOCKS5_AUTH_METHOD_NONE]);
         handshake_req.write_to(&mut conn).await?;
         let handshake_resp = HandshakeResponse::read_from(&mut conn).await?;
@@ -28,7 +42,7 @@ impl Socks5UdpSocket {
         }
         let req_header = TcpRequestHeader::new(
             Command::UdpAssociate,
-            Address::SocketAddress("0.0.0.0:0".parse().expect("never error")),
+            Address::SocketAddress(unspecified_addr(socks5_server)),
         );
         req_header.write_to(&mut conn).await?;
         let resp_header = TcpResponseHeader::read_from(&mut conn).await?;
@@ -39,6 +53,10 @@ impl Socks5UdpSocket {
             ));
         }
         let server_bind_addr = match resp_header.address {
+            // Unspecified means the relay listens on the address of the server.
+            Address::SocketAddress(addr) if addr.ip().is_unspecified() => {
+                SocketAddr::new(socks5_server.ip(), addr.port())
+            }
             Address::SocketAddress(addr) => addr,
             Address::DomainNameAddress(_, _) => {
                 return Err(Error::new(
@@ -54,9 +72,9 @@ impl Socks5UdpSocket {
         })
     }
 
-    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+    pub async fn send_to<A: Into<Address>>(&self, buf: &[u8], addr: A) -> Result<usize> {
         let mut buffer = vec![0; 1500];
-        let udp_header = UdpAssociateHeader::new(0, Address::SocketAddress(addr));
+        let udp_header = UdpAssociateHeader::new(0, addr.into());
         let mut size = 0;
         udp_header.write_to_buf(&mut buffer[size..].as_mut());
         size += udp_header.serialized_len();
@@ -0,0 +1,88 @@
+//! Opt-in logging of AEAD frame boundaries, to diagnose interoperability issues with a
+//! server implementation. Only lengths and nonce counters are logged, never payload.
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::info;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turn frame logging on or off for all connections, including open ones.
+pub fn set_frame_trace(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn frame_trace_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Counters of one direction of a connection, kept even while logging is off so they
+/// are right when it is turned on mid-connection.
+pub(crate) struct FrameTrace {
+    direction: &'static str,
+    peer: String,
+    frames: u64,
+    nonce: u64,
+}
+
+impl FrameTrace {
+    pub fn new(direction: &'static str) -> Self {
+        FrameTrace {
+            direction,
+            peer: "-".to_string(),
+            frames: 0,
+            nonce: 0,
+        }
+    }
+
+    pub fn set_peer(&mut self, peer: String) {
+        self.peer = peer;
+    }
+
+    /// A chunk of `len` bytes was sealed or opened, with one nonce for its length and one
+    /// for its data.
+    pub fn frame(&mut self, len: usize) {
+        if frame_trace_enabled() {
+            info!(
+                target: "ssclient::frames",
+                peer = %self.peer,
+                direction = self.direction,
+                frame = self.frames,
+                nonce = self.nonce,
+                len,
+                "aead frame"
+            );
+        }
+        self.frames += 1;
+        self.nonce += 2;
+    }
+
+    /// Opening the `part` of the current frame failed, with the nonce counter it used.
+    pub fn failed(&self, part: &'static str, nonce_offset: u64) {
+        if frame_trace_enabled() {
+            info!(
+                target: "ssclient::frames",
+                peer = %self.peer,
+                direction = self.direction,
+                frame = self.frames,
+                nonce = self.nonce + nonce_offset,
+                part,
+                "aead frame failed to decrypt"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_counters() {
+        let mut trace = FrameTrace::new("send");
+        trace.frame(5);
+        set_frame_trace(true);
+        trace.frame(0x3fff);
+        set_frame_trace(false);
+        assert_eq!(trace.frames, 2);
+        assert_eq!(trace.nonce, 4);
+    }
+}
@@ -1,8 +1,10 @@
+mod frame_trace;
 mod tcp_io;
 mod udp_io;
 
 const BUFFER_SIZE: usize = 8 * 1024; // 8K buffer
 
+pub use frame_trace::{frame_trace_enabled, set_frame_trace};
 pub use tcp_io::SSTcpStream;
-pub use udp_io::crypto_io::{decrypt_payload, encrypt_payload};
+pub use udp_io::crypto_io::{decrypt_payload, encrypt_payload, encrypt_payload_with_random};
 pub use udp_io::SSUdpSocket;
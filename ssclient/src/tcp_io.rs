@@ -15,7 +15,9 @@ use async_std::task::ready;
 use bytes::{Bytes, BytesMut};
 use tracing::trace;
 
-use crypto::{CipherCategory, CipherType};
+use crypto::{CipherCategory, CipherType, Random, ThreadRandom};
+
+use crate::udp_io::crypto_io::gen_salt;
 
 use self::{
     aead::{DecryptedReader as AeadDecryptedReader, EncryptedWriter as AeadEncryptedWriter},
@@ -64,49 +66,53 @@ impl SSTcpStream {
         addr: Address,
         method: CipherType,
         key: Bytes,
+    ) -> Result<SSTcpStream> {
+        SSTcpStream::connect_with_random(server_addr, addr, method, key, &ThreadRandom).await
+    }
+
+    /// Like `connect`, but draws the IV or salt from `random`
+    pub async fn connect_with_random(
+        server_addr: SocketAddr,
+        addr: Address,
+        method: CipherType,
+        key: Bytes,
+        random: &dyn Random,
     ) -> Result<SSTcpStream> {
         let stream = TcpStream::connect(server_addr).await?;
-        let prev_len = match method.category() {
-            CipherCategory::Stream => method.iv_size(),
-            CipherCategory::Aead => method.salt_size(),
-        };
+        SSTcpStream::connect_stream_with_random(stream, addr, method, key, random, None).await
+    }
 
-        let iv = match method.category() {
-            CipherCategory::Stream => {
-                let local_iv = method.gen_init_vec();
-                trace!("generated Stream cipher IV {:?}", local_iv);
-                local_iv
-            }
-            CipherCategory::Aead => {
-                let local_salt = method.gen_salt();
-                trace!("generated AEAD cipher salt {:?}", local_salt);
-                local_salt
-            }
-        };
+    /// Like `connect`, over `stream` already connected to the server
+    pub async fn connect_stream(
+        stream: TcpStream,
+        addr: Address,
+        method: CipherType,
+        key: Bytes,
+    ) -> Result<SSTcpStream> {
+        SSTcpStream::connect_stream_with_salt_size(stream, addr, method, key, None).await
+    }
 
-        let enc = match method.category() {
-            CipherCategory::Stream => EncryptedWriter::Stream(StreamEncryptedWriter::new(
-                stream.clone(),
-                method,
-                &key,
-                iv,
-            )),
-            CipherCategory::Aead => {
-                EncryptedWriter::Aead(AeadEncryptedWriter::new(stream.clone(), method, &key, iv))
-            }
-        };
+    /// Like `connect_stream`, with AEAD salts of `salt_size` bytes instead of the key size
+    pub async fn connect_stream_with_salt_size(
+        stream: TcpStream,
+        addr: Address,
+        method: CipherType,
+        key: Bytes,
+        salt_size: Option<usize>,
+    ) -> Result<SSTcpStream> {
+        SSTcpStream::connect_stream_with_random(stream, addr, method, key, &ThreadRandom, salt_size)
+            .await
+    }
 
-        let mut ss_stream = SSTcpStream {
-            stream,
-            dec: None,
-            enc: Arc::new(Mutex::new(enc)),
-            read_status: Arc::new(Mutex::new(ReadStatus::WaitIv(
-                vec![0u8; prev_len],
-                0usize,
-                method,
-                key,
-            ))),
-        };
+    async fn connect_stream_with_random(
+        stream: TcpStream,
+        addr: Address,
+        method: CipherType,
+        key: Bytes,
+        random: &dyn Random,
+        salt_size: Option<usize>,
+    ) -> Result<SSTcpStream> {
+        let mut ss_stream = SSTcpStream::handshake(stream, method, key, random, salt_size);
 
         let mut addr_buf = BytesMut::with_capacity(addr.serialized_len());
         addr.write_to_buf(&mut addr_buf);
@@ -115,19 +121,39 @@ impl SSTcpStream {
     }
 
     pub fn accept(stream: TcpStream, method: CipherType, key: Bytes) -> SSTcpStream {
+        SSTcpStream::accept_with_random(stream, method, key, &ThreadRandom)
+    }
+
+    /// Like `accept`, but draws the IV or salt from `random`
+    pub fn accept_with_random(
+        stream: TcpStream,
+        method: CipherType,
+        key: Bytes,
+        random: &dyn Random,
+    ) -> SSTcpStream {
+        SSTcpStream::handshake(stream, method, key, random, None)
+    }
+
+    fn handshake(
+        stream: TcpStream,
+        method: CipherType,
+        key: Bytes,
+        random: &dyn Random,
+        salt_size: Option<usize>,
+    ) -> SSTcpStream {
         let prev_len = match method.category() {
             CipherCategory::Stream => method.iv_size(),
-            CipherCategory::Aead => method.salt_size(),
+            CipherCategory::Aead => salt_size.unwrap_or_else(|| method.salt_size()),
         };
 
         let iv = match method.category() {
             CipherCategory::Stream => {
-                let local_iv = method.gen_init_vec();
+                let local_iv = method.gen_init_vec_with(random);
                 trace!("generated Stream cipher IV {:?}", local_iv);
                 local_iv
             }
             CipherCategory::Aead => {
-                let local_salt = method.gen_salt();
+                let local_salt = gen_salt(method, salt_size, random);
                 trace!("generated AEAD cipher salt {:?}", local_salt);
                 local_salt
             }
@@ -141,7 +167,11 @@ impl SSTcpStream {
                 iv,
             )),
             CipherCategory::Aead => {
-                EncryptedWriter::Aead(AeadEncryptedWriter::new(stream.clone(), method, &key, iv))
+                let mut writer = AeadEncryptedWriter::new(stream.clone(), method, &key, iv);
+                if let Ok(peer) = stream.peer_addr() {
+                    writer.set_peer(peer.to_string());
+                }
+                EncryptedWriter::Aead(writer)
             }
         };
 
@@ -188,12 +218,12 @@ impl SSTcpStream {
                 }
                 CipherCategory::Aead => {
                     trace!("got AEAD cipher salt {:?}", &buf);
-                    DecryptedReader::Aead(AeadDecryptedReader::new(
-                        self.stream.clone(),
-                        method,
-                        key,
-                        &buf,
-                    ))
+                    let mut reader =
+                        AeadDecryptedReader::new(self.stream.clone(), method, key, &buf);
+                    if let Ok(peer) = self.stream.peer_addr() {
+                        reader.set_peer(peer.to_string());
+                    }
+                    DecryptedReader::Aead(reader)
                 }
             };
 
@@ -326,4 +356,42 @@ mod tests {
             h.await;
         })
     }
+
+    #[test]
+    fn test_seeded_handshake_is_reproducible() {
+        let method = CipherType::ChaCha20IetfPoly1305;
+        let key = method.bytes_to_key(b"GwEU01uXWm0Pp6t08");
+        let addr = Address::DomainNameAddress("twitter.com".to_string(), 443);
+        let handshake_len = method.salt_size() + 2 + method.tag_size();
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = listener.local_addr().unwrap();
+            let h = spawn(async move {
+                let mut handshakes = vec![];
+                for _ in 0..2 {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut buf = vec![0; handshake_len];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    handshakes.push(buf);
+                }
+                handshakes
+            });
+            let mut conns = vec![];
+            for _ in 0..2 {
+                let random = crypto::SeededRandom::new(7);
+                let conn = SSTcpStream::connect_with_random(
+                    server,
+                    addr.clone(),
+                    method,
+                    key.clone(),
+                    &random,
+                )
+                .await
+                .unwrap();
+                conns.push(conn);
+            }
+            let handshakes = h.await;
+            assert_eq!(handshakes[0], handshakes[1]);
+        })
+    }
 }
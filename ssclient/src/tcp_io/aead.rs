@@ -45,6 +45,7 @@ use async_std::task::ready;
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, Bytes, BytesMut};
 
+use crate::frame_trace::FrameTrace;
 use crate::BUFFER_SIZE;
 use async_std::io::{Read, Write};
 use crypto::{self, BoxAeadDecryptor, BoxAeadEncryptor, CipherType};
@@ -68,6 +69,7 @@ pub struct DecryptedReader<T> {
     tag_size: usize,
     steps: DecryptReadStep,
     got_final: bool,
+    trace: FrameTrace,
 }
 
 impl<T: Read + Write + Unpin> DecryptedReader<T> {
@@ -81,9 +83,15 @@ impl<T: Read + Write + Unpin> DecryptedReader<T> {
             tag_size: t.tag_size(),
             steps: DecryptReadStep::Length,
             got_final: false,
+            trace: FrameTrace::new("recv"),
         }
     }
 
+    /// Name the server in frame logs.
+    pub fn set_peer(&mut self, peer: String) {
+        self.trace.set_peer(peer);
+    }
+
     fn poll_read_decrypted(
         &mut self,
         ctx: &mut Context<'_>,
@@ -119,7 +127,10 @@ impl<T: Read + Write + Unpin> DecryptedReader<T> {
         // Done reading, decrypt it
         let len = {
             let mut len_buf = [0u8; 2];
-            self.cipher.decrypt(&self.buffer[..], &mut len_buf)?;
+            if let Err(e) = self.cipher.decrypt(&self.buffer[..], &mut len_buf) {
+                self.trace.failed("length", 0);
+                return Poll::Ready(Err(e.into()));
+            }
             BigEndian::read_u16(&len_buf) as usize
         };
 
@@ -149,11 +160,15 @@ impl<T: Read + Write + Unpin> DecryptedReader<T> {
             // It has enough space, I am sure about that
             let buffer =
                 slice::from_raw_parts_mut(self.data.bytes_mut().as_mut_ptr() as *mut u8, size);
-            self.cipher.decrypt(&self.buffer[..], buffer)?;
+            if let Err(e) = self.cipher.decrypt(&self.buffer[..], buffer) {
+                self.trace.failed("data", 1);
+                return Poll::Ready(Err(e.into()));
+            }
 
             // Move forward the pointer
             self.data.advance_mut(size);
         }
+        self.trace.frame(size);
 
         // Clear buffer before overwriting it
         self.buffer.clear();
@@ -223,6 +238,7 @@ pub struct EncryptedWriter<T> {
     tag_size: usize,
     steps: EncryptWriteStep,
     nonce: Option<Bytes>,
+    trace: FrameTrace,
 }
 
 impl<T: Read + Write + Unpin> EncryptedWriter<T> {
@@ -234,9 +250,15 @@ impl<T: Read + Write + Unpin> EncryptedWriter<T> {
             tag_size: t.tag_size(),
             steps: EncryptWriteStep::Nothing,
             nonce: Some(nonce),
+            trace: FrameTrace::new("send"),
         }
     }
 
+    /// Name the server in frame logs.
+    pub fn set_peer(&mut self, peer: String) {
+        self.trace.set_peer(peer);
+    }
+
     fn poll_write_encrypted(
         &mut self,
         ctx: &mut Context<'_>,
@@ -297,6 +319,7 @@ impl<T: Read + Write + Unpin> EncryptedWriter<T> {
 
                         buf.advance_mut(output_length);
                     }
+                    self.trace.frame(data.len());
 
                     self.steps = EncryptWriteStep::Writing(buf, 0);
                 }
@@ -3,17 +3,17 @@ pub mod crypto_io;
 
 use std::{
     io,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
 
 use bytes::{Bytes, BytesMut};
 use tracing::debug;
 
-use self::crypto_io::{decrypt_payload, encrypt_payload};
+use self::crypto_io::{decrypt_payload_with_salt_size, encrypt_payload_with_salt_size};
 
 use async_std::net::UdpSocket;
 use config::Address;
-use crypto::CipherType;
+use crypto::{thread_random, CipherType, SharedRandom};
 
 pub const MAXIMUM_UDP_PAYLOAD_SIZE: usize = 1500;
 
@@ -22,6 +22,8 @@ pub struct SSUdpSocket {
     socket: UdpSocket,
     method: CipherType,
     key: Bytes,
+    random: SharedRandom,
+    salt_size: Option<usize>,
 }
 
 impl SSUdpSocket {
@@ -31,7 +33,11 @@ impl SSUdpSocket {
         method: CipherType,
         key: Bytes,
     ) -> io::Result<SSUdpSocket> {
-        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+        let local_ip = match server_addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let local_addr = SocketAddr::new(local_ip, 0);
         let socket = UdpSocket::bind(local_addr).await?;
         socket.connect(server_addr).await?;
 
@@ -39,6 +45,8 @@ impl SSUdpSocket {
             socket,
             method,
             key,
+            random: thread_random(),
+            salt_size: None,
         })
     }
     pub fn bind(socket: UdpSocket, method: CipherType, key: Bytes) -> SSUdpSocket {
@@ -46,12 +54,26 @@ impl SSUdpSocket {
             socket,
             method,
             key,
+            random: thread_random(),
+            salt_size: None,
         }
     }
 
+    /// Draw the IV or salt of every packet from `random` instead of the thread rng
+    pub fn with_random(mut self, random: SharedRandom) -> SSUdpSocket {
+        self.random = random;
+        self
+    }
+
+    /// Use AEAD salts of `salt_size` bytes, the key size when `None`
+    pub fn with_salt_size(mut self, salt_size: Option<usize>) -> SSUdpSocket {
+        self.salt_size = salt_size;
+        self
+    }
+
     /// Send a UDP packet to addr through proxy
-    pub async fn send_to(&self, payload: &[u8], sock_addr: SocketAddr) -> io::Result<usize> {
-        let addr: Address = sock_addr.into();
+    pub async fn send_to<A: Into<Address>>(&self, payload: &[u8], addr: A) -> io::Result<usize> {
+        let addr: Address = addr.into();
         debug!(
             "UDP server client send to {}, payload length {} bytes",
             addr,
@@ -64,7 +86,14 @@ impl SSUdpSocket {
         send_buf.extend_from_slice(payload);
 
         let mut encrypt_buf = BytesMut::with_capacity(MAXIMUM_UDP_PAYLOAD_SIZE);
-        encrypt_payload(self.method, &self.key, &send_buf, &mut encrypt_buf)?;
+        encrypt_payload_with_salt_size(
+            self.method,
+            &self.key,
+            &send_buf,
+            &mut encrypt_buf,
+            self.random.as_ref(),
+            self.salt_size,
+        )?;
 
         let send_len = self.socket.send(&encrypt_buf[..]).await?;
 
@@ -81,11 +110,12 @@ impl SSUdpSocket {
         let recv_n = self.socket.recv(&mut recv_buf).await?;
         let mut decrypt_buf = BytesMut::with_capacity(MAXIMUM_UDP_PAYLOAD_SIZE);
 
-        let decrypt_size = decrypt_payload(
+        let decrypt_size = decrypt_payload_with_salt_size(
             self.method,
             &self.key,
             &recv_buf[..recv_n],
             &mut decrypt_buf,
+            self.salt_size,
         )?;
         let addr = Address::read_from(&mut decrypt_buf.as_ref()).await?;
         let payload = &decrypt_buf[addr.serialized_len()..decrypt_size];
@@ -124,7 +154,7 @@ mod tests {
         let key = method.bytes_to_key(password.as_bytes());
         let server = "127.0.0.1:14188".to_socket_addrs().unwrap().next().unwrap();
         let data = b"GET / HTTP/1.1\r\n\r\n";
-        let addr = "127.0.0.1:443".parse().unwrap();
+        let addr: SocketAddr = "127.0.0.1:443".parse().unwrap();
         block_on(async {
             let key_clone = key.clone();
             let h = spawn(async move {
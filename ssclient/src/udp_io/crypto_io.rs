@@ -22,8 +22,8 @@
 
 use std::io::{Error, ErrorKind, Result};
 
-use bytes::{BufMut, BytesMut};
-use crypto::{CipherCategory, CipherType, CryptoMode};
+use bytes::{BufMut, Bytes, BytesMut};
+use crypto::{CipherCategory, CipherType, CryptoMode, Random, ThreadRandom};
 
 /// Encrypt payload into ShadowSocks UDP encrypted packet
 pub fn encrypt_payload(
@@ -31,10 +31,37 @@ pub fn encrypt_payload(
     key: &[u8],
     payload: &[u8],
     output: &mut BytesMut,
+) -> Result<usize> {
+    encrypt_payload_with_random(t, key, payload, output, &ThreadRandom)
+}
+
+/// Like `encrypt_payload`, but draws the IV or salt from `random`
+pub fn encrypt_payload_with_random(
+    t: CipherType,
+    key: &[u8],
+    payload: &[u8],
+    output: &mut BytesMut,
+    random: &dyn Random,
+) -> Result<usize> {
+    encrypt_payload_with_salt_size(t, key, payload, output, random, None)
+}
+
+/// Like `encrypt_payload_with_random`, with AEAD salts of `salt_size` bytes instead of
+/// the key size
+pub fn encrypt_payload_with_salt_size(
+    t: CipherType,
+    key: &[u8],
+    payload: &[u8],
+    output: &mut BytesMut,
+    random: &dyn Random,
+    salt_size: Option<usize>,
 ) -> Result<usize> {
     match t.category() {
-        CipherCategory::Stream => encrypt_payload_stream(t, key, payload, output),
-        CipherCategory::Aead => encrypt_payload_aead(t, key, payload, output),
+        CipherCategory::Stream => encrypt_payload_stream(t, key, payload, output, random),
+        CipherCategory::Aead => {
+            let salt = gen_salt(t, salt_size, random);
+            encrypt_payload_aead(t, key, payload, output, &salt)
+        }
     }
 }
 
@@ -44,10 +71,24 @@ pub fn decrypt_payload(
     key: &[u8],
     payload: &[u8],
     output: &mut BytesMut,
+) -> Result<usize> {
+    decrypt_payload_with_salt_size(t, key, payload, output, None)
+}
+
+/// Like `decrypt_payload`, with AEAD salts of `salt_size` bytes instead of the key size
+pub fn decrypt_payload_with_salt_size(
+    t: CipherType,
+    key: &[u8],
+    payload: &[u8],
+    output: &mut BytesMut,
+    salt_size: Option<usize>,
 ) -> Result<usize> {
     match t.category() {
         CipherCategory::Stream => decrypt_payload_stream(t, key, payload, output),
-        CipherCategory::Aead => decrypt_payload_aead(t, key, payload, output),
+        CipherCategory::Aead => {
+            let salt_size = salt_size.unwrap_or_else(|| t.salt_size());
+            decrypt_payload_aead(t, key, payload, output, salt_size)
+        }
     }
 }
 
@@ -57,13 +98,13 @@ fn encrypt_payload_aead(
     key: &[u8],
     payload: &[u8],
     output: &mut BytesMut,
+    salt: &[u8],
 ) -> Result<usize> {
-    let salt = t.gen_salt();
     let tag_size = t.tag_size();
     let mut cipher = crypto::new_aead_encryptor(t, key, &salt);
 
     let salt_len = salt.len();
-    output.put_slice(&salt);
+    output.put_slice(salt);
     output.resize(salt_len + payload.len() + tag_size, 0);
 
     cipher.encrypt(
@@ -79,9 +120,9 @@ fn decrypt_payload_aead(
     key: &[u8],
     payload: &[u8],
     output: &mut BytesMut,
+    salt_size: usize,
 ) -> Result<usize> {
     let tag_size = t.tag_size();
-    let salt_size = t.salt_size();
 
     if payload.len() < tag_size + salt_size {
         let err = Error::new(ErrorKind::UnexpectedEof, "udp packet too short");
@@ -100,13 +141,26 @@ fn decrypt_payload_aead(
     Ok(data_length)
 }
 
+/// The salt of an AEAD cipher, `salt_size` bytes or the key size.
+pub(crate) fn gen_salt(t: CipherType, salt_size: Option<usize>, random: &dyn Random) -> Bytes {
+    match salt_size {
+        Some(size) => {
+            let mut salt = vec![0; size];
+            random.fill_bytes(&mut salt);
+            Bytes::from(salt)
+        }
+        None => t.gen_salt_with(random),
+    }
+}
+
 fn encrypt_payload_stream(
     t: CipherType,
     key: &[u8],
     payload: &[u8],
     output: &mut BytesMut,
+    random: &dyn Random,
 ) -> Result<usize> {
-    let iv = t.gen_init_vec();
+    let iv = t.gen_init_vec_with(random);
     let mut cipher = crypto::new_stream(t, key, &iv, CryptoMode::Encrypt);
 
     output.put_slice(&iv);
@@ -146,11 +200,38 @@ mod tests {
         let payload = b"payload";
         let mut output = BytesMut::with_capacity(MAXIMUM_UDP_PAYLOAD_SIZE);
         let mut output2 = BytesMut::with_capacity(MAXIMUM_UDP_PAYLOAD_SIZE);
-        let size = encrypt_payload_aead(cipher_type, &key, payload, &mut output).unwrap();
-        let size2 = decrypt_payload_aead(cipher_type, &key, &output[..size], &mut output2).unwrap();
+        let salt = cipher_type.gen_salt();
+        let size = encrypt_payload_aead(cipher_type, &key, payload, &mut output, &salt).unwrap();
+        let size2 =
+            decrypt_payload_aead(cipher_type, &key, &output[..size], &mut output2, salt.len())
+                .unwrap();
         assert_eq!(&output2[..size2], payload);
     }
 
+    #[test]
+    fn test_payload_salt_size() {
+        let cipher_type = CipherType::ChaCha20IetfPoly1305;
+        let key = cipher_type.bytes_to_key(b"key");
+        let mut output = BytesMut::with_capacity(MAXIMUM_UDP_PAYLOAD_SIZE);
+        let mut output2 = BytesMut::with_capacity(MAXIMUM_UDP_PAYLOAD_SIZE);
+        let size = encrypt_payload_with_salt_size(
+            cipher_type,
+            &key,
+            b"payload",
+            &mut output,
+            &ThreadRandom,
+            Some(16),
+        )
+        .unwrap();
+        assert_eq!(size, 16 + 7 + cipher_type.tag_size());
+        let size2 =
+            decrypt_payload_with_salt_size(cipher_type, &key, &output, &mut output2, Some(16))
+                .unwrap();
+        assert_eq!(&output2[..size2], b"payload");
+        let mut output3 = BytesMut::with_capacity(MAXIMUM_UDP_PAYLOAD_SIZE);
+        assert!(decrypt_payload(cipher_type, &key, &output, &mut output3).is_err());
+    }
+
     #[test]
     fn test_encrypt_and_decrypt_payload_stream() {
         let cipher_type = CipherType::ChaCha20Ietf;
@@ -158,7 +239,8 @@ mod tests {
         let payload = b"payload";
         let mut output = BytesMut::with_capacity(MAXIMUM_UDP_PAYLOAD_SIZE);
         let mut output2 = BytesMut::with_capacity(MAXIMUM_UDP_PAYLOAD_SIZE);
-        let size = encrypt_payload_stream(cipher_type, &key, payload, &mut output).unwrap();
+        let size =
+            encrypt_payload_stream(cipher_type, &key, payload, &mut output, &ThreadRandom).unwrap();
         let size2 =
             decrypt_payload_stream(cipher_type, &key, &output[..size], &mut output2).unwrap();
         assert_eq!(
@@ -166,4 +248,19 @@ mod tests {
             std::str::from_utf8(payload)
         );
     }
+
+    #[test]
+    fn test_seeded_payload_is_reproducible() {
+        let cipher_type = CipherType::ChaCha20IetfPoly1305;
+        let key = cipher_type.bytes_to_key(b"key");
+        let encrypt = |seed| {
+            let mut output = BytesMut::with_capacity(MAXIMUM_UDP_PAYLOAD_SIZE);
+            let random = crypto::SeededRandom::new(seed);
+            encrypt_payload_with_random(cipher_type, &key, b"payload", &mut output, &random)
+                .unwrap();
+            output
+        };
+        assert_eq!(encrypt(1), encrypt(1));
+        assert_ne!(encrypt(1), encrypt(2));
+    }
 }
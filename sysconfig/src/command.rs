@@ -1,23 +1,28 @@
+use std::io;
 use std::process::Command;
 use tracing::debug;
 
+#[cfg(unix)]
 pub fn run_cmd(cmd: &str, args: &[&str]) -> String {
-    let output = Command::new(cmd)
-        .args(args)
-        .output()
-        .expect("run cmd failed");
+    try_run_cmd(cmd, args).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// The stdout of `cmd`, or an error with its stderr when it fails.
+pub fn try_run_cmd(cmd: &str, args: &[&str]) -> io::Result<String> {
+    let output = Command::new(cmd).args(args).output()?;
     debug!("{} {:?}", cmd, args);
 
     if !output.status.success() {
-        panic!(
-            "{} {}\nstdout: {}\nstderr: {}",
-            cmd,
-            args.join(" "),
-            std::str::from_utf8(&output.stdout).expect("utf8"),
-            std::str::from_utf8(&output.stderr).expect("utf8")
-        );
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "{} {}\nstdout: {}\nstderr: {}",
+                cmd,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        ));
     }
-    std::str::from_utf8(&output.stdout)
-        .expect("utf8")
-        .to_string()
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
 }
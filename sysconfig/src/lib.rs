@@ -1,12 +1,35 @@
 mod command;
 mod net;
-#[cfg(target_arch = "x86_64")]
+#[cfg(target_os = "linux")]
+mod privileges;
 mod proc;
+#[cfg(unix)]
 mod ulimit;
 
-pub use net::{setup_ip, DNSSetup, IpForward};
-#[cfg(target_arch = "x86_64")]
-pub use proc::sys::{list_system_proc_socks, list_user_proc_socks};
-#[cfg(target_arch = "x86_64")]
-pub use proc::SocketInfo;
+#[cfg(windows)]
+pub use net::get_mtu;
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd"))]
+pub use net::BypassRule;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub use net::{default_interface, set_outbound_interface};
+pub use net::{
+    mark_socket, marked_tcp_connect, marked_udp_socket, reset_on_close, set_tcp_buffer_size,
+    set_tcp_buffers, set_tcp_keepalive, AsSocket, RawSocket,
+};
+#[cfg(unix)]
+pub use net::{network_state, NetworkMonitor};
+#[cfg(target_os = "linux")]
+pub use net::{
+    original_dst, resolved_manages_dns, tproxy_tcp_listener, ResolvedDNS, TproxyRoute,
+    SEEKER_FWMARK, TPROXY_FWMARK,
+};
+#[cfg(unix)]
+pub use net::{probe_path_mtu, IpForward};
+pub use net::{set_mtu, setup_ip, setup_ip6, AutoRoute, DNSSetup, KillSwitchFirewall};
+#[cfg(target_os = "linux")]
+pub use privileges::drop_privileges;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub use proc::sys::{find_socket_process, list_system_proc_socks, list_user_proc_socks};
+pub use proc::{ProcessInfo, SocketInfo};
+#[cfg(unix)]
 pub use ulimit::{get_rlimit_no_file, set_rlimit_no_file};
@@ -0,0 +1,121 @@
+use crate::command::try_run_cmd;
+use std::io;
+use std::process::Command;
+use tracing::{info, warn};
+
+/// Together they cover the whole address space, and win over the default route without
+/// replacing it.
+const TUN_ROUTES: &[&str] = &["0.0.0.0/1", "128.0.0.0/1"];
+const TUN_ROUTES6: &[&str] = &["::/1", "8000::/1"];
+
+/// Routes sending traffic into the tun, removed again on drop, also when installing them
+/// fails. Tun routes a crash left behind are replaced when they are installed.
+///
+/// The `excluded` networks, CIDRs of either family, keep going through the gateway of the
+/// default route. Routes the user already had for them are left alone.
+pub struct AutoRoute {
+    tun_name: String,
+    ipv6: bool,
+    /// Networks with the gateway seeker routed them to.
+    excluded: Vec<(String, String)>,
+}
+
+fn route(args: &[&str]) -> bool {
+    Command::new("route")
+        .arg("-n")
+        .args(args)
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false)
+}
+
+/// The gateway of the default route of the family, before the tun routes are added.
+pub(super) fn default_gateway(inet6: bool) -> Option<String> {
+    let mut args = vec!["-n", "get"];
+    if inet6 {
+        args.push("-inet6");
+    }
+    args.push("default");
+    let output = Command::new("route").args(&args).output().ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find(|l| l.trim_start().starts_with("gateway:"))
+        .and_then(|l| l.split_whitespace().last())
+        .map(|s| s.to_string())
+}
+
+fn family(net: &str) -> &'static str {
+    if net.contains(':') {
+        "-inet6"
+    } else {
+        "-inet"
+    }
+}
+
+impl AutoRoute {
+    /// Route all traffic into the tun, IPv6 too when the tun has an IPv6 address.
+    pub fn tun(tun_name: &str, ipv6: bool, excluded: &[String]) -> io::Result<Self> {
+        info!("Install routes to {}", tun_name);
+        let gateway = default_gateway(false);
+        let gateway6 = default_gateway(true);
+        let mut auto_route = AutoRoute {
+            tun_name: tun_name.to_string(),
+            ipv6,
+            excluded: vec![],
+        };
+        for cidr in excluded {
+            let gateway = if cidr.contains(':') {
+                &gateway6
+            } else {
+                &gateway
+            };
+            match gateway {
+                // Fails when the route exists, which then stays as the user set it up.
+                Some(gateway) => {
+                    if route(&["add", family(cidr), "-net", cidr, gateway]) {
+                        auto_route.excluded.push((cidr.clone(), gateway.clone()));
+                    } else {
+                        warn!(%cidr, "can not exclude network from the tun");
+                    }
+                }
+                None => warn!(%cidr, "no default gateway to exclude network from the tun"),
+            }
+        }
+        let tun_routes = TUN_ROUTES.iter().chain(TUN_ROUTES6.iter().filter(|_| ipv6));
+        for net in tun_routes {
+            let _ = route(&["delete", family(net), "-net", net]);
+            let args = [
+                "-n",
+                "add",
+                family(net),
+                "-net",
+                net,
+                "-interface",
+                tun_name,
+            ];
+            try_run_cmd("route", &args)?;
+        }
+        Ok(auto_route)
+    }
+}
+
+impl Drop for AutoRoute {
+    fn drop(&mut self) {
+        info!("Remove routes to {}", self.tun_name);
+        let ipv6 = self.ipv6;
+        let tun_routes = TUN_ROUTES.iter().chain(TUN_ROUTES6.iter().filter(|_| ipv6));
+        for net in tun_routes {
+            let _ = route(&[
+                "delete",
+                family(net),
+                "-net",
+                net,
+                "-interface",
+                &self.tun_name,
+            ]);
+        }
+        for (cidr, gateway) in &self.excluded {
+            let _ = route(&["delete", family(cidr), "-net", cidr, gateway]);
+        }
+    }
+}
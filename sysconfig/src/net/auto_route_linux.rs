@@ -0,0 +1,188 @@
+use super::firewall::SEEKER_FWMARK;
+use super::mark::ip_rule;
+use super::tproxy::TPROXY_FWMARK;
+use crate::command::try_run_cmd;
+use std::io;
+use tracing::{info, warn};
+
+const ROUTE_TABLE: &str = "1310";
+/// Before the rule sending everything to `ROUTE_TABLE`, after the `BypassRule` of marked
+/// sockets.
+const EXCLUDE_RULE_PREF: &str = "1310";
+const ROUTE_RULE_PREF: &str = "1311";
+const NFT_TABLE: &str = "seeker_auto_route";
+
+/// Routes and firewall rules sending traffic to seeker, removed again on drop, also when
+/// installing them fails. Whatever a crash left behind is removed before they are installed.
+///
+/// Traffic to the `excluded` networks, CIDRs of either family, and of seeker's own marked
+/// sockets is left alone.
+pub struct AutoRoute {
+    nft: bool,
+}
+
+fn family(cidr: &str) -> &'static str {
+    if cidr.contains(':') {
+        "-6"
+    } else {
+        "-4"
+    }
+}
+
+fn remove_routes() {
+    for &family in &["-4", "-6"] {
+        while ip_rule(&[family, "rule", "del", "pref", EXCLUDE_RULE_PREF]) {}
+        let _ = ip_rule(&[family, "rule", "del", "pref", ROUTE_RULE_PREF]);
+        let _ = ip_rule(&[family, "route", "flush", "table", ROUTE_TABLE]);
+    }
+}
+
+fn remove_nft() {
+    let _ = std::process::Command::new("nft")
+        .args(&["delete", "table", "inet", NFT_TABLE])
+        .output();
+}
+
+fn nft(args: &[&str]) -> io::Result<()> {
+    try_run_cmd("nft", args)?;
+    Ok(())
+}
+
+fn nft_rule(chain: &str, rule: &[&str]) -> io::Result<()> {
+    let mut args = vec!["add", "rule", "inet", NFT_TABLE, chain];
+    args.extend(rule);
+    nft(&args)
+}
+
+/// `return` for the `excluded` networks, the first rules of `chain`.
+fn nft_exclude(chain: &str, excluded: &[String]) -> io::Result<()> {
+    for cidr in excluded {
+        let family = if cidr.contains(':') { "ip6" } else { "ip" };
+        nft_rule(chain, &[family, "daddr", cidr, "return"])?;
+    }
+    Ok(())
+}
+
+impl AutoRoute {
+    /// Route all traffic into the tun, IPv6 too when the tun has an IPv6 address.
+    pub fn tun(tun_name: &str, ipv6: bool, excluded: &[String]) -> io::Result<Self> {
+        info!("Install routes to {}", tun_name);
+        remove_routes();
+        let auto_route = AutoRoute { nft: false };
+        for cidr in excluded {
+            let args = [
+                family(cidr),
+                "rule",
+                "add",
+                "to",
+                cidr,
+                "lookup",
+                "main",
+                "pref",
+                EXCLUDE_RULE_PREF,
+            ];
+            if !ip_rule(&args) {
+                warn!(%cidr, "can not exclude network from the tun");
+            }
+        }
+        let families: &[&str] = if ipv6 { &["-4", "-6"] } else { &["-4"] };
+        for &family in families {
+            let route = [
+                family,
+                "route",
+                "add",
+                "default",
+                "dev",
+                tun_name,
+                "table",
+                ROUTE_TABLE,
+            ];
+            let rule = [
+                family,
+                "rule",
+                "add",
+                "lookup",
+                ROUTE_TABLE,
+                "pref",
+                ROUTE_RULE_PREF,
+            ];
+            try_run_cmd("ip", &route)?;
+            try_run_cmd("ip", &rule)?;
+        }
+        Ok(auto_route)
+    }
+
+    /// nftables rules handing TCP connections passing through to the TPROXY listener on
+    /// `port`, see `TproxyRoute`.
+    pub fn tproxy(port: u16, excluded: &[String]) -> io::Result<Self> {
+        info!("Install tproxy firewall rules");
+        remove_nft();
+        nft(&["add", "table", "inet", NFT_TABLE])?;
+        let auto_route = AutoRoute { nft: true };
+        nft(&[
+            "add",
+            "chain",
+            "inet",
+            NFT_TABLE,
+            "prerouting",
+            "{",
+            "type",
+            "filter",
+            "hook",
+            "prerouting",
+            "priority",
+            "mangle",
+            ";",
+            "policy",
+            "accept",
+            ";",
+            "}",
+        ])?;
+        nft_exclude("prerouting", excluded)?;
+        let to = format!(":{}", port);
+        let mark = TPROXY_FWMARK.to_string();
+        for &(nfproto, family) in &[("ipv4", "ip"), ("ipv6", "ip6")] {
+            nft_rule(
+                "prerouting",
+                &[
+                    "meta", "nfproto", nfproto, "meta", "l4proto", "tcp", "tproxy", family, "to",
+                    &to, "meta", "mark", "set", &mark, "accept",
+                ],
+            )?;
+        }
+        Ok(auto_route)
+    }
+
+    /// nftables rules redirecting TCP connections of this host and those passing through
+    /// to the listener on `port`.
+    pub fn redirect(port: u16, excluded: &[String]) -> io::Result<Self> {
+        info!("Install redirect firewall rules");
+        remove_nft();
+        nft(&["add", "table", "inet", NFT_TABLE])?;
+        let auto_route = AutoRoute { nft: true };
+        let mark = SEEKER_FWMARK.to_string();
+        let to = format!(":{}", port);
+        for &(chain, hook) in &[("prerouting", "prerouting"), ("output", "output")] {
+            nft(&[
+                "add", "chain", "inet", NFT_TABLE, chain, "{", "type", "nat", "hook", hook,
+                "priority", "-100", ";", "policy", "accept", ";", "}",
+            ])?;
+            nft_rule(chain, &["meta", "mark", &mark, "return"])?;
+            nft_exclude(chain, excluded)?;
+            nft_rule(chain, &["meta", "l4proto", "tcp", "redirect", "to", &to])?;
+        }
+        Ok(auto_route)
+    }
+}
+
+impl Drop for AutoRoute {
+    fn drop(&mut self) {
+        if self.nft {
+            info!("Remove auto route firewall rules");
+            remove_nft();
+        } else {
+            info!("Remove auto routes");
+            remove_routes();
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use super::sys::default_route;
+use crate::command::try_run_cmd;
+use std::io;
+use tracing::{info, warn};
+
+/// Together they cover the whole address space, and win over the default route without
+/// replacing it.
+const TUN_ROUTES: &[&str] = &["0.0.0.0/1", "128.0.0.0/1"];
+const TUN_ROUTES6: &[&str] = &["::/1", "8000::/1"];
+
+/// Routes sending traffic into the adapter, removed again on drop, also when installing them
+/// fails. They are only added to the active store, so a reboot clears routes a crash left
+/// behind.
+///
+/// The `excluded` networks, CIDRs of either family, keep going through the default route.
+/// Routes the user already had for them are left alone.
+pub struct AutoRoute {
+    tun_name: String,
+    ipv6: bool,
+    /// Networks with the interface and next hop seeker routed them to.
+    excluded: Vec<(String, String, String)>,
+}
+
+fn family(net: &str) -> &'static str {
+    if net.contains(':') {
+        "ipv6"
+    } else {
+        "ipv4"
+    }
+}
+
+fn route(command: &str, net: &str, interface: &str, next_hop: Option<&str>) -> io::Result<()> {
+    let mut args = vec!["interface", family(net), command, "route", net, interface];
+    args.extend(next_hop);
+    args.push("store=active");
+    try_run_cmd("netsh", &args)?;
+    Ok(())
+}
+
+impl AutoRoute {
+    /// Route all traffic into the adapter, IPv6 too when it has an IPv6 address.
+    pub fn tun(tun_name: &str, ipv6: bool, excluded: &[String]) -> io::Result<Self> {
+        info!("Install routes to {}", tun_name);
+        let default = default_route(false);
+        let default6 = default_route(true);
+        let mut auto_route = AutoRoute {
+            tun_name: tun_name.to_string(),
+            ipv6,
+            excluded: vec![],
+        };
+        for cidr in excluded {
+            let default = if cidr.contains(':') {
+                &default6
+            } else {
+                &default
+            };
+            match default {
+                // Fails when the route exists, which then stays as the user set it up.
+                Some((interface, next_hop)) => {
+                    if route("add", cidr, interface, Some(next_hop)).is_ok() {
+                        let excluded = (cidr.clone(), interface.clone(), next_hop.clone());
+                        auto_route.excluded.push(excluded);
+                    } else {
+                        warn!(%cidr, "can not exclude network from the tun");
+                    }
+                }
+                None => warn!(%cidr, "no default route to exclude network from the tun"),
+            }
+        }
+        let tun_routes = TUN_ROUTES.iter().chain(TUN_ROUTES6.iter().filter(|_| ipv6));
+        for net in tun_routes {
+            let _ = route("delete", net, tun_name, None);
+            route("add", net, tun_name, None)?;
+        }
+        Ok(auto_route)
+    }
+}
+
+impl Drop for AutoRoute {
+    fn drop(&mut self) {
+        info!("Remove routes to {}", self.tun_name);
+        let ipv6 = self.ipv6;
+        let tun_routes = TUN_ROUTES.iter().chain(TUN_ROUTES6.iter().filter(|_| ipv6));
+        for net in tun_routes {
+            let _ = route("delete", net, &self.tun_name, None);
+        }
+        for (cidr, interface, next_hop) in &self.excluded {
+            let _ = route("delete", cidr, interface, Some(next_hop));
+        }
+    }
+}
@@ -0,0 +1,25 @@
+//! FreeBSD and OpenBSD, set up like macOS through `ifconfig` and `route`, with the DNS in
+//! `/etc/resolv.conf` like on Linux.
+use crate::command::try_run_cmd;
+use std::io;
+
+pub use super::resolv_conf::DNSSetup;
+
+/// tun devices are point to point, `ip` is used for both ends and `cidr` routed to it.
+pub fn setup_ip(tun_name: &str, ip: &str, cidr: &str) -> io::Result<()> {
+    try_run_cmd("ifconfig", &[tun_name, "inet", ip, ip, "up"])?;
+    try_run_cmd("route", &["add", "-net", cidr, "-interface", tun_name])?;
+    Ok(())
+}
+
+pub fn setup_ip6(tun_name: &str, ip: &str, cidr: &str) -> io::Result<()> {
+    let prefix = cidr.split('/').nth(1).unwrap_or("128");
+    try_run_cmd("ifconfig", &[tun_name, "inet6", ip, "prefixlen", prefix])?;
+    try_run_cmd("route", &["add", "-inet6", cidr, "-interface", tun_name])?;
+    Ok(())
+}
+
+pub fn set_mtu(tun_name: &str, mtu: u32) -> io::Result<()> {
+    try_run_cmd("ifconfig", &[tun_name, "mtu", &mtu.to_string()])?;
+    Ok(())
+}
@@ -1,7 +1,17 @@
-use crate::command::run_cmd;
+use crate::command::{run_cmd, try_run_cmd};
+use std::io;
 use std::net::IpAddr;
+use std::process::Command;
 use tracing::info;
 
+/// Have mDNSResponder drop answers cached from the DNS servers before the change.
+fn flush_dns_cache() {
+    let _ = Command::new("dscacheutil").arg("-flushcache").output();
+    let _ = Command::new("killall")
+        .args(&["-HUP", "mDNSResponder"])
+        .output();
+}
+
 pub struct DNSSetup {
     primary_network: String,
     original_dns: Vec<String>,
@@ -34,11 +44,17 @@ impl DNSSetup {
                 &["-setdnsservers", &network, "127.0.0.1", &dns],
             );
         }
+        flush_dns_cache();
         DNSSetup {
             primary_network: network,
             original_dns,
         }
     }
+
+    /// DNS servers configured before seeker took over.
+    pub fn original_dns(&self) -> &[String] {
+        &self.original_dns
+    }
 }
 
 impl Drop for DNSSetup {
@@ -54,22 +70,41 @@ impl Drop for DNSSetup {
         info!("Restore original DNS: {:?}", self.original_dns);
 
         let _ = run_cmd("networksetup", &args);
+        flush_dns_cache();
     }
 }
 
-pub fn setup_ip(tun_name: &str, ip: &str, cidr: &str) {
-    let _ = run_cmd("ifconfig", &[tun_name, ip, ip]);
-    let _ = run_cmd("route", &["add", cidr, ip]);
+pub fn setup_ip(tun_name: &str, ip: &str, cidr: &str) -> io::Result<()> {
+    try_run_cmd("ifconfig", &[tun_name, ip, ip])?;
+    try_run_cmd("route", &["add", cidr, ip])?;
+    Ok(())
 }
 
-fn get_primary_network() -> String {
+pub fn setup_ip6(tun_name: &str, ip: &str, cidr: &str) -> io::Result<()> {
+    let prefix = cidr.split('/').nth(1).unwrap_or("128");
+    try_run_cmd("ifconfig", &[tun_name, "inet6", ip, "prefixlen", prefix])?;
+    try_run_cmd("route", &["add", "-inet6", cidr, "-interface", tun_name])?;
+    Ok(())
+}
+
+pub fn set_mtu(tun_name: &str, mtu: u32) -> io::Result<()> {
+    try_run_cmd("ifconfig", &[tun_name, "mtu", &mtu.to_string()])?;
+    Ok(())
+}
+
+/// Name of the interface carrying the default route, e.g. `en0`, none while offline.
+pub fn default_interface() -> Option<String> {
     let route_ret = run_cmd("route", &["-n", "get", "0.0.0.0"]);
-    let device = route_ret
+    route_ret
         .lines()
         .find(|l| l.contains("interface:"))
         .and_then(|l| l.split_whitespace().last())
-        .map(|s| s.trim())
-        .expect("get primary device");
+        .map(|s| s.trim().to_string())
+}
+
+fn get_primary_network() -> String {
+    let device = default_interface().expect("get primary device");
+    let device = device.as_str();
     info!("Primary device is {}", device);
     let network_services = run_cmd("networksetup", &["-listallhardwareports"]);
     let mut iter = network_services.lines().peekable();
@@ -0,0 +1,149 @@
+use crate::command::try_run_cmd;
+use std::io;
+use std::net::IpAddr;
+use std::process::Command;
+use tracing::{info, warn};
+
+/// The default /etc/pf.conf evaluates every anchor below `com.apple`.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const ANCHOR: &str = "com.apple/seeker";
+/// The anchor rule of the main ruleset evaluating `ANCHOR`.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const ANCHOR_RULE: &str = "com.apple/*";
+/// The BSDs have no anchors by default, pf.conf needs an `anchor "seeker"` line.
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+const ANCHOR: &str = "seeker";
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+const ANCHOR_RULE: &str = "seeker";
+
+/// Blocks every egress packet except those through the tun, to loopback or to `allowed`
+/// addresses.
+///
+/// The anchor outlives seeker when it is killed, until it is flushed on drop or pf is
+/// reloaded.
+pub struct KillSwitchFirewall {
+    token: Option<String>,
+}
+
+fn pfctl(args: &[&str]) -> io::Result<String> {
+    try_run_cmd("pfctl", args)
+}
+
+/// Whether the main ruleset `rules`, as printed by `pfctl -sr`, evaluates `ANCHOR`.
+fn evaluates_anchor(rules: &str) -> bool {
+    let anchor = format!("anchor \"{}\"", ANCHOR_RULE);
+    rules.lines().any(|l| l.trim_start().starts_with(&anchor))
+}
+
+impl KillSwitchFirewall {
+    pub fn new(tun_name: &str, allowed: &[IpAddr]) -> io::Result<Self> {
+        info!("Install kill switch firewall rules");
+        let main_rules = pfctl(&["-sr"])?;
+        // pf has not been set up since boot, its default rules evaluate the anchor.
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        let main_rules = if main_rules.trim().is_empty() {
+            pfctl(&["-f", "/etc/pf.conf"])?;
+            pfctl(&["-sr"])?
+        } else {
+            main_rules
+        };
+        if !evaluates_anchor(&main_rules) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "pf.conf has no `anchor \"{}\"` rule, pf would ignore the kill switch",
+                    ANCHOR_RULE
+                ),
+            ));
+        }
+        let path = std::env::temp_dir().join("seeker_kill_switch.pf.conf");
+        std::fs::write(&path, generate_rules(tun_name, allowed))?;
+        let path = path
+            .to_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "temp dir is not utf-8"))?;
+        pfctl(&["-a", ANCHOR, "-f", path])?;
+        // Dropped from here on, the anchor is flushed again if pf can not be enabled.
+        let mut firewall = KillSwitchFirewall { token: None };
+        firewall.token = enable_pf()?;
+        let loaded = pfctl(&["-a", ANCHOR, "-sr"])?;
+        if !loaded.contains("block drop out all") {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("kill switch rules missing from anchor {}", ANCHOR),
+            ));
+        }
+        Ok(firewall)
+    }
+}
+
+impl Drop for KillSwitchFirewall {
+    fn drop(&mut self) {
+        info!("Remove kill switch firewall rules");
+        if let Err(e) = pfctl(&["-a", ANCHOR, "-F", "all"]) {
+            warn!(?e, "remove kill switch firewall rules");
+        }
+        if let Some(token) = &self.token {
+            let _ = pfctl(&["-X", token]);
+        }
+    }
+}
+
+/// pfctl prints the reference token of `-E` to stderr.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn enable_pf() -> io::Result<Option<String>> {
+    let output = Command::new("pfctl").arg("-E").output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("pfctl -E: {}", stderr.trim()),
+        ));
+    }
+    Ok(stderr
+        .lines()
+        .find(|l| l.starts_with("Token"))
+        .and_then(|l| l.split(':').last())
+        .map(|t| t.trim().to_string()))
+}
+
+/// Without reference counting pf stays enabled, `-e` fails when it already is, so whether
+/// it is enabled is read from `-si` afterwards.
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+fn enable_pf() -> io::Result<Option<String>> {
+    let _ = Command::new("pfctl").arg("-e").output();
+    let info = pfctl(&["-si"])?;
+    if !info.lines().any(|l| l.starts_with("Status: Enabled")) {
+        return Err(io::Error::new(io::ErrorKind::Other, "can not enable pf"));
+    }
+    Ok(None)
+}
+
+fn generate_rules(tun_name: &str, allowed: &[IpAddr]) -> String {
+    let mut rules = String::new();
+    rules.push_str("pass out quick on lo0 all\n");
+    rules.push_str(&format!("pass out quick on {} all\n", tun_name));
+    for ip in allowed {
+        rules.push_str(&format!("pass out quick to {}\n", ip));
+    }
+    rules.push_str("block drop out all\n");
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluates_anchor() {
+        let rules = format!(
+            "scrub-anchor \"{0}\" all fragment reassemble\nanchor \"{0}\" all\n",
+            ANCHOR_RULE
+        );
+        assert!(evaluates_anchor(&rules));
+        assert!(!evaluates_anchor("pass out all flags S/SA keep state\n"));
+        assert!(!evaluates_anchor(&format!(
+            "scrub-anchor \"{}\" all\n",
+            ANCHOR_RULE
+        )));
+    }
+}
@@ -0,0 +1,63 @@
+use crate::command::try_run_cmd;
+use std::io;
+use std::net::IpAddr;
+use tracing::{info, warn};
+
+const TABLE: &str = "seeker_kill_switch";
+/// Firewall mark carried by sockets created by seeker itself.
+pub const SEEKER_FWMARK: u32 = 0x1300;
+
+/// Blocks every egress packet except those through the tun, to loopback, to `allowed`
+/// addresses or marked with `SEEKER_FWMARK`. Connections opened before seeker started are
+/// blocked too, unless they go through the tun or to `allowed` addresses.
+///
+/// nftables keeps the table when seeker is killed, it is only removed on drop or on reboot.
+pub struct KillSwitchFirewall;
+
+fn nft(args: &[&str]) -> io::Result<()> {
+    try_run_cmd("nft", args)?;
+    Ok(())
+}
+
+impl KillSwitchFirewall {
+    pub fn new(tun_name: &str, allowed: &[IpAddr]) -> io::Result<Self> {
+        info!("Install kill switch firewall rules");
+        // Remove rules left by a previous crash before installing new ones.
+        let _ = std::process::Command::new("nft")
+            .args(&["delete", "table", "inet", TABLE])
+            .output();
+        nft(&["add", "table", "inet", TABLE])?;
+        // Dropped from here on, the table goes away again if a rule fails.
+        let firewall = KillSwitchFirewall;
+        nft(&[
+            "add", "chain", "inet", TABLE, "output", "{", "type", "filter", "hook", "output",
+            "priority", "0", ";", "policy", "drop", ";", "}",
+        ])?;
+        let mark = SEEKER_FWMARK.to_string();
+        let mut rules = vec![
+            vec!["oifname", "lo", "accept"],
+            vec!["oifname", tun_name, "accept"],
+            vec!["meta", "mark", &mark, "accept"],
+        ];
+        let addrs = allowed.iter().map(|ip| ip.to_string()).collect::<Vec<_>>();
+        for (ip, addr) in allowed.iter().zip(&addrs) {
+            let family = if ip.is_ipv4() { "ip" } else { "ip6" };
+            rules.push(vec![family, "daddr", addr, "accept"]);
+        }
+        for rule in rules {
+            let mut args = vec!["add", "rule", "inet", TABLE, "output"];
+            args.extend(rule);
+            nft(&args)?;
+        }
+        Ok(firewall)
+    }
+}
+
+impl Drop for KillSwitchFirewall {
+    fn drop(&mut self) {
+        info!("Remove kill switch firewall rules");
+        if let Err(e) = nft(&["delete", "table", "inet", TABLE]) {
+            warn!(?e, "remove kill switch firewall rules");
+        }
+    }
+}
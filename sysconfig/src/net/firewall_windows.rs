@@ -0,0 +1,130 @@
+use super::sys::{powershell, quote};
+use std::io;
+use std::net::IpAddr;
+use tracing::{info, warn};
+
+/// Group of the allow rules, by which those a crash left behind are found again.
+const GROUP: &str = "seeker kill switch";
+
+/// Blocks every outbound connection except those through the tun, of seeker itself or to
+/// `allowed` addresses, with the Windows Firewall. Loopback is always exempt from it.
+///
+/// The firewall keeps blocking when seeker is killed. The next start finds the rules by
+/// their group and puts the default back to allowing connections once it exits.
+pub struct KillSwitchFirewall {
+    /// Firewall profiles with whether they were enabled and their outbound default, e.g.
+    /// `("Domain", "NotConfigured", "NotConfigured")`.
+    profiles: Vec<(String, String, String)>,
+}
+
+fn parse_profiles(output: &str) -> Vec<(String, String, String)> {
+    output
+        .lines()
+        .filter_map(|l| {
+            let mut fields = l.trim().split('|');
+            Some((
+                fields.next()?.to_string(),
+                fields.next()?.to_string(),
+                fields.next()?.to_string(),
+            ))
+        })
+        .filter(|(name, _, _)| !name.is_empty())
+        .collect()
+}
+
+impl KillSwitchFirewall {
+    pub fn new(tun_name: &str, allowed: &[IpAddr]) -> io::Result<Self> {
+        info!("Install kill switch firewall rules");
+        let group = quote(GROUP);
+        let left_behind = powershell(&format!(
+            "@(Get-NetFirewallRule -Group {} -ErrorAction SilentlyContinue).Count",
+            group
+        ))?;
+        let mut profiles = parse_profiles(&powershell(
+            "Get-NetFirewallProfile | ForEach-Object { \
+             $_.Name + '|' + $_.Enabled + '|' + $_.DefaultOutboundAction }",
+        )?);
+        if left_behind.trim() != "0" {
+            warn!("remove kill switch firewall rules left by a previous run");
+            powershell(&format!("Remove-NetFirewallRule -Group {}", group))?;
+            // Blocking is what the previous run left, not how the profiles were before.
+            for (_, enabled, outbound) in &mut profiles {
+                *enabled = "NotConfigured".to_string();
+                *outbound = "NotConfigured".to_string();
+            }
+        }
+        // Dropped from here on, the profiles are put back if a rule fails.
+        let firewall = KillSwitchFirewall { profiles };
+
+        let exe = std::env::current_exe()?;
+        let exe = exe.to_str().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seeker path is not utf-8")
+        })?;
+        let mut rules = vec![
+            format!("-InterfaceAlias {}", quote(tun_name)),
+            format!("-Program {}", quote(exe)),
+        ];
+        if !allowed.is_empty() {
+            let addrs = allowed.iter().map(|ip| ip.to_string()).collect::<Vec<_>>();
+            rules.push(format!("-RemoteAddress {}", addrs.join(",")));
+        }
+        for rule in &rules {
+            powershell(&format!(
+                "New-NetFirewallRule -DisplayName {0} -Group {0} -Direction Outbound \
+                 -Action Allow {1} | Out-Null",
+                group, rule
+            ))?;
+        }
+        powershell("Set-NetFirewallProfile -All -Enabled True -DefaultOutboundAction Block")?;
+        Ok(firewall)
+    }
+}
+
+impl Drop for KillSwitchFirewall {
+    fn drop(&mut self) {
+        info!("Remove kill switch firewall rules");
+        for (name, enabled, outbound) in &self.profiles {
+            let restore = powershell(&format!(
+                "Set-NetFirewallProfile -Name {} -Enabled {} -DefaultOutboundAction {}",
+                quote(name),
+                enabled,
+                outbound
+            ));
+            if let Err(e) = restore {
+                warn!(?e, %name, "restore firewall profile");
+            }
+        }
+        let remove = powershell(&format!(
+            "Remove-NetFirewallRule -Group {} -ErrorAction SilentlyContinue",
+            quote(GROUP)
+        ));
+        if let Err(e) = remove {
+            warn!(?e, "remove kill switch firewall rules");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profiles() {
+        let profiles = parse_profiles("Domain|True|NotConfigured\r\nPublic|False|Block\r\n\r\n");
+        assert_eq!(
+            profiles,
+            vec![
+                (
+                    "Domain".to_string(),
+                    "True".to_string(),
+                    "NotConfigured".to_string()
+                ),
+                (
+                    "Public".to_string(),
+                    "False".to_string(),
+                    "Block".to_string()
+                ),
+            ]
+        );
+    }
+}
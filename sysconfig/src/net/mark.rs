@@ -0,0 +1,395 @@
+//! Sockets for seeker's own traffic, marked so it can be routed around the tun.
+//!
+//! On Linux they carry `SEEKER_FWMARK`, on macOS they are bound to the outbound interface.
+//! On the BSDs they use `BYPASS_TABLE`, the FIB or rtable `BypassRule` routes around the tun.
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Once;
+use std::time::Duration;
+use tracing::warn;
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+use super::auto_route::default_gateway;
+#[cfg(target_os = "linux")]
+use super::firewall::SEEKER_FWMARK;
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const IP_BOUND_IF: libc::c_int = 25;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const IPV6_BOUND_IF: libc::c_int = 125;
+
+/// The FIB on FreeBSD and the rtable on OpenBSD of seeker's own sockets.
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+const BYPASS_TABLE: u32 = 1;
+#[cfg(target_os = "freebsd")]
+const SO_SETFIB: libc::c_int = 0x1014;
+#[cfg(target_os = "openbsd")]
+const SO_RTABLE: libc::c_int = 0x1021;
+
+/// Index of the interface seeker's sockets are bound to, 0 when unset.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+static OUTBOUND_INTERFACE: AtomicU32 = AtomicU32::new(0);
+
+static MARK_WARNING: Once = Once::new();
+
+/// Send and receive buffer of seeker's TCP connections, 0 leaves them to the kernel.
+static TCP_BUFFER_SIZE: AtomicU32 = AtomicU32::new(0);
+
+pub(super) fn setsockopt(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: u32,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const u32 as *const libc::c_void,
+            mem::size_of::<u32>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Bind seeker's sockets to `name`, the interface of the default route.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub fn set_outbound_interface(name: &str) -> io::Result<()> {
+    let c_name = std::ffi::CString::new(name)?;
+    let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    OUTBOUND_INTERFACE.store(index, Ordering::SeqCst);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn mark_socket(fd: RawFd, _ipv6: bool) -> io::Result<()> {
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_MARK, SEEKER_FWMARK)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub fn mark_socket(fd: RawFd, ipv6: bool) -> io::Result<()> {
+    let index = OUTBOUND_INTERFACE.load(Ordering::SeqCst);
+    if index == 0 {
+        return Ok(());
+    }
+    if ipv6 {
+        setsockopt(fd, libc::IPPROTO_IPV6, IPV6_BOUND_IF, index)
+    } else {
+        setsockopt(fd, libc::IPPROTO_IP, IP_BOUND_IF, index)
+    }
+}
+
+/// Fails while the FIB does not exist, FreeBSD only has one unless `net.fibs` is raised.
+#[cfg(target_os = "freebsd")]
+pub fn mark_socket(fd: RawFd, _ipv6: bool) -> io::Result<()> {
+    setsockopt(fd, libc::SOL_SOCKET, SO_SETFIB, BYPASS_TABLE)
+}
+
+#[cfg(target_os = "openbsd")]
+pub fn mark_socket(fd: RawFd, _ipv6: bool) -> io::Result<()> {
+    setsockopt(fd, libc::SOL_SOCKET, SO_RTABLE, BYPASS_TABLE)
+}
+
+/// Send TCP keepalive probes after the connection was idle for `idle`, then every
+/// `interval`, and give up on it after `count` unanswered probes, the system's default
+/// number when `None`. NAT gateways keep the mapping of connections with keepalives.
+pub fn set_tcp_keepalive(
+    fd: RawFd,
+    idle: Duration,
+    interval: Duration,
+    count: Option<u32>,
+) -> io::Result<()> {
+    let secs = |d: Duration| d.as_secs().max(1) as u32;
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs(idle))?;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPALIVE, secs(idle))?;
+    // OpenBSD only has the system wide net.inet.tcp.keepidle and keepintvl.
+    #[cfg(not(target_os = "openbsd"))]
+    {
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, secs(interval))?;
+        if let Some(count) = count {
+            setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, count)?;
+        }
+    }
+    #[cfg(target_os = "openbsd")]
+    let _ = (secs, idle, interval, count);
+    Ok(())
+}
+
+/// Size the send and receive buffers of the TCP sockets `marked_tcp_connect` opens from
+/// now on, 0 leaves them to the kernel's autotuning.
+pub fn set_tcp_buffer_size(size: u32) {
+    TCP_BUFFER_SIZE.store(size, Ordering::SeqCst);
+}
+
+/// Set the send and receive buffers of `fd` to `size` bytes. The window scale is agreed on
+/// in the handshake, so this is to happen before connecting, or on the listening socket.
+pub fn set_tcp_buffers(fd: RawFd, size: u32) -> io::Result<()> {
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, size)?;
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, size)
+}
+
+/// Close `fd` with a RST instead of a FIN, by lingering for no time.
+pub fn reset_on_close(fd: RawFd) -> io::Result<()> {
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Marking needs privileges, without them sockets are used unmarked.
+fn try_mark_socket(fd: RawFd, ipv6: bool) {
+    if let Err(e) = mark_socket(fd, ipv6) {
+        MARK_WARNING.call_once(|| warn!(?e, "can not mark outbound sockets"));
+    }
+}
+
+pub(super) fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(a) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr = libc::in_addr {
+                s_addr: u32::from_ne_bytes(a.ip().octets()),
+            };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(a) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_scope_id = a.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// Start connecting a marked, non-blocking TCP socket to `addr`.
+///
+/// The connection is established once the socket becomes writable.
+pub fn marked_tcp_connect(addr: &SocketAddr) -> io::Result<TcpStream> {
+    let family = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owned from here on, so the fd is closed on errors.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    stream.set_nonblocking(true)?;
+    try_mark_socket(fd, addr.is_ipv6());
+    let buffer_size = TCP_BUFFER_SIZE.load(Ordering::SeqCst);
+    if buffer_size != 0 {
+        set_tcp_buffers(fd, buffer_size)?;
+    }
+    let (storage, len) = sockaddr(addr);
+    let ret = unsafe { libc::connect(fd, &storage as *const _ as *const libc::sockaddr, len) };
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+    Ok(stream)
+}
+
+/// A marked UDP socket bound to the wildcard address of the family of `peer`.
+pub fn marked_udp_socket(peer: &SocketAddr) -> io::Result<UdpSocket> {
+    let socket = if peer.is_ipv4() {
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?
+    } else {
+        UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?
+    };
+    try_mark_socket(socket.as_raw_fd(), peer.is_ipv6());
+    Ok(socket)
+}
+
+/// Policy routing rule sending marked packets through the main table, so they skip
+/// routes to the tun installed in other tables.
+#[cfg(target_os = "linux")]
+pub struct BypassRule;
+
+#[cfg(target_os = "linux")]
+const BYPASS_RULE_PREF: &str = "1300";
+
+#[cfg(target_os = "linux")]
+pub(super) fn ip_rule(args: &[&str]) -> bool {
+    std::process::Command::new("ip")
+        .args(args)
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false)
+}
+
+#[cfg(target_os = "linux")]
+impl BypassRule {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let mark = SEEKER_FWMARK.to_string();
+        for &family in &["-4", "-6"] {
+            // Remove a rule left by a previous crash first.
+            let _ = ip_rule(&[family, "rule", "del", "pref", BYPASS_RULE_PREF]);
+            let args = [
+                family,
+                "rule",
+                "add",
+                "fwmark",
+                mark.as_str(),
+                "lookup",
+                "main",
+                "pref",
+                BYPASS_RULE_PREF,
+            ];
+            if !ip_rule(&args) {
+                warn!(%family, "can not add routing rule for marked sockets");
+            }
+        }
+        BypassRule
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for BypassRule {
+    fn drop(&mut self) {
+        for &family in &["-4", "-6"] {
+            let _ = ip_rule(&[family, "rule", "del", "pref", BYPASS_RULE_PREF]);
+        }
+    }
+}
+
+/// The default routes of the main table copied to `BYPASS_TABLE`, so marked sockets skip the
+/// routes to the tun there. Set up before the tun routes, which hide the default route.
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+pub struct BypassRule {
+    families: Vec<&'static str>,
+}
+
+/// `route` on the default route of `family` in `BYPASS_TABLE`.
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+fn table_route(command: &str, family: &str, gateway: Option<&str>) -> bool {
+    let table = BYPASS_TABLE.to_string();
+    let mut args = vec!["-n"];
+    #[cfg(target_os = "openbsd")]
+    args.extend(&["-T", table.as_str()]);
+    args.push(command);
+    #[cfg(target_os = "freebsd")]
+    args.extend(&["-fib", table.as_str()]);
+    args.extend(&[family, "default"]);
+    args.extend(gateway);
+    std::process::Command::new("route")
+        .args(&args)
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false)
+}
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+impl BypassRule {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let mut families = vec![];
+        for &(family, inet6) in &[("-inet", false), ("-inet6", true)] {
+            let gateway = match default_gateway(inet6) {
+                Some(gateway) => gateway,
+                None => continue,
+            };
+            // Remove a route left by a previous crash first.
+            let _ = table_route("delete", family, None);
+            if table_route("add", family, Some(&gateway)) {
+                families.push(family);
+            } else {
+                warn!(
+                    %family,
+                    table = BYPASS_TABLE,
+                    "can not add default route for marked sockets"
+                );
+            }
+        }
+        BypassRule { families }
+    }
+}
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+impl Drop for BypassRule {
+    fn drop(&mut self) {
+        for family in &self.families {
+            let _ = table_route("delete", family, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_marked_sockets() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = marked_tcp_connect(&addr).unwrap();
+        let (accepted, peer) = listener.accept().unwrap();
+        stream.set_nonblocking(false).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        assert_eq!(accepted.peer_addr().unwrap(), peer);
+
+        let socket = marked_udp_socket(&"[::1]:53".parse().unwrap()).unwrap();
+        assert!(socket.local_addr().unwrap().is_ipv6());
+    }
+
+    #[test]
+    fn test_tcp_buffers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        set_tcp_buffers(listener.as_raw_fd(), 256 * 1024).unwrap();
+        let mut value: u32 = 0;
+        let mut len = mem::size_of::<u32>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                listener.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUF,
+                &mut value as *mut u32 as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        // Linux doubles the size for its bookkeeping.
+        assert!(value >= 256 * 1024);
+    }
+}
@@ -0,0 +1,132 @@
+//! Sockets for seeker's own traffic on Windows.
+//!
+//! Windows has no marks for sockets, seeker's own traffic stays off the tun through the
+//! routes `AutoRoute` leaves out for the servers and DNS servers seeker talks to directly.
+use super::RawSocket;
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::io;
+use std::mem::{self, ManuallyDrop};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::os::windows::io::FromRawSocket;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use winapi::shared::mstcpip::{tcp_keepalive, SIO_KEEPALIVE_VALS};
+use winapi::um::winsock2::{setsockopt, WSAIoctl, SOCKET_ERROR};
+
+/// `TCP_KEEPCNT`, from Windows 10 1703 on.
+const TCP_KEEPCNT: i32 = 16;
+const IPPROTO_TCP: i32 = 6;
+
+/// Send and receive buffer of seeker's TCP connections, 0 leaves them to the kernel.
+static TCP_BUFFER_SIZE: AtomicU32 = AtomicU32::new(0);
+
+/// `socket` as a socket2 socket, which leaves it open when dropped.
+fn borrowed(socket: RawSocket) -> ManuallyDrop<Socket> {
+    ManuallyDrop::new(unsafe { Socket::from_raw_socket(socket) })
+}
+
+/// Windows has no marks, the sockets are left as they are.
+pub fn mark_socket(_socket: RawSocket, _ipv6: bool) -> io::Result<()> {
+    Ok(())
+}
+
+/// Keepalive probes after `idle`, then every `interval`. Windows rounds both up to whole
+/// seconds as well, and takes `count` from Windows 10 1703 on, before it sends 10 probes.
+pub fn set_tcp_keepalive(
+    socket: RawSocket,
+    idle: Duration,
+    interval: Duration,
+    count: Option<u32>,
+) -> io::Result<()> {
+    let millis = |d: Duration| d.as_millis().max(1000) as u32;
+    let mut values = tcp_keepalive {
+        onoff: 1,
+        keepalivetime: millis(idle),
+        keepaliveinterval: millis(interval),
+    };
+    let mut returned: u32 = 0;
+    let ret = unsafe {
+        WSAIoctl(
+            socket as _,
+            SIO_KEEPALIVE_VALS,
+            &mut values as *mut tcp_keepalive as *mut _,
+            mem::size_of::<tcp_keepalive>() as u32,
+            ptr::null_mut(),
+            0,
+            &mut returned,
+            ptr::null_mut(),
+            None,
+        )
+    };
+    if ret == SOCKET_ERROR {
+        return Err(io::Error::last_os_error());
+    }
+    if let Some(count) = count {
+        let ret = unsafe {
+            setsockopt(
+                socket as _,
+                IPPROTO_TCP,
+                TCP_KEEPCNT,
+                &count as *const u32 as *const _,
+                mem::size_of::<u32>() as i32,
+            )
+        };
+        if ret == SOCKET_ERROR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Size the buffers of the TCP sockets `marked_tcp_connect` opens from now on, 0 leaves
+/// them to the system.
+pub fn set_tcp_buffer_size(size: u32) {
+    TCP_BUFFER_SIZE.store(size, Ordering::SeqCst);
+}
+
+/// Set the send and receive buffers of `socket` to `size` bytes, before connecting or on the
+/// listening socket.
+pub fn set_tcp_buffers(socket: RawSocket, size: u32) -> io::Result<()> {
+    let socket = borrowed(socket);
+    socket.set_send_buffer_size(size as usize)?;
+    socket.set_recv_buffer_size(size as usize)
+}
+
+/// Close `socket` with a RST instead of a FIN, by lingering for no time.
+pub fn reset_on_close(socket: RawSocket) -> io::Result<()> {
+    borrowed(socket).set_linger(Some(Duration::from_secs(0)))
+}
+
+/// Start connecting a non-blocking TCP socket to `addr`.
+///
+/// The connection is established once the socket becomes writable.
+pub fn marked_tcp_connect(addr: &SocketAddr) -> io::Result<TcpStream> {
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::stream(), None)?;
+    socket.set_nonblocking(true)?;
+    let buffer_size = TCP_BUFFER_SIZE.load(Ordering::SeqCst);
+    if buffer_size != 0 {
+        socket.set_send_buffer_size(buffer_size as usize)?;
+        socket.set_recv_buffer_size(buffer_size as usize)?;
+    }
+    match socket.connect(&SockAddr::from(*addr)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+        Err(e) => return Err(e),
+    }
+    Ok(socket.into_tcp_stream())
+}
+
+/// A UDP socket bound to the wildcard address of the family of `peer`.
+pub fn marked_udp_socket(peer: &SocketAddr) -> io::Result<UdpSocket> {
+    if peer.is_ipv4() {
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+    }
+}
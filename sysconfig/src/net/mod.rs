@@ -1,3 +1,4 @@
+#[cfg(unix)]
 use crate::command::run_cmd;
 
 #[cfg(any(
@@ -10,10 +11,37 @@ const IP_FORWARDING_KEY: &str = "net.inet.ip.forwarding";
 #[cfg(target_os = "linux")]
 const IP_FORWARDING_KEY: &str = "net.ipv4.ip_forward";
 
+/// A socket of the system, the descriptor on unix and the handle on Windows.
+#[cfg(unix)]
+pub type RawSocket = std::os::unix::io::RawFd;
+#[cfg(windows)]
+pub type RawSocket = std::os::windows::io::RawSocket;
+
+/// The `RawSocket` of the sockets of std and async-std, on every platform.
+pub trait AsSocket {
+    fn raw_socket(&self) -> RawSocket;
+}
+
+#[cfg(unix)]
+impl<T: std::os::unix::io::AsRawFd> AsSocket for T {
+    fn raw_socket(&self) -> RawSocket {
+        self.as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl<T: std::os::windows::io::AsRawSocket> AsSocket for T {
+    fn raw_socket(&self) -> RawSocket {
+        self.as_raw_socket()
+    }
+}
+
+#[cfg(unix)]
 pub struct IpForward {
     original_option: usize,
 }
 
+#[cfg(unix)]
 impl IpForward {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
@@ -26,6 +54,7 @@ impl IpForward {
     }
 }
 
+#[cfg(unix)]
 impl Drop for IpForward {
     fn drop(&mut self) {
         let _ = run_cmd(
@@ -46,4 +75,92 @@ pub mod sys;
 #[path = "linux.rs"]
 pub mod sys;
 
-pub use sys::{setup_ip, DNSSetup};
+#[cfg(windows)]
+#[path = "windows.rs"]
+pub mod sys;
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+#[path = "bsd.rs"]
+pub mod sys;
+
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd"))]
+mod resolv_conf;
+#[cfg(target_os = "linux")]
+mod resolved;
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd"
+))]
+#[path = "firewall_darwin.rs"]
+mod firewall;
+
+#[cfg(target_os = "linux")]
+#[path = "firewall_linux.rs"]
+mod firewall;
+
+#[cfg(windows)]
+#[path = "firewall_windows.rs"]
+mod firewall;
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd"
+))]
+#[path = "auto_route_darwin.rs"]
+mod auto_route;
+
+#[cfg(target_os = "linux")]
+#[path = "auto_route_linux.rs"]
+mod auto_route;
+
+#[cfg(windows)]
+#[path = "auto_route_windows.rs"]
+mod auto_route;
+
+#[cfg(unix)]
+mod mark;
+#[cfg(windows)]
+#[path = "mark_windows.rs"]
+mod mark;
+#[cfg(unix)]
+mod monitor;
+#[cfg(unix)]
+mod pmtu;
+
+#[cfg(target_os = "linux")]
+mod redirect;
+#[cfg(target_os = "linux")]
+mod tproxy;
+
+pub use auto_route::AutoRoute;
+pub use firewall::KillSwitchFirewall;
+#[cfg(target_os = "linux")]
+pub use firewall::SEEKER_FWMARK;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub use mark::set_outbound_interface;
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd"))]
+pub use mark::BypassRule;
+pub use mark::{
+    mark_socket, marked_tcp_connect, marked_udp_socket, reset_on_close, set_tcp_buffer_size,
+    set_tcp_buffers, set_tcp_keepalive,
+};
+#[cfg(unix)]
+pub use monitor::{network_state, NetworkMonitor};
+#[cfg(unix)]
+pub use pmtu::probe_path_mtu;
+#[cfg(target_os = "linux")]
+pub use redirect::original_dst;
+#[cfg(target_os = "linux")]
+pub use resolved::{resolved_manages_dns, ResolvedDNS};
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub use sys::default_interface;
+#[cfg(windows)]
+pub use sys::get_mtu;
+pub use sys::{set_mtu, setup_ip, setup_ip6, DNSSetup};
+#[cfg(target_os = "linux")]
+pub use tproxy::{tproxy_tcp_listener, TproxyRoute, TPROXY_FWMARK};
@@ -0,0 +1,238 @@
+//! Notifications of network changes from the kernel: a netlink socket on Linux, a routing
+//! socket on macOS and the BSDs. Both report interfaces, addresses and routes coming and
+//! going, including those seeker changes itself, so `network_state` tells real changes of
+//! the network from noise.
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::RawFd;
+
+#[cfg(target_os = "linux")]
+const RTMGRP_LINK: u32 = 0x1;
+#[cfg(target_os = "linux")]
+const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+#[cfg(target_os = "linux")]
+const RTMGRP_IPV4_ROUTE: u32 = 0x40;
+#[cfg(target_os = "linux")]
+const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+#[cfg(target_os = "linux")]
+const RTMGRP_IPV6_ROUTE: u32 = 0x400;
+/// Flags of IPv6 addresses, as in `/proc/net/if_inet6`.
+#[cfg(target_os = "linux")]
+const IFA_F_TEMPORARY: u32 = 0x01;
+#[cfg(target_os = "linux")]
+const IFA_F_DEPRECATED: u32 = 0x20;
+
+pub struct NetworkMonitor {
+    fd: RawFd,
+}
+
+impl NetworkMonitor {
+    #[cfg(target_os = "linux")]
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let monitor = NetworkMonitor { fd };
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = RTMGRP_LINK
+            | RTMGRP_IPV4_IFADDR
+            | RTMGRP_IPV4_ROUTE
+            | RTMGRP_IPV6_IFADDR
+            | RTMGRP_IPV6_ROUTE;
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(monitor)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::socket(libc::PF_ROUTE, libc::SOCK_RAW, libc::AF_UNSPEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(NetworkMonitor { fd })
+    }
+
+    /// Block until the kernel reports a change.
+    pub fn wait(&self) -> io::Result<()> {
+        self.recv(0).map(|_| ())
+    }
+
+    /// Throw away the changes reported since the last `wait`.
+    pub fn drain(&self) {
+        while let Ok(true) = self.recv(libc::MSG_DONTWAIT) {}
+    }
+
+    fn recv(&self, flags: libc::c_int) -> io::Result<bool> {
+        let mut buf = [0u8; 8192];
+        let n = unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut _, buf.len(), flags) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n > 0)
+    }
+}
+
+impl Drop for NetworkMonitor {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// The addresses of the interfaces that are up, except loopback and `skip`, the tun. The
+/// network changed when they did.
+///
+/// IPv6 privacy addresses rotate every few hours without the network changing, so temporary
+/// and deprecated addresses are left out.
+pub fn network_state(skip: &str) -> Vec<(String, IpAddr)> {
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return vec![];
+    }
+    let transient = transient_ipv6();
+    let mut state = vec![];
+    let mut cur = addrs;
+    while !cur.is_null() {
+        let ifa = unsafe { &*cur };
+        cur = ifa.ifa_next;
+        let flags = ifa.ifa_flags as libc::c_int;
+        if ifa.ifa_addr.is_null() || flags & libc::IFF_UP == 0 || flags & libc::IFF_LOOPBACK != 0 {
+            continue;
+        }
+        let name = unsafe { std::ffi::CStr::from_ptr(ifa.ifa_name) }
+            .to_string_lossy()
+            .into_owned();
+        if name == skip {
+            continue;
+        }
+        let ip = match unsafe { (*ifa.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                if transient.contains(&(name.clone(), ip)) {
+                    continue;
+                }
+                IpAddr::V6(stable_part(ip))
+            }
+            _ => continue,
+        };
+        state.push((name, ip));
+    }
+    unsafe { libc::freeifaddrs(addrs) };
+    state.sort();
+    state.dedup();
+    state
+}
+
+/// The temporary and deprecated IPv6 addresses, with their interfaces.
+#[cfg(target_os = "linux")]
+fn transient_ipv6() -> Vec<(String, Ipv6Addr)> {
+    match std::fs::read_to_string("/proc/net/if_inet6") {
+        Ok(content) => parse_if_inet6(&content)
+            .into_iter()
+            .filter(|(_, _, flags)| flags & (IFA_F_TEMPORARY | IFA_F_DEPRECATED) != 0)
+            .map(|(name, ip, _)| (name, ip))
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// `getifaddrs` has no flags of the addresses here.
+#[cfg(not(target_os = "linux"))]
+fn transient_ipv6() -> Vec<(String, Ipv6Addr)> {
+    vec![]
+}
+
+/// Lines like `20010db8000000000000000000000001 02 40 00 01 eth0`: the address, interface
+/// index, prefix length, scope and flags in hex, and the interface.
+#[cfg(target_os = "linux")]
+fn parse_if_inet6(content: &str) -> Vec<(String, Ipv6Addr, u32)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 6 {
+                return None;
+            }
+            let ip = u128::from_str_radix(fields[0], 16).ok()?;
+            let flags = u32::from_str_radix(fields[4], 16).ok()?;
+            Some((fields[5].to_string(), Ipv6Addr::from(ip), flags))
+        })
+        .collect()
+}
+
+/// Linux leaves out temporary addresses by their flags.
+#[cfg(target_os = "linux")]
+fn stable_part(ip: Ipv6Addr) -> Ipv6Addr {
+    ip
+}
+
+/// Without the flags of the addresses, only the /64 prefix counts, which the temporary
+/// addresses share with the stable one.
+#[cfg(not(target_os = "linux"))]
+fn stable_part(ip: Ipv6Addr) -> Ipv6Addr {
+    let mut segments = ip.segments();
+    for segment in &mut segments[4..] {
+        *segment = 0;
+    }
+    Ipv6Addr::from(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_state() {
+        let state = network_state("");
+        assert!(state.iter().all(|(_, ip)| !ip.is_loopback()));
+        let mut sorted = state.clone();
+        sorted.sort();
+        assert_eq!(state, sorted);
+        if let Some((name, _)) = state.first() {
+            assert!(network_state(name).iter().all(|(n, _)| n != name));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_if_inet6() {
+        let content = "\
+            20010db8000000000000000000000001 02 40 00 80     eth0\n\
+            20010db80000000012345678abcdef01 02 40 00 01     eth0\n\
+            fe800000000000000000000000000001 03 40 20 a0    wlan0\n";
+        assert_eq!(
+            parse_if_inet6(content),
+            vec![
+                ("eth0".to_string(), "2001:db8::1".parse().unwrap(), 0x80),
+                (
+                    "eth0".to_string(),
+                    "2001:db8::1234:5678:abcd:ef01".parse().unwrap(),
+                    0x01
+                ),
+                ("wlan0".to_string(), "fe80::1".parse().unwrap(), 0xa0),
+            ]
+        );
+    }
+}
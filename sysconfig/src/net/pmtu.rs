@@ -0,0 +1,130 @@
+//! Path MTU probing with datagrams routers are not allowed to fragment.
+//!
+//! Datagrams too big for a link on the path are dropped by its router, which answers with an
+//! ICMP error. The kernel then lowers the MTU it keeps for the destination, and refuses the
+//! next datagrams of that size with `EMSGSIZE`. On Linux the exact value is read back with
+//! `IP_MTU`, elsewhere the largest size still accepted is the path MTU.
+use super::mark::{marked_udp_socket, setsockopt};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// MTUs of Ethernet, PPPoE and the usual tunnels, tried from the largest.
+const CANDIDATE_MTUS: &[u32] = &[1500, 1492, 1480, 1460, 1440, 1420, 1400, 1380, 1350, 1280];
+/// Time for the ICMP error of a router on the path to come back.
+const ICMP_WAIT: Duration = Duration::from_millis(300);
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const IP_DONTFRAG: libc::c_int = 28;
+#[cfg(target_os = "freebsd")]
+const IP_DONTFRAG: libc::c_int = 67;
+#[cfg(not(target_os = "linux"))]
+const IPV6_DONTFRAG: libc::c_int = 62;
+
+/// The path MTU to `peer`, found with marked datagrams sent to it, so they leave through the
+/// network instead of the tun. Blocks while waiting for ICMP errors.
+pub fn probe_path_mtu(peer: SocketAddr) -> io::Result<u32> {
+    let socket = marked_udp_socket(&peer)?;
+    socket.connect(peer)?;
+    set_dont_fragment(&socket, peer.is_ipv6())?;
+    // IP and UDP headers.
+    let headers = if peer.is_ipv4() { 28 } else { 48 };
+    for &mtu in CANDIDATE_MTUS {
+        let payload = vec![0; (mtu - headers) as usize];
+        // The first send fails when the kernel already knows the route is narrower, the
+        // second when a router on the path answered the first.
+        if !fits(&socket, &payload)? {
+            continue;
+        }
+        sleep(ICMP_WAIT);
+        if fits(&socket, &payload)? {
+            let known = kernel_path_mtu(&socket, peer.is_ipv6());
+            return Ok(known.map_or(mtu, |known| known.min(mtu)));
+        }
+    }
+    Ok(CANDIDATE_MTUS[CANDIDATE_MTUS.len() - 1])
+}
+
+fn fits(socket: &UdpSocket, payload: &[u8]) -> io::Result<bool> {
+    match socket.send(payload) {
+        Ok(_) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) => Ok(false),
+        // A closed port answers an earlier datagram, which then made it all the way.
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &UdpSocket, ipv6: bool) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+    if ipv6 {
+        setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO as u32,
+        )
+    } else {
+        setsockopt(
+            fd,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO as u32,
+        )
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+fn set_dont_fragment(socket: &UdpSocket, ipv6: bool) -> io::Result<()> {
+    let (level, name) = if ipv6 {
+        (libc::IPPROTO_IPV6, IPV6_DONTFRAG)
+    } else {
+        (libc::IPPROTO_IP, IP_DONTFRAG)
+    };
+    setsockopt(socket.as_raw_fd(), level, name, 1)
+}
+
+/// OpenBSD fragments IPv4 datagrams of sockets as it likes.
+#[cfg(target_os = "openbsd")]
+fn set_dont_fragment(socket: &UdpSocket, ipv6: bool) -> io::Result<()> {
+    if !ipv6 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "ipv4 datagrams can not be sent unfragmented",
+        ));
+    }
+    setsockopt(socket.as_raw_fd(), libc::IPPROTO_IPV6, IPV6_DONTFRAG, 1)
+}
+
+/// The MTU the kernel keeps for the destination of the connected `socket`.
+#[cfg(target_os = "linux")]
+fn kernel_path_mtu(socket: &UdpSocket, ipv6: bool) -> Option<u32> {
+    let (level, name) = if ipv6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_MTU)
+    };
+    let mut value: u32 = 0;
+    let mut len = std::mem::size_of::<u32>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut u32 as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+    Some(value)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel_path_mtu(_socket: &UdpSocket, _ipv6: bool) -> Option<u32> {
+    None
+}
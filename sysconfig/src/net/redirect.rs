@@ -0,0 +1,70 @@
+//! REDIRECT inbound on Linux: iptables `REDIRECT` or nftables `redirect` rules rewrite the
+//! destination of connections to a local listener, conntrack remembers where they were
+//! going and hands it out with `SO_ORIGINAL_DST`.
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::os::unix::io::RawFd;
+
+const SO_ORIGINAL_DST: libc::c_int = 80;
+const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+            Some(SocketAddr::new(ip.into(), u16::from_be(sin.sin_port)))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// Where the connection accepted on `fd` was going before a REDIRECT rule sent it to us.
+pub fn original_dst(fd: RawFd, ipv6: bool) -> io::Result<SocketAddr> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let (level, name) = if ipv6 {
+        (libc::SOL_IPV6, IP6T_SO_ORIGINAL_DST)
+    } else {
+        (libc::SOL_IP, SO_ORIGINAL_DST)
+    };
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut storage as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    from_sockaddr(&storage)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown address family"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::mark::sockaddr;
+
+    #[test]
+    fn test_from_sockaddr() {
+        for addr in &["10.0.0.1:443", "[2001:db8::1]:8443"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let (storage, _) = sockaddr(&addr);
+            assert_eq!(from_sockaddr(&storage), Some(addr));
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! DNS through systemd-resolved, on Linux systems where it manages `/etc/resolv.conf`.
+//!
+//! resolved rewrites the stub file it links there, so instead seeker's DNS server becomes
+//! the DNS server of the tun, with the `~.` routing domain sending every query to it.
+use super::resolv_conf::get_original_dns;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::process::Command;
+use tracing::{info, warn};
+
+const RESOLV_PATH: &str = "/etc/resolv.conf";
+/// The servers resolved forwards to, in resolv.conf syntax.
+const UPSTREAM_RESOLV_PATH: &str = "/run/systemd/resolve/resolv.conf";
+
+fn resolvectl(args: &[&str]) -> io::Result<()> {
+    let output = Command::new("resolvectl").args(args).output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "resolvectl {}: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `/etc/resolv.conf` links to a file of systemd-resolved.
+pub fn resolved_manages_dns() -> bool {
+    fs::read_link(RESOLV_PATH)
+        .map(|target| is_resolved_path(&target.to_string_lossy()))
+        .unwrap_or(false)
+}
+
+fn is_resolved_path(target: &str) -> bool {
+    target.contains("systemd/resolve") || target.ends_with("systemd/resolv.conf")
+}
+
+pub struct ResolvedDNS {
+    link: String,
+    original_dns: Vec<String>,
+}
+
+impl ResolvedDNS {
+    /// DNS servers resolved forwards to, before seeker takes over.
+    pub fn upstream_dns() -> Vec<String> {
+        fs::read_to_string(UPSTREAM_RESOLV_PATH)
+            .map(|content| get_original_dns(&content, ""))
+            .unwrap_or_default()
+    }
+
+    /// Make `server` the DNS server of `link` for all domains.
+    pub fn new(link: &str, server: SocketAddr) -> io::Result<Self> {
+        info!(%link, %server, "setup dns with systemd-resolved");
+        let original_dns = ResolvedDNS::upstream_dns();
+        // resolvectl takes a port only since systemd 246.
+        let server = if server.port() == 53 {
+            server.ip().to_string()
+        } else {
+            server.to_string()
+        };
+        resolvectl(&["dns", link, &server])?;
+        resolvectl(&["domain", link, "~."])?;
+        // Older versions have no default-route, for them `~.` is enough.
+        if let Err(e) = resolvectl(&["default-route", link, "yes"]) {
+            warn!(?e, "set default dns route");
+        }
+        let _ = resolvectl(&["flush-caches"]);
+        Ok(ResolvedDNS {
+            link: link.to_string(),
+            original_dns,
+        })
+    }
+
+    /// DNS servers configured before seeker took over.
+    pub fn original_dns(&self) -> &[String] {
+        &self.original_dns
+    }
+}
+
+/// resolved forgets the settings of a link when it goes away, so failing to revert them
+/// after seeker switched `user` does no harm.
+impl Drop for ResolvedDNS {
+    fn drop(&mut self) {
+        info!("Restore original DNS: {:?}", self.original_dns);
+        if let Err(e) = resolvectl(&["revert", &self.link]) {
+            warn!(?e, "restore dns of systemd-resolved");
+        }
+        let _ = resolvectl(&["flush-caches"]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_resolved_path() {
+        assert!(is_resolved_path("../run/systemd/resolve/stub-resolv.conf"));
+        assert!(is_resolved_path("/usr/lib/systemd/resolv.conf"));
+        assert!(!is_resolved_path("/run/NetworkManager/resolv.conf"));
+    }
+}
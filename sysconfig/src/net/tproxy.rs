@@ -0,0 +1,100 @@
+//! TPROXY inbound on Linux: nftables rules on the router mark connections passing through
+//! and hand them to a local listener, which sees their original destination as its local
+//! address. The kernel stack keeps terminating the TCP connections.
+use super::mark::{ip_rule, setsockopt, sockaddr};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::io::FromRawFd;
+use tracing::warn;
+
+/// Mark the TPROXY rules put on redirected packets, routed to the local host by
+/// `TproxyRoute`.
+pub const TPROXY_FWMARK: u32 = 0x1301;
+
+const TPROXY_TABLE: &str = "1301";
+const TPROXY_RULE_PREF: &str = "1301";
+
+const IP_TRANSPARENT: libc::c_int = 19;
+const IPV6_TRANSPARENT: libc::c_int = 75;
+
+/// A listener accepting connections to any address, as redirected by TPROXY rules.
+/// Needs `CAP_NET_ADMIN`.
+pub fn tproxy_tcp_listener(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let family = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owned from here on, so the fd is closed on errors.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    if addr.is_ipv4() {
+        setsockopt(fd, libc::IPPROTO_IP, IP_TRANSPARENT, 1)?;
+    } else {
+        setsockopt(fd, libc::IPPROTO_IPV6, IPV6_TRANSPARENT, 1)?;
+    }
+    let (storage, len) = sockaddr(addr);
+    if unsafe { libc::bind(fd, &storage as *const _ as *const libc::sockaddr, len) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::listen(fd, 1024) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Policy routing delivering packets marked with `TPROXY_FWMARK` to the local host, so
+/// the TPROXY listener gets them instead of the packets being forwarded.
+pub struct TproxyRoute;
+
+impl TproxyRoute {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let mark = TPROXY_FWMARK.to_string();
+        for &(family, default) in &[("-4", "0.0.0.0/0"), ("-6", "::/0")] {
+            // Remove what a previous crash left first.
+            let _ = ip_rule(&[family, "rule", "del", "pref", TPROXY_RULE_PREF]);
+            let _ = ip_rule(&[family, "route", "flush", "table", TPROXY_TABLE]);
+            let rule = [
+                family,
+                "rule",
+                "add",
+                "fwmark",
+                mark.as_str(),
+                "lookup",
+                TPROXY_TABLE,
+                "pref",
+                TPROXY_RULE_PREF,
+            ];
+            let route = [
+                family,
+                "route",
+                "add",
+                "local",
+                default,
+                "dev",
+                "lo",
+                "table",
+                TPROXY_TABLE,
+            ];
+            if !ip_rule(&rule) || !ip_rule(&route) {
+                warn!(%family, "can not add routing for tproxy");
+            }
+        }
+        TproxyRoute
+    }
+}
+
+impl Drop for TproxyRoute {
+    fn drop(&mut self) {
+        for &family in &["-4", "-6"] {
+            let _ = ip_rule(&[family, "rule", "del", "pref", TPROXY_RULE_PREF]);
+            let _ = ip_rule(&[family, "route", "flush", "table", TPROXY_TABLE]);
+        }
+    }
+}
@@ -0,0 +1,322 @@
+//! Windows, set up through `netsh`, with the settings `netsh` can not print in a stable
+//! format read through PowerShell.
+use crate::command::try_run_cmd;
+use std::io;
+use std::net::IpAddr;
+use tracing::{info, warn};
+
+/// The TCP/IP settings of the interface with the GUID that follows.
+const INTERFACES_KEY: &str = r"HKLM:\SYSTEM\CurrentControlSet\Services\Tcpip\Parameters\Interfaces";
+
+fn netsh(args: &[&str]) -> io::Result<()> {
+    try_run_cmd("netsh", args)?;
+    Ok(())
+}
+
+/// Errors of cmdlets stop the script, so they fail the command.
+pub(super) fn powershell(script: &str) -> io::Result<String> {
+    let script = format!("$ErrorActionPreference = 'Stop'; {}", script);
+    try_run_cmd(
+        "powershell",
+        &["-NoProfile", "-NonInteractive", "-Command", &script],
+    )
+}
+
+/// `s` as a single quoted PowerShell string.
+pub(super) fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// The interface and next hop of the default route of the family with the lowest metric, e.g.
+/// `("Ethernet", "192.168.1.1")`, none while offline.
+pub fn default_route(inet6: bool) -> Option<(String, String)> {
+    let prefix = if inet6 { "::/0" } else { "0.0.0.0/0" };
+    let script = format!(
+        "Get-NetRoute -DestinationPrefix {} \
+         | Sort-Object {{ $_.RouteMetric + $_.InterfaceMetric }} | Select-Object -First 1 \
+         | ForEach-Object {{ $_.InterfaceAlias + '|' + $_.NextHop }}",
+        prefix
+    );
+    match powershell(&script) {
+        Ok(output) => parse_default_route(&output),
+        Err(e) => {
+            warn!(?e, "get default route");
+            None
+        }
+    }
+}
+
+fn parse_default_route(output: &str) -> Option<(String, String)> {
+    let output = output.trim();
+    let split = output.rfind('|')?;
+    let (interface, next_hop) = (&output[..split], &output[split + 1..]);
+    if interface.is_empty() || next_hop.parse::<IpAddr>().is_err() {
+        return None;
+    }
+    Some((interface.to_string(), next_hop.to_string()))
+}
+
+/// Addresses separated by lines, spaces or commas, skipping what is not an IP address.
+fn parse_addresses(output: &str) -> Vec<String> {
+    output
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(|s| s.parse::<IpAddr>().ok())
+        .map(|ip| ip.to_string())
+        .collect()
+}
+
+/// Points the DNS of the interface of the default route at seeker, and puts it back on drop.
+/// Servers set by hand are restored as they were, the others are handed out by DHCP again.
+pub struct DNSSetup {
+    interface: Option<String>,
+    /// Servers set by hand, empty for those from DHCP.
+    static_dns: Vec<String>,
+    original_dns: Vec<String>,
+}
+
+impl DNSSetup {
+    #[allow(clippy::new_without_default)]
+    pub fn new(dns: String) -> Self {
+        let interface = match default_route(false) {
+            Some((interface, _)) => interface,
+            None => {
+                warn!("no default route, dns is not set up");
+                return DNSSetup {
+                    interface: None,
+                    static_dns: vec![],
+                    original_dns: vec![],
+                };
+            }
+        };
+        info!("Primary interface is {}", &interface);
+        let original_dns = powershell(&format!(
+            "(Get-DnsClientServerAddress -InterfaceAlias {} -AddressFamily IPv4).ServerAddresses",
+            quote(&interface)
+        ))
+        .map(|output| parse_addresses(&output))
+        .unwrap_or_else(|e| {
+            warn!(?e, "get original dns");
+            vec![]
+        })
+        .into_iter()
+        .filter(|ip| ip != "127.0.0.1" && *ip != dns)
+        .collect::<Vec<_>>();
+        // The registry only has servers set by hand, DHCP ones are kept elsewhere.
+        let static_dns = powershell(&format!(
+            "$guid = (Get-NetAdapter -InterfaceAlias {}).InterfaceGuid; \
+             (Get-ItemProperty \"{}\\$guid\").NameServer",
+            quote(&interface),
+            INTERFACES_KEY
+        ))
+        .map(|output| parse_addresses(&output))
+        .unwrap_or_default();
+        info!("Original DNS is {:?}", &original_dns);
+
+        let name = format!("name={}", interface);
+        let mut servers = vec!["127.0.0.1"];
+        if !dns.is_empty() {
+            servers.push(&dns);
+        }
+        if let Err(e) = set_dns_servers(&name, &servers) {
+            warn!(?e, "setup dns");
+        }
+        DNSSetup {
+            interface: Some(interface),
+            static_dns,
+            original_dns,
+        }
+    }
+
+    /// DNS servers configured before seeker took over.
+    pub fn original_dns(&self) -> &[String] {
+        &self.original_dns
+    }
+}
+
+fn set_dns_servers(name: &str, servers: &[&str]) -> io::Result<()> {
+    let (first, rest) = match servers.split_first() {
+        Some(split) => split,
+        None => {
+            return netsh(&[
+                "interface",
+                "ipv4",
+                "set",
+                "dnsservers",
+                name,
+                "source=dhcp",
+            ])
+        }
+    };
+    netsh(&[
+        "interface",
+        "ipv4",
+        "set",
+        "dnsservers",
+        name,
+        "static",
+        first,
+        "register=primary",
+        "validate=no",
+    ])?;
+    for (i, server) in rest.iter().enumerate() {
+        let index = format!("index={}", i + 2);
+        netsh(&[
+            "interface",
+            "ipv4",
+            "add",
+            "dnsservers",
+            name,
+            server,
+            &index,
+            "validate=no",
+        ])?;
+    }
+    Ok(())
+}
+
+impl Drop for DNSSetup {
+    fn drop(&mut self) {
+        let interface = match &self.interface {
+            Some(interface) => interface,
+            None => return,
+        };
+        info!("Restore original DNS: {:?}", self.static_dns);
+        let servers = self
+            .static_dns
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        if let Err(e) = set_dns_servers(&format!("name={}", interface), &servers) {
+            warn!(?e, "restore dns");
+        }
+    }
+}
+
+/// `255.255.0.0` for the `11.0.0.0/16` of `cidr`.
+fn netmask(cidr: &str) -> String {
+    let prefix: u32 = cidr
+        .split('/')
+        .nth(1)
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(32);
+    let mask = if prefix == 0 {
+        0
+    } else {
+        u32::MAX << (32 - prefix.min(32))
+    };
+    std::net::Ipv4Addr::from(mask).to_string()
+}
+
+/// The adapter gets the lowest metric, so Windows prefers it and asks its DNS server first.
+pub fn setup_ip(tun_name: &str, ip: &str, cidr: &str) -> io::Result<()> {
+    let ip = ip.split('/').next().unwrap_or(ip);
+    let name = format!("name={}", tun_name);
+    netsh(&[
+        "interface",
+        "ipv4",
+        "set",
+        "address",
+        &name,
+        "static",
+        ip,
+        &netmask(cidr),
+    ])?;
+    netsh(&[
+        "interface",
+        "ipv4",
+        "set",
+        "dnsservers",
+        &name,
+        "static",
+        ip,
+        "register=none",
+        "validate=no",
+    ])?;
+    netsh(&[
+        "interface",
+        "ipv4",
+        "set",
+        "interface",
+        tun_name,
+        "metric=1",
+    ])
+}
+
+pub fn setup_ip6(tun_name: &str, ip: &str, cidr: &str) -> io::Result<()> {
+    let prefix = cidr.split('/').nth(1).unwrap_or("128");
+    netsh(&[
+        "interface",
+        "ipv6",
+        "add",
+        "address",
+        tun_name,
+        &format!("{}/{}", ip, prefix),
+    ])?;
+    netsh(&[
+        "interface",
+        "ipv6",
+        "add",
+        "route",
+        cidr,
+        tun_name,
+        "store=active",
+    ])
+}
+
+pub fn set_mtu(tun_name: &str, mtu: u32) -> io::Result<()> {
+    netsh(&[
+        "interface",
+        "ipv4",
+        "set",
+        "subinterface",
+        tun_name,
+        &format!("mtu={}", mtu),
+        "store=active",
+    ])
+}
+
+/// The IPv4 MTU of the adapter `tun_name`.
+pub fn get_mtu(tun_name: &str) -> io::Result<u32> {
+    let output = powershell(&format!(
+        "(Get-NetIPInterface -InterfaceAlias {} -AddressFamily IPv4).NlMtu",
+        quote(tun_name)
+    ))?;
+    output.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("mtu of {}: {:?}", tun_name, output.trim()),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_netmask() {
+        assert_eq!(netmask("11.0.0.0/16"), "255.255.0.0");
+        assert_eq!(netmask("10.0.0.0/8"), "255.0.0.0");
+        assert_eq!(netmask("0.0.0.0/0"), "0.0.0.0");
+    }
+
+    #[test]
+    fn test_parse_output() {
+        assert_eq!(
+            parse_default_route("Wi-Fi | 2|192.168.1.1\r\n"),
+            Some(("Wi-Fi | 2".to_string(), "192.168.1.1".to_string()))
+        );
+        assert_eq!(parse_default_route(""), None);
+        assert_eq!(parse_default_route("Ethernet|"), None);
+        assert_eq!(
+            parse_addresses("8.8.8.8\r\n1.1.1.1\r\n"),
+            vec!["8.8.8.8", "1.1.1.1"]
+        );
+        assert_eq!(
+            parse_addresses("8.8.8.8,1.1.1.1"),
+            vec!["8.8.8.8", "1.1.1.1"]
+        );
+        assert!(parse_addresses("\r\n").is_empty());
+        assert_eq!(quote("it's"), "'it''s'");
+    }
+}
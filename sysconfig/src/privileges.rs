@@ -0,0 +1,92 @@
+//! Switching to an unprivileged user once seeker has set up what needs root. It keeps
+//! `CAP_NET_ADMIN` to mark its sockets and to remove its routes and firewall rules on exit,
+//! and `CAP_NET_BIND_SERVICE` to bind DNS on port 53 again on reloads.
+use std::ffi::CString;
+use std::io::{self, ErrorKind};
+
+const CAP_NET_BIND_SERVICE: u32 = 10;
+const CAP_NET_ADMIN: u32 = 12;
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+fn check(ret: libc::c_long) -> io::Result<()> {
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Become `user` and its primary group, dropping every capability but the two seeker
+/// needs. They are raised as ambient capabilities too, so the `ip` and `nft` commands run
+/// on exit still work.
+pub fn drop_privileges(user: &str) -> io::Result<()> {
+    let name = CString::new(user).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("unknown user {}", user),
+        ));
+    }
+    let (uid, gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+    unsafe {
+        check(libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0).into())?;
+        check(libc::setgroups(0, std::ptr::null()).into())?;
+        check(libc::setgid(gid).into())?;
+        check(libc::setuid(uid).into())?;
+    }
+
+    let kept = (1 << CAP_NET_ADMIN) | (1 << CAP_NET_BIND_SERVICE);
+    let header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [
+        CapData {
+            effective: kept,
+            permitted: kept,
+            inheritable: kept,
+        },
+        CapData::default(),
+    ];
+    check(unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) })?;
+    for cap in &[CAP_NET_ADMIN, CAP_NET_BIND_SERVICE] {
+        check(
+            unsafe {
+                libc::prctl(
+                    libc::PR_CAP_AMBIENT,
+                    libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong,
+                    *cap as libc::c_ulong,
+                    0,
+                    0,
+                )
+            }
+            .into(),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_user() {
+        let err = drop_privileges("seeker-no-such-user").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
@@ -1,12 +1,17 @@
 #![allow(dead_code)]
-use super::SocketInfo;
+use super::{ProcessInfo, SocketInfo};
 use libproc::libproc::proc_pid::{
-    listpidinfo, listpids, pidfdinfo, InSockInfo, ListFDs, ProcFDType, ProcType, SocketFDInfo,
-    SocketInfoKind,
+    listpidinfo, listpids, pidfdinfo, pidpath, InSockInfo, ListFDs, ProcFDType, ProcType,
+    SocketFDInfo, SocketInfoKind,
 };
 use std::collections::HashMap;
 use std::io::Result;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+
+/// `insi_vflag` bit of sockets carrying IPv4 traffic, also set for mapped addresses on
+/// IPv6 sockets.
+const INI_IPV4: u8 = 0x1;
 
 pub fn list_system_proc_socks() -> Result<HashMap<i32, Vec<SocketInfo>>> {
     let pids = listpids(ProcType::ProcAllPIDS, 0)?;
@@ -52,6 +57,69 @@ fn list_sockaddr(pid: i32) -> Result<Vec<SocketInfo>> {
     Ok(addrs)
 }
 
+/// The process owning the TCP or UDP socket bound to `local`.
+///
+/// This walks the fds of all processes, callers should cache the result.
+pub fn find_socket_process(local: SocketAddr, udp: bool) -> Result<Option<ProcessInfo>> {
+    for pid in listpids(ProcType::ProcAllPIDS, 0)? {
+        let pid = pid as i32;
+        // Processes of other users can not be inspected without privileges.
+        let fds = match listpidinfo::<ListFDs>(pid, 4000) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds {
+            if let ProcFDType::Socket = fd.proc_fdtype.into() {
+                if let Ok(socket) = pidfdinfo::<SocketFDInfo>(pid, fd.proc_fd) {
+                    if socket_local_addr(&socket, udp)
+                        .map_or(false, |addr| addr_matches(addr, local))
+                    {
+                        return Ok(Some(process_info(pid)));
+                    }
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn socket_local_addr(socket: &SocketFDInfo, udp: bool) -> Option<SocketAddr> {
+    // access to the members of `soi_proto` is unsafe becasuse of union type.
+    let kind: SocketInfoKind = socket.psi.soi_kind.into();
+    let info = match (kind, udp) {
+        (SocketInfoKind::Tcp, false) => unsafe { socket.psi.soi_proto.pri_tcp.tcpsi_ini },
+        (SocketInfoKind::In, true) => unsafe { socket.psi.soi_proto.pri_in },
+        _ => return None,
+    };
+    let family = if info.insi_vflag & INI_IPV4 != 0 {
+        libc::AF_INET
+    } else {
+        socket.psi.soi_family
+    };
+    Some(get_local_addr(info, family))
+}
+
+/// UDP sockets are often bound to the wildcard address, those match on the port alone.
+fn addr_matches(addr: SocketAddr, local: SocketAddr) -> bool {
+    addr == local || (addr.ip().is_unspecified() && addr.port() == local.port())
+}
+
+fn process_info(pid: i32) -> ProcessInfo {
+    let path = pidpath(pid).ok().map(PathBuf::from);
+    let name = path
+        .as_ref()
+        .and_then(|p| p.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .or_else(|| libproc::libproc::proc_pid::name(pid).ok())
+        .unwrap_or_default();
+    ProcessInfo {
+        pid,
+        name,
+        path,
+        uid: None,
+    }
+}
+
 fn get_local_addr(in_sock_info: InSockInfo, family: i32) -> SocketAddr {
     // change endian and cut off because insi_lport is network endian and 16bit witdh.
     let mut port = 0;
@@ -114,4 +182,14 @@ mod test {
             .find(|sockets| sockets.iter().find(|s| s.local.port() == 8888).is_some())
             .is_some());
     }
+
+    #[test]
+    fn test_find_socket_process() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let process = find_socket_process(socket.local_addr().unwrap(), true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(process.pid, std::process::id() as i32);
+        assert!(process.path.is_some());
+    }
 }
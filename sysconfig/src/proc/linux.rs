@@ -1,7 +1,12 @@
-use crate::SocketInfo;
-use procfs::process::FDTarget;
+use crate::{ProcessInfo, SocketInfo};
+use procfs::process::{FDTarget, Process};
 use std::collections::HashMap;
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+
+/// Pids of the processes holding socket inodes, as the last walk of all fds found them.
+static SOCKET_PIDS: Mutex<Option<HashMap<u32, i32>>> = Mutex::new(None);
 
 pub fn list_system_proc_socks() -> Result<HashMap<i32, Vec<SocketInfo>>> {
     let all_procs = procfs::process::all_processes().expect("list all processes");
@@ -68,6 +73,118 @@ pub fn list_user_proc_socks(uid: u32) -> Result<HashMap<i32, Vec<SocketInfo>>> {
     Ok(socks_map)
 }
 
+fn proc_error(e: procfs::ProcError) -> Error {
+    Error::new(ErrorKind::Other, format!("{:?}", e))
+}
+
+/// Sockets listed in `tcp6` and `udp6` show IPv4 peers as mapped addresses.
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(a) => match a.ip().to_ipv4() {
+            Some(ip) if a.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+                SocketAddr::new(IpAddr::V4(ip), a.port())
+            }
+            _ => addr,
+        },
+        _ => addr,
+    }
+}
+
+/// Inode of the socket bound to `local`.
+///
+/// UDP sockets are often bound to the wildcard address, those match on the port alone.
+fn socket_inode(local: SocketAddr, udp: bool) -> Result<Option<u32>> {
+    let entries: Vec<(SocketAddr, u32)> = if udp {
+        let v4 = procfs::net::udp().map_err(proc_error)?;
+        let v6 = procfs::net::udp6().unwrap_or_default();
+        v4.into_iter()
+            .chain(v6)
+            .map(|e| (e.local_address, e.inode))
+            .collect()
+    } else {
+        let v4 = procfs::net::tcp().map_err(proc_error)?;
+        let v6 = procfs::net::tcp6().unwrap_or_default();
+        v4.into_iter()
+            .chain(v6)
+            .map(|e| (e.local_address, e.inode))
+            .collect()
+    };
+    let exact = entries.iter().find(|(addr, _)| unmap(*addr) == local);
+    let wildcard = || {
+        entries
+            .iter()
+            .find(|(addr, _)| udp && addr.ip().is_unspecified() && addr.port() == local.port())
+    };
+    Ok(exact.or_else(wildcard).map(|(_, inode)| *inode))
+}
+
+fn process_info(process: &Process) -> ProcessInfo {
+    let path = process.exe().ok();
+    let name = path
+        .as_ref()
+        .and_then(|p| p.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        // Kernel threads and processes of other users without privileges have no exe.
+        .unwrap_or_else(|| process.stat.comm.clone());
+    ProcessInfo {
+        pid: process.pid(),
+        name,
+        path,
+        uid: Some(process.owner),
+    }
+}
+
+fn holds_socket(process: &Process, inode: u32) -> bool {
+    process.fd().map_or(false, |fds| {
+        fds.iter()
+            .any(|fd| matches!(fd.target, FDTarget::Socket(i) if i == inode))
+    })
+}
+
+/// The process holding the socket `inode`. The fds of all processes are only walked again
+/// when the socket is newer than the last walk, or its process closed it since.
+fn socket_process(inode: u32) -> Result<Option<Process>> {
+    let mut pids = SOCKET_PIDS.lock().unwrap_or_else(|e| e.into_inner());
+    let known = pids
+        .as_ref()
+        .and_then(|pids| pids.get(&inode))
+        .and_then(|pid| Process::new(*pid).ok());
+    if let Some(process) = known.filter(|p| holds_socket(p, inode)) {
+        return Ok(Some(process));
+    }
+    let all_procs = procfs::process::all_processes().map_err(proc_error)?;
+    let mut fresh = HashMap::new();
+    let mut owner = None;
+    for process in all_procs {
+        let mut holds = false;
+        if let Ok(fds) = process.fd() {
+            for fd in fds {
+                if let FDTarget::Socket(i) = fd.target {
+                    fresh.insert(i, process.pid());
+                    holds |= i == inode;
+                }
+            }
+        }
+        if holds && owner.is_none() {
+            owner = Some(process);
+        }
+    }
+    *pids = Some(fresh);
+    Ok(owner)
+}
+
+/// The process owning the TCP or UDP socket bound to `local`.
+///
+/// Sockets of processes seen before are found quickly, new ones walk the fds of all
+/// processes, callers should cache the result.
+pub fn find_socket_process(local: SocketAddr, udp: bool) -> Result<Option<ProcessInfo>> {
+    let inode = match socket_inode(local, udp)? {
+        Some(inode) => inode,
+        None => return Ok(None),
+    };
+    Ok(socket_process(inode)?.map(|process| process_info(&process)))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -82,4 +199,28 @@ mod test {
             .values()
             .any(|sockets| sockets.iter().any(|s| s.local.port() == 65532)));
     }
+
+    #[test]
+    fn test_find_socket_process() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let process = find_socket_process(socket.local_addr().unwrap(), true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(process.pid, std::process::id() as i32);
+        assert!(!process.name.is_empty());
+        assert_eq!(process.uid, Some(unsafe { libc::getuid() }));
+        let inode = socket_inode(socket.local_addr().unwrap(), true)
+            .unwrap()
+            .unwrap();
+        let pids = SOCKET_PIDS.lock().unwrap();
+        assert_eq!(pids.as_ref().unwrap().get(&inode), Some(&process.pid));
+        drop(pids);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _stream = std::net::TcpStream::connect(addr).unwrap();
+        let (_accepted, peer) = listener.accept().unwrap();
+        let process = find_socket_process(peer, false).unwrap().unwrap();
+        assert_eq!(process.pid, std::process::id() as i32);
+    }
 }
@@ -1,4 +1,5 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct SocketInfo {
@@ -6,10 +7,21 @@ pub struct SocketInfo {
     pub remote: SocketAddr,
 }
 
+/// The process owning a socket.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProcessInfo {
+    pub pid: i32,
+    /// File name of the executable.
+    pub name: String,
+    pub path: Option<PathBuf>,
+    /// Owner of the process, not looked up on macOS yet.
+    pub uid: Option<u32>,
+}
+
 #[cfg(target_os = "macos")]
 #[path = "darwin.rs"]
 pub mod sys;
 
-#[cfg(all(target_os = "linux"))]
+#[cfg(target_os = "linux")]
 #[path = "linux.rs"]
 pub mod sys;
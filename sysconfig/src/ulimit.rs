@@ -1,15 +1,9 @@
 use std::io;
 
 pub fn set_rlimit_no_file(no: u64) -> io::Result<()> {
-    #[cfg(target_arch = "x86_64")]
     let rlim = libc::rlimit {
-        rlim_cur: no,
-        rlim_max: no,
-    };
-    #[cfg(target_arch = "arm")]
-    let rlim = libc::rlimit {
-        rlim_cur: no as u32,
-        rlim_max: no as u32,
+        rlim_cur: no as libc::rlim_t,
+        rlim_max: no as libc::rlim_t,
     };
     let ret = unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlim) };
     if ret == -1 {
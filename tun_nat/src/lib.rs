@@ -1,166 +1,248 @@
+mod stack;
 mod tun_socket;
 
+pub use crate::stack::{Reply, Stack, StackKind};
+#[cfg(unix)]
+pub use crate::tun_socket::receive_fd;
 use crate::tun_socket::TunSocket;
 use bitvec::vec::BitVec;
 use parking_lot::RwLock;
-use smoltcp::wire::{IpAddress, IpProtocol, Ipv4Cidr, Ipv4Packet, TcpPacket, UdpPacket};
+use smoltcp::wire::{Ipv4Cidr, Ipv6Cidr};
 use std::collections::HashMap;
 use std::io::Result;
-use std::io::{Read, Write};
-use std::net::{Ipv4Addr, SocketAddr};
+use std::io::{ErrorKind, IoSlice, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::SystemTime;
-use sysconfig::setup_ip;
+use sysconfig::{setup_ip, setup_ip6};
 
 const BEGIN_PORT: u16 = 50000;
 const END_PORT: u16 = 60000;
 const EXPIRE_SECONDS: u64 = 60 * 1000;
+/// `virtio_net_hdr` in front of each packet with `offload`.
+const VNET_HDR_LEN: usize = 10;
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
 
-macro_rules! route_packet {
-    ($packet_ty: tt, $ipv4_packet: expr, $session_manager: expr, $relay_addr: expr, $relay_port: expr) => {{
-        let src_addr = $ipv4_packet.src_addr().into();
-        let dest_addr = $ipv4_packet.dst_addr().into();
-        let mut packet = $packet_ty::new_checked($ipv4_packet.payload_mut()).unwrap();
-        let src_port = packet.src_port();
-        let dest_port = packet.dst_port();
-
-        if let Some((new_src_addr, new_src_port, new_dst_addr, new_dest_port)) =
-            if src_addr == $relay_addr && src_port == $relay_port {
-                let session_manager = $session_manager.read();
-                if let Some(assoc) = session_manager.get_by_port(dest_port) {
-                    Some((
-                        assoc.dest_addr.into(),
-                        assoc.dest_port,
-                        assoc.src_addr.into(),
-                        assoc.src_port,
-                    ))
-                } else {
-                    None
-                }
-            } else {
-                let mut session_manager = $session_manager.write();
-                let port =
-                    session_manager.get_or_create_session(src_addr, src_port, dest_addr, dest_port);
-                session_manager.update_activity_for_port(port);
-                Some((dest_addr.into(), port, $relay_addr.into(), $relay_port))
-            }
-        {
-            packet.set_src_port(new_src_port);
-            packet.set_dst_port(new_dest_port);
-            packet.fill_checksum(
-                &IpAddress::Ipv4(new_src_addr),
-                &IpAddress::Ipv4(new_dst_addr),
-            );
-            $ipv4_packet.set_src_addr(new_src_addr);
-            $ipv4_packet.set_dst_addr(new_dst_addr);
-
-            $ipv4_packet.fill_checksum();
-            Some($ipv4_packet)
-        } else {
-            None
-        }
-    }};
-}
-
-pub fn run_nat(
+fn setup_tun_ip(
     tun_name: &str,
     tun_ip: Ipv4Addr,
     tun_cidr: Ipv4Cidr,
-    relay_port: u16,
-) -> Result<SessionManager> {
-    let mut tun = TunSocket::new(tun_name)?;
-    let tun_name = tun.name()?;
-    if cfg!(target_os = "macos") {
+    tun6: Option<(Ipv6Addr, Ipv6Cidr)>,
+) -> Result<()> {
+    if cfg!(any(
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "openbsd"
+    )) {
         setup_ip(
-            &tun_name,
+            tun_name,
             tun_ip.to_string().as_str(),
             tun_cidr.to_string().as_str(),
-        );
+        )?;
     } else {
         let new_ip =
             Ipv4Cidr::from_netmask(tun_ip.into(), tun_cidr.netmask()).expect("convert netmask");
         setup_ip(
-            &tun_name,
+            tun_name,
             new_ip.to_string().as_str(),
             tun_cidr.to_string().as_str(),
-        );
+        )?;
+    }
+
+    if let Some((tun_ip6, tun_cidr6)) = tun6 {
+        setup_ip6(
+            tun_name,
+            tun_ip6.to_string().as_str(),
+            tun_cidr6.to_string().as_str(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Where the tun of `run_nat` comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunOpen {
+    /// Create the tun and set up its addresses.
+    Create,
+    /// Open a persistent tun created and addressed beforehand, by `ip tuntap add mode tun
+    /// user seeker` for example, which needs no root.
+    Persistent,
+    /// Use the tun another process opened as this descriptor.
+    Fd(i32),
+}
+
+/// Only a created tun gets its addresses set up, the others are used as they are.
+///
+/// With `offload` a tun seeker opens itself moves TCP in segments of up to 64 KB, and with
+/// more than one of `queues` it is a multi-queue tun with a thread and a stack per queue.
+/// Both are Linux only. A tun given as a descriptor has one queue without offload.
+#[allow(clippy::too_many_arguments)]
+pub fn run_nat(
+    tun_name: &str,
+    open: TunOpen,
+    offload: bool,
+    queues: usize,
+    tun_ip: Ipv4Addr,
+    tun_cidr: Ipv4Cidr,
+    tun6: Option<(Ipv6Addr, Ipv6Cidr)>,
+    relay_port: u16,
+    stack: StackKind,
+) -> Result<SessionManager> {
+    // Descriptors of other processes come without the header.
+    let offload = offload && !matches!(open, TunOpen::Fd(_));
+    let tuns = match open {
+        TunOpen::Fd(fd) => vec![TunSocket::from_fd(fd, tun_name)?],
+        _ if queues > 1 => TunSocket::with_queues(tun_name, queues, offload)?,
+        _ if offload => vec![TunSocket::with_offload(tun_name)?],
+        TunOpen::Create | TunOpen::Persistent => vec![TunSocket::new(tun_name)?],
+    };
+    let tun_name = tuns[0].name()?;
+    if open == TunOpen::Create {
+        setup_tun_ip(&tun_name, tun_ip, tun_cidr, tun6)?;
     }
 
     let relay_addr = tun_ip;
+    let relay_addr6 = tun6.map(|(ip, _)| ip);
 
+    // The queues share the sessions, replies of the relay may come in on any of them.
     let session_manager = Arc::new(RwLock::new(InnerSessionManager::new(BEGIN_PORT, END_PORT)));
-    let sesion_mamager_clone = session_manager.clone();
-    let _handle = thread::spawn(move || {
-        let mut buf = vec![0; 2000];
+    let path_mtu = Arc::new(AtomicU32::new(0));
+    for tun in tuns {
+        let stack = stack::new_stack(
+            stack,
+            session_manager.clone(),
+            path_mtu.clone(),
+            relay_addr,
+            relay_addr6,
+            relay_port,
+        );
+        let _handle = thread::spawn(move || run_queue(tun, stack, offload));
+    }
+    Ok(SessionManager {
+        inner: session_manager,
+        path_mtu,
+    })
+}
 
-        loop {
-            let size = tun.read(&mut buf).unwrap();
-            if size == 0 {
-                eprintln!("tun read return 0, exit now");
-                break;
-            }
-            let mut ipv4_packet = match Ipv4Packet::new_checked(&mut buf[..size]) {
-                Err(_) => continue,
-                Ok(p) => p,
-            };
+/// Feed the packets read from `tun` to `stack` and write its replies back.
+fn run_queue(mut tun: TunSocket, mut stack: Box<dyn Stack>, offload: bool) {
+    let header_len = if offload { VNET_HDR_LEN } else { 0 };
+    // Sized for the MTU, longer packets would be cut short or fail to read.
+    let mtu = tun.mtu().unwrap_or(0);
+    let mut buf = vec![
+        0;
+        if offload {
+            header_len + 65535
+        } else {
+            mtu.max(2000)
+        }
+    ];
 
-            if let Some(packet) = match ipv4_packet.protocol() {
-                IpProtocol::Udp => route_packet!(
-                    UdpPacket,
-                    ipv4_packet,
-                    session_manager,
-                    relay_addr,
-                    relay_port
-                ),
-                IpProtocol::Tcp => route_packet!(
-                    TcpPacket,
-                    ipv4_packet,
-                    session_manager,
-                    relay_addr,
-                    relay_port
-                ),
-                _ => continue,
-            } {
-                let _ = tun.write(packet.as_ref()).unwrap();
+    loop {
+        let size = match tun.read(&mut buf) {
+            Ok(size) => size,
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                eprintln!("drop packet read from tun: {}", e);
+                continue;
             }
+            Err(e) => panic!("read tun: {}", e),
+        };
+        if size == 0 {
+            eprintln!("tun read return 0, exit now");
+            break;
         }
-    });
-    Ok(SessionManager {
-        inner: sesion_mamager_clone,
-    })
+        if size <= header_len {
+            continue;
+        }
+        // Packets rewritten in place are still described by their header, those the stack
+        // built get an empty one.
+        let (header, packet) = buf[..size].split_at_mut(header_len);
+        let partial_checksum =
+            offload && header[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM == VIRTIO_NET_HDR_F_NEEDS_CSUM;
+        let header = &*header;
+        stack.input(packet, partial_checksum, &mut |reply: Reply| {
+            let written = if offload {
+                let header = if reply.generated {
+                    &[0; VNET_HDR_LEN][..]
+                } else {
+                    header
+                };
+                tun.write_vectored(&[IoSlice::new(header), IoSlice::new(reply.packet)])
+            } else {
+                tun.write(reply.packet)
+            };
+            match written {
+                Ok(_) => {}
+                // Longer than the MTU of a tun which checks it, or a GSO reply it refuses.
+                Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                    eprintln!("drop packet written to tun: {}", e);
+                }
+                Err(e) => panic!("write tun: {}", e),
+            }
+        });
+    }
 }
 
 pub struct Association {
-    pub src_addr: Ipv4Addr,
+    pub src_addr: IpAddr,
     pub src_port: u16,
-    pub dest_addr: Ipv4Addr,
+    pub dest_addr: IpAddr,
     pub dest_port: u16,
-    last_activity_ts: u64,
+    /// Updated under the read lock, by every packet of the session.
+    last_activity_ts: AtomicU64,
 }
 
 #[derive(Clone)]
 pub struct SessionManager {
     inner: Arc<RwLock<InnerSessionManager>>,
+    path_mtu: Arc<AtomicU32>,
+}
+
+/// An empty table, for running without a tun.
+impl Default for SessionManager {
+    fn default() -> Self {
+        SessionManager {
+            inner: Arc::new(RwLock::new(InnerSessionManager::new(BEGIN_PORT, END_PORT))),
+            path_mtu: Arc::new(AtomicU32::new(0)),
+        }
+    }
 }
 
 impl SessionManager {
+    /// The largest packet from the tun that makes it to the servers once relayed. TCP
+    /// handshakes get their MSS clamped to it, and UDP packets bigger than it are answered
+    /// with "fragmentation needed" or "packet too big". 0, the default, leaves packets be.
+    pub fn set_path_mtu(&self, mtu: u32) {
+        self.path_mtu.store(mtu, Ordering::Relaxed);
+    }
+
     pub fn get_by_port(&self, port: u16) -> Option<(SocketAddr, SocketAddr)> {
         let inner = self.inner.read();
         if let Some(assoc) = inner.map.get(&port) {
             Some((
-                SocketAddr::new(assoc.src_addr.into(), assoc.src_port),
-                SocketAddr::new(assoc.dest_addr.into(), assoc.dest_port),
+                SocketAddr::new(assoc.src_addr, assoc.src_port),
+                SocketAddr::new(assoc.dest_addr, assoc.dest_port),
             ))
         } else {
             None
         }
     }
+
+    /// Number of sessions in the NAT table, expired ones included until they are evicted.
+    pub fn len(&self) -> usize {
+        self.inner.read().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 struct InnerSessionManager {
     map: HashMap<u16, Association>,
-    reverse_map: HashMap<(Ipv4Addr, u16, Ipv4Addr, u16), u16>,
+    reverse_map: HashMap<(IpAddr, u16, IpAddr, u16), u16>,
     begin_port: u16,
     next_index: u16,
     available_ports: BitVec,
@@ -208,17 +290,34 @@ impl InnerSessionManager {
         self.map.get(&port)
     }
 
-    pub fn update_activity_for_port(&mut self, port: u16) {
-        if let Some(assoc) = self.map.get_mut(&port) {
-            assoc.last_activity_ts = now();
+    pub fn update_activity_for_port(&self, port: u16) {
+        if let Some(assoc) = self.map.get(&port) {
+            assoc.last_activity_ts.store(now(), Ordering::Relaxed);
         }
     }
 
+    /// The port of the session from `src_addr:src_port` to `dest_addr:dest_port`, marked
+    /// active, when there is one. Only new sessions need `get_or_create_session` and the
+    /// write lock.
+    pub fn touch_session(
+        &self,
+        src_addr: IpAddr,
+        src_port: u16,
+        dest_addr: IpAddr,
+        dest_port: u16,
+    ) -> Option<u16> {
+        let port = *self
+            .reverse_map
+            .get(&(src_addr, src_port, dest_addr, dest_port))?;
+        self.update_activity_for_port(port);
+        Some(port)
+    }
+
     pub fn get_or_create_session(
         &mut self,
-        src_addr: Ipv4Addr,
+        src_addr: IpAddr,
         src_port: u16,
-        dest_addr: Ipv4Addr,
+        dest_addr: IpAddr,
         dest_port: u16,
     ) -> u16 {
         if let Some(port) = self
@@ -238,7 +337,7 @@ impl InnerSessionManager {
                 src_port,
                 dest_addr,
                 dest_port,
-                last_activity_ts: now,
+                last_activity_ts: AtomicU64::new(now),
             },
         );
         self.reverse_map
@@ -249,7 +348,7 @@ impl InnerSessionManager {
         let available_ports = &mut self.available_ports;
         let begin_port = self.begin_port;
         map.retain(|port, assoc| {
-            let retain = now - assoc.last_activity_ts < EXPIRE_SECONDS;
+            let retain = now - assoc.last_activity_ts.load(Ordering::Relaxed) < EXPIRE_SECONDS;
             if !retain {
                 reverse_map.remove(&(
                     assoc.src_addr,
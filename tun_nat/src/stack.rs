@@ -0,0 +1,767 @@
+//! Network stacks handling the packets read from the tun.
+//!
+//! A stack gets every packet the system routes into the tun and decides what is written
+//! back. Connections have to end up at the relay listening on `relay_addr:relay_port`,
+//! with the session manager recording where they were originally going.
+//!
+//! Another stack is a `Stack` implementation, a `StackKind` variant built by `new_stack`,
+//! and a `TunStack` variant in the config mapped to it.
+use crate::InnerSessionManager;
+use parking_lot::RwLock;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{
+    Icmpv4Message, Icmpv4Packet, Icmpv6Message, Icmpv6Packet, IpAddress, IpProtocol, Ipv4Packet,
+    Ipv4Repr, Ipv6Packet, Ipv6Repr, TcpPacket, UdpPacket,
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// Stacks that can be selected with `tun_stack`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackKind {
+    /// Rewrite addresses and let the kernel stack terminate the connections.
+    Nat,
+}
+
+/// A packet a stack writes back to the tun.
+pub struct Reply<'a> {
+    pub packet: &'a [u8],
+    /// Whether the stack built the packet, like an ICMP error, instead of rewriting the
+    /// packet read in place. The virtio_net_hdr read with `tun_offload` only describes the
+    /// latter.
+    pub generated: bool,
+}
+
+pub trait Stack: Send {
+    /// Handle `packet` read from the tun, packets to write back to the tun go to `reply`.
+    ///
+    /// With `partial_checksum` the TCP or UDP checksum of `packet` only covers the pseudo
+    /// header, the kernel adds the rest when it offloads checksums. Replies are to keep it
+    /// that way.
+    fn input(&mut self, packet: &mut [u8], partial_checksum: bool, reply: &mut dyn FnMut(Reply));
+}
+
+pub(crate) fn new_stack(
+    kind: StackKind,
+    session_manager: Arc<RwLock<InnerSessionManager>>,
+    path_mtu: Arc<AtomicU32>,
+    relay_addr: Ipv4Addr,
+    relay_addr6: Option<Ipv6Addr>,
+    relay_port: u16,
+) -> Box<dyn Stack> {
+    match kind {
+        StackKind::Nat => Box::new(NatStack {
+            session_manager,
+            path_mtu,
+            relay_addr,
+            relay_addr6,
+            relay_port,
+        }),
+    }
+}
+
+fn to_std(addr: IpAddress) -> IpAddr {
+    match addr {
+        IpAddress::Ipv4(addr) => IpAddr::V4(addr.into()),
+        IpAddress::Ipv6(addr) => IpAddr::V6(addr.into()),
+        _ => unreachable!("packets have concrete addresses"),
+    }
+}
+
+fn from_std(addr: IpAddr) -> IpAddress {
+    match addr {
+        IpAddr::V4(addr) => IpAddress::Ipv4(addr.into()),
+        IpAddr::V6(addr) => IpAddress::Ipv6(addr.into()),
+    }
+}
+
+/// One's complement sum of the pseudo header of a TCP or UDP packet of `length` bytes, what
+/// its checksum field holds while the kernel is left to add the rest.
+fn pseudo_header_checksum(
+    src_addr: &IpAddress,
+    dst_addr: &IpAddress,
+    protocol: IpProtocol,
+    length: u32,
+) -> u16 {
+    let mut sum: u32 = 0;
+    for addr in &[src_addr, dst_addr] {
+        for word in addr.as_bytes().chunks(2) {
+            sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+        }
+    }
+    sum += u32::from(u8::from(protocol)) + (length >> 16) + (length & 0xffff);
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Rewrites the addresses and ports of the TCP or UDP packet in `$ip_packet`, an IPv4 or
+/// IPv6 packet as given by `$ip_ty`. Evaluates to whether the packet is to be written back,
+/// the IPv4 header checksum is left to the caller.
+macro_rules! route_packet {
+    ($packet_ty: tt, $protocol: expr, $ip_ty: ident, $ip_packet: expr, $partial_checksum: expr, $session_manager: expr, $relay_addr: expr, $relay_port: expr) => {{
+        let length = $ip_packet.payload_mut().len() as u32;
+        let src_addr = to_std(IpAddress::$ip_ty($ip_packet.src_addr()));
+        let dest_addr = to_std(IpAddress::$ip_ty($ip_packet.dst_addr()));
+        // Truncated segments are dropped.
+        match $packet_ty::new_checked($ip_packet.payload_mut()) {
+            Err(_) => false,
+            Ok(mut packet) => {
+                let src_port = packet.src_port();
+                let dest_port = packet.dst_port();
+
+                if let Some((new_src_addr, new_src_port, new_dst_addr, new_dest_port)) =
+                    if src_addr == $relay_addr && src_port == $relay_port {
+                        let session_manager = $session_manager.read();
+                        if let Some(assoc) = session_manager.get_by_port(dest_port) {
+                            Some((
+                                assoc.dest_addr,
+                                assoc.dest_port,
+                                assoc.src_addr,
+                                assoc.src_port,
+                            ))
+                        } else {
+                            None
+                        }
+                    } else {
+                        // Packets of known sessions, nearly all of them, share the read lock.
+                        let known = $session_manager
+                            .read()
+                            .touch_session(src_addr, src_port, dest_addr, dest_port);
+                        let port = known.unwrap_or_else(|| {
+                            $session_manager
+                                .write()
+                                .get_or_create_session(src_addr, src_port, dest_addr, dest_port)
+                        });
+                        Some((dest_addr, port, $relay_addr, $relay_port))
+                    }
+                {
+                    // Ports are shared by IPv4 and IPv6 sessions, so a reply can look up a
+                    // session of the other family.
+                    match (from_std(new_src_addr), from_std(new_dst_addr)) {
+                        (IpAddress::$ip_ty(new_src_addr), IpAddress::$ip_ty(new_dst_addr)) => {
+                            let new_src = IpAddress::$ip_ty(new_src_addr);
+                            let new_dst = IpAddress::$ip_ty(new_dst_addr);
+                            packet.set_src_port(new_src_port);
+                            packet.set_dst_port(new_dest_port);
+                            if $partial_checksum {
+                                packet.set_checksum(pseudo_header_checksum(
+                                    &new_src, &new_dst, $protocol, length,
+                                ));
+                            } else {
+                                packet.fill_checksum(&new_src, &new_dst);
+                            }
+                            $ip_packet.set_src_addr(new_src_addr);
+                            $ip_packet.set_dst_addr(new_dst_addr);
+                            true
+                        }
+                        _ => false,
+                    }
+                } else {
+                    false
+                }
+            }
+        }
+    }};
+}
+
+/// Turns the ICMP echo request in `packet` into its reply, false for other messages.
+fn echo_reply_v4<T: AsRef<[u8]> + AsMut<[u8]>>(packet: &mut Ipv4Packet<T>) -> bool {
+    {
+        let mut icmp = match Icmpv4Packet::new_checked(packet.payload_mut()) {
+            Ok(icmp) => icmp,
+            Err(_) => return false,
+        };
+        if icmp.msg_type() != Icmpv4Message::EchoRequest {
+            return false;
+        }
+        icmp.set_msg_type(Icmpv4Message::EchoReply);
+        icmp.fill_checksum();
+    }
+    let (src_addr, dst_addr) = (packet.src_addr(), packet.dst_addr());
+    packet.set_src_addr(dst_addr);
+    packet.set_dst_addr(src_addr);
+    packet.set_hop_limit(64);
+    true
+}
+
+/// Turns the ICMPv6 echo request in `packet` into its reply, false for other messages.
+fn echo_reply_v6<T: AsRef<[u8]> + AsMut<[u8]>>(packet: &mut Ipv6Packet<T>) -> bool {
+    let (src_addr, dst_addr) = (packet.src_addr(), packet.dst_addr());
+    {
+        let mut icmp = match Icmpv6Packet::new_checked(packet.payload_mut()) {
+            Ok(icmp) => icmp,
+            Err(_) => return false,
+        };
+        if icmp.msg_type() != Icmpv6Message::EchoRequest {
+            return false;
+        }
+        icmp.set_msg_type(Icmpv6Message::EchoReply);
+        icmp.fill_checksum(&IpAddress::Ipv6(dst_addr), &IpAddress::Ipv6(src_addr));
+    }
+    packet.set_src_addr(dst_addr);
+    packet.set_dst_addr(src_addr);
+    packet.set_hop_limit(64);
+    true
+}
+
+/// Whether the TCP or UDP `segment` from `src_addr` is sent by the relay, to a client.
+fn from_relay(src_addr: IpAddr, segment: &[u8], relay_addr: IpAddr, relay_port: u16) -> bool {
+    src_addr == relay_addr && segment.get(..2) == Some(&relay_port.to_be_bytes()[..])
+}
+
+/// Lowers the MSS option of the TCP SYN in `segment` to `mss`, so neither end sends
+/// segments bigger than the path carries. The checksum is left to the caller.
+fn clamp_mss(segment: &mut [u8], mss: u16) {
+    if segment.len() < 20 || segment[13] & 0x02 == 0 {
+        return;
+    }
+    let header_len = usize::from(segment[12] >> 4) * 4;
+    let options = match segment.get_mut(20..header_len) {
+        Some(options) => options,
+        None => return,
+    };
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            0 => return,
+            1 => i += 1,
+            kind => {
+                let len = match options.get(i + 1) {
+                    Some(&len) if len >= 2 => usize::from(len),
+                    _ => return,
+                };
+                if kind == 2 && len == 4 && i + 4 <= options.len() {
+                    let current = u16::from_be_bytes([options[i + 2], options[i + 3]]);
+                    if current > mss {
+                        options[i + 2..i + 4].copy_from_slice(&mss.to_be_bytes());
+                    }
+                    return;
+                }
+                i += len;
+            }
+        }
+    }
+}
+
+/// The ICMP "fragmentation needed" answer to the IPv4 `packet`, when it is bigger than `mtu`
+/// and must not be fragmented. It quotes the header and the first 8 bytes of the payload, as
+/// hosts match it to their socket by them.
+///
+/// It comes from the destination of `packet`, as if a router on the way sent it. The client
+/// may be the tun itself, which drops errors claiming to come from its own address.
+fn frag_needed_v4<T: AsRef<[u8]>>(packet: &Ipv4Packet<T>, mtu: u32) -> Option<Vec<u8>> {
+    let total_len = usize::from(packet.total_len());
+    if !packet.dont_frag() || total_len as u32 <= mtu {
+        return None;
+    }
+    let quoted = &packet.as_ref()[..(usize::from(packet.header_len()) + 8).min(total_len)];
+    let ip = Ipv4Repr {
+        src_addr: packet.dst_addr(),
+        dst_addr: packet.src_addr(),
+        protocol: IpProtocol::Icmp,
+        payload_len: 8 + quoted.len(),
+        hop_limit: 64,
+    };
+    let mut buf = vec![0; ip.buffer_len() + ip.payload_len];
+    ip.emit(
+        &mut Ipv4Packet::new_unchecked(&mut buf),
+        &ChecksumCapabilities::default(),
+    );
+    let icmp = &mut buf[ip.buffer_len()..];
+    icmp[6..8].copy_from_slice(&(mtu.min(u32::from(u16::MAX)) as u16).to_be_bytes());
+    icmp[8..].copy_from_slice(quoted);
+    let mut icmp = Icmpv4Packet::new_unchecked(icmp);
+    icmp.set_msg_type(Icmpv4Message::DstUnreachable);
+    icmp.set_msg_code(4);
+    icmp.fill_checksum();
+    Some(buf)
+}
+
+/// The ICMPv6 "packet too big" answer to `packet`, when it is bigger than `mtu`, from its
+/// destination like `frag_needed_v4`. IPv6 links carry at least 1280 bytes, so smaller
+/// values are not signalled, and the original packet is quoted as far as the answer stays
+/// within that.
+fn packet_too_big_v6<T: AsRef<[u8]>>(packet: &Ipv6Packet<T>, mtu: u32) -> Option<Vec<u8>> {
+    let total_len = 40 + usize::from(packet.payload_len());
+    if mtu < 1280 || total_len as u32 <= mtu {
+        return None;
+    }
+    let quoted = &packet.as_ref()[..total_len.min(1280 - 48)];
+    let src_addr = packet.dst_addr();
+    let dst_addr = packet.src_addr();
+    let ip = Ipv6Repr {
+        src_addr,
+        dst_addr,
+        next_header: IpProtocol::Icmpv6,
+        payload_len: 8 + quoted.len(),
+        hop_limit: 64,
+    };
+    let mut buf = vec![0; ip.buffer_len() + ip.payload_len];
+    ip.emit(&mut Ipv6Packet::new_unchecked(&mut buf));
+    let icmp = &mut buf[ip.buffer_len()..];
+    icmp[4..8].copy_from_slice(&mtu.to_be_bytes());
+    icmp[8..].copy_from_slice(quoted);
+    let mut icmp = Icmpv6Packet::new_unchecked(icmp);
+    icmp.set_msg_type(Icmpv6Message::PktTooBig);
+    icmp.set_msg_code(0);
+    icmp.fill_checksum(&IpAddress::Ipv6(src_addr), &IpAddress::Ipv6(dst_addr));
+    Some(buf)
+}
+
+/// Sends packets from clients on to the relay, as if they came from the target, and
+/// replies of the relay back to the client. The source port of each connection is
+/// replaced by a port of the session manager, which maps it back to the real addresses.
+/// IPv6 packets go to the relay on `relay_addr6`, and are dropped when there is none.
+///
+/// Echo requests are answered by the stack itself, whatever their destination, so `ping`
+/// shows the tun works. The round trip is to seeker, not to the target.
+///
+/// With a `path_mtu` set, the MSS of TCP handshakes from clients is clamped to it, and
+/// their UDP packets bigger than it are answered with "fragmentation needed" or "packet
+/// too big" instead of being relayed, so the sender lowers its packet size rather than
+/// stalling.
+struct NatStack {
+    session_manager: Arc<RwLock<InnerSessionManager>>,
+    path_mtu: Arc<AtomicU32>,
+    relay_addr: Ipv4Addr,
+    relay_addr6: Option<Ipv6Addr>,
+    relay_port: u16,
+}
+
+impl NatStack {
+    fn input_v4(
+        &mut self,
+        packet: &mut [u8],
+        partial_checksum: bool,
+        reply: &mut dyn FnMut(Reply),
+    ) {
+        let mut ipv4_packet = match Ipv4Packet::new_checked(packet) {
+            Err(_) => return,
+            Ok(p) => p,
+        };
+        let session_manager = &self.session_manager;
+        let relay_addr = IpAddr::V4(self.relay_addr);
+        let relay_port = self.relay_port;
+        let path_mtu = self.path_mtu.load(Ordering::Relaxed);
+        let src_addr = IpAddr::V4(ipv4_packet.src_addr().into());
+        if path_mtu != 0 && !from_relay(src_addr, ipv4_packet.payload(), relay_addr, relay_port) {
+            match ipv4_packet.protocol() {
+                IpProtocol::Udp => {
+                    if let Some(icmp) = frag_needed_v4(&ipv4_packet, path_mtu) {
+                        reply(Reply {
+                            packet: &icmp,
+                            generated: true,
+                        });
+                        return;
+                    }
+                }
+                IpProtocol::Tcp => clamp_mss(
+                    ipv4_packet.payload_mut(),
+                    path_mtu.saturating_sub(40).min(u32::from(u16::MAX)) as u16,
+                ),
+                _ => {}
+            }
+        }
+        let routed = match ipv4_packet.protocol() {
+            IpProtocol::Udp => route_packet!(
+                UdpPacket,
+                IpProtocol::Udp,
+                Ipv4,
+                ipv4_packet,
+                partial_checksum,
+                session_manager,
+                relay_addr,
+                relay_port
+            ),
+            IpProtocol::Tcp => route_packet!(
+                TcpPacket,
+                IpProtocol::Tcp,
+                Ipv4,
+                ipv4_packet,
+                partial_checksum,
+                session_manager,
+                relay_addr,
+                relay_port
+            ),
+            IpProtocol::Icmp => echo_reply_v4(&mut ipv4_packet),
+            _ => return,
+        };
+        if routed {
+            ipv4_packet.fill_checksum();
+            reply(Reply {
+                packet: ipv4_packet.as_ref(),
+                generated: false,
+            });
+        }
+    }
+
+    /// Only packets carrying TCP or UDP right after the fixed header are handled, extension
+    /// headers are rare on the local link the tun is.
+    fn input_v6(
+        &mut self,
+        packet: &mut [u8],
+        partial_checksum: bool,
+        reply: &mut dyn FnMut(Reply),
+    ) {
+        let relay_addr6 = match self.relay_addr6 {
+            Some(addr) => addr,
+            None => return,
+        };
+        let relay_addr = IpAddr::V6(relay_addr6);
+        let mut ipv6_packet = match Ipv6Packet::new_checked(packet) {
+            Err(_) => return,
+            Ok(p) => p,
+        };
+        let session_manager = &self.session_manager;
+        let relay_port = self.relay_port;
+        let path_mtu = self.path_mtu.load(Ordering::Relaxed);
+        let src_addr = IpAddr::V6(ipv6_packet.src_addr().into());
+        if path_mtu != 0 && !from_relay(src_addr, ipv6_packet.payload(), relay_addr, relay_port) {
+            match ipv6_packet.next_header() {
+                IpProtocol::Udp => {
+                    if let Some(icmp) = packet_too_big_v6(&ipv6_packet, path_mtu) {
+                        reply(Reply {
+                            packet: &icmp,
+                            generated: true,
+                        });
+                        return;
+                    }
+                }
+                IpProtocol::Tcp => clamp_mss(
+                    ipv6_packet.payload_mut(),
+                    path_mtu.saturating_sub(60).min(u32::from(u16::MAX)) as u16,
+                ),
+                _ => {}
+            }
+        }
+        let routed = match ipv6_packet.next_header() {
+            IpProtocol::Udp => route_packet!(
+                UdpPacket,
+                IpProtocol::Udp,
+                Ipv6,
+                ipv6_packet,
+                partial_checksum,
+                session_manager,
+                relay_addr,
+                relay_port
+            ),
+            IpProtocol::Tcp => route_packet!(
+                TcpPacket,
+                IpProtocol::Tcp,
+                Ipv6,
+                ipv6_packet,
+                partial_checksum,
+                session_manager,
+                relay_addr,
+                relay_port
+            ),
+            IpProtocol::Icmpv6 => echo_reply_v6(&mut ipv6_packet),
+            _ => return,
+        };
+        if routed {
+            reply(Reply {
+                packet: ipv6_packet.as_ref(),
+                generated: false,
+            });
+        }
+    }
+}
+
+impl Stack for NatStack {
+    fn input(&mut self, packet: &mut [u8], partial_checksum: bool, reply: &mut dyn FnMut(Reply)) {
+        match packet.first().map(|b| b >> 4) {
+            Some(4) => self.input_v4(packet, partial_checksum, reply),
+            Some(6) => self.input_v6(packet, partial_checksum, reply),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::wire::{Icmpv4Repr, Ipv4Address, Ipv6Address, UdpRepr};
+
+    fn udp6(src: &str, src_port: u16, dst: &str, dst_port: u16) -> Vec<u8> {
+        let src_addr = Ipv6Address::from(src.parse::<Ipv6Addr>().unwrap());
+        let dst_addr = Ipv6Address::from(dst.parse::<Ipv6Addr>().unwrap());
+        let udp = UdpRepr {
+            src_port,
+            dst_port,
+            payload: b"ping",
+        };
+        let ip = Ipv6Repr {
+            src_addr,
+            dst_addr,
+            next_header: IpProtocol::Udp,
+            payload_len: udp.buffer_len(),
+            hop_limit: 64,
+        };
+        let mut buf = vec![0; ip.buffer_len() + udp.buffer_len()];
+        let mut ip_packet = Ipv6Packet::new_unchecked(&mut buf);
+        ip.emit(&mut ip_packet);
+        udp.emit(
+            &mut UdpPacket::new_unchecked(ip_packet.payload_mut()),
+            &IpAddress::Ipv6(src_addr),
+            &IpAddress::Ipv6(dst_addr),
+            &ChecksumCapabilities::default(),
+        );
+        buf
+    }
+
+    /// Source and destination of the UDP packet in `packet`, checking its checksum.
+    fn endpoints(packet: &[u8]) -> ((Ipv6Addr, u16), (Ipv6Addr, u16)) {
+        let ip = Ipv6Packet::new_checked(packet).unwrap();
+        let udp = UdpPacket::new_checked(ip.payload()).unwrap();
+        assert!(udp.verify_checksum(
+            &IpAddress::Ipv6(ip.src_addr()),
+            &IpAddress::Ipv6(ip.dst_addr())
+        ));
+        (
+            (ip.src_addr().into(), udp.src_port()),
+            (ip.dst_addr().into(), udp.dst_port()),
+        )
+    }
+
+    /// Add the rest of the UDP packet in `packet` to the pseudo header sum in its checksum,
+    /// like the kernel does with offloaded checksums.
+    fn complete_checksum(packet: &mut [u8]) {
+        let mut ip = Ipv6Packet::new_checked(packet).unwrap();
+        let segment = ip.payload_mut();
+        let mut sum: u32 = segment
+            .chunks(2)
+            .map(|w| u32::from(u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)])))
+            .sum();
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        segment[6..8].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+    }
+
+    #[test]
+    fn test_nat_partial_checksum() {
+        let session_manager = Arc::new(RwLock::new(InnerSessionManager::new(50000, 50010)));
+        let relay: Ipv6Addr = "fd00::1".parse().unwrap();
+        let mut stack = new_stack(
+            StackKind::Nat,
+            session_manager,
+            Arc::new(AtomicU32::new(0)),
+            Ipv4Addr::new(11, 0, 0, 1),
+            Some(relay),
+            1300,
+        );
+
+        let mut packet = udp6("fd00::2", 5353, "2001:db8::1", 443);
+        let mut ip = Ipv6Packet::new_checked(&mut packet[..]).unwrap();
+        let pseudo = pseudo_header_checksum(
+            &IpAddress::Ipv6(ip.src_addr()),
+            &IpAddress::Ipv6(ip.dst_addr()),
+            IpProtocol::Udp,
+            u32::from(ip.payload_len()),
+        );
+        UdpPacket::new_checked(ip.payload_mut())
+            .unwrap()
+            .set_checksum(pseudo);
+
+        let mut out = vec![];
+        stack.input(&mut packet, true, &mut |r| out = r.packet.to_vec());
+        complete_checksum(&mut out);
+        let ((src, _), dst) = endpoints(&out);
+        assert_eq!(src, "2001:db8::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(dst, (relay, 1300));
+    }
+
+    #[test]
+    fn test_nat_ipv6() {
+        let session_manager = Arc::new(RwLock::new(InnerSessionManager::new(50000, 50010)));
+        let relay: Ipv6Addr = "fd00::1".parse().unwrap();
+        let client: Ipv6Addr = "fd00::2".parse().unwrap();
+        let target: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut stack = new_stack(
+            StackKind::Nat,
+            session_manager,
+            Arc::new(AtomicU32::new(0)),
+            Ipv4Addr::new(11, 0, 0, 1),
+            Some(relay),
+            1300,
+        );
+
+        let mut out = vec![];
+        let mut packet = udp6("fd00::2", 5353, "2001:db8::1", 443);
+        stack.input(&mut packet, false, &mut |r| out = r.packet.to_vec());
+        let ((src, port), dst) = endpoints(&out);
+        assert_eq!(src, target);
+        assert_eq!(dst, (relay, 1300));
+
+        let mut packet = udp6("fd00::1", 1300, "2001:db8::1", port);
+        stack.input(&mut packet, false, &mut |r| out = r.packet.to_vec());
+        assert_eq!(endpoints(&out), ((target, 443), (client, 5353)));
+
+        // Without a relay address IPv6 packets are dropped.
+        let mut stack = new_stack(
+            StackKind::Nat,
+            Arc::new(RwLock::new(InnerSessionManager::new(50000, 50010))),
+            Arc::new(AtomicU32::new(0)),
+            Ipv4Addr::new(11, 0, 0, 1),
+            None,
+            1300,
+        );
+        let mut written = false;
+        let mut packet = udp6("fd00::2", 5353, "2001:db8::1", 443);
+        stack.input(&mut packet, false, &mut |_| written = true);
+        assert!(!written);
+    }
+
+    #[test]
+    fn test_echo_reply() {
+        let session_manager = Arc::new(RwLock::new(InnerSessionManager::new(50000, 50010)));
+        let mut stack = new_stack(
+            StackKind::Nat,
+            session_manager,
+            Arc::new(AtomicU32::new(0)),
+            Ipv4Addr::new(11, 0, 0, 1),
+            None,
+            1300,
+        );
+        let client = Ipv4Address::new(11, 0, 0, 1);
+        let target = Ipv4Address::new(8, 8, 8, 8);
+        let icmp = Icmpv4Repr::EchoRequest {
+            ident: 1,
+            seq_no: 7,
+            data: b"ping",
+        };
+        let ip = Ipv4Repr {
+            src_addr: client,
+            dst_addr: target,
+            protocol: IpProtocol::Icmp,
+            payload_len: icmp.buffer_len(),
+            hop_limit: 64,
+        };
+        let checksum = ChecksumCapabilities::default();
+        let mut packet = vec![0; ip.buffer_len() + icmp.buffer_len()];
+        let mut ip_packet = Ipv4Packet::new_unchecked(&mut packet[..]);
+        ip.emit(&mut ip_packet, &checksum);
+        icmp.emit(
+            &mut Icmpv4Packet::new_unchecked(ip_packet.payload_mut()),
+            &checksum,
+        );
+
+        let mut replies = vec![];
+        stack.input(&mut packet, false, &mut |r| replies.push(r.packet.to_vec()));
+        assert_eq!(replies.len(), 1);
+        let ip_packet = Ipv4Packet::new_checked(&replies[0][..]).unwrap();
+        assert!(ip_packet.verify_checksum());
+        assert_eq!(ip_packet.src_addr(), target);
+        assert_eq!(ip_packet.dst_addr(), client);
+        let icmp_packet = Icmpv4Packet::new_checked(ip_packet.payload()).unwrap();
+        assert!(icmp_packet.verify_checksum());
+        assert_eq!(icmp_packet.msg_type(), Icmpv4Message::EchoReply);
+        assert_eq!(icmp_packet.echo_seq_no(), 7);
+        assert_eq!(icmp_packet.data(), b"ping");
+    }
+
+    #[test]
+    fn test_clamp_mss() {
+        // A SYN with a NOP, an MSS of 1460 and the window scale option.
+        let mut segment = vec![0; 32];
+        segment[12] = 8 << 4;
+        segment[13] = 0x02;
+        segment[20..32].copy_from_slice(&[1, 2, 4, 0x05, 0xb4, 3, 3, 7, 0, 0, 0, 0]);
+        clamp_mss(&mut segment, 1360);
+        assert_eq!(&segment[21..25], &[2, 4, 0x05, 0x50]);
+        // A bigger limit leaves it be, as do segments other than SYNs.
+        clamp_mss(&mut segment, 1400);
+        assert_eq!(&segment[23..25], &[0x05, 0x50]);
+        segment[13] = 0x10;
+        clamp_mss(&mut segment, 1000);
+        assert_eq!(&segment[23..25], &[0x05, 0x50]);
+    }
+
+    /// A UDP packet of `client` to 8.8.8.8:443 with the don't fragment bit.
+    fn dont_frag_udp(client: Ipv4Address) -> Vec<u8> {
+        let udp = UdpRepr {
+            src_port: 5353,
+            dst_port: 443,
+            payload: &[0; 100],
+        };
+        let ip = Ipv4Repr {
+            src_addr: client,
+            dst_addr: Ipv4Address::new(8, 8, 8, 8),
+            protocol: IpProtocol::Udp,
+            payload_len: udp.buffer_len(),
+            hop_limit: 64,
+        };
+        let checksum = ChecksumCapabilities::default();
+        let mut packet = vec![0; ip.buffer_len() + udp.buffer_len()];
+        let mut ip_packet = Ipv4Packet::new_unchecked(&mut packet[..]);
+        ip.emit(&mut ip_packet, &checksum);
+        ip_packet.set_dont_frag(true);
+        ip_packet.fill_checksum();
+        udp.emit(
+            &mut UdpPacket::new_unchecked(ip_packet.payload_mut()),
+            &IpAddress::Ipv4(client),
+            &IpAddress::Ipv4(ip.dst_addr),
+            &checksum,
+        );
+        packet
+    }
+
+    #[test]
+    fn test_frag_needed() {
+        let session_manager = Arc::new(RwLock::new(InnerSessionManager::new(50000, 50010)));
+        let path_mtu = Arc::new(AtomicU32::new(0));
+        let mut stack = new_stack(
+            StackKind::Nat,
+            session_manager,
+            path_mtu.clone(),
+            Ipv4Addr::new(11, 0, 0, 1),
+            None,
+            1300,
+        );
+        let target = Ipv4Address::new(8, 8, 8, 8);
+        // A client behind the tun, and one on the host with the address of the tun.
+        for &client in &[Ipv4Address::new(11, 0, 0, 2), Ipv4Address::new(11, 0, 0, 1)] {
+            let packet = dont_frag_udp(client);
+            path_mtu.store(100, Ordering::Relaxed);
+            let mut replies = vec![];
+            let mut generated = false;
+            stack.input(&mut packet.clone(), false, &mut |r| {
+                replies.push(r.packet.to_vec());
+                generated = r.generated;
+            });
+            assert_eq!(replies.len(), 1);
+            assert!(generated);
+            let ip_packet = Ipv4Packet::new_checked(&replies[0][..]).unwrap();
+            assert!(ip_packet.verify_checksum());
+            assert_eq!(ip_packet.src_addr(), target);
+            assert_eq!(ip_packet.dst_addr(), client);
+            let icmp_packet = Icmpv4Packet::new_checked(ip_packet.payload()).unwrap();
+            assert!(icmp_packet.verify_checksum());
+            assert_eq!(icmp_packet.msg_type(), Icmpv4Message::DstUnreachable);
+            assert_eq!(icmp_packet.msg_code(), 4);
+            assert_eq!(&ip_packet.payload()[6..8], &100u16.to_be_bytes());
+            assert_eq!(icmp_packet.data(), &packet[..28]);
+        }
+        let mut packet = dont_frag_udp(Ipv4Address::new(11, 0, 0, 2));
+        let mut replies = vec![];
+
+        // Packets that fit are relayed.
+        path_mtu.store(1500, Ordering::Relaxed);
+        replies.clear();
+        stack.input(&mut packet, false, &mut |r| replies.push(r.packet.to_vec()));
+        let ip_packet = Ipv4Packet::new_checked(&replies[0][..]).unwrap();
+        assert_eq!(ip_packet.protocol(), IpProtocol::Udp);
+
+        // Packets of the relay to clients are not checked, there is no session for this one.
+        path_mtu.store(100, Ordering::Relaxed);
+        replies.clear();
+        let mut ip_packet = Ipv4Packet::new_unchecked(&mut packet[..]);
+        ip_packet.set_src_addr(Ipv4Address::new(11, 0, 0, 1));
+        ip_packet.payload_mut()[..2].copy_from_slice(&1300u16.to_be_bytes());
+        stack.input(&mut packet, false, &mut |r| replies.push(r.packet.to_vec()));
+        assert!(replies.is_empty());
+    }
+}
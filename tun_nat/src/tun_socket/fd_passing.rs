@@ -0,0 +1,98 @@
+//! Receiving the tun from the process that opened it, like the Android `VpnService` of
+//! an app running seeker, which can not open tun devices itself.
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+/// Connect to the unix socket at `path` and receive a file descriptor sent with
+/// `SCM_RIGHTS`, along with at least one byte of data.
+pub fn receive_fd(path: &str) -> Result<RawFd> {
+    let stream = UnixStream::connect(path)?;
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    if unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    if cmsg.is_null()
+        || unsafe { (*cmsg).cmsg_level } != libc::SOL_SOCKET
+        || unsafe { (*cmsg).cmsg_type } != libc::SCM_RIGHTS
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("no file descriptor received from {}", path),
+        ));
+    }
+    Ok(unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    fn send_fd(stream: &UnixStream, fd: RawFd) {
+        let mut data = [1u8];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+        let mut control = vec![0u8; space];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+            assert!(libc::sendmsg(stream.as_raw_fd(), &msg, 0) > 0);
+        }
+    }
+
+    #[test]
+    fn test_receive_fd() {
+        let dir = std::env::temp_dir().join(format!("seeker-fd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tun.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let file_path = dir.join("tun");
+        File::create(&file_path).unwrap().write_all(b"tun").unwrap();
+        let sent = File::open(&file_path).unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            send_fd(&stream, sent.as_raw_fd());
+        });
+
+        let fd = receive_fd(path.to_str().unwrap()).unwrap();
+        handle.join().unwrap();
+        let mut content = String::new();
+        let mut received = unsafe { File::from_raw_fd(fd) };
+        received.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "tun");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(receive_fd("/nonexistent/seeker.sock").is_err());
+    }
+}
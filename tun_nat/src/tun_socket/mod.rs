@@ -2,8 +2,21 @@
 #[path = "tun_darwin.rs"]
 pub mod tun;
 
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "android"))]
 #[path = "tun_linux.rs"]
 pub mod tun;
 
+#[cfg(windows)]
+#[path = "tun_windows.rs"]
+pub mod tun;
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+#[path = "tun_bsd.rs"]
+pub mod tun;
+
+#[cfg(unix)]
+mod fd_passing;
+
+#[cfg(unix)]
+pub use self::fd_passing::receive_fd;
 pub use self::tun::TunSocket;
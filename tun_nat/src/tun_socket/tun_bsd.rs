@@ -0,0 +1,220 @@
+//! The tun device on FreeBSD and OpenBSD: `/dev/tunN`, a character device rather than the
+//! control socket of macOS. Packets carry the same 4 byte address family prefix, on FreeBSD
+//! once `TUNSIFHEAD` turned it on, on OpenBSD always.
+use libc::*;
+use std::io::{self, Error, ErrorKind, Read, Result, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+#[cfg(target_os = "freebsd")]
+const TUNSIFHEAD: c_ulong = 0x8004_7460;
+#[cfg(target_os = "freebsd")]
+const SIOCGIFMTU: c_ulong = 0xc020_6933;
+#[cfg(target_os = "openbsd")]
+const SIOCGIFMTU: c_ulong = 0xc020_697e;
+
+#[repr(C)]
+struct ifreq {
+    ifr_name: [c_uchar; IF_NAMESIZE],
+    ifr_mtu: c_int,
+    _pad: [u8; 12],
+}
+
+#[derive(Debug)]
+pub struct TunSocket {
+    fd: RawFd,
+    name: String,
+}
+
+impl Drop for TunSocket {
+    fn drop(&mut self) {
+        unsafe { close(self.fd) };
+    }
+}
+
+impl AsRawFd for TunSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+/// The BSDs number their tun devices, they are named `tunN`.
+pub fn parse_tun_name(name: &str) -> Result<u32> {
+    name.strip_prefix("tun")
+        .and_then(|idx| idx.parse::<u32>().ok())
+        .ok_or_else(|| io::ErrorKind::NotFound.into())
+}
+
+impl TunSocket {
+    pub fn new(name: &str) -> Result<TunSocket> {
+        parse_tun_name(name)?;
+        let path = std::ffi::CString::new(format!("/dev/{}", name))?;
+        let fd = match unsafe { open(path.as_ptr(), O_RDWR | O_CLOEXEC) } {
+            -1 => return Err(Error::last_os_error()),
+            fd => fd,
+        };
+
+        #[cfg(target_os = "freebsd")]
+        {
+            let on: c_int = 1;
+            if unsafe { ioctl(fd, TUNSIFHEAD, &on) } < 0 {
+                unsafe { close(fd) };
+                return Err(Error::last_os_error());
+            }
+        }
+
+        Ok(TunSocket {
+            fd,
+            name: name.to_string(),
+        })
+    }
+
+    pub fn with_offload(_name: &str) -> Result<TunSocket> {
+        Err(Error::new(
+            ErrorKind::Other,
+            "tun_offload is only supported on Linux",
+        ))
+    }
+
+    pub fn with_queues(_name: &str, _queues: usize, _offload: bool) -> Result<Vec<TunSocket>> {
+        Err(Error::new(
+            ErrorKind::Other,
+            "tun_queues is only supported on Linux",
+        ))
+    }
+
+    /// Take over the tun device open as `fd`, named `name`.
+    pub fn from_fd(fd: RawFd, name: &str) -> Result<TunSocket> {
+        Ok(TunSocket {
+            fd,
+            name: name.to_string(),
+        })
+    }
+
+    pub fn name(&self) -> Result<String> {
+        Ok(self.name.clone())
+    }
+
+    pub fn set_non_blocking(self) -> Result<TunSocket> {
+        match unsafe { fcntl(self.fd, F_GETFL) } {
+            -1 => Err(Error::last_os_error()),
+            flags => match unsafe { fcntl(self.fd, F_SETFL, flags | O_NONBLOCK) } {
+                -1 => Err(Error::last_os_error()),
+                _ => Ok(self),
+            },
+        }
+    }
+
+    /// Get the current MTU value
+    pub fn mtu(&self) -> Result<usize> {
+        let fd = match unsafe { socket(AF_INET, SOCK_DGRAM, 0) } {
+            -1 => return Err(Error::last_os_error()),
+            fd => fd,
+        };
+
+        let mut ifr = ifreq {
+            ifr_name: [0; IF_NAMESIZE],
+            ifr_mtu: 0,
+            _pad: [0; 12],
+        };
+        let iface_name = self.name.as_bytes();
+        ifr.ifr_name[..iface_name.len()].copy_from_slice(iface_name);
+
+        let ret = unsafe { ioctl(fd, SIOCGIFMTU, &mut ifr) };
+        unsafe { close(fd) };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(ifr.ifr_mtu as _)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut hdr = [0u8; 4];
+        let mut iov = [
+            iovec {
+                iov_base: hdr.as_mut_ptr() as _,
+                iov_len: hdr.len(),
+            },
+            iovec {
+                iov_base: buf.as_mut_ptr() as _,
+                iov_len: buf.len(),
+            },
+        ];
+        match unsafe { readv(self.fd, iov.as_mut_ptr(), iov.len() as _) } {
+            -1 => Err(Error::last_os_error()),
+            0 => Ok(0),
+            // A packet has to follow the address family.
+            n @ 1..=4 => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("short read of {} bytes from tun", n),
+            )),
+            n => Ok((n - 4) as usize),
+        }
+    }
+
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        let af = match buf.first().map(|b| b >> 4) {
+            Some(6) => AF_INET6,
+            _ => AF_INET,
+        };
+        let mut hdr = (af as u32).to_be_bytes();
+        let iov = [
+            iovec {
+                iov_base: hdr.as_mut_ptr() as _,
+                iov_len: hdr.len(),
+            },
+            iovec {
+                iov_base: buf.as_ptr() as _,
+                iov_len: buf.len(),
+            },
+        ];
+        match unsafe { writev(self.fd, iov.as_ptr(), iov.len() as _) } {
+            -1 => Err(Error::last_os_error()),
+            n => Ok((n as usize).saturating_sub(4)),
+        }
+    }
+}
+
+impl Read for TunSocket {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.recv(buf)
+    }
+}
+
+impl Write for TunSocket {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.send(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for &TunSocket {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.recv(buf)
+    }
+}
+
+impl Write for &TunSocket {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.send(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tun_name() {
+        assert_eq!(parse_tun_name("tun0").unwrap(), 0);
+        assert_eq!(parse_tun_name("tun12").unwrap(), 12);
+        assert!(parse_tun_name("tun").is_err());
+        assert!(parse_tun_name("utun4").is_err());
+    }
+}
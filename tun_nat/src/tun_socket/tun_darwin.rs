@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: BSD-3-Clause
 use libc::*;
 use std::io;
-use std::io::{Error, Read, Result, Write};
+use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::mem::size_of;
 use std::mem::size_of_val;
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -128,6 +128,25 @@ impl TunSocket {
         Ok(TunSocket { fd })
     }
 
+    pub fn with_offload(_name: &str) -> Result<TunSocket> {
+        Err(Error::new(
+            ErrorKind::Other,
+            "tun_offload is only supported on Linux",
+        ))
+    }
+
+    pub fn with_queues(_name: &str, _queues: usize, _offload: bool) -> Result<Vec<TunSocket>> {
+        Err(Error::new(
+            ErrorKind::Other,
+            "tun_queues is only supported on Linux",
+        ))
+    }
+
+    /// Take over the utun socket open as `fd`, its name is looked up from it.
+    pub fn from_fd(fd: RawFd, _name: &str) -> Result<TunSocket> {
+        Ok(TunSocket { fd })
+    }
+
     pub fn name(&self) -> Result<String> {
         let mut tunnel_name = [0u8; 256];
         let mut tunnel_name_len: socklen_t = tunnel_name.len() as u32;
@@ -212,7 +231,6 @@ impl TunSocket {
         }
     }
 
-    #[allow(dead_code)]
     pub fn write6(&self, src: &[u8]) -> Result<usize> {
         self.af_write(src, AF_INET6 as u8)
     }
@@ -273,7 +291,10 @@ impl Read for &TunSocket {
 
 impl Write for &TunSocket {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        self.af_write(buf, AF_INET as u8)
+        match buf.first().map(|b| b >> 4) {
+            Some(6) => self.write6(buf),
+            _ => self.af_write(buf, AF_INET as u8),
+        }
     }
 
     fn flush(&mut self) -> Result<()> {
@@ -2,10 +2,17 @@
 // SPDX-License-Identifier: BSD-3-Clause
 #![allow(dead_code)]
 use libc::*;
-use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::io::{Error, ErrorKind, IoSlice, Read, Result, Write};
 use std::os::unix::io::{AsRawFd, RawFd};
 
 const TUNSETIFF: u64 = 0x4004_54ca;
+const TUNGETIFF: u64 = 0x8004_54d2;
+const TUNSETOFFLOAD: u64 = 0x4004_54d0;
+const IFF_VNET_HDR: c_int = 0x4000;
+const IFF_MULTI_QUEUE: c_int = 0x0100;
+const TUN_F_CSUM: c_uint = 0x01;
+const TUN_F_TSO4: c_uint = 0x02;
+const TUN_F_TSO6: c_uint = 0x04;
 
 #[repr(C)]
 union IfrIfru {
@@ -55,6 +62,45 @@ impl AsRawFd for TunSocket {
 
 impl TunSocket {
     pub fn new(name: &str) -> Result<TunSocket> {
+        TunSocket::open(name, IFF_TUN | IFF_NO_PI)
+    }
+
+    /// Open the tun with TCP segmentation and checksum offload: the kernel hands over TCP
+    /// segments of up to 64 KB with their checksums left to complete, each packet read or
+    /// written comes after a `virtio_net_hdr` describing that.
+    pub fn with_offload(name: &str) -> Result<TunSocket> {
+        let tun = TunSocket::open(name, IFF_TUN | IFF_NO_PI | IFF_VNET_HDR)?;
+        tun.set_offload()?;
+        Ok(tun)
+    }
+
+    /// Open `queues` queues of a multi-queue tun, the kernel spreads flows over them by the
+    /// hash of their addresses and ports. With `offload` each is set up like `with_offload`.
+    pub fn with_queues(name: &str, queues: usize, offload: bool) -> Result<Vec<TunSocket>> {
+        let mut flags = IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE;
+        if offload {
+            flags |= IFF_VNET_HDR;
+        }
+        (0..queues)
+            .map(|_| {
+                let tun = TunSocket::open(name, flags)?;
+                if offload {
+                    tun.set_offload()?;
+                }
+                Ok(tun)
+            })
+            .collect()
+    }
+
+    fn set_offload(&self) -> Result<()> {
+        let offload = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6;
+        if unsafe { ioctl(self.fd, TUNSETOFFLOAD as _, offload as c_ulong) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn open(name: &str, flags: c_int) -> Result<TunSocket> {
         let fd = match unsafe { open(b"/dev/net/tun\0".as_ptr() as _, O_RDWR) } {
             -1 => return Err(Error::last_os_error()),
             fd => fd,
@@ -64,7 +110,7 @@ impl TunSocket {
         let mut ifr = ifreq {
             ifr_name: [0; IFNAMSIZ],
             ifr_ifru: IfrIfru {
-                ifru_flags: (IFF_TUN | IFF_NO_PI) as _,
+                ifru_flags: flags as _,
             },
         };
 
@@ -83,6 +129,25 @@ impl TunSocket {
         Ok(TunSocket { fd, name })
     }
 
+    /// Take over the tun device open as `fd`, named `name` unless the kernel knows better.
+    pub fn from_fd(fd: RawFd, name: &str) -> Result<TunSocket> {
+        let mut ifr = ifreq {
+            ifr_name: [0; IFNAMSIZ],
+            ifr_ifru: IfrIfru { ifru_flags: 0 },
+        };
+        let name = if unsafe { ioctl(fd, TUNGETIFF as _, &mut ifr) } < 0 {
+            name.to_string()
+        } else {
+            let len = ifr
+                .ifr_name
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(IFNAMSIZ);
+            String::from_utf8_lossy(&ifr.ifr_name[..len]).into_owned()
+        };
+        Ok(TunSocket { fd, name })
+    }
+
     pub fn name(&self) -> Result<String> {
         Ok(self.name.clone())
     }
@@ -140,6 +205,14 @@ impl Write for TunSocket {
         }
     }
 
+    /// A packet and the `virtio_net_hdr` in front of it are written together.
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+        match unsafe { writev(self.fd, bufs.as_ptr() as *const iovec, bufs.len() as _) } {
+            -1 => Ok(0),
+            n => Ok(n as usize),
+        }
+    }
+
     fn flush(&mut self) -> Result<()> {
         Ok(())
     }
@@ -0,0 +1,164 @@
+//! The tun device on Windows: a wintun adapter, created when seeker starts and removed
+//! when it exits. `wintun.dll` is loaded from the directory of the executable, so it ships
+//! next to `seeker.exe` in the installer.
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Adapters seeker creates are grouped under this pool name in the device manager.
+const POOL: &str = "Seeker";
+
+pub struct TunSocket {
+    name: String,
+    /// The adapter of `session`, kept open alongside it.
+    _adapter: Arc<wintun::Adapter>,
+    session: Arc<wintun::Session>,
+    /// The MTU of the adapter when last looked up.
+    mtu: AtomicUsize,
+}
+
+fn other<E: std::fmt::Display>(e: E) -> Error {
+    Error::new(ErrorKind::Other, e.to_string())
+}
+
+impl TunSocket {
+    pub fn new(name: &str) -> Result<TunSocket> {
+        let wintun = unsafe { wintun::load() }.map_err(|e| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("load wintun.dll, put it next to seeker.exe: {}", e),
+            )
+        })?;
+        let adapter = match wintun::Adapter::open(&wintun, name) {
+            Ok(adapter) => adapter,
+            Err(_) => wintun::Adapter::create(&wintun, POOL, name, None).map_err(other)?,
+        };
+        let session = Arc::new(
+            adapter
+                .start_session(wintun::MAX_RING_CAPACITY)
+                .map_err(other)?,
+        );
+        let mtu = sysconfig::get_mtu(name)? as usize;
+        Ok(TunSocket {
+            name: name.to_string(),
+            _adapter: adapter,
+            session,
+            mtu: AtomicUsize::new(mtu),
+        })
+    }
+
+    pub fn with_offload(_name: &str) -> Result<TunSocket> {
+        Err(Error::new(
+            ErrorKind::Other,
+            "tun_offload is only supported on Linux",
+        ))
+    }
+
+    pub fn with_queues(_name: &str, _queues: usize, _offload: bool) -> Result<Vec<TunSocket>> {
+        Err(Error::new(
+            ErrorKind::Other,
+            "tun_queues is only supported on Linux",
+        ))
+    }
+
+    /// wintun adapters are not file descriptors.
+    pub fn from_fd(_fd: i32, _name: &str) -> Result<TunSocket> {
+        Err(Error::new(
+            ErrorKind::Other,
+            "tun_fd is not supported on Windows",
+        ))
+    }
+
+    pub fn name(&self) -> Result<String> {
+        Ok(self.name.clone())
+    }
+
+    /// Reads block in wintun's own wait, there is no non-blocking mode.
+    pub fn set_non_blocking(self) -> Result<TunSocket> {
+        Ok(self)
+    }
+
+    pub fn mtu(&self) -> Result<usize> {
+        let mtu = sysconfig::get_mtu(&self.name)? as usize;
+        self.mtu.store(mtu, Ordering::Relaxed);
+        Ok(mtu)
+    }
+
+    /// Packets longer than `buf` are dropped with an `InvalidData` error.
+    fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let packet = self.session.receive_blocking().map_err(other)?;
+        let bytes = packet.bytes();
+        if bytes.len() > buf.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "packet of {} bytes longer than the {} byte buffer",
+                    bytes.len(),
+                    buf.len()
+                ),
+            ));
+        }
+        buf[..bytes.len()].copy_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    /// Packets longer than the MTU are refused with an `InvalidInput` error. The MTU is looked
+    /// up again before refusing one, it may have been raised since.
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        if buf.len() > self.mtu.load(Ordering::Relaxed) && buf.len() > self.mtu()? {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "packet of {} bytes longer than the mtu of {}",
+                    buf.len(),
+                    self.name
+                ),
+            ));
+        }
+        let len = u16::try_from(buf.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "packet longer than 65535 bytes"))?;
+        let mut packet = self.session.allocate_send_packet(len).map_err(other)?;
+        packet.bytes_mut().copy_from_slice(buf);
+        self.session.send_packet(packet);
+        Ok(buf.len())
+    }
+}
+
+impl Drop for TunSocket {
+    fn drop(&mut self) {
+        let _ = self.session.shutdown();
+    }
+}
+
+impl Read for TunSocket {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.recv(buf)
+    }
+}
+
+impl Write for TunSocket {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.send(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for &TunSocket {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.recv(buf)
+    }
+}
+
+impl Write for &TunSocket {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.send(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}